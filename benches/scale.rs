@@ -0,0 +1,91 @@
+//! Stress benchmark for the scheduling core.
+//!
+//! Registers tens of thousands of jobs and measures the throughput of
+//! loading them and of the queue operations done on every tick.
+//!
+//! Run with `cargo bench --bench scale`, optionally passing the number of jobs.
+
+use chrono::{Duration, Local};
+use std::time::Instant;
+use xcrond::event::EventQueue;
+use xcrond::{Cron, Job, JobId, JobSpec};
+
+const DEFAULT_JOBS: usize = 100_000;
+
+fn report(what: &str, n: usize, start: Instant) {
+    let elapsed = start.elapsed();
+    println!(
+        "{:<28} {:>8} ops in {:>10.2?} ({:>10.0} ops/s)",
+        what,
+        n,
+        elapsed,
+        n as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let n = std::env::args()
+        .skip(1)
+        .find_map(|a| a.parse().ok())
+        .unwrap_or(DEFAULT_JOBS);
+
+    // Spread the jobs over every second of the minute, as a busy host would
+    let specs: Vec<JobSpec> = (0..n)
+        .map(|i| {
+            JobSpec::new(
+                &format!("job {}", i),
+                "/bin/true",
+                &format!("{} * * * * *", i % 60),
+            )
+        })
+        .collect();
+
+    let start = Instant::now();
+    let mut c = Cron::builder().build().unwrap();
+    for res in c.add_jobs(specs.clone()) {
+        res.unwrap();
+    }
+    report("load (batched)", n, start);
+
+    let start = Instant::now();
+    for (i, _) in specs.iter().enumerate() {
+        c.remove_job(JobId::new(i as u64));
+    }
+    report("remove", n, start);
+
+    let start = Instant::now();
+    let jobs: Vec<Job> = (0..n)
+        .map(|i| {
+            Job::new(
+                JobId::new(i as u64),
+                format!("job {}", i),
+                "/bin/true".to_string(),
+                &format!("{} * * * * *", i % 60),
+                None,
+            )
+            .unwrap()
+        })
+        .collect();
+    report("parse", n, start);
+
+    let mut q = EventQueue::default();
+    let start = Instant::now();
+    for j in &jobs {
+        q.enqueue(j.clone());
+    }
+    report("enqueue", n, start);
+
+    // One tick: dequeue every due event and requeue its jobs
+    let start = Instant::now();
+    let mut requeued = 0;
+    while requeued < n {
+        let e = q.dequeue().unwrap();
+        for j in e.into_jobs() {
+            let next = j.get_next() + Duration::minutes(1);
+            q.enqueue(j.occurrence(Local::now(), next));
+            requeued += 1;
+        }
+    }
+    report("dequeue + requeue", n, start);
+    assert_eq!(q.len(), n);
+}
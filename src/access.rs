@@ -0,0 +1,113 @@
+//! Access control of the users' jobs, following traditional cron.
+//!
+//! If the allow file exists, only the users it lists may have jobs. Else if
+//! the deny file exists, every user but those it lists may. If neither
+//! exists, every user may. Root always may. Both files list one user name
+//! per line, lines starting with `#` are comments.
+
+use crate::error::{Result, XcrondError};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Default path of the allow file
+pub const ALLOW_FILE: &str = "/etc/xcrond.allow";
+/// Default path of the deny file
+pub const DENY_FILE: &str = "/etc/xcrond.deny";
+
+/// Access tells which users may have jobs
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Access {
+    allow: Option<HashSet<String>>,
+    deny: Option<HashSet<String>>,
+}
+
+impl Access {
+    /// load reads the allow and deny files, which may not exist
+    pub fn load(allow: &Path, deny: &Path) -> Result<Self> {
+        Ok(Access {
+            allow: read_users(allow)?,
+            deny: read_users(deny)?,
+        })
+    }
+
+    /// deny_all returns an access denying every user but root
+    pub fn deny_all() -> Self {
+        Access {
+            allow: Some(HashSet::new()),
+            deny: None,
+        }
+    }
+
+    /// permits tells whether `user` may have jobs
+    pub fn permits(&self, user: &str) -> bool {
+        if user == "root" {
+            return true;
+        }
+        match (&self.allow, &self.deny) {
+            (Some(allow), _) => allow.contains(user),
+            (None, Some(deny)) => !deny.contains(user),
+            (None, None) => true,
+        }
+    }
+
+    /// check fails if `user` may not have jobs
+    pub fn check(&self, user: &str) -> Result<()> {
+        if self.permits(user) {
+            Ok(())
+        } else {
+            Err(XcrondError::AccessDenied(user.to_string()))
+        }
+    }
+}
+
+/// read_users returns the users listed in the file at `path`, None if it
+/// doesn't exist
+fn read_users(path: &Path) -> Result<Option<HashSet<String>>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(users(&content))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(source) => Err(XcrondError::Io {
+            path: path.to_path_buf(),
+            source,
+        }),
+    }
+}
+
+fn users(content: &str) -> HashSet<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_cron() {
+        let open = Access::default();
+        assert!(open.permits("alice"));
+
+        let deny = Access {
+            allow: None,
+            deny: Some(users("# no jobs\nbob\n")),
+        };
+        assert!(deny.permits("alice"));
+        assert!(!deny.permits("bob"));
+
+        // The allow file wins over the deny file
+        let allow = Access {
+            allow: Some(users("alice\n")),
+            deny: Some(users("alice\n")),
+        };
+        assert!(allow.permits("alice"));
+        assert!(!allow.permits("bob"));
+        assert!(allow.permits("root"));
+        assert!(allow.check("bob").is_err());
+    }
+}
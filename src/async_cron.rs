@@ -0,0 +1,220 @@
+//! Async variant of the scheduler, running on a tokio runtime.
+//!
+//! Every job gets its own task sleeping on tokio timers until its next
+//! occurrence. Jobs are either commands spawned through `tokio::process`
+//! or async closures run as tasks on the runtime.
+//!
+//! ```no_run
+//! use xcrond::async_cron::AsyncCron;
+//! use xcrond::JobSpec;
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let mut c = AsyncCron::new();
+//!     c.add_job(JobSpec::new("touch", "/usr/bin/touch /tmp/1", "0 * * * * *"))
+//!         .unwrap();
+//!     c.add_task("hello", "0/10 * * * * *", || async { println!("hello") })
+//!         .unwrap();
+//!     c.start().join().await;
+//! }
+//! ```
+
+use crate::error::{Result, XcrondError};
+use crate::job::{split_words, time_until, upcoming, JobId, JobSpec};
+use chrono::Local;
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+// Longest sleep between two checks of the wall clock
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+type TaskFn = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Work is what an async job does on each occurrence
+#[derive(Clone)]
+enum Work {
+    /// spawn a process with the given arguments
+    Process(Vec<String>),
+    /// run an async task on the runtime
+    Task(TaskFn),
+}
+
+struct AsyncJob {
+    id: JobId,
+    name: String,
+    schedule: Schedule,
+    work: Work,
+}
+
+/// AsyncCron schedules jobs on a tokio runtime
+#[derive(Default)]
+pub struct AsyncCron {
+    timezone: Option<Tz>,
+    jobs: Vec<AsyncJob>,
+    next_id: u64,
+}
+
+impl AsyncCron {
+    pub fn new() -> Self {
+        AsyncCron::default()
+    }
+
+    /// timezone sets the timezone job schedules are evaluated in.
+    /// Defaults to the local timezone.
+    pub fn timezone(mut self, tz: Tz) -> Self {
+        self.timezone = Some(tz);
+        self
+    }
+
+    /// add_job registers a job spawning the spec's command on every occurrence
+    pub fn add_job(&mut self, spec: JobSpec) -> Result<JobId> {
+        if spec.cmd.trim().is_empty() {
+            return Err(XcrondError::EmptyCommand(spec.name));
+        }
+        let expr = spec.expression()?;
+        let args = split_words(&spec.cmd).map_err(|reason| XcrondError::InvalidCommand {
+            name: spec.name.clone(),
+            reason,
+        })?;
+        self.register(spec.id, spec.name, &expr, Work::Process(args))
+    }
+
+    /// add_task registers an async task run on every occurrence of the schedule
+    pub fn add_task<F, Fut>(&mut self, name: &str, schedule: &str, f: F) -> Result<JobId>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let task: TaskFn = Arc::new(move || Box::pin(f()));
+        self.register(None, name.to_string(), schedule, Work::Task(task))
+    }
+
+    fn register(
+        &mut self,
+        id: Option<JobId>,
+        name: String,
+        expr: &str,
+        work: Work,
+    ) -> Result<JobId> {
+        let schedule = match Schedule::from_str(expr) {
+            Ok(s) => s,
+            Err(err) => {
+                return Err(XcrondError::InvalidSchedule {
+                    name,
+                    expr: expr.to_string(),
+                    reason: err.to_string(),
+                });
+            }
+        };
+
+        let id = match id {
+            Some(id) if self.jobs.iter().any(|j| j.id == id) => {
+                return Err(XcrondError::DuplicateId { name, id });
+            }
+            Some(id) => id,
+            None => JobId::new(self.next_id),
+        };
+        // Ids aren't reused, as by `Cron`
+        self.next_id = std::cmp::max(self.next_id, id.as_u64().saturating_add(1));
+        info!("[{} {}] Registered job", name, id);
+        self.jobs.push(AsyncJob {
+            id,
+            name,
+            schedule,
+            work,
+        });
+        Ok(id)
+    }
+
+    /// start spawns a scheduling task per job on the current tokio runtime.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime.
+    pub fn start(self) -> AsyncCronHandle {
+        let tz = self.timezone;
+        let tasks = self
+            .jobs
+            .into_iter()
+            .map(|j| tokio::spawn(schedule_job(j, tz)))
+            .collect();
+
+        AsyncCronHandle { tasks }
+    }
+}
+
+/// AsyncCronHandle controls the tasks spawned by `AsyncCron::start`
+pub struct AsyncCronHandle {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl AsyncCronHandle {
+    /// join waits until every job's schedule has finished
+    pub async fn join(self) {
+        for t in self.tasks {
+            let _ = t.await;
+        }
+    }
+
+    /// shutdown stops scheduling new occurrences.
+    /// Jobs already running are left to complete.
+    pub fn shutdown(self) {
+        for t in self.tasks {
+            t.abort();
+        }
+    }
+}
+
+/// schedule_job sleeps until each occurrence of the job and runs it,
+/// until the job's schedule finishes
+async fn schedule_job(j: AsyncJob, tz: Option<Tz>) {
+    let mut after = Local::now();
+    while let Some(next) = upcoming(&j.schedule, tz, after) {
+        // Timers follow the monotonic clock, check the wall clock again on
+        // every wakeup in case it was adjusted or the system suspended
+        loop {
+            let d = time_until(Local::now(), next);
+            if d == Duration::from_secs(0) {
+                break;
+            }
+            time::sleep(std::cmp::min(d, RECHECK_INTERVAL)).await;
+        }
+        after = next;
+
+        match &j.work {
+            Work::Process(args) => {
+                tokio::spawn(run_process(format!("{} {}", j.name, j.id), args.clone()));
+            }
+            Work::Task(f) => {
+                debug!("[{} {}] Running task", j.name, j.id);
+                tokio::spawn(f());
+            }
+        }
+    }
+
+    info!("[{} {}] Job Schedule Finished", j.name, j.id);
+}
+
+async fn run_process(name: String, args: Vec<String>) {
+    let mut child = match Command::new(&args[0]).args(&args[1..]).spawn() {
+        Ok(c) => c,
+        Err(err) => {
+            error!("[{}] Failed to execute `{}`: {}", name, args[0], err);
+            return;
+        }
+    };
+
+    info!("[{}] Spawned child {:?}", name, child.id());
+    match child.wait().await {
+        Ok(status) => info!("[{}] Process exited with {}", name, status),
+        Err(err) => error!("[{}] Failed to wait for process: {}", name, err),
+    }
+}
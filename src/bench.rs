@@ -0,0 +1,217 @@
+//! Built-in benchmark of the scheduling core.
+//!
+//! Registers a synthetic population of jobs and runs the scheduler against a
+//! `ManualClock`, so minutes of scheduling take as long as the dispatching
+//! itself. Jobs are recorded instead of being run: the figures measure the
+//! scheduler, not fork and exec.
+
+use crate::clock::ManualClock;
+use crate::error::Result;
+use crate::event::EventQueue;
+use crate::job::{Job, JobSpec};
+use crate::simulate;
+use crate::state::Shared;
+use chrono::{Local, TimeZone};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Options of a benchmark run
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// number of jobs registered
+    pub jobs: usize,
+    /// number of distinct seconds of the minute the jobs are spread over,
+    /// 1 making every job due at once
+    pub spread: u32,
+    /// virtual time the scheduler is run for
+    pub duration: Duration,
+}
+
+/// Throughput of an operation
+#[derive(Debug, Clone)]
+pub struct Throughput {
+    pub ops: usize,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    fn since(ops: usize, start: Instant) -> Self {
+        Throughput {
+            ops,
+            elapsed: start.elapsed(),
+        }
+    }
+
+    /// per_sec returns the number of operations per second
+    pub fn per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>9} ops in {:>10.2?} ({:>10.0} ops/s)",
+            self.ops,
+            self.elapsed,
+            self.per_sec()
+        )
+    }
+}
+
+/// Report holds the results of a benchmark run
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub options: BenchOptions,
+    /// registering the jobs
+    pub load: Throughput,
+    pub enqueue: Throughput,
+    pub dequeue: Throughput,
+    /// dispatching the occurrences due over the virtual duration
+    pub dispatch: Throughput,
+    /// time taken to dispatch the jobs due at the same time: median, 99th
+    /// percentile and maximum
+    pub latency: (Duration, Duration, Duration),
+    /// resident memory taken by the registered jobs, in bytes
+    pub memory: Option<u64>,
+    /// peak resident memory of the process, in bytes
+    pub peak_memory: Option<u64>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let o = &self.options;
+        writeln!(
+            f,
+            "{} jobs spread over {} seconds, {:?} of virtual time",
+            o.jobs, o.spread, o.duration
+        )?;
+        writeln!(f, "{:<18} {}", "load", self.load)?;
+        writeln!(f, "{:<18} {}", "enqueue", self.enqueue)?;
+        writeln!(f, "{:<18} {}", "dequeue", self.dequeue)?;
+        writeln!(f, "{:<18} {}", "dispatch", self.dispatch)?;
+        let (p50, p99, max) = self.latency;
+        writeln!(
+            f,
+            "{:<18} p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+            "dispatch latency", p50, p99, max
+        )?;
+        match (self.memory, self.peak_memory) {
+            (Some(m), Some(peak)) => write!(
+                f,
+                "{:<18} {:.1} MiB for the jobs ({} bytes/job), peak {:.1} MiB",
+                "memory",
+                m as f64 / MIB,
+                m / o.jobs.max(1) as u64,
+                peak as f64 / MIB
+            ),
+            _ => write!(f, "{:<18} unavailable", "memory"),
+        }
+    }
+}
+
+const MIB: f64 = (1 << 20) as f64;
+
+/// run runs the benchmark
+pub fn run(mut options: BenchOptions) -> Result<Report> {
+    options.spread = options.spread.clamp(1, 60);
+    let spread = options.spread;
+    let specs: Vec<JobSpec> = (0..options.jobs)
+        .map(|i| {
+            JobSpec::new(
+                &format!("job {}", i),
+                // Identical jobs would only be registered once
+                &format!("/bin/true {}", i),
+                &format!("{} * * * * *", i as u32 % spread),
+            )
+        })
+        .collect();
+
+    let start_time = Local.timestamp_opt(1_500_000_000, 0).unwrap();
+    let clock = Arc::new(ManualClock::new(start_time));
+    let mut shared = Shared::new(None, None);
+    shared.set_clock(clock.clone());
+
+    let rss = resident_memory();
+    let start = Instant::now();
+    for res in shared.add_jobs(specs) {
+        res?;
+    }
+    let load = Throughput::since(options.jobs, start);
+    let memory = rss.and_then(|before| Some(resident_memory()?.saturating_sub(before)));
+
+    let mut state = shared.lock();
+    let jobs: Vec<Job> = state.jobs.values().cloned().collect();
+    let mut q = EventQueue::default();
+    let start = Instant::now();
+    for j in jobs {
+        q.enqueue(j);
+    }
+    let enqueue = Throughput::since(q.len(), start);
+    let start = Instant::now();
+    let mut n = 0;
+    while let Some(e) = q.dequeue() {
+        n += e.get_jobs().len();
+    }
+    let dequeue = Throughput::since(n, start);
+
+    let until = start_time
+        + chrono::Duration::from_std(options.duration).unwrap_or_else(|_| chrono::Duration::zero());
+    let mut latencies = vec![];
+    let mut firings = vec![];
+    let mut dispatched = 0;
+    let start = Instant::now();
+    loop {
+        let tick = Instant::now();
+        if !simulate::step(&mut state, &clock, until, usize::MAX, &mut firings) {
+            break;
+        }
+        latencies.push(tick.elapsed());
+        dispatched += firings.len();
+        firings.clear();
+    }
+    let dispatch = Throughput::since(dispatched, start);
+    drop(state);
+
+    latencies.sort_unstable();
+    let percentile = |p: usize| match latencies.len() {
+        0 => Duration::default(),
+        n => latencies[std::cmp::min(n * p / 100, n - 1)],
+    };
+    Ok(Report {
+        latency: (percentile(50), percentile(99), percentile(100)),
+        options,
+        load,
+        enqueue,
+        dequeue,
+        dispatch,
+        memory,
+        peak_memory: peak_memory(),
+    })
+}
+
+/// resident_memory returns the resident memory of the process, in bytes
+fn resident_memory() -> Option<u64> {
+    proc_status("VmRSS:")
+}
+
+/// peak_memory returns the peak resident memory of the process, in bytes
+fn peak_memory() -> Option<u64> {
+    proc_status("VmHWM:")
+}
+
+/// proc_status reads a memory figure from /proc/self/status, where
+/// available
+fn proc_status(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with(field))?;
+    let kb: u64 = line[field.len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
@@ -0,0 +1,194 @@
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::process;
+use xcrond::ipc::{self, Reply, Request};
+use xcrond::{Firing, JobInfo, RunId, RunStatus};
+
+// Format of the times printed in tables
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S %z";
+
+/// Inspect and control a running xcrond daemon through its control socket.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Control socket of the daemon
+    #[arg(long, value_name = "PATH", default_value = ipc::SOCKET, global = true)]
+    socket: PathBuf,
+
+    /// Print the reply of the daemon as JSON rather than a table
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the registered jobs with their last and next run
+    List,
+
+    /// List the occurrences and retries waiting to run, earliest first
+    Queue,
+
+    /// Print the next times a job is scheduled at
+    Next { job: String },
+
+    /// Run a job now, without affecting its schedule
+    Run {
+        job: String,
+
+        /// Wait for the run to finish, exiting with 1 if it failed
+        #[arg(long)]
+        wait: bool,
+    },
+
+    /// Wait for a run to finish, exiting with 1 if it failed
+    Wait { run: u64 },
+
+    /// Skip the occurrences of a job until it is resumed
+    Pause { job: String },
+
+    /// Resume a paused job
+    Resume { job: String },
+
+    /// Load the Jobfile and its fragments again
+    Reload,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let request = match &cli.command {
+        Command::List => Request::List,
+        Command::Queue => Request::Queue,
+        Command::Next { job } => Request::Next(job.clone()),
+        Command::Run { job, .. } => Request::Run(job.clone()),
+        Command::Wait { run } => Request::Wait(RunId::new(*run)),
+        Command::Pause { job } => Request::Pause(job.clone()),
+        Command::Resume { job } => Request::Resume(job.clone()),
+        Command::Reload => Request::Reload,
+    };
+
+    let mut reply = send(&cli.socket, &request);
+    if let (Command::Run { wait: true, .. }, Reply::Started(run)) = (&cli.command, &reply) {
+        // Answered once the run is recorded
+        reply = send(&cli.socket, &Request::Wait(*run));
+    }
+    if cli.json {
+        match serde_json::to_string_pretty(&reply) {
+            Ok(json) => println!("{}", json),
+            Err(err) => eprintln!("Failed to format the reply: {}", err),
+        }
+        match reply {
+            Reply::Error(_) => process::exit(1),
+            Reply::Finished(r) if !r.status.success() => process::exit(1),
+            _ => {}
+        }
+        return;
+    }
+
+    match reply {
+        Reply::Done => match &request {
+            Request::Pause(job) => println!("Paused {}", job),
+            Request::Resume(job) => println!("Resumed {}", job),
+            _ => {}
+        },
+        Reply::Started(run) => {
+            if let Request::Run(job) = &request {
+                println!("Started {} of {}", run, job);
+            }
+        }
+        Reply::Finished(r) => {
+            println!("{} of {} {}", r.run, r.name, describe(&r.status));
+            if !r.status.success() {
+                process::exit(1);
+            }
+        }
+        Reply::Error(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        Reply::Jobs(jobs) => print_jobs(&jobs),
+        Reply::Queue(pending) => print_queue(&pending),
+        Reply::Upcoming(times) => {
+            for t in times {
+                println!("{}", t.format(TIME_FORMAT));
+            }
+        }
+        Reply::Reloaded(r) => println!("Reloaded: {} jobs added, {} updated, {} removed", r.added, r.updated, r.removed),
+    }
+}
+
+/// send sends the request to the daemon, exiting if it can't be reached
+fn send(socket: &Path, request: &Request) -> Reply {
+    match ipc::request(socket, request) {
+        Ok(r) => r,
+        Err(err) => {
+            eprintln!("Failed to reach the daemon on {}: {}", socket.display(), err);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_jobs(jobs: &[JobInfo]) {
+    let width = jobs.iter().map(|j| j.name.len()).max().unwrap_or(0).max(4);
+    println!(
+        "{:width$}  {:>4}  {:20}  {:25}  {:25}  STATUS",
+        "NAME",
+        "ID",
+        "SCHEDULE",
+        "LAST RUN",
+        "NEXT RUN",
+        width = width
+    );
+    for j in jobs {
+        let last = j.last_result.as_ref();
+        println!(
+            "{:width$}  {:>4}  {:20}  {:25}  {:25}  {}",
+            j.name,
+            j.id.to_string(),
+            j.schedule,
+            last.map_or_else(|| "-".to_string(), |r| r.started.format(TIME_FORMAT).to_string()),
+            j.next.format(TIME_FORMAT).to_string(),
+            status(j),
+            width = width
+        );
+    }
+}
+
+/// status describes the state of the job: running or paused, else how its
+/// last run ended
+fn status(j: &JobInfo) -> String {
+    let mut status = match &j.last_result {
+        _ if j.running > 0 => "running".to_string(),
+        None => "-".to_string(),
+        Some(r) => describe(&r.status),
+    };
+    if j.paused {
+        status.push_str(", paused");
+    }
+    status
+}
+
+/// describe tells how a run ended
+fn describe(status: &RunStatus) -> String {
+    match status {
+        RunStatus::Exited(code) => format!("exited {}", code),
+        RunStatus::Signaled(signal) => format!("killed by {}", signal),
+        RunStatus::FailedToStart(reason) => format!("failed to start: {}", reason),
+    }
+}
+
+fn print_queue(pending: &[Firing]) {
+    let width = pending.iter().map(|f| f.name.len()).max().unwrap_or(0).max(4);
+    println!("{:25}  {:width$}  {:>4}", "DUE", "NAME", "ID", width = width);
+    for f in pending {
+        println!(
+            "{:25}  {:width$}  {:>4}",
+            f.time.format(TIME_FORMAT).to_string(),
+            f.name,
+            f.job.to_string(),
+            width = width
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Blackout windows suppressing the runs of jobs.
+//!
+//! A window is a range of the day, e.g. `22:00-06:00`, or of the week, e.g.
+//! `Sat 22:00-Sun 06:00`, evaluated in the timezone of the job. Windows are
+//! set on a job, or in the Jobfile for every job. Occurrences falling in a
+//! window are skipped, or deferred to its end according to the blackout
+//! policy of the job.
+
+use chrono::{DateTime, Datelike, Local, Timelike, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+const DAY: u32 = 24 * 60;
+const WEEK: u32 = 7 * DAY;
+
+/// BlackoutPolicy is what happens to the occurrences of a job falling in a
+/// blackout window
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlackoutPolicy {
+    /// the occurrence is skipped, the job runs at its next occurrence
+    #[default]
+    Skip,
+    /// the occurrence runs once the window is over
+    Defer,
+}
+
+/// Blackout is a daily or weekly window during which jobs don't run
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Blackout {
+    /// start and end in minutes from midnight, or from Monday midnight if
+    /// `weekly`. The end is excluded.
+    start: u32,
+    end: u32,
+    weekly: bool,
+    spec: String,
+}
+
+impl Blackout {
+    /// end_of returns when the window containing `t` ends, None if `t`
+    /// isn't in the window. `t` is evaluated in `tz`, local time if None.
+    pub fn end_of(&self, t: DateTime<Local>, tz: Option<Tz>) -> Option<DateTime<Local>> {
+        let (minute, weekday) = match tz {
+            Some(tz) => {
+                let t = t.with_timezone(&tz);
+                (t.hour() * 60 + t.minute(), t.weekday())
+            }
+            None => (t.hour() * 60 + t.minute(), t.weekday()),
+        };
+        let (pos, period) = if self.weekly {
+            (weekday.num_days_from_monday() * DAY + minute, WEEK)
+        } else {
+            (minute, DAY)
+        };
+        let inside = if self.start <= self.end {
+            self.start <= pos && pos < self.end
+        } else {
+            pos >= self.start || pos < self.end
+        };
+        if !inside {
+            return None;
+        }
+        let left = (self.end + period - pos) % period;
+        let start_of_minute = t - chrono::Duration::seconds(i64::from(t.second()))
+            - chrono::Duration::nanoseconds(i64::from(t.nanosecond()));
+        Some(start_of_minute + chrono::Duration::minutes(i64::from(left)))
+    }
+}
+
+impl FromStr for Blackout {
+    type Err = String;
+
+    /// from_str parses `HH:MM-HH:MM` or `Day HH:MM-Day HH:MM`, the end day
+    /// defaulting to the start day
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once(['-', '–'])
+            .ok_or_else(|| format!("expected a range such as `22:00-06:00` or `Sat 22:00-Sun 06:00`, found `{}`", s))?;
+        let (start_day, start) = point(from)?;
+        let (end_day, end) = point(to)?;
+        let (start, end, weekly) = match (start_day, end_day) {
+            (None, None) => (start, end, false),
+            // Ending the next day if it ends before it starts
+            (Some(d), None) if end <= start => (d * DAY + start, (d + 1) * DAY + end, true),
+            (Some(d), None) => (d * DAY + start, d * DAY + end, true),
+            (Some(a), Some(b)) => (a * DAY + start, b * DAY + end, true),
+            (None, Some(_)) => return Err(format!("`{}` names the day of the end but not of the start", s)),
+        };
+        if start == end {
+            return Err(format!("`{}` is empty", s));
+        }
+        Ok(Blackout {
+            start,
+            end: end % if weekly { WEEK } else { DAY },
+            weekly,
+            spec: s.trim().to_string(),
+        })
+    }
+}
+
+/// point parses a bound of a window, an optional day of the week and a time
+/// of the day, returning the day numbered from 0 on Monday and the minutes
+/// from midnight
+fn point(s: &str) -> Result<(Option<u32>, u32), String> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+    let (day, time) = match parts.as_slice() {
+        [time] => (None, *time),
+        [day, time] => {
+            let day = Weekday::from_str(day).map_err(|_| format!("unknown day `{}`", day))?;
+            (Some(day.num_days_from_monday()), *time)
+        }
+        _ => return Err(format!("expected `HH:MM` or `Day HH:MM`, found `{}`", s.trim())),
+    };
+    let (h, m) = time
+        .split_once(':')
+        .and_then(|(h, m)| Some((h.parse::<u32>().ok()?, m.parse::<u32>().ok()?)))
+        .filter(|(h, m)| (*h < 24 || (*h, *m) == (24, 0)) && *m < 60)
+        .ok_or_else(|| format!("invalid time `{}`", time))?;
+    Ok((day, h * 60 + m))
+}
+
+impl TryFrom<String> for Blackout {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Blackout> for String {
+    fn from(b: Blackout) -> Self {
+        b.spec
+    }
+}
+
+impl fmt::Display for Blackout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn finds_the_end_of_windows() {
+        // January 5, 2030 is a Saturday
+        let utc = |d: u32, h: u32, m: u32| Tz::UTC.with_ymd_and_hms(2030, 1, d, h, m, 0).unwrap().with_timezone(&Local);
+        let t = |d: u32, h: u32, m: u32| utc(d, h, m) + chrono::Duration::seconds(30);
+        let end = |b: &str, at| b.parse::<Blackout>().unwrap().end_of(at, Some(Tz::UTC));
+
+        assert_eq!(end("22:00-06:00", t(5, 23, 10)), Some(utc(6, 6, 0)));
+        assert_eq!(end("22:00-06:00", t(6, 5, 59)), Some(utc(6, 6, 0)));
+        assert_eq!(end("22:00-06:00", t(6, 6, 0)), None);
+        assert_eq!(end("12:00-13:00", t(5, 12, 30)), Some(utc(5, 13, 0)));
+        assert_eq!(end("Sat 22:00-Sun 06:00", t(5, 23, 0)), Some(utc(6, 6, 0)));
+        assert_eq!(end("Sat 22:00-Sun 06:00", t(6, 1, 0)), Some(utc(6, 6, 0)));
+        assert_eq!(end("Sat 22:00-Sun 06:00", t(4, 23, 0)), None);
+        assert_eq!(end("Sun 22:00-Mon 02:00", t(7, 1, 0)), Some(utc(7, 2, 0)));
+
+        assert!("22:00".parse::<Blackout>().is_err());
+        assert!("25:00-06:00".parse::<Blackout>().is_err());
+        assert!("22:00-Sun 06:00".parse::<Blackout>().is_err());
+        assert!("Fry 22:00-23:00".parse::<Blackout>().is_err());
+    }
+}
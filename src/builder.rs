@@ -0,0 +1,256 @@
+use crate::cgroup;
+use crate::clock::Clock;
+use crate::cluster::{ClusterLock, LeaderElection, Membership};
+use crate::error::Result;
+use crate::job::JobSpec;
+use crate::mail::Mailer;
+use crate::namespace::Namespace;
+use crate::observer::SchedulerObserver;
+use crate::ratelimit::TokenBucket;
+use crate::state::Shared;
+use crate::Cron;
+use chrono_tz::Tz;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// CronBuilder configures a `Cron` instance in code.
+///
+/// ```no_run
+/// use xcrond::{Cron, JobSpec};
+///
+/// let mut c = Cron::builder()
+///     .max_concurrent(4)
+///     .job(JobSpec::new("cleanup", "/usr/bin/rm -rf /tmp/cache", "0 0 * * * *"))
+///     .build()?;
+/// c.run();
+/// # Ok::<(), xcrond::XcrondError>(())
+/// ```
+#[derive(Default)]
+pub struct CronBuilder {
+    config_path: Option<PathBuf>,
+    config_dir: Option<PathBuf>,
+    state_path: Option<PathBuf>,
+    journal_path: Option<PathBuf>,
+    log_dir: Option<PathBuf>,
+    cgroup_dir: Option<PathBuf>,
+    mailer: Option<Mailer>,
+    timezone: Option<Tz>,
+    clock: Option<Arc<dyn Clock>>,
+    max_concurrent: Option<usize>,
+    start_rate: Option<u32>,
+    heartbeat: Option<Duration>,
+    max_shutdown_wait: Option<Duration>,
+    cluster_lock: Option<Arc<dyn ClusterLock>>,
+    election: Option<(Arc<dyn LeaderElection>, String)>,
+    sharding: Option<(Arc<dyn Membership>, String)>,
+    replication: Option<TcpListener>,
+    standby: Option<(String, Duration)>,
+    namespaces: Vec<Namespace>,
+    jobs: Vec<JobSpec>,
+    observers: Vec<Arc<dyn SchedulerObserver>>,
+}
+
+impl CronBuilder {
+    /// config_path sets the Jobfile to load jobs from when `Cron::init` is called
+    pub fn config_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// config_dir sets a directory of Jobfile fragments, the `*.toml` files
+    /// of which are loaded along with the Jobfile when `Cron::init` is called
+    pub fn config_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.config_dir = Some(dir.into());
+        self
+    }
+
+    /// state_path sets the file the jobs' last runs are persisted to,
+    /// so they survive restarts. The state is loaded by `Cron::init`.
+    pub fn state_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.state_path = Some(path.into());
+        self
+    }
+
+    /// journal_path sets the write-ahead journal recording the runs of the
+    /// jobs with `journal` set. The journal is opened by `Cron::init`.
+    pub fn journal_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.journal_path = Some(path.into());
+        self
+    }
+
+    /// mailer sets how the output of runs is mailed, by sendmail if not set
+    pub fn mailer(mut self, mailer: Mailer) -> Self {
+        self.mailer = Some(mailer);
+        self
+    }
+
+    /// log_dir sets the directory the standard output and error of the runs
+    /// are written to, a file per run in a directory per job
+    pub fn log_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.log_dir = Some(dir.into());
+        self
+    }
+
+    /// cgroup_dir sets the cgroup v2 directory the cgroups of the runs of
+    /// jobs with cgroup limits are created in, e.g. `/sys/fs/cgroup/xcrond`.
+    /// It has to hold no process itself.
+    pub fn cgroup_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.cgroup_dir = Some(dir.into());
+        self
+    }
+
+    /// timezone sets the timezone job schedules are evaluated in.
+    /// Defaults to the local timezone.
+    pub fn timezone(mut self, tz: Tz) -> Self {
+        self.timezone = Some(tz);
+        self
+    }
+
+    /// clock sets the clock the instance tells the time with, e.g. a
+    /// `ManualClock` in tests. Defaults to the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// max_concurrent limits the number of jobs running at the same time.
+    /// Occurrences that would exceed the limit are skipped.
+    pub fn max_concurrent(mut self, max: usize) -> Self {
+        self.max_concurrent = Some(max);
+        self
+    }
+
+    /// max_start_rate limits the number of jobs launched per second.
+    /// Launches beyond the rate are delayed, so jobs due at the same time
+    /// ramp up smoothly.
+    pub fn max_start_rate(mut self, rate: u32) -> Self {
+        self.start_rate = Some(rate);
+        self
+    }
+
+    /// heartbeat makes the run loop wake up at least once per interval and
+    /// call `SchedulerObserver::heartbeat`, e.g. to pet a watchdog
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    /// max_shutdown_wait bounds how long `CronHandle::terminate` waits for
+    /// jobs with the `wait` shutdown policy to finish. Defaults to an hour.
+    pub fn max_shutdown_wait(mut self, max: Duration) -> Self {
+        self.max_shutdown_wait = Some(max);
+        self
+    }
+
+    /// cluster_lock sets the lock claiming the occurrences of jobs with
+    /// `singleton_cluster` set, shared with the other hosts defining them
+    pub fn cluster_lock(mut self, lock: Arc<dyn ClusterLock>) -> Self {
+        self.cluster_lock = Some(lock);
+        self
+    }
+
+    /// leader_election makes the instance run jobs only while it holds the
+    /// lease named `key`, shared with standby instances of the same config.
+    /// Standby instances take over when the leader stops renewing it.
+    pub fn leader_election(mut self, election: Arc<dyn LeaderElection>, key: &str) -> Self {
+        self.election = Some((election, key.to_string()));
+        self
+    }
+
+    /// sharding splits the jobs between the instances registered in `group`,
+    /// which share the same jobs. Each instance runs its shard only, and the
+    /// jobs are rebalanced as instances join or leave.
+    pub fn sharding(mut self, membership: Arc<dyn Membership>, group: &str) -> Self {
+        self.sharding = Some((membership, group.to_string()));
+        self
+    }
+
+    /// replicate serves the state of the jobs to standby instances
+    /// connecting to `listener`
+    pub fn replicate(mut self, listener: TcpListener) -> Self {
+        self.replication = Some(listener);
+        self
+    }
+
+    /// standby makes the instance follow the state of the primary listening
+    /// at `primary` instead of scheduling jobs. It takes over if it doesn't
+    /// hear from the primary for `failover_after`, running the occurrences
+    /// missed in the meantime once.
+    pub fn standby(mut self, primary: &str, failover_after: Duration) -> Self {
+        self.standby = Some((primary.to_string(), failover_after));
+        self
+    }
+
+    /// namespace sets the limits shared by the jobs of a namespace
+    pub fn namespace(mut self, ns: Namespace) -> Self {
+        self.namespaces.push(ns);
+        self
+    }
+
+    /// job adds a job to be scheduled
+    pub fn job(mut self, spec: JobSpec) -> Self {
+        self.jobs.push(spec);
+        self
+    }
+
+    /// observer registers an observer notified of scheduling events
+    pub fn observer(mut self, o: Arc<dyn SchedulerObserver>) -> Self {
+        self.observers.push(o);
+        self
+    }
+
+    /// build creates the `Cron` instance and schedules all jobs added to the builder.
+    /// Fails if any of the jobs is invalid.
+    pub fn build(self) -> Result<Cron> {
+        let mut shared = Shared::new(self.timezone, self.max_concurrent);
+        if let Some(clock) = self.clock {
+            shared.set_clock(clock);
+        }
+        shared.heartbeat = self.heartbeat;
+        shared.cluster_lock = self.cluster_lock;
+        shared.election = self.election;
+        shared.sharding = self.sharding;
+        shared.standby = self.standby;
+        {
+            let mut state = shared.lock();
+            state.standby = shared.standby.is_some();
+            state.start_rate = self.start_rate.map(TokenBucket::new);
+            state.mailer = self.mailer.unwrap_or_default();
+        }
+        if let Some(max) = self.max_shutdown_wait {
+            shared.max_shutdown_wait = max;
+        }
+        shared.config_path = self.config_path;
+        shared.config_dir = self.config_dir;
+        shared.log_dir = self.log_dir;
+        if let Some(dir) = &self.cgroup_dir {
+            // Runs fail to set limits whose controller isn't enabled
+            if let Err(err) = cgroup::enable(dir) {
+                warn!("Failed to enable the controllers of {}: {}", dir.display(), err);
+            }
+        }
+        shared.cgroup_dir = self.cgroup_dir;
+        let mut c = Cron {
+            state_path: self.state_path,
+            journal_path: self.journal_path,
+            shared: Arc::new(shared),
+            coordinators: vec![],
+            replication: self.replication,
+        };
+
+        for o in self.observers {
+            c.add_observer(o);
+        }
+        for ns in self.namespaces {
+            c.set_namespace(ns);
+        }
+
+        for res in c.add_jobs(self.jobs) {
+            res?;
+        }
+
+        Ok(c)
+    }
+}
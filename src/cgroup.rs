@@ -0,0 +1,212 @@
+//! cgroup v2 control of the resources of runs.
+//!
+//! Unlike rlimits, which limit each process, a cgroup limits a run as a
+//! whole, with all the processes it forks. When a cgroup directory is set,
+//! e.g. `/sys/fs/cgroup/xcrond`, every run of a job with cgroup limits gets
+//! a cgroup of its own under it, named after the job and the run, with the
+//! limits written to its `memory.max`, `cpu.max` and `pids.max`. The child
+//! moves itself into the cgroup before exec. Once the run is reaped, the
+//! processes it left behind are killed and the cgroup removed.
+//!
+//! The directory has to be writable by the daemon and hold no process
+//! itself, as cgroup v2 only lets the cgroups without processes hand
+//! controllers down to their children.
+
+use crate::joblog;
+use crate::run::RunId;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+// Controllers of the limits, enabled for the children of the directory
+const CONTROLLERS: &str = "+memory +cpu +pids";
+// Period cpu.max is enforced over, in microseconds
+const CPU_PERIOD: u64 = 100_000;
+// How long killed processes are given to leave the cgroup before removing
+// it fails
+const REMOVE_ATTEMPTS: u32 = 10;
+const REMOVE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// CgroupLimits are the resources a run may use, with all its processes
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CgroupLimits {
+    /// memory, in bytes. Processes are killed by the OOM killer beyond it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_max: Option<u64>,
+    /// CPU time, in percent of a CPU, e.g. 150 for one and a half
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_max: Option<u32>,
+    /// number of processes and threads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids_max: Option<u64>,
+}
+
+impl CgroupLimits {
+    /// is_empty returns true if no resource is limited
+    pub fn is_empty(&self) -> bool {
+        *self == CgroupLimits::default()
+    }
+}
+
+/// enable hands the controllers of the limits down to the cgroups created
+/// in `dir`
+pub(crate) fn enable(dir: &Path) -> io::Result<()> {
+    fs::write(dir.join("cgroup.subtree_control"), CONTROLLERS)
+}
+
+/// Cgroup is the cgroup of a run
+#[derive(Debug)]
+pub(crate) struct Cgroup {
+    pub path: PathBuf,
+}
+
+impl Cgroup {
+    /// create creates the cgroup of the run of the job named `name` in
+    /// `dir`, with the given limits
+    pub fn create(dir: &Path, name: &str, run: RunId, limits: &CgroupLimits) -> io::Result<Self> {
+        let path = dir.join(format!("{}-{}", joblog::file_name(name), run.as_u64()));
+        fs::create_dir(&path)?;
+        let cgroup = Cgroup { path };
+        if let Err(err) = cgroup.limit(limits) {
+            cgroup.remove();
+            return Err(err);
+        }
+        Ok(cgroup)
+    }
+
+    fn limit(&self, limits: &CgroupLimits) -> io::Result<()> {
+        let write = |file: &str, value: String| {
+            fs::write(self.path.join(file), value)
+                .map_err(|err| io::Error::new(err.kind(), format!("failed to write {}: {}", file, err)))
+        };
+        if let Some(bytes) = limits.memory_max {
+            write("memory.max", bytes.to_string())?;
+        }
+        if let Some(percent) = limits.cpu_max {
+            write("cpu.max", format!("{} {}", u64::from(percent) * CPU_PERIOD / 100, CPU_PERIOD))?;
+        }
+        if let Some(n) = limits.pids_max {
+            write("pids.max", n.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// enter makes the process run by `cmd` move into the cgroup before
+    /// exec, so it never runs outside of it
+    pub fn enter(&self, cmd: &mut Command) -> io::Result<()> {
+        // Opened close-on-exec, the child only writes to it
+        let procs: File = OpenOptions::new().write(true).open(self.path.join("cgroup.procs"))?;
+        unsafe {
+            // Only async signal safe calls between fork and exec. Writing
+            // 0 moves the writing process.
+            cmd.pre_exec(move || {
+                if libc::write(procs.as_raw_fd(), b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// kill sends SIGKILL to every process in the cgroup, the run's and
+    /// those it forked
+    pub fn kill(&self) {
+        let killed = OpenOptions::new()
+            .write(true)
+            .open(self.path.join("cgroup.kill"))
+            .and_then(|mut f| f.write_all(b"1"));
+        match killed {
+            Ok(()) => return,
+            // cgroup.kill is only there from Linux 5.14, the processes are
+            // killed one by one before
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!("Failed to kill the processes of the cgroup {}: {}", self.path.display(), err),
+        }
+
+        let procs = match fs::read_to_string(self.path.join("cgroup.procs")) {
+            Ok(procs) => procs,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return,
+            Err(err) => {
+                warn!("Failed to list the processes of the cgroup {}: {}", self.path.display(), err);
+                return;
+            }
+        };
+        for pid in procs.lines().filter_map(|l| l.trim().parse::<libc::pid_t>().ok()) {
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        }
+    }
+
+    /// remove kills the processes left in the cgroup and deletes it
+    pub fn remove(&self) {
+        self.kill();
+        let mut removed = fs::remove_dir(&self.path);
+        for _ in 1..REMOVE_ATTEMPTS {
+            match &removed {
+                // The killed processes haven't exited yet
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => thread::sleep(REMOVE_INTERVAL),
+                _ => break,
+            }
+            removed = fs::remove_dir(&self.path);
+        }
+        if let Err(err) = removed {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove the cgroup {}: {}", self.path.display(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn writes_the_limits() {
+        let dir = env::temp_dir().join(format!("xcrond-test-{}.cgroup", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let limits = CgroupLimits {
+            memory_max: Some(64 << 20),
+            cpu_max: Some(150),
+            pids_max: None,
+        };
+        let cg = Cgroup::create(&dir, "backup db", RunId::new(7), &limits).unwrap();
+        assert_eq!(cg.path, dir.join("backup_db-7"));
+        let read = |file: &str| fs::read_to_string(cg.path.join(file)).ok();
+        assert_eq!(read("memory.max").as_deref(), Some("67108864"));
+        assert_eq!(read("cpu.max").as_deref(), Some("150000 100000"));
+        assert_eq!(read("pids.max"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removes_the_cgroup_if_it_cant_be_limited() {
+        // The path of the cgroup is just short enough to be created, those
+        // of its files are too long to be written
+        let base = env::temp_dir().join(format!("xcrond-test-{}.cgroups", std::process::id()));
+        let mut dir = base.clone();
+        let len = libc::PATH_MAX as usize - 1 - "/backup-7".len();
+        while dir.as_os_str().len() < len {
+            let n = std::cmp::min(200, len - dir.as_os_str().len() - 1);
+            dir.push("d".repeat(n));
+        }
+        fs::create_dir_all(&dir).unwrap();
+
+        let limits = CgroupLimits {
+            pids_max: Some(10),
+            ..Default::default()
+        };
+        assert!(Cgroup::create(&dir, "backup", RunId::new(7), &limits).is_err());
+        assert!(!dir.join("backup-7").exists());
+        assert!(Cgroup::create(&dir, "backup", RunId::new(7), &CgroupLimits::default()).is_ok());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+//! Source of time for the scheduler.
+//!
+//! The scheduler reads the time and waits for occurrences to come due
+//! through a `Clock`, the system clock unless another one is given to
+//! `CronBuilder::clock`. `ManualClock` only moves when told to, so the
+//! scheduling logic can be tested without waiting for real time to pass.
+
+use crate::job;
+use chrono::{DateTime, Local};
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use crate::timer::{WakeReason, Waker};
+
+// Longest wait without timerfd, condvar timeouts follow the monotonic clock
+// which stops while the system is suspended. Bounds how late a resume is
+// noticed.
+const COARSE_WAIT: Duration = Duration::from_secs(30);
+
+/// Clock tells the time and waits for it to come
+pub trait Clock: Send + Sync {
+    /// now returns the wall clock time
+    fn now(&self) -> DateTime<Local>;
+
+    /// instant returns the monotonic time, which doesn't move when the
+    /// wall clock is set nor while the system is suspended
+    fn instant(&self) -> Instant;
+
+    /// sleep_until blocks until the wall clock reaches `t` or `wake` is
+    /// called, forever if `t` is None. It may return early: callers check
+    /// the time again when it returns.
+    /// A call to `wake` while no thread is sleeping makes the next sleep
+    /// return right away.
+    fn sleep_until(&self, t: Option<DateTime<Local>>);
+
+    /// wake interrupts the thread blocked in `sleep_until`
+    fn wake(&self);
+}
+
+/// SystemClock is the clock of the system. Sleeps are timed with timerfd
+/// where available, so they end as soon as the wall clock is changed.
+#[derive(Default)]
+pub struct SystemClock {
+    // created by the first sleep
+    #[cfg(target_os = "linux")]
+    waker: std::sync::OnceLock<Option<Waker>>,
+    woken: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl SystemClock {
+    #[cfg(target_os = "linux")]
+    fn waker(&self) -> Option<&Waker> {
+        let waker = self.waker.get_or_init(|| match Waker::new() {
+            Ok(w) => Some(w),
+            Err(err) => {
+                warn!("Failed to create timerfd, falling back to coarse wakeups: {}", err);
+                None
+            }
+        });
+        waker.as_ref()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, t: Option<DateTime<Local>>) {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(w) = self.waker() {
+                match w.wait_until(t) {
+                    Ok(WakeReason::ClockChanged) => info!("System clock changed, rescheduling"),
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!("Failed to wait on timerfd: {}", err);
+                        std::thread::sleep(Duration::from_secs(1));
+                    }
+                }
+                return;
+            }
+        }
+
+        let mut woken = self.woken.lock().unwrap();
+        if !*woken {
+            woken = match t {
+                Some(t) => {
+                    let timeout = std::cmp::min(job::time_until(Local::now(), t), COARSE_WAIT);
+                    self.cond.wait_timeout(woken, timeout).unwrap().0
+                }
+                None => self.cond.wait(woken).unwrap(),
+            };
+        }
+        *woken = false;
+    }
+
+    fn wake(&self) {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(w) = self.waker() {
+                w.wake();
+                return;
+            }
+        }
+
+        *self.woken.lock().unwrap() = true;
+        self.cond.notify_all();
+    }
+}
+
+/// ManualClock is a clock that only moves when set or advanced, to test
+/// scheduling without real waits.
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// use xcrond::clock::{Clock, ManualClock};
+///
+/// let clock = Arc::new(ManualClock::new(chrono::Local::now()));
+/// let start = clock.now();
+/// clock.advance(Duration::from_secs(60));
+/// assert_eq!((clock.now() - start).num_seconds(), 60);
+/// ```
+pub struct ManualClock {
+    time: Mutex<ManualTime>,
+    cond: Condvar,
+}
+
+struct ManualTime {
+    now: DateTime<Local>,
+    instant: Instant,
+    woken: bool,
+}
+
+impl ManualClock {
+    /// new returns a clock stopped at `now`
+    pub fn new(now: DateTime<Local>) -> Self {
+        ManualClock {
+            time: Mutex::new(ManualTime {
+                now,
+                instant: Instant::now(),
+                woken: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// advance moves the clock forward, as if `d` went by
+    pub fn advance(&self, d: Duration) {
+        let mut time = self.time.lock().unwrap();
+        time.now += chrono::Duration::from_std(d).unwrap_or_else(|_| chrono::Duration::zero());
+        time.instant += d;
+        self.cond.notify_all();
+    }
+
+    /// set sets the wall clock time without moving the monotonic time, as if
+    /// the clock was adjusted or the system suspended
+    pub fn set(&self, now: DateTime<Local>) {
+        self.time.lock().unwrap().now = now;
+        self.cond.notify_all();
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Local> {
+        self.time.lock().unwrap().now
+    }
+
+    fn instant(&self) -> Instant {
+        self.time.lock().unwrap().instant
+    }
+
+    fn sleep_until(&self, t: Option<DateTime<Local>>) {
+        let mut time = self.time.lock().unwrap();
+        while !time.woken && t.is_none_or(|t| time.now < t) {
+            time = self.cond.wait(time).unwrap();
+        }
+        time.woken = false;
+    }
+
+    fn wake(&self) {
+        self.time.lock().unwrap().woken = true;
+        self.cond.notify_all();
+    }
+}
+
+/// SharedClock is the clock of a `Cron` instance, the system clock by default
+#[derive(Clone)]
+pub(crate) struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        SharedClock(clock)
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock(Arc::new(SystemClock::default()))
+    }
+}
+
+impl Deref for SharedClock {
+    type Target = dyn Clock;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
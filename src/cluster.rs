@@ -0,0 +1,504 @@
+//! Locks shared by the hosts of a cluster running the same jobs.
+//!
+//! Jobs defined on several hosts for redundancy set `singleton_cluster`, and
+//! every host claims each of their occurrences through a `ClusterLock`
+//! before running it. Only the host that got the claim runs the occurrence.
+//!
+//! Active/passive pairs share their whole configuration instead, and only
+//! the instance elected leader through a `LeaderElection` runs jobs.
+//!
+//! Very large job sets are split between the members of a group instead:
+//! instances register through `Membership` and each runs its shard of the
+//! jobs, rebalanced as members join or leave.
+//!
+//! Claims, leases and members are stored in Redis (`RedisLock`, with the `cluster`
+//! feature) or in a directory on a shared filesystem (`FileLock`).
+
+use crate::error::{Result, XcrondError};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How long a claim waits for another host to release the claim file
+const FILE_LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+// Expired claim files are removed at most once per interval
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// ClusterLock claims occurrences of jobs for this host
+pub trait ClusterLock: Send + Sync {
+    /// try_acquire claims `key` for this host until `ttl` elapses.
+    /// Returns false if another host already claimed it.
+    fn try_acquire(&self, key: &str, ttl: Duration) -> io::Result<bool>;
+}
+
+/// LeaderElection elects a single instance among the ones sharing a lease
+pub trait LeaderElection: Send + Sync {
+    /// campaign acquires the lease named `key` for this instance, or renews
+    /// it if this instance already holds it, until `ttl` elapses.
+    /// Returns false if another instance holds the lease.
+    fn campaign(&self, key: &str, ttl: Duration) -> io::Result<bool>;
+
+    /// resign releases the lease if this instance holds it, so another
+    /// instance can take over right away
+    fn resign(&self, key: &str) -> io::Result<()>;
+}
+
+/// Membership tracks the live instances of a group sharing their jobs
+pub trait Membership: Send + Sync {
+    /// id returns the name of this instance in the groups it joins
+    fn id(&self) -> String;
+
+    /// join registers this instance in `group` until `ttl` elapses, or
+    /// extends its registration
+    fn join(&self, group: &str, ttl: Duration) -> io::Result<()>;
+
+    /// members returns the names of the live members of `group`
+    fn members(&self, group: &str) -> io::Result<Vec<String>>;
+
+    /// leave removes this instance from `group`
+    fn leave(&self, group: &str) -> io::Result<()>;
+}
+
+/// Shard is the part of the jobs run by a member of a group.
+/// Jobs are assigned with rendezvous hashing, so when the group changes only
+/// the jobs of the members that joined or left move.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Shard {
+    pub me: String,
+    pub members: Vec<String>,
+}
+
+impl Shard {
+    /// owns returns true if the job is in this member's shard
+    pub fn owns(&self, job: &str) -> bool {
+        self.members
+            .iter()
+            .max_by_key(|m| (weight(m, job), *m))
+            .is_some_and(|m| *m == self.me)
+    }
+}
+
+/// weight hashes the member and job names with FNV-1a and a final mix.
+/// The hash has to be the same on every instance, whatever their version.
+fn weight(member: &str, job: &str) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in member.bytes().chain(Some(0)).chain(job.bytes()) {
+        h ^= u64::from(b);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^ (h >> 33)
+}
+
+/// FileLock stores claims in a directory shared by the hosts, e.g. over NFS.
+///
+/// Each key is a file holding the instance that claimed it and when the
+/// claim expires. Files are updated under POSIX record locks, which unlike flock
+/// are forwarded to the NFS server on every platform. A host that crashed
+/// while holding a record lock loses it when its NFS lease expires, and
+/// claims past their expiry are stale and taken over. Expired claim files
+/// are removed now and then, a host that got the lock of a removed file
+/// opens the path again.
+pub struct FileLock {
+    dir: PathBuf,
+    holder: String,
+    last_cleanup: Mutex<Option<Instant>>,
+}
+
+impl FileLock {
+    /// new stores the claims in `dir`, creating it if needed
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(|source| XcrondError::Write {
+            path: dir.clone(),
+            source,
+        })?;
+        Ok(FileLock {
+            dir,
+            holder: holder(),
+            last_cleanup: Mutex::new(None),
+        })
+    }
+
+    /// open_locked opens the claim file at `path` and waits for its record lock
+    fn open_locked(&self, path: &Path) -> io::Result<File> {
+        let deadline = Instant::now() + FILE_LOCK_TIMEOUT;
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            lock(&file, deadline)?;
+
+            // The file may have been removed by a cleanup while we waited,
+            // a claim written to it would be lost to the hosts opening the
+            // path afresh
+            let locked = file.metadata()?;
+            match fs::metadata(path) {
+                Ok(m) if locked.nlink() > 0 && (m.dev(), m.ino()) == (locked.dev(), locked.ino()) => return Ok(file),
+                Ok(_) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// cleanup removes the claim files that expired, at most once per interval
+    fn cleanup(&self) {
+        {
+            let mut last = self.last_cleanup.lock().unwrap();
+            if last.is_some_and(|t| t.elapsed() < CLEANUP_INTERVAL) {
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(e) => e,
+            Err(err) => {
+                warn!("Failed to list {}: {}", self.dir.display(), err);
+                return;
+            }
+        };
+        for path in entries.flatten().map(|e| e.path()) {
+            let expired = self
+                .open_locked(&path)
+                .and_then(|mut f| read_claim(&mut f))
+                .map(|claim| claim.is_none_or(|(_, expires)| expires <= now_millis()));
+            if let Ok(true) = expired {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+
+    /// claim claims `key` until `ttl` elapses, renewing our own claim if `renew`
+    fn claim(&self, key: &str, ttl: Duration, renew: bool) -> io::Result<bool> {
+        let mut file = self.open_locked(&self.path(key))?;
+
+        let now = now_millis();
+        match read_claim(&mut file)? {
+            Some((holder, _)) if renew && holder == self.holder => {}
+            Some((_, expires)) if expires > now => return Ok(false),
+            Some((holder, _)) => info!("Taking over the stale claim of {} by {}", key, holder),
+            None => {}
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{} {}", self.holder, now + ttl.as_millis() as u64)?;
+        file.sync_data()?;
+        // Closing the file releases the record lock
+        Ok(true)
+    }
+
+    /// path returns the claim file of `key`.
+    /// Keys are file names, job names may contain separators.
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key.replace('/', "_"))
+    }
+}
+
+impl ClusterLock for FileLock {
+    fn try_acquire(&self, key: &str, ttl: Duration) -> io::Result<bool> {
+        let claimed = self.claim(key, ttl, false)?;
+        self.cleanup();
+        Ok(claimed)
+    }
+}
+
+impl LeaderElection for FileLock {
+    fn campaign(&self, key: &str, ttl: Duration) -> io::Result<bool> {
+        self.claim(key, ttl, true)
+    }
+
+    fn resign(&self, key: &str) -> io::Result<()> {
+        let mut file = self.open_locked(&self.path(key))?;
+        if let Some((holder, _)) = read_claim(&mut file)? {
+            if holder == self.holder {
+                file.set_len(0)?;
+                file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Members are files named after them in the group's directory, holding
+/// when their registration expires
+impl Membership for FileLock {
+    fn id(&self) -> String {
+        self.holder.clone()
+    }
+
+    fn join(&self, group: &str, ttl: Duration) -> io::Result<()> {
+        let dir = self.path(group);
+        fs::create_dir_all(&dir)?;
+
+        // Replaced atomically, members are listed without locking
+        let tmp = dir.join(format!(".{}", self.holder));
+        fs::write(&tmp, format!("{}\n", now_millis() + ttl.as_millis() as u64))?;
+        fs::rename(&tmp, dir.join(&self.holder))
+    }
+
+    fn members(&self, group: &str) -> io::Result<Vec<String>> {
+        let now = now_millis();
+        let mut members = vec![];
+        for entry in fs::read_dir(self.path(group))? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(n) if !n.starts_with('.') => n.to_string(),
+                _ => continue,
+            };
+            let expires: Option<u64> = fs::read_to_string(&path).ok().and_then(|c| c.trim().parse().ok());
+            match expires {
+                Some(e) if e > now => members.push(name),
+                // Left without saying goodbye
+                _ => {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        Ok(members)
+    }
+
+    fn leave(&self, group: &str) -> io::Result<()> {
+        fs::remove_file(self.path(group).join(&self.holder))
+    }
+}
+
+/// lock waits for the record lock of the file, until `deadline`
+fn lock(file: &File, deadline: Instant) -> io::Result<()> {
+    loop {
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = libc::F_WRLCK as _;
+        lock.l_whence = libc::SEEK_SET as _;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) } == 0 {
+            return Ok(());
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EACCES) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(50));
+            }
+            _ => return Err(err),
+        }
+    }
+}
+
+/// read_claim returns the instance holding the claim and when it expires,
+/// in milliseconds since the epoch
+fn read_claim(file: &mut File) -> io::Result<Option<(String, u64)>> {
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let mut fields = content.split_whitespace();
+    match (fields.next(), fields.next().and_then(|e| e.parse().ok())) {
+        (Some(host), Some(expires)) => Ok(Some((host.to_string(), expires))),
+        _ => Ok(None),
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// holder identifies this instance as the holder of the keys it claims,
+/// as `host:pid`
+fn holder() -> String {
+    format!("{}:{}", hostname(), std::process::id())
+}
+
+/// hostname returns the name of this host, empty if it can't be told
+pub(crate) fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let len = match ret {
+        0 => buf.iter().position(|b| *b == 0).unwrap_or(buf.len()),
+        _ => 0,
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(feature = "cluster")]
+pub use self::redis_lock::RedisLock;
+
+#[cfg(feature = "cluster")]
+mod redis_lock {
+    use super::{holder, now_millis, ClusterLock, LeaderElection, Membership};
+    use crate::error::Result;
+    use std::io;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // Bounds the time the run loop is blocked by an unreachable server
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    // Acquires the lease, or extends it if we hold it
+    const CAMPAIGN: &str = "
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+        end
+        if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+            return 1
+        end
+        return 0";
+
+    // Deletes the lease if we hold it
+    const RESIGN: &str = "
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            return redis.call('DEL', KEYS[1])
+        end
+        return 0";
+
+    /// RedisLock claims keys with `SET key holder NX PX ttl` on a Redis server
+    pub struct RedisLock {
+        client: redis::Client,
+        conn: Mutex<Option<redis::Connection>>,
+        holder: String,
+    }
+
+    impl RedisLock {
+        /// new creates a lock on the server at `url`, e.g. `redis://host:6379/0`.
+        /// The server is connected to on first use.
+        pub fn new(url: &str) -> Result<Self> {
+            Ok(RedisLock {
+                client: redis::Client::open(url)?,
+                conn: Mutex::new(None),
+                holder: holder(),
+            })
+        }
+
+        /// query runs the command, connecting to the server if needed
+        fn query<T: redis::FromRedisValue>(&self, cmd: &redis::Cmd) -> io::Result<T> {
+            let mut conn = self.conn.lock().unwrap();
+            let mut run = || -> redis::RedisResult<T> {
+                if conn.is_none() {
+                    let c = self.client.get_connection_with_timeout(TIMEOUT)?;
+                    c.set_read_timeout(Some(TIMEOUT))?;
+                    c.set_write_timeout(Some(TIMEOUT))?;
+                    *conn = Some(c);
+                }
+                cmd.query(conn.as_mut().unwrap())
+            };
+            run().map_err(|err| {
+                // Reconnect on the next command
+                *conn = None;
+                io::Error::other(err)
+            })
+        }
+
+        fn eval(&self, script: &str, key: &str, ttl: Option<Duration>) -> io::Result<bool> {
+            let mut cmd = redis::cmd("EVAL");
+            cmd.arg(script).arg(1).arg(key).arg(&self.holder);
+            if let Some(ttl) = ttl {
+                cmd.arg(ttl.as_millis() as u64);
+            }
+            Ok(self.query::<i64>(&cmd)? == 1)
+        }
+    }
+
+    impl ClusterLock for RedisLock {
+        fn try_acquire(&self, key: &str, ttl: Duration) -> io::Result<bool> {
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(key)
+                .arg(&self.holder)
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl.as_millis() as u64);
+            Ok(self.query::<Option<String>>(&cmd)?.is_some())
+        }
+    }
+
+    /// Members are stored in a sorted set, scored by when their
+    /// registration expires
+    impl Membership for RedisLock {
+        fn id(&self) -> String {
+            self.holder.clone()
+        }
+
+        fn join(&self, group: &str, ttl: Duration) -> io::Result<()> {
+            let mut cmd = redis::cmd("ZADD");
+            cmd.arg(group)
+                .arg(now_millis() + ttl.as_millis() as u64)
+                .arg(&self.holder);
+            self.query::<i64>(&cmd).map(|_| ())
+        }
+
+        fn members(&self, group: &str) -> io::Result<Vec<String>> {
+            let now = now_millis();
+            let mut cmd = redis::cmd("ZREMRANGEBYSCORE");
+            cmd.arg(group).arg("-inf").arg(now);
+            self.query::<i64>(&cmd)?;
+
+            let mut cmd = redis::cmd("ZRANGEBYSCORE");
+            cmd.arg(group).arg(format!("({}", now)).arg("+inf");
+            self.query(&cmd)
+        }
+
+        fn leave(&self, group: &str) -> io::Result<()> {
+            let mut cmd = redis::cmd("ZREM");
+            cmd.arg(group).arg(&self.holder);
+            self.query::<i64>(&cmd).map(|_| ())
+        }
+    }
+
+    impl LeaderElection for RedisLock {
+        fn campaign(&self, key: &str, ttl: Duration) -> io::Result<bool> {
+            self.eval(CAMPAIGN, key, Some(ttl))
+        }
+
+        fn resign(&self, key: &str) -> io::Result<()> {
+            self.eval(RESIGN, key, None).map(|_| ())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn claims_the_file_in_place_of_a_removed_one() {
+        let dir = env::temp_dir().join(format!("xcrond-test-{}.claims", std::process::id()));
+        let lock = FileLock::new(&dir).unwrap();
+        let path = lock.path("a");
+        fs::write(&path, "other:1 0\n").unwrap();
+
+        // Another host holds the record lock of the expired claim while it
+        // removes it, record locks being per process
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            unsafe {
+                let fd = libc::open(c_path.as_ptr(), libc::O_RDWR);
+                let mut l: libc::flock = std::mem::zeroed();
+                l.l_type = libc::F_WRLCK as _;
+                l.l_whence = libc::SEEK_SET as _;
+                libc::fcntl(fd, libc::F_SETLKW, &l);
+                libc::usleep(300_000);
+                libc::unlink(c_path.as_ptr());
+                libc::_exit(0);
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert!(lock.try_acquire("a", Duration::from_secs(60)).unwrap());
+        unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) };
+
+        let claim = read_claim(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(claim.map(|(holder, _)| holder), Some(lock.holder.clone()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
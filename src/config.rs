@@ -0,0 +1,710 @@
+use crate::blackout::Blackout;
+use crate::diagnostic::{closest, Diagnostic, Diagnostics};
+use crate::dialect::Dialect;
+use crate::error::{Result, XcrondError};
+use crate::job::{JobSpec, ScheduleKind};
+use crate::namespace::Namespace;
+use crate::priority::NICE_RANGE;
+use crate::schema::{self, JOBFILE_MIGRATIONS};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Jobfile is the on-disk format of the job definitions.
+/// See the `Jobfile` in the repository root for an example.
+#[derive(Deserialize)]
+pub struct Jobfile {
+    #[serde(default)]
+    pub namespace: Vec<Namespace>,
+    #[serde(default)]
+    pub job: Vec<JobSpec>,
+    /// blackout windows of every job
+    #[serde(default)]
+    pub blackout: Vec<Blackout>,
+    /// addresses the output of the runs is mailed to, unless the job sets
+    /// its own
+    #[serde(default)]
+    pub mailto: Option<String>,
+    /// shell running the commands of the jobs that don't set their own
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// PATH of the jobs that don't set their own
+    #[serde(default)]
+    pub path: Option<String>,
+    /// environment variables of every job, the job's own win
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+// Metadata key holding the fragment a job was loaded from
+pub(crate) const FRAGMENT_KEY: &str = "jobfile";
+
+// Keys of the tables of a Jobfile, unknown keys are most likely typos
+const TOP_KEYS: &[&str] = &["version", "dialect", "aliases", "blackout", "mailto", "shell", "path", "env", "namespace", "job"];
+const JOB_KEYS: &[&str] = &[
+    "id",
+    "name",
+    "cmd",
+    "schedule",
+    "dialect",
+    "timezone",
+    "metadata",
+    "lock",
+    "shutdown_policy",
+    "misfire_policy",
+    "overlap_policy",
+    "journal",
+    "singleton_cluster",
+    "namespace",
+    "login_shell",
+    "pipe_to",
+    "daily_budget",
+    "jitter",
+    "blackout",
+    "blackout_policy",
+    "timeout",
+    "max_retries",
+    "backoff",
+    "mailto",
+    "shell",
+    "path",
+    "env",
+    "user",
+    "group",
+    "umask",
+    "nice",
+    "io_priority",
+    "limits",
+    "cgroup",
+];
+const NAMESPACE_KEYS: &[&str] = &["name", "max_jobs", "max_concurrent", "cpu_time", "memory"];
+
+// Shorthands accepted in place of a schedule expression. Dialects other
+// than xcrond's may accept more, e.g. `@midnight`.
+const MACROS: &[&str] = &["@yearly", "@annually", "@monthly", "@weekly", "@daily", "@hourly"];
+
+/// load_jobfile reads and parses the Jobfile at `path`.
+/// Jobfiles of older versions are migrated.
+pub fn load_jobfile(path: &Path) -> Result<Jobfile> {
+    let content = fs::read_to_string(path).map_err(|source| XcrondError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse_jobfile(&content, path)
+}
+
+/// load reads the Jobfile at `path` and the fragments of `dir`, merged.
+/// Fails if the Jobfile can't be loaded, fragments that can't be are logged
+/// and skipped.
+pub fn load(path: Option<&Path>, dir: Option<&Path>) -> Result<Jobfile> {
+    let mut skipped = vec![];
+    let config = load_all(path, dir, &mut skipped)?;
+    for (path, err) in skipped {
+        error!("Skipping {}: {}", path.display(), err);
+    }
+    Ok(config)
+}
+
+/// load_all is `load`, adding the fragments that can't be loaded to
+/// `skipped` with the reason why
+pub fn load_all(
+    path: Option<&Path>,
+    dir: Option<&Path>,
+    skipped: &mut Vec<(PathBuf, XcrondError)>,
+) -> Result<Jobfile> {
+    let mut config = Jobfile {
+        namespace: vec![],
+        job: vec![],
+        blackout: vec![],
+        mailto: None,
+        shell: None,
+        path: None,
+        env: BTreeMap::new(),
+    };
+    let mut jobfiles = vec![];
+    if let Some(path) = path {
+        info!("Loading jobs from {}", path.display());
+        jobfiles.push(load_jobfile(path)?);
+    }
+    if let Some(dir) = dir {
+        // A broken fragment only loses its own jobs
+        for path in fragments(dir)? {
+            info!("Loading jobs from {}", path.display());
+            match load_jobfile(&path) {
+                Ok(mut jobfile) => {
+                    for spec in &mut jobfile.job {
+                        spec.metadata.insert(FRAGMENT_KEY.to_string(), path.display().to_string());
+                    }
+                    jobfiles.push(jobfile);
+                }
+                Err(err) => skipped.push((path, err)),
+            }
+        }
+    }
+    for jobfile in jobfiles {
+        config.namespace.extend(jobfile.namespace);
+        config.job.extend(jobfile.job);
+        config.blackout.extend(jobfile.blackout);
+        // The Jobfile's address wins over those of the fragments
+        config.mailto = config.mailto.or(jobfile.mailto);
+        config.shell = config.shell.or(jobfile.shell);
+        config.path = config.path.or(jobfile.path);
+        for (k, v) in jobfile.env {
+            config.env.entry(k).or_insert(v);
+        }
+    }
+    // Jobs are run with the shell of the Jobfile unless they set their own
+    if let Some(shell) = &config.shell {
+        for spec in config.job.iter_mut().filter(|s| s.shell.is_none()) {
+            spec.shell = Some(shell.clone());
+        }
+    }
+    if let Some(path) = &config.path {
+        for spec in config.job.iter_mut().filter(|s| s.path.is_none()) {
+            spec.path = Some(path.clone());
+        }
+    }
+    for spec in &mut config.job {
+        for (k, v) in &config.env {
+            spec.env.entry(k.clone()).or_insert_with(|| v.clone());
+        }
+    }
+    Ok(config)
+}
+
+/// fragments returns the Jobfile fragments of `dir`, its `*.toml` files,
+/// in the order they are loaded. A missing directory has none.
+pub fn fragments(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(source) => {
+            return Err(XcrondError::Io {
+                path: dir.to_path_buf(),
+                source,
+            })
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+        .map(|e| e.path())
+        .filter(|p| {
+            let hidden = p.file_name().and_then(|n| n.to_str()).is_none_or(|n| n.starts_with('.'));
+            !hidden && p.extension().is_some_and(|e| e == "toml")
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// parse_jobfile parses the content of the Jobfile at `path`. Every problem
+/// found is reported at once, likely typos are only logged.
+fn parse_jobfile(content: &str, path: &Path) -> Result<Jobfile> {
+    let source = Source::new(content);
+    let mut doc: Value = match toml::from_str(content) {
+        Ok(doc) => doc,
+        Err(err) => {
+            let d = Diagnostic::new(path, format!("invalid TOML: {}", err));
+            let d = match err.line_col() {
+                Some((line, col)) => source.point(d, line, col, 1),
+                None => d,
+            };
+            return Err(XcrondError::Config(Diagnostics(vec![d])));
+        }
+    };
+    schema::migrate(&mut doc, JOBFILE_MIGRATIONS, path)?;
+
+    let mut check = Check {
+        path,
+        source: &source,
+        errors: vec![],
+    };
+    check.unknown_keys(&doc, None, 0, TOP_KEYS);
+
+    // Dialect of the schedules of the jobs that don't set theirs
+    let dialect = match doc.get("dialect").map(|v| Dialect::deserialize(v.clone())) {
+        Some(Ok(d)) => Some(d),
+        Some(Err(err)) => {
+            check.top_error("dialect", format!("invalid dialect: {}", err));
+            None
+        }
+        None => None,
+    };
+    let aliases = check.aliases(&doc);
+
+    // Blackout windows of every job
+    let blackout = match doc.get("blackout").map(|v| Vec::<Blackout>::deserialize(v.clone())) {
+        Some(Ok(b)) => b,
+        Some(Err(err)) => {
+            check.top_error("blackout", format!("invalid blackout window: {}", err));
+            vec![]
+        }
+        None => vec![],
+    };
+
+    let mailto = check.string(&doc, "mailto");
+    let shell = check.string(&doc, "shell");
+    let path = check.string(&doc, "path");
+    let env = match doc.get("env").map(|v| BTreeMap::<String, String>::deserialize(v.clone())) {
+        Some(Ok(env)) => env,
+        Some(Err(err)) => {
+            check.top_error("env", format!("invalid env: {}", err));
+            BTreeMap::new()
+        }
+        None => BTreeMap::new(),
+    };
+
+    let mut namespaces = vec![];
+    for (i, v) in tables(&doc, "namespace").iter().enumerate() {
+        check.unknown_keys(v, Some("namespace"), i, NAMESPACE_KEYS);
+        match Namespace::deserialize((*v).clone()) {
+            Ok(ns) => namespaces.push(ns),
+            Err(err) => check.error("namespace", i, None, format!("invalid namespace: {}", err), None),
+        }
+    }
+
+    let mut jobs = vec![];
+    let mut ids = HashMap::new();
+    let mut pipes = vec![];
+    for (i, v) in tables(&doc, "job").iter().enumerate() {
+        check.unknown_keys(v, Some("job"), i, JOB_KEYS);
+        let mut spec = match JobSpec::deserialize((*v).clone()) {
+            Ok(spec) => spec,
+            Err(err) => {
+                check.error("job", i, None, format!("invalid job: {}", err), None);
+                continue;
+            }
+        };
+
+        if spec.dialect.is_none() {
+            spec.dialect = dialect;
+        }
+        if let Some(expr) = aliases.get(spec.schedule.trim()) {
+            spec.schedule = expr.clone();
+        }
+
+        if spec.cmd.trim().is_empty() {
+            check.error("job", i, Some("cmd"), format!("[{}] command is empty", spec.name), None);
+        }
+        let schedule = spec
+            .dialect
+            .unwrap_or_default()
+            .normalize(&spec.schedule, &spec.name)
+            .and_then(|expr| ScheduleKind::parse(&expr));
+        if is_alias(&spec.schedule) {
+            let known: Vec<&str> = aliases.keys().map(String::as_str).chain(MACROS.iter().copied()).collect();
+            let msg = format!("[{}] unknown schedule alias `{}`", spec.name, spec.schedule.trim());
+            let help = closest(spec.schedule.trim(), &known)
+                .map(|a| format!("did you mean `{}`?", a))
+                .or_else(|| suggest_schedule(&spec.schedule));
+            check.error("job", i, Some("schedule"), msg, help);
+        } else if let Err(err) = schedule {
+            let msg = format!("[{}] invalid schedule `{}`: {}", spec.name, spec.schedule, err);
+            let help = match spec.dialect {
+                None | Some(Dialect::Xcrond) => suggest_schedule(&spec.schedule),
+                Some(_) => None,
+            };
+            check.error("job", i, Some("schedule"), msg, help);
+        }
+        if let Some(nice) = spec.nice.filter(|n| *n < NICE_RANGE.0 || *n > NICE_RANGE.1) {
+            let msg = format!("[{}] invalid nice {}", spec.name, nice);
+            check.error("job", i, Some("nice"), msg, Some("from -20 to 19".to_string()));
+        }
+        if let Err(XcrondError::InvalidUmask { value, .. }) = spec.umask() {
+            let msg = format!("[{}] invalid umask `{}`", spec.name, value);
+            check.error("job", i, Some("umask"), msg, Some("e.g. `022` or `077`".to_string()));
+        }
+        for duration in &[spec.jitter(), spec.timeout(), spec.backoff()] {
+            if let Err(XcrondError::InvalidDuration { key, reason, .. }) = duration {
+                let msg = format!("[{}] invalid {}: {}", spec.name, key, reason);
+                check.error("job", i, Some(key), msg, Some("e.g. `30s`, `5m` or `1h30m`".to_string()));
+            }
+        }
+        if let Some(id) = spec.id {
+            if let Some(other) = ids.insert(id, spec.name.clone()) {
+                let msg = format!("[{}] id {} is already used by {}", spec.name, id, other);
+                check.error("job", i, Some("id"), msg, None);
+            }
+        }
+        if let Some(to) = &spec.pipe_to {
+            pipes.push((i, spec.name.clone(), to.clone()));
+        }
+        jobs.push(spec);
+    }
+
+    // Jobs can only pipe to the jobs of the same file
+    let names: Vec<&str> = jobs.iter().map(|j| j.name.as_str()).collect();
+    for (i, name, to) in pipes {
+        if to == name {
+            check.error("job", i, Some("pipe_to"), format!("[{}] can't pipe to itself", name), None);
+        } else if !names.contains(&to.as_str()) {
+            let msg = format!("[{}] pipes to an unknown job `{}`", name, to);
+            let help = closest(&to, &names).map(|n| format!("did you mean `{}`?", n));
+            check.error("job", i, Some("pipe_to"), msg, help);
+        }
+    }
+
+    if !check.errors.is_empty() {
+        return Err(XcrondError::Config(Diagnostics(check.errors)));
+    }
+    Ok(Jobfile {
+        namespace: namespaces,
+        job: jobs,
+        blackout,
+        mailto,
+        shell,
+        path,
+        env,
+    })
+}
+
+/// tables returns the tables of the array of tables `key`
+fn tables<'a>(doc: &'a Value, key: &str) -> Vec<&'a Value> {
+    match doc.get(key) {
+        Some(Value::Array(a)) => a.iter().collect(),
+        _ => vec![],
+    }
+}
+
+/// is_alias tells whether `expr` refers to an alias rather than to a
+/// shorthand of the cron dialects
+fn is_alias(expr: &str) -> bool {
+    let expr = expr.trim();
+    expr.starts_with('@') && !MACROS.contains(&expr) && expr != "@midnight" && expr != "@reboot"
+}
+
+/// suggest_schedule suggests a fix for an invalid schedule expression
+fn suggest_schedule(expr: &str) -> Option<String> {
+    let expr = expr.trim();
+    if expr.starts_with('@') {
+        if expr == "@minute" || expr == "@every_minute" {
+            return Some("use `0 * * * * *` to run every minute".to_string());
+        }
+        return closest(expr, MACROS).map(|m| format!("did you mean `{}`?", m));
+    }
+    match expr.split_whitespace().count() {
+        5 => Some(format!(
+            "did you mean 6-field syntax? Schedules start with a seconds field: `0 {}`",
+            expr
+        )),
+        n if n < 5 => Some("schedules have 6 fields: sec min hour day-of-month month day-of-week".to_string()),
+        _ => None,
+    }
+}
+
+/// Check collects the problems found in a Jobfile
+struct Check<'a> {
+    path: &'a Path,
+    source: &'a Source<'a>,
+    errors: Vec<Diagnostic>,
+}
+
+impl Check<'_> {
+    /// error records a problem with the `index`th table of `array`, pointing
+    /// at the value of `key` if given
+    fn error(&mut self, array: &str, index: usize, key: Option<&str>, msg: String, help: Option<String>) {
+        let mut d = Diagnostic::new(self.path, msg);
+        d = match key.and_then(|k| self.source.value(array, index, k)) {
+            Some((line, col, len)) => self.source.point(d, line, col, len),
+            None => match self.source.header(array, index) {
+                Some(line) => self.source.point(d, line, 0, self.source.line(line).trim_end().len()),
+                None => d,
+            },
+        };
+        if let Some(help) = help {
+            d = d.with_help(help);
+        }
+        self.errors.push(d);
+    }
+
+    /// aliases returns the schedule aliases of the `[aliases]` table, by
+    /// their `@name`. Aliases must be valid schedules of the Jobfile's dialect
+    /// and can't shadow the shorthands of the dialects.
+    fn aliases(&mut self, doc: &Value) -> HashMap<String, String> {
+        let mut aliases = HashMap::new();
+        let table = match doc.get("aliases") {
+            Some(Value::Table(t)) => t,
+            Some(_) => {
+                self.top_error("aliases", "`aliases` must be a table of schedules".to_string());
+                return aliases;
+            }
+            None => return aliases,
+        };
+        let dialect = doc
+            .get("dialect")
+            .and_then(|d| Dialect::deserialize(d.clone()).ok())
+            .unwrap_or_default();
+        for (key, v) in table {
+            let name = format!("@{}", key.trim_start_matches('@'));
+            let mut error = |msg: String| {
+                let mut d = Diagnostic::new(self.path, msg);
+                if let Some((line, col, len)) = self.source.table_value("aliases", key) {
+                    d = self.source.point(d, line, col, len);
+                }
+                self.errors.push(d);
+            };
+            let expr = match v.as_str() {
+                Some(e) => e,
+                None => {
+                    error(format!("alias `{}` must be a schedule string", name));
+                    continue;
+                }
+            };
+            if !is_alias(&name) {
+                error(format!("alias `{}` shadows a shorthand", name));
+                continue;
+            }
+            if let Err(err) = dialect
+                .normalize(expr, &name)
+                .and_then(|e| ScheduleKind::parse(&e))
+            {
+                error(format!("alias `{}` has an invalid schedule `{}`: {}", name, expr, err));
+                continue;
+            }
+            aliases.insert(name, expr.to_string());
+        }
+        aliases
+    }
+
+    /// string returns the string at the top-level `key`, recording an
+    /// error if it isn't one
+    fn string(&mut self, doc: &Value, key: &str) -> Option<String> {
+        match doc.get(key) {
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(v) => {
+                self.top_error(key, format!("invalid {}: expected a string, found {}", key, v.type_str()));
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// top_error records a problem with the top level `key`
+    fn top_error(&mut self, key: &str, msg: String) {
+        let mut d = Diagnostic::new(self.path, msg);
+        if let Some((line, col)) = self.source.key(None, 0, key) {
+            d = self.source.point(d, line, col, self.source.line(line).trim_end().len() - col);
+        }
+        self.errors.push(d);
+    }
+
+    /// unknown_keys warns about the keys of the table missing from `known`
+    fn unknown_keys(&self, table: &Value, array: Option<&str>, index: usize, known: &[&str]) {
+        let table = match table.as_table() {
+            Some(t) => t,
+            None => return,
+        };
+        for k in table.keys().filter(|k| !known.contains(&k.as_str())) {
+            let mut d = Diagnostic::new(self.path, format!("unknown key `{}` is ignored", k));
+            if let Some((line, col)) = self.source.key(array, index, k) {
+                d = self.source.point(d, line, col, k.chars().count());
+            }
+            if let Some(c) = closest(k, known) {
+                d = d.with_help(format!("did you mean `{}`?", c));
+            }
+            warn!("{}", d);
+        }
+    }
+}
+
+/// Source locates the keys of a TOML document in its text
+struct Source<'a> {
+    lines: Vec<&'a str>,
+}
+
+impl<'a> Source<'a> {
+    fn new(text: &'a str) -> Self {
+        Source {
+            lines: text.lines().collect(),
+        }
+    }
+
+    fn line(&self, n: usize) -> &'a str {
+        self.lines.get(n).copied().unwrap_or("")
+    }
+
+    /// point points `d` at `len` characters from the 0-based `line` and
+    /// `col`
+    fn point(&self, d: Diagnostic, line: usize, col: usize, len: usize) -> Diagnostic {
+        d.at(line + 1, col + 1, len, self.line(line))
+    }
+
+    /// header returns the line of the header of the `index`th table of the
+    /// array of tables `array`
+    fn header(&self, array: &str, index: usize) -> Option<usize> {
+        let header = format!("[[{}]]", array);
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.trim() == header)
+            .nth(index)
+            .map(|(n, _)| n)
+    }
+
+    /// key returns the line and column of `key` in the `index`th table of the
+    /// array of tables `array`, or at the top level of the document
+    fn key(&self, array: Option<&str>, index: usize, key: &str) -> Option<(usize, usize)> {
+        let start = match array {
+            Some(a) => self.header(a, index)? + 1,
+            None => 0,
+        };
+        self.key_from(start, key)
+    }
+
+    /// key_from returns the line and column of `key` in the table starting
+    /// on line `start`
+    fn key_from(&self, start: usize, key: &str) -> Option<(usize, usize)> {
+        self.lines[start..]
+            .iter()
+            .take_while(|l| !l.trim_start().starts_with('['))
+            .enumerate()
+            .find(|(_, l)| {
+                let l = l.trim_start();
+                l.strip_prefix(key)
+                    .is_some_and(|rest| rest.trim_start().starts_with('='))
+            })
+            .map(|(n, l)| (start + n, l.len() - l.trim_start().len()))
+    }
+
+    /// value returns the line, column and length of the value of `key` in
+    /// the `index`th table of the array of tables `array`
+    fn value(&self, array: &str, index: usize, key: &str) -> Option<(usize, usize, usize)> {
+        let (line, _) = self.key(Some(array), index, key)?;
+        self.value_at(line)
+    }
+
+    /// table_value returns the line, column and length of the value of `key`
+    /// in the table `table`
+    fn table_value(&self, table: &str, key: &str) -> Option<(usize, usize, usize)> {
+        let header = format!("[{}]", table);
+        let start = self.lines.iter().position(|l| l.trim() == header)? + 1;
+        let line = self.key_from(start, key)?.0;
+        self.value_at(line)
+    }
+
+    /// value_at returns the line, column and length of the value of the key
+    /// defined on `line`
+    fn value_at(&self, line: usize) -> Option<(usize, usize, usize)> {
+        let text = self.line(line);
+        let eq = text.find('=')?;
+        let value = text[eq + 1..].trim();
+        let col = text.chars().count() - text[eq + 1..].trim_start().chars().count();
+        Some((line, col, value.chars().count()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_problem() {
+        let content = "\
+[[job]]
+name = 'a'
+cmd = '/bin/true'
+schedule = '*/5 * * * *'
+
+[[job]]
+name = 'b'
+cmd = ''
+schedule = '@daly'
+";
+        let err = match parse_jobfile(content, Path::new("Jobfile")) {
+            Err(XcrondError::Config(d)) => d.0,
+            _ => panic!("the Jobfile is invalid"),
+        };
+        assert_eq!(err.len(), 3);
+
+        assert_eq!(err[0].position, Some((4, 12)));
+        assert_eq!(err[0].len, 13);
+        assert_eq!(
+            err[0].help.as_deref(),
+            Some("did you mean 6-field syntax? Schedules start with a seconds field: `0 */5 * * * *`")
+        );
+        assert_eq!(err[1].position, Some((8, 7)));
+        assert_eq!(err[2].position, Some((9, 12)));
+        assert_eq!(err[2].help.as_deref(), Some("did you mean `@daily`?"));
+    }
+
+    #[test]
+    fn applies_the_file_dialect() {
+        let content = "\
+dialect = 'vixie'
+
+[[job]]
+name = 'a'
+cmd = '/bin/true'
+schedule = '*/5 * * * 1-5'
+
+[[job]]
+name = 'b'
+cmd = '/bin/true'
+schedule = '0 0 2 ? * *'
+dialect = 'quartz'
+";
+        let jobfile = parse_jobfile(content, Path::new("Jobfile")).unwrap();
+        assert_eq!(jobfile.job[0].dialect, Some(Dialect::Vixie));
+        assert_eq!(jobfile.job[1].dialect, Some(Dialect::Quartz));
+        assert_eq!(jobfile.job[0].expression().unwrap(), "0 */5 * * * Mon-Fri");
+
+        let content = "dialect = 'vixie'\n[[job]]\nname = 'a'\ncmd = '/bin/true'\nschedule = '0 */5 * * * *'\n";
+        let err = match parse_jobfile(content, Path::new("Jobfile")) {
+            Err(XcrondError::Config(d)) => d.0,
+            _ => panic!("the schedule has 6 fields"),
+        };
+        assert_eq!(err.len(), 1);
+        assert_eq!(err[0].position, Some((5, 12)));
+    }
+
+    #[test]
+    fn expands_aliases() {
+        let content = "\
+[aliases]
+backup = '0 30 2 * * *'
+businesshours = '0 0 9-17 * * Mon-Fri'
+
+[[job]]
+name = 'a'
+cmd = '/bin/true'
+schedule = '@backup'
+";
+        let jobfile = parse_jobfile(content, Path::new("Jobfile")).unwrap();
+        assert_eq!(jobfile.job[0].schedule, "0 30 2 * * *");
+
+        let content = "\
+[aliases]
+backup = '0 30 2 * * *'
+daily = '0 0 1 * * *'
+nightly = '0 0 25 * * *'
+
+[[job]]
+name = 'a'
+cmd = '/bin/true'
+schedule = '@bakup'
+";
+        let err = match parse_jobfile(content, Path::new("Jobfile")) {
+            Err(XcrondError::Config(d)) => d.0,
+            _ => panic!("the Jobfile is invalid"),
+        };
+        assert_eq!(err.len(), 3);
+        assert!(err[0].message.contains("shadows"));
+        assert_eq!(err[1].position, Some((4, 11)));
+        assert_eq!(err[2].message, "[a] unknown schedule alias `@bakup`");
+        assert_eq!(err[2].help.as_deref(), Some("did you mean `@backup`?"));
+    }
+
+    #[test]
+    fn points_at_syntax_errors() {
+        let err = match parse_jobfile("[[job]]\nname = 'a\n", Path::new("Jobfile")) {
+            Err(XcrondError::Config(d)) => d.0,
+            _ => panic!("the Jobfile is invalid"),
+        };
+        assert_eq!(err.len(), 1);
+        assert_eq!(err[0].position.map(|p| p.0), Some(2));
+    }
+}
@@ -0,0 +1,78 @@
+//! Detaching the daemon from the terminal, for hosts without a service manager.
+
+use crate::error::{Result, XcrondError};
+use nix::unistd::{chdir, dup2, fork, setsid, ForkResult};
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// daemonize detaches the process with the classic double fork: the
+/// original process exits, the daemon runs in a new session without a
+/// controlling terminal and with `/` as working directory.
+/// Stdin is redirected to /dev/null, stdout and stderr are appended to
+/// `log` if given and discarded otherwise.
+///
+/// Must be called before any thread is spawned, only the calling thread
+/// survives the fork. Relative paths used afterwards resolve from `/`.
+pub fn daemonize(log: Option<&Path>) -> Result<()> {
+    // Open files before forking so errors are reported to the terminal
+    let stdin = open_null(false)?;
+    let stdout = match log {
+        Some(path) => open_log(path)?,
+        None => open_null(true)?,
+    };
+
+    detach()?;
+    setsid().map_err(XcrondError::Daemonize)?;
+    // Not a session leader anymore, so a terminal can never be reacquired
+    detach()?;
+
+    chdir("/").map_err(XcrondError::Daemonize)?;
+    redirect(&stdin, libc::STDIN_FILENO)?;
+    redirect(&stdout, libc::STDOUT_FILENO)?;
+    redirect(&stdout, libc::STDERR_FILENO)?;
+    Ok(())
+}
+
+/// redirect_logs appends stdout and stderr, where the logs go, to the file
+/// at `path`, for daemons running in the foreground
+pub fn redirect_logs(path: &Path) -> Result<()> {
+    let f = open_log(path)?;
+    redirect(&f, libc::STDOUT_FILENO)?;
+    redirect(&f, libc::STDERR_FILENO)
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|source| XcrondError::Write {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+fn open_null(write: bool) -> Result<File> {
+    OpenOptions::new()
+        .read(!write)
+        .write(write)
+        .open("/dev/null")
+        .map_err(|source| XcrondError::Io {
+            path: "/dev/null".into(),
+            source,
+        })
+}
+
+/// detach forks, exiting in the parent
+fn detach() -> Result<()> {
+    match fork().map_err(XcrondError::Daemonize)? {
+        ForkResult::Parent { .. } => unsafe { libc::_exit(0) },
+        ForkResult::Child => Ok(()),
+    }
+}
+
+fn redirect(f: &File, fd: libc::c_int) -> Result<()> {
+    dup2(f.as_raw_fd(), fd).map_err(XcrondError::Daemonize)?;
+    Ok(())
+}
@@ -0,0 +1,130 @@
+//! Diagnostics of configuration files.
+//!
+//! Problems found in a file are collected rather than reported one at a time,
+//! each pointing at the offending text and suggesting a fix when one is
+//! likely, e.g.
+//!
+//! ```text
+//! Jobfile:12:12: invalid schedule `*/5 * * * *`: Invalid cron expression.
+//!    |
+//! 12 | schedule = '*/5 * * * *'
+//!    |            ^^^^^^^^^^^^^
+//!    = help: did you mean 6-field syntax? Schedules start with a seconds field: `0 */5 * * * *`
+//! ```
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// Diagnostic is a problem found in a configuration file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub path: PathBuf,
+    /// position of the offending text, 1-based line and column, if known
+    pub position: Option<(usize, usize)>,
+    /// line holding the offending text
+    pub source_line: Option<String>,
+    /// length of the offending text, in characters
+    pub len: usize,
+    pub message: String,
+    /// suggested fix
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new<P: Into<PathBuf>>(path: P, message: String) -> Self {
+        Diagnostic {
+            path: path.into(),
+            position: None,
+            source_line: None,
+            len: 0,
+            message,
+            help: None,
+        }
+    }
+
+    /// at points the diagnostic at `len` characters of `source_line`, from
+    /// the 1-based `line` and `column`
+    pub fn at(mut self, line: usize, column: usize, len: usize, source_line: &str) -> Self {
+        self.position = Some((line, column));
+        self.len = len;
+        self.source_line = Some(source_line.to_string());
+        self
+    }
+
+    /// with_help adds a suggestion to the diagnostic
+    pub fn with_help(mut self, help: String) -> Self {
+        self.help = Some(help);
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(f, "{}:{}:{}: {}", self.path.display(), line, column, self.message)?,
+            None => write!(f, "{}: {}", self.path.display(), self.message)?,
+        }
+
+        let gutter = self.position.map_or(0, |(line, _)| line.to_string().len());
+        if let (Some((line, column)), Some(text)) = (self.position, &self.source_line) {
+            let pad = " ".repeat(gutter);
+            write!(f, "\n{} |", pad)?;
+            write!(f, "\n{} | {}", line, text)?;
+            write!(
+                f,
+                "\n{} | {}{}",
+                pad,
+                " ".repeat(column.saturating_sub(1)),
+                "^".repeat(std::cmp::max(self.len, 1))
+            )?;
+        }
+        if let Some(help) = &self.help {
+            write!(f, "\n{} = help: {}", " ".repeat(gutter), help)?;
+        }
+        Ok(())
+    }
+}
+
+/// Diagnostics are the problems preventing a file from being loaded
+#[derive(Debug, Clone)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, d) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", d)?;
+        }
+        Ok(())
+    }
+}
+
+/// closest returns the candidate closest to `word`, if one is close enough
+/// to be a likely typo
+pub fn closest<'a>(word: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let max = std::cmp::max(2, word.chars().count() / 3);
+    candidates
+        .iter()
+        .map(|c| (distance(word, c), *c))
+        .filter(|(d, _)| *d <= max)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+/// distance returns the Levenshtein distance between `a` and `b`
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = std::cmp::min(cost, std::cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
@@ -0,0 +1,292 @@
+//! Cron dialects schedules can be written in.
+//!
+//! Schedules are written in the xcrond dialect by default: 6 or 7 fields
+//! starting with the seconds, days of the week numbered from 1 on Sunday.
+//! Schedules copied from other ecosystems can keep their syntax by setting
+//! their dialect, they are normalized to the xcrond dialect when the job is
+//! registered:
+//!
+//! - `vixie`: the 5 fields of crontabs, days of the week numbered from 0 or
+//!   7 on Sunday, and the `@midnight` shorthand. GitHub Actions schedules
+//!   use this dialect.
+//! - `quartz`: 6 or 7 fields as in xcrond, with `?` for "no specific value".
+//!   The `L`, `W` and `#` extensions aren't supported.
+//! - `jenkins`: the vixie dialect with the `H` hash extension, e.g.
+//!   `H H(0-6) * * *`, spreading the jobs over the range with a value derived
+//!   from their name.
+//! - `systemd`: the calendar events of systemd timers, e.g.
+//!   `Mon..Fri *-*-* 09:00:00` or `daily`, as written in `OnCalendar=`.
+//!   Timezones and days counted from the end of the month aren't supported.
+
+use crate::import::on_calendar;
+use crate::job::{interval, one_shot};
+use serde::{Deserialize, Serialize};
+
+/// Dialect is the cron dialect a schedule is written in
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dialect {
+    #[default]
+    Xcrond,
+    Vixie,
+    Quartz,
+    Jenkins,
+    Systemd,
+}
+
+// Ranges of the 5 fields of vixie and Jenkins schedules. Jenkins hashes the
+// day of month within 1-28 so the job runs every month.
+const FIELDS: &[(&str, u32, u32)] = &[
+    ("minute", 0, 59),
+    ("hour", 0, 23),
+    ("day of month", 1, 28),
+    ("month", 1, 12),
+    ("day of week", 0, 6),
+];
+
+impl Dialect {
+    /// normalize converts `expr`, a schedule of this dialect, to the xcrond
+    /// dialect. `name`, the name of the job, seeds the hashes of Jenkins
+    /// schedules. Intervals such as `every 90s` and single occurrences
+    /// such as `at 2030-01-01T09:00:00Z` are the same in every dialect.
+    pub fn normalize(self, expr: &str, name: &str) -> Result<String, String> {
+        let expr = expr.trim();
+        if interval(expr).is_some() || one_shot(expr).is_some() {
+            return Ok(expr.to_string());
+        }
+        match self {
+            Dialect::Xcrond => Ok(expr.to_string()),
+            Dialect::Vixie => vixie(expr),
+            Dialect::Quartz => quartz(expr),
+            Dialect::Jenkins => vixie(&jenkins(expr, name)?),
+            Dialect::Systemd => on_calendar(expr),
+        }
+    }
+}
+
+/// vixie converts a 5-field crontab schedule
+fn vixie(expr: &str) -> Result<String, String> {
+    match expr {
+        "@midnight" => return Ok("@daily".to_string()),
+        "@reboot" => return Err("@reboot has no equivalent".to_string()),
+        e if e.starts_with('@') => return Ok(e.to_string()),
+        _ => {}
+    }
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    if fields.len() != 5 {
+        return Err(format!("expected 5 fields, found {}", fields.len()));
+    }
+    Ok(format!(
+        "0 {} {} {} {} {}",
+        fields[0],
+        fields[1],
+        fields[2],
+        fields[3],
+        crontab_weekdays(fields[4])?
+    ))
+}
+
+/// quartz converts a Quartz schedule, whose fields are those of xcrond
+fn quartz(expr: &str) -> Result<String, String> {
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    if fields.len() != 6 && fields.len() != 7 {
+        return Err(format!("expected 6 or 7 fields, found {}", fields.len()));
+    }
+    fields
+        .iter()
+        .map(|f| match *f {
+            "?" => Ok("*"),
+            f if f.split([',', '-', '/']).any(unsupported) => {
+                Err(format!("`{}` isn't supported", f))
+            }
+            f => Ok(f),
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|fields| fields.join(" "))
+}
+
+/// unsupported tells whether an item of a Quartz field uses the `L`, `W` or
+/// `#` extensions, unlike the names of months and days such as `JUL`
+fn unsupported(item: &str) -> bool {
+    let named = item.len() >= 3 && item.chars().all(|c| c.is_ascii_alphabetic());
+    item.contains('#') || (item.contains(['L', 'W']) && !named)
+}
+
+/// jenkins replaces the hashes of a Jenkins schedule with the values derived
+/// from `name`, giving a vixie schedule
+fn jenkins(expr: &str, name: &str) -> Result<String, String> {
+    let expr = match expr {
+        "@yearly" | "@annually" => "H H H H *",
+        "@monthly" => "H H H * *",
+        "@weekly" => "H H * * H",
+        "@daily" | "@midnight" => "H H * * *",
+        "@hourly" => "H * * * *",
+        e => e,
+    };
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    if fields.len() != 5 {
+        return Err(format!("expected 5 fields, found {}", fields.len()));
+    }
+    let seed = fnv1a(name);
+    fields
+        .iter()
+        .zip(FIELDS)
+        .enumerate()
+        .map(|(i, (f, &(field, min, max)))| {
+            // Each field gets its own value, so that `H H` doesn't run at
+            // 5:05, 6:06...
+            let hash = seed.rotate_left(i as u32 * 13);
+            f.split(',')
+                .map(|item| {
+                    hashed(item, hash, min, max).map_err(|e| format!("{} `{}`: {}", field, f, e))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(|items| items.join(","))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|fields| fields.join(" "))
+}
+
+/// hashed replaces the hash of an item of a Jenkins field: `H` is a value of
+/// the field's range, `H(a-b)` a value of `a-b`, and `H/n` or `H(a-b)/n`
+/// start the steps at a value of the first step
+fn hashed(item: &str, hash: u64, min: u32, max: u32) -> Result<String, String> {
+    let rest = match item.strip_prefix('H') {
+        Some(rest) => rest,
+        None => return Ok(item.to_string()),
+    };
+    let (range, step) = match rest.split_once('/') {
+        Some((r, s)) => (r, Some(s)),
+        None => (rest, None),
+    };
+    let (lo, hi) = match range {
+        "" => (min, max),
+        r => {
+            let (a, b) = r
+                .strip_prefix('(')
+                .and_then(|r| r.strip_suffix(')'))
+                .and_then(|r| r.split_once('-'))
+                .ok_or_else(|| format!("invalid hash `{}`", item))?;
+            let a: u32 = a.parse().map_err(|_| format!("invalid hash `{}`", item))?;
+            let b: u32 = b.parse().map_err(|_| format!("invalid hash `{}`", item))?;
+            if a > b || a < min || b > max {
+                return Err(format!("invalid hash range `{}`", item));
+            }
+            (a, b)
+        }
+    };
+    match step {
+        Some(s) => {
+            let step: u32 = s.parse().map_err(|_| format!("invalid step `{}`", s))?;
+            if step == 0 {
+                return Err(format!("invalid step `{}`", s));
+            }
+            let start = lo + (hash % u64::from(std::cmp::min(step, hi - lo + 1))) as u32;
+            Ok(format!("{}-{}/{}", start, hi, step))
+        }
+        None => Ok((lo + (hash % u64::from(hi - lo + 1)) as u32).to_string()),
+    }
+}
+
+/// fnv1a is the FNV-1a hash of `s`, stable across releases and platforms
+pub(crate) fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Names of the days of the week, numbered from 0 on Sunday as crontabs do
+pub(crate) const CRONTAB_WEEKDAYS: &[&str] = &["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// crontab_weekdays names the days of the week of a crontab field, which
+/// are numbered from 0 or 7 on Sunday instead of 1
+pub(crate) fn crontab_weekdays(f: &str) -> Result<String, String> {
+    let day = |d: &str| match d.parse::<usize>() {
+        Ok(n) if n < CRONTAB_WEEKDAYS.len() => Ok(CRONTAB_WEEKDAYS[n].to_string()),
+        Ok(_) => Err(format!("invalid day of week `{}`", d)),
+        Err(_) => Ok(d.to_string()),
+    };
+    f.split(',')
+        .map(|item| {
+            let (range, step) = match item.split_once('/') {
+                Some((r, s)) => (r, Some(s)),
+                None => (item, None),
+            };
+            let range = match range.split_once('-') {
+                // Ranges can't wrap around, 7 ends the week on Sunday
+                Some((a, "7")) if day(a)? != "Sun" => format!("{}-Sat,Sun", day(a)?),
+                Some((a, b)) => format!("{}-{}", day(a)?, day(b)?),
+                None => day(range)?,
+            };
+            Ok(match step {
+                Some(_) if range.contains(',') => {
+                    return Err(format!("unsupported day of week `{}`", f))
+                }
+                Some(s) => format!("{}/{}", range, s),
+                None => range,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|items| items.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cron::Schedule;
+    use std::str::FromStr;
+
+    #[test]
+    fn normalizes_dialects() {
+        let cases = [
+            (Dialect::Xcrond, "0 30 8 * * Mon-Fri", "0 30 8 * * Mon-Fri"),
+            (Dialect::Vixie, "30 8 * * 1-5", "0 30 8 * * Mon-Fri"),
+            (Dialect::Vixie, "0 22 * * 0,6", "0 0 22 * * Sun,Sat"),
+            (Dialect::Vixie, "@midnight", "@daily"),
+            (Dialect::Quartz, "0 15 10 ? JUL WED", "0 15 10 * JUL WED"),
+            (Dialect::Jenkins, "30 8 * * 1-5", "0 30 8 * * Mon-Fri"),
+            (Dialect::Systemd, "Mon..Fri *-*-* 09:00:00", "0 0 9 * * Mon-Fri"),
+            (Dialect::Systemd, "weekly", "0 0 0 * * Mon"),
+        ];
+        for (dialect, expr, normalized) in cases.iter() {
+            assert_eq!(
+                dialect.normalize(expr, "job").as_deref(),
+                Ok(*normalized),
+                "{}",
+                expr
+            );
+        }
+        assert!(Dialect::Vixie.normalize("0 30 8 * * *", "job").is_err());
+        assert!(Dialect::Quartz.normalize("0 15 10 L * ?", "job").is_err());
+        assert!(Dialect::Quartz.normalize("0 15 10 ? * 6#3", "job").is_err());
+        assert!(Dialect::Systemd.normalize("*-*~01", "job").is_err());
+    }
+
+    #[test]
+    fn hashes_jenkins_schedules() {
+        for expr in ["H H * * *", "H/15 H(8-17) * * 1-5", "H H H H *", "@weekly"].iter() {
+            let a = Dialect::Jenkins.normalize(expr, "backup").unwrap();
+            assert!(!a.contains('H'), "{}", a);
+            assert!(Schedule::from_str(&a).is_ok(), "{}", a);
+            // Stable for a job, spread across jobs
+            assert_eq!(Dialect::Jenkins.normalize(expr, "backup").unwrap(), a);
+        }
+        let spread: std::collections::HashSet<_> = (0..20)
+            .map(|i| {
+                Dialect::Jenkins
+                    .normalize("H * * * *", &format!("job {}", i))
+                    .unwrap()
+            })
+            .collect();
+        assert!(spread.len() > 10);
+
+        let hours = Dialect::Jenkins
+            .normalize("0 H(8-17) * * *", "backup")
+            .unwrap();
+        let hour: u32 = hours.split(' ').nth(2).unwrap().parse().unwrap();
+        assert!((8..=17).contains(&hour));
+        assert!(Dialect::Jenkins
+            .normalize("H(50-70) * * * *", "backup")
+            .is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use crate::diagnostic::Diagnostics;
+use crate::job::JobId;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// XcrondError is the error type of every fallible xcrond operation
+#[derive(Debug, Error)]
+pub enum XcrondError {
+    #[error("[{name}] Invalid schedule `{expr}`: {reason}")]
+    InvalidSchedule {
+        name: String,
+        expr: String,
+        reason: String,
+    },
+
+    #[error("[{name}] Invalid {key} `{value}`: {reason}")]
+    InvalidDuration {
+        name: String,
+        key: &'static str,
+        value: String,
+        reason: String,
+    },
+
+    #[error("[{0}] Schedule has no upcoming occurrences")]
+    ScheduleFinished(String),
+
+    #[error("[{name}] Job id {id} is already registered")]
+    DuplicateId { name: String, id: JobId },
+
+    #[error("[{name}] Runs the same command on the same schedule as {other}, registered once")]
+    DuplicateJob { name: String, other: String },
+
+    #[error("[{name}] Namespace {namespace} already has its maximum of {max} jobs")]
+    NamespaceFull {
+        name: String,
+        namespace: String,
+        max: usize,
+    },
+
+    #[error("[{0}] Command is empty")]
+    EmptyCommand(String),
+
+    #[error("[{name}] Command not found: `{program}` isn't an executable file or in PATH")]
+    CommandNotFound { name: String, program: String },
+
+    #[error("[{name}] Invalid command: {reason}")]
+    InvalidCommand { name: String, reason: String },
+
+    #[error("[{name}] Invalid environment variable `{var}`")]
+    InvalidEnv { name: String, var: String },
+
+    #[error("[{name}] Invalid umask `{value}`: expected an octal mode such as `022`")]
+    InvalidUmask { name: String, value: String },
+
+    #[error("[{name}] Invalid nice `{value}`: expected a value from -20 to 19")]
+    InvalidNice { name: String, value: i32 },
+
+    #[error("Failed to read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Failed to write {}: {source}", path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Failed to parse {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    #[error("{0}")]
+    Config(Diagnostics),
+
+    #[error("{0} isn't allowed to have jobs, see xcrond.allow and xcrond.deny")]
+    AccessDenied(String),
+
+    #[error("Failed to import {}: {reason}", path.display())]
+    Import { path: PathBuf, reason: String },
+
+    #[error("Failed to serialize {}: {source}", path.display())]
+    Serialize {
+        path: PathBuf,
+        #[source]
+        source: toml::ser::Error,
+    },
+
+    #[error("{}: invalid version `{version}`", path.display())]
+    InvalidVersion { path: PathBuf, version: String },
+
+    #[error("{}: version {version} is newer than the supported version {supported}", path.display())]
+    UnsupportedVersion {
+        path: PathBuf,
+        version: u32,
+        supported: u32,
+    },
+
+    #[error("{} is held by another running instance", path.display())]
+    AlreadyRunning { path: PathBuf, pid: Option<i32> },
+
+    #[error("Failed to listen on {addr}: {source}")]
+    Listen {
+        addr: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Failed to daemonize: {0}")]
+    Daemonize(#[source] nix::Error),
+
+    #[cfg(feature = "daemon")]
+    #[error("Failed to drop privileges to {user}: {source}")]
+    DropPrivileges {
+        user: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[cfg(feature = "daemon")]
+    #[error("Failed to hand over to the new instance: {0}")]
+    Handoff(#[source] io::Error),
+
+    #[cfg(feature = "cluster")]
+    #[error("Cluster lock error: {0}")]
+    Cluster(#[from] redis::RedisError),
+
+    #[cfg(feature = "history")]
+    #[error("History store error: {0}")]
+    History(#[from] rusqlite::Error),
+
+    #[error("Failed to initialize logger: {0}")]
+    Logger(#[from] log::SetLoggerError),
+}
+
+pub type Result<T> = std::result::Result<T, XcrondError>;
@@ -1,38 +1,58 @@
-use crate::job::Job;
+//! Time ordered queue of scheduled work.
+//!
+//! The queue is generic over its payload so it can be reused for any kind
+//! of work, not only process spawning jobs. Payloads due at the same instant
+//! are grouped into a single `Event`.
+
+use crate::job::{Job, JobId};
 use chrono::{DateTime, Local};
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+/// Scheduled is implemented by payloads that can be put in an `EventQueue`
+pub trait Scheduled {
+    /// time returns when the payload is due
+    fn time(&self) -> DateTime<Local>;
+}
+
+impl Scheduled for Job {
+    fn time(&self) -> DateTime<Local> {
+        self.get_next()
+    }
+}
 
 #[derive(Eq, Clone)]
-pub struct Event {
+pub struct Event<T = Job> {
     time: DateTime<Local>,
-    jobs: Vec<Job>,
+    jobs: Vec<T>,
 }
 
-impl std::fmt::Debug for Event {
+impl<T: Debug> std::fmt::Debug for Event<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "Time: {} -> Jobs: {:?}", self.time, self.jobs)
     }
 }
 
-impl Ord for Event {
-    fn cmp(&self, other: &Event) -> Ordering {
+impl<T: Eq> Ord for Event<T> {
+    fn cmp(&self, other: &Event<T>) -> Ordering {
         self.time.cmp(&other.time)
     }
 }
 
-impl PartialOrd for Event {
-    fn partial_cmp(&self, other: &Event) -> Option<Ordering> {
-        Some(self.cmp(&other))
+impl<T: Eq> PartialOrd for Event<T> {
+    fn partial_cmp(&self, other: &Event<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
-impl PartialEq for Event {
-    fn eq(&self, other: &Event) -> bool {
+impl<T> PartialEq for Event<T> {
+    fn eq(&self, other: &Event<T>) -> bool {
         self.time == other.time
     }
 }
 
-impl Event {
+impl<T> Event<T> {
     pub fn new(t: DateTime<Local>) -> Self {
         Event {
             time: t,
@@ -40,63 +60,291 @@ impl Event {
         }
     }
 
-    pub fn push_job(&mut self, j: Job) {
+    pub fn push_job(&mut self, j: T) {
         self.jobs.push(j)
     }
 
-    pub fn get_jobs(&self) -> &Vec<Job> {
+    pub fn get_jobs(&self) -> &Vec<T> {
         &self.jobs
     }
 
+    /// into_jobs consumes the event, returning its payloads
+    pub fn into_jobs(self) -> Vec<T> {
+        self.jobs
+    }
+
     pub fn get_time(&self) -> DateTime<Local> {
         self.time
     }
 }
 
-#[derive(Default)]
-pub struct EventQueue {
-    queue: Vec<Event>,
+/// EventQueue keeps scheduled payloads ordered by their due time.
+/// Payloads due at the same instant are merged into a single event.
+pub struct EventQueue<T = Job> {
+    queue: BTreeMap<DateTime<Local>, Event<T>>,
+    // number of payloads across all events
+    len: usize,
 }
 
-impl EventQueue {
-    pub fn enqueue(&mut self, j: Job) {
-        if self.queue.is_empty() {
-            let mut e = Event::new(j.get_next());
-            e.jobs.push(j);
-            self.queue.push(e);
-        } else {
-            // Algorithm for enqueuing
-            // 1. if event exists in queue, append job(s) from event into existing event
-            // 2. else push the event in correct position
-
-            // Note that the binary search is done using j.next.cmp and not probe.cmp
-            // This is done because we want the binary search to work in reverse order
-            // rather than traditional order because we are maintainig the queue
-            // in reverse order
-            match self
-                .queue
-                .binary_search_by(|probe| j.get_next().cmp(&probe.time))
-            {
-                Ok(pos) => {
-                    // Already in the vector
-                    self.queue[pos].push_job(j);
-                }
-                Err(pos) => {
-                    // Not in the vector
-                    let mut e = Event::new(j.get_next());
-                    e.push_job(j);
-                    self.queue.insert(pos, e);
-                }
-            }
+impl<T> Default for EventQueue<T> {
+    fn default() -> Self {
+        EventQueue {
+            queue: BTreeMap::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T: Scheduled + Debug> EventQueue<T> {
+    /// enqueue adds the payload to the event at its due time,
+    /// creating the event if needed
+    pub fn enqueue(&mut self, j: T) {
+        let time = j.time();
+        self.queue
+            .entry(time)
+            .or_insert_with(|| Event::new(time))
+            .push_job(j);
+        self.len += 1;
+    }
+
+    /// dequeue removes and returns the earliest event
+    pub fn dequeue(&mut self) -> Option<Event<T>> {
+        let time = *self.queue.keys().next()?;
+        let e = self.queue.remove(&time)?;
+        self.len -= e.jobs.len();
+        Some(e)
+    }
+
+    /// peek returns the earliest event without removing it
+    pub fn peek(&self) -> Option<&Event<T>> {
+        self.queue.values().next()
+    }
+
+    /// retain keeps only the payloads for which `f` returns true.
+    /// Events left without any payload are removed from the queue.
+    /// Returns true if at least one payload was removed.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) -> bool {
+        let before = self.len;
+        for e in self.queue.values_mut() {
+            e.jobs.retain(&mut f);
         }
+        self.queue.retain(|_, e| !e.jobs.is_empty());
+        self.len = self.queue.values().map(|e| e.jobs.len()).sum();
+        self.len != before
+    }
+
+    /// retain_at is like `retain`, but only looks at the payloads due at `time`
+    pub fn retain_at<F: FnMut(&T) -> bool>(&mut self, time: DateTime<Local>, f: F) -> bool {
+        let e = match self.queue.get_mut(&time) {
+            Some(e) => e,
+            None => return false,
+        };
+        let before = e.jobs.len();
+        e.jobs.retain(f);
+        let removed = before - e.jobs.len();
+        if e.jobs.is_empty() {
+            self.queue.remove(&time);
+        }
+        self.len -= removed;
+        removed > 0
+    }
+
+    /// iter_at_mut returns an iterator over the payloads due at `time`
+    pub fn iter_at_mut(&mut self, time: DateTime<Local>) -> impl Iterator<Item = &mut T> {
+        self.queue.get_mut(&time).into_iter().flat_map(|e| e.jobs.iter_mut())
+    }
+
+    /// iter returns an iterator over every pending payload, earliest first
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.queue.values().flat_map(|e| e.jobs.iter())
+    }
+
+    /// iter_mut returns an iterator over every pending payload, in no particular order
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.queue.values_mut().flat_map(|e| e.jobs.iter_mut())
     }
 
-    pub fn dequeue(&mut self) -> Option<Event> {
-        self.queue.pop()
+    /// len returns the number of pending payloads in the queue
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
     pub fn debug_print(&self) {
-        // print queue for debugging purpose
-        debug!("Queue: {:?}", self.queue);
+        // print the head of the queue for debugging purpose, the whole queue
+        // can hold far too many jobs to be logged on every tick
+        debug!("Queue: {} jobs, next: {:?}", self.len, self.peek());
+    }
+}
+
+impl EventQueue<Job> {
+    /// remove drops every pending occurrence of the given job.
+    /// Returns true if at least one occurrence was removed.
+    pub fn remove(&mut self, id: JobId) -> bool {
+        self.retain(|j| j.get_id() != id)
+    }
+
+    /// remove_at drops the occurrence of the given job due at `time`.
+    /// Unlike `remove`, it doesn't scan the whole queue.
+    pub fn remove_at(&mut self, id: JobId, time: DateTime<Local>) -> bool {
+        self.retain_at(time, |j| j.get_id() != id)
+    }
+
+    /// contains_at returns true if an occurrence of the given job is due at `time`
+    pub fn contains_at(&self, id: JobId, time: DateTime<Local>) -> bool {
+        self.queue.get(&time).is_some_and(|e| e.jobs.iter().any(|j| j.get_id() == id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[derive(Debug, PartialEq)]
+    struct Payload(&'static str, DateTime<Local>);
+
+    impl Scheduled for Payload {
+        fn time(&self) -> DateTime<Local> {
+            self.1
+        }
+    }
+
+    fn at(secs: i64) -> DateTime<Local> {
+        Local.timestamp_opt(1_500_000_000, 0).unwrap() + Duration::seconds(secs)
+    }
+
+    #[test]
+    fn dequeues_in_time_order() {
+        let mut q = EventQueue::default();
+        q.enqueue(Payload("c", at(30)));
+        q.enqueue(Payload("a", at(10)));
+        q.enqueue(Payload("b", at(20)));
+
+        assert_eq!(q.peek().unwrap().get_time(), at(10));
+        let order: Vec<_> = std::iter::from_fn(|| q.dequeue())
+            .map(|e| e.get_jobs()[0].0)
+            .collect();
+        assert_eq!(order, vec!["a", "b", "c"]);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn merges_payloads_due_at_the_same_time() {
+        let mut q = EventQueue::default();
+        q.enqueue(Payload("a", at(10)));
+        q.enqueue(Payload("b", at(20)));
+        q.enqueue(Payload("c", at(10)));
+        assert_eq!(q.len(), 3);
+
+        let e = q.dequeue().unwrap();
+        assert_eq!(e.get_time(), at(10));
+        assert_eq!(e.get_jobs(), &vec![Payload("a", at(10)), Payload("c", at(10))]);
+        assert_eq!(q.len(), 1);
+    }
+
+    #[test]
+    fn retain_drops_emptied_events() {
+        let mut q = EventQueue::default();
+        q.enqueue(Payload("a", at(10)));
+        q.enqueue(Payload("b", at(10)));
+        q.enqueue(Payload("c", at(20)));
+
+        assert!(q.retain(|p| p.0 != "a" && p.0 != "b"));
+        assert!(!q.retain(|p| p.0 != "a"));
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.peek().unwrap().get_time(), at(20));
+    }
+
+    #[test]
+    fn retain_at_only_touches_one_event() {
+        let mut q = EventQueue::default();
+        q.enqueue(Payload("a", at(10)));
+        q.enqueue(Payload("a", at(20)));
+
+        assert!(q.retain_at(at(10), |p| p.0 != "a"));
+        assert!(!q.retain_at(at(30), |p| p.0 != "a"));
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.peek().unwrap().get_time(), at(20));
+    }
+
+    #[test]
+    fn dequeue_on_empty_queue() {
+        let mut q: EventQueue<Payload> = EventQueue::default();
+        assert!(q.dequeue().is_none());
+        assert!(q.peek().is_none());
+    }
+
+    mod properties {
+        use super::super::EventQueue;
+        use crate::testing::{check_dequeued, drain, items, time, Item};
+        use proptest::prelude::*;
+
+        proptest! {
+            #[test]
+            fn dequeues_every_item_in_order(items in items(64)) {
+                let mut q = EventQueue::default();
+                for i in &items {
+                    q.enqueue(i.clone());
+                }
+                prop_assert_eq!(q.len(), items.len());
+                let events = drain(&mut q)?;
+                check_dequeued(&items, &events)?;
+            }
+
+            #[test]
+            fn peek_is_the_next_dequeued(items in items(64)) {
+                let mut q = EventQueue::default();
+                for i in items {
+                    q.enqueue(i);
+                }
+                while let Some(t) = q.peek().map(|e| e.get_time()) {
+                    prop_assert_eq!(q.dequeue().unwrap().get_time(), t);
+                }
+            }
+
+            #[test]
+            fn retain_keeps_only_matching_items(items in items(64), at in time()) {
+                let mut q = EventQueue::default();
+                for i in &items {
+                    q.enqueue(i.clone());
+                }
+                let even = |i: &Item| i.id.is_multiple_of(2);
+                let removed = q.retain(even);
+                q.retain_at(at, |i| !i.id.is_multiple_of(3));
+
+                let kept: Vec<Item> = items
+                    .iter()
+                    .filter(|i| even(i) && (i.time != at || !i.id.is_multiple_of(3)))
+                    .cloned()
+                    .collect();
+                prop_assert_eq!(removed, items.iter().any(|i| !even(i)));
+                let events = drain(&mut q)?;
+                check_dequeued(&kept, &events)?;
+            }
+
+            #[test]
+            fn interleaved_operations_lose_nothing(items in items(64), split in 0usize..64) {
+                let mut q = EventQueue::default();
+                let (first, rest) = items.split_at(std::cmp::min(split, items.len()));
+                for i in first {
+                    q.enqueue(i.clone());
+                }
+                // Items enqueued after a dequeue may be due earlier than it
+                let mut dequeued: Vec<Item> = q.dequeue().map(|e| e.into_jobs()).unwrap_or_default();
+                for i in rest {
+                    q.enqueue(i.clone());
+                }
+                let events = drain(&mut q)?;
+                dequeued.extend(events.into_iter().flat_map(|e| e.into_jobs()));
+
+                let mut ids: Vec<usize> = dequeued.iter().map(|i| i.id).collect();
+                ids.sort_unstable();
+                prop_assert_eq!(ids, (0..items.len()).collect::<Vec<_>>());
+            }
+        }
     }
 }
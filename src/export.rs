@@ -0,0 +1,511 @@
+//! Exporting jobs to other schedulers.
+//!
+//! Jobs are converted to a systemd timer unit and the service it activates,
+//! the reverse of `import::systemd_timer`, or to crontab entries, the
+//! reverse of `import::crontab`. Importing the exported jobs gives back the
+//! same schedules.
+
+use crate::job::{interval, one_shot, parse_duration, JobInfo, MisfirePolicy, OverlapPolicy};
+use crate::priority::IoClass;
+use chrono::{DateTime, Utc};
+use std::fmt::Write;
+
+/// SystemdUnits are the unit files running a job with systemd
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SystemdUnits {
+    /// name of the units, without the `.timer` and `.service` suffixes
+    pub name: String,
+    pub timer: String,
+    pub service: String,
+}
+
+/// systemd_units returns the timer and service units equivalent to `job`,
+/// or why the job can't be run by systemd
+pub fn systemd_units(job: &JobInfo) -> Result<SystemdUnits, String> {
+    // Timers don't start a service that is still running, skipping the
+    // overlapping occurrences
+    match job.overlap_policy {
+        OverlapPolicy::Queue => return Err("queues overlapping runs".to_string()),
+        OverlapPolicy::Replace => return Err("replaces overlapping runs".to_string()),
+        OverlapPolicy::Allow | OverlapPolicy::Skip => {}
+    }
+    if job.max_retries > 0 {
+        return Err("retries failed runs".to_string());
+    }
+    // Intervals run from the activation of the timer, then from the end of
+    // the previous run
+    let trigger = match (interval(&job.schedule), one_shot(&job.schedule)) {
+        (Some(i), _) => {
+            let secs = parse_duration(i)?.as_secs();
+            format!("OnActiveSec={}\nOnUnitInactiveSec={}", secs, secs)
+        }
+        (_, Some(t)) => {
+            let t = DateTime::parse_from_rfc3339(t).map_err(|err| format!("has an invalid time `{}`: {}", t, err))?;
+            format!("OnCalendar={}", t.with_timezone(&Utc).format("%Y-%m-%d %H:%M:%S UTC"))
+        }
+        _ => {
+            let mut calendar = calendar(&job.schedule)?;
+            if let Some(tz) = job.timezone {
+                calendar = format!("{} {}", calendar, tz.name());
+            }
+            format!("OnCalendar={}", calendar)
+        }
+    };
+    let name = format!("xcrond-{}", unit_name(&job.name));
+
+    let mut timer = String::new();
+    let _ = writeln!(timer, "[Unit]\nDescription=Timer of {}\n", job.name);
+    let _ = writeln!(timer, "[Timer]\n{}\nAccuracySec=1s", trigger);
+    // Occurrences missed while the system was down are run once at boot
+    if job.misfire_policy == MisfirePolicy::RunOnce && interval(&job.schedule).is_none() {
+        let _ = writeln!(timer, "Persistent=true");
+    }
+    if let Some(jitter) = job.jitter {
+        let _ = writeln!(timer, "RandomizedDelaySec={}", jitter.as_secs());
+    }
+    let _ = writeln!(timer, "\n[Install]\nWantedBy=timers.target");
+
+    let mut service = String::new();
+    let _ = writeln!(service, "[Unit]\nDescription={}\n", job.name);
+    let _ = writeln!(service, "[Service]\nType=oneshot\nExecStart={}", exec_start(job));
+    if let Some(user) = &job.user {
+        let _ = writeln!(service, "User={}", user);
+    }
+    if let Some(group) = &job.group {
+        let _ = writeln!(service, "Group={}", group);
+    }
+    if let Some(umask) = job.umask {
+        let _ = writeln!(service, "UMask={:04o}", umask);
+    }
+    if let Some(nice) = job.nice {
+        let _ = writeln!(service, "Nice={}", nice);
+    }
+    let limits = &job.limits;
+    for (key, limit) in &[
+        ("LimitCPU", limits.cpu_time),
+        ("LimitAS", limits.memory),
+        ("LimitNOFILE", limits.open_files),
+        ("LimitFSIZE", limits.file_size),
+    ] {
+        if let Some(limit) = limit {
+            let _ = writeln!(service, "{}={}", key, limit);
+        }
+    }
+    let cgroup = &job.cgroup;
+    if let Some(bytes) = cgroup.memory_max {
+        let _ = writeln!(service, "MemoryMax={}", bytes);
+    }
+    if let Some(percent) = cgroup.cpu_max {
+        let _ = writeln!(service, "CPUQuota={}%", percent);
+    }
+    if let Some(n) = cgroup.pids_max {
+        let _ = writeln!(service, "TasksMax={}", n);
+    }
+    if let Some(io) = job.io_priority {
+        let class = match io.class {
+            IoClass::Realtime => "realtime",
+            IoClass::BestEffort => "best-effort",
+            IoClass::Idle => "idle",
+        };
+        let _ = writeln!(service, "IOSchedulingClass={}", class);
+        if io.class != IoClass::Idle {
+            let _ = writeln!(service, "IOSchedulingPriority={}", io.level);
+        }
+    }
+    // systemd expands specifiers in environment assignments too
+    for (k, v) in &job.env {
+        let mut var = String::new();
+        for c in format!("{}={}", k, v).chars() {
+            match c {
+                '\\' | '"' => var.push('\\'),
+                '%' => var.push('%'),
+                _ => {}
+            }
+            var.push(c);
+        }
+        let _ = writeln!(service, "Environment=\"{}\"", var);
+    }
+    // systemd looks programs up in its own PATH, the unit's only applies to
+    // the command
+    if let Some(path) = &job.path {
+        let _ = writeln!(service, "Environment=PATH={}", path);
+    }
+    // Runs exceeding the timeout are sent SIGTERM, then SIGKILL 10s later
+    if let Some(timeout) = job.timeout {
+        let _ = writeln!(service, "TimeoutStartSec={}\nTimeoutStopSec=10", timeout.as_secs());
+    }
+
+    Ok(SystemdUnits { name, timer, service })
+}
+
+/// exec_start returns the command line of the service running `job`
+fn exec_start(job: &JobInfo) -> String {
+    match &job.shell {
+        // systemd expands specifiers and variables even in quotes
+        Some(shell) => {
+            let mut cmd = String::new();
+            for c in job.cmd.chars() {
+                match c {
+                    '\\' | '"' => cmd.push('\\'),
+                    '%' | '$' => cmd.push(c),
+                    _ => {}
+                }
+                cmd.push(c);
+            }
+            format!("{} -c \"{}\"", shell, cmd)
+        }
+        None => job.cmd.clone(),
+    }
+}
+
+/// unit_name turns a job name into a valid unit name
+fn unit_name(job: &str) -> String {
+    let mut name = String::new();
+    for c in job.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            name.push(c.to_ascii_lowercase());
+        } else if !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    name.trim_matches('-').to_string()
+}
+
+// Names of the days of the week, numbered from 1 on Sunday as cron does
+const WEEKDAYS: &[&str] = &["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// calendar converts a cron expression to the equivalent systemd calendar
+/// event, e.g. `0 30 8 * * Mon-Fri` to `Mon..Fri *-*-* 08:30:00`
+pub fn calendar(expr: &str) -> Result<String, String> {
+    let shorthand = match expr.trim() {
+        "@yearly" | "@annually" => Some("yearly"),
+        "@monthly" => Some("monthly"),
+        "@weekly" => Some("weekly"),
+        "@daily" => Some("daily"),
+        "@hourly" => Some("hourly"),
+        _ => None,
+    };
+    if let Some(s) = shorthand {
+        return Ok(s.to_string());
+    }
+
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    let (sec, min, hour, day, month, weekday, year) = match fields.as_slice() {
+        [s, m, h, d, mo, w] => (*s, *m, *h, *d, *mo, *w, "*"),
+        [s, m, h, d, mo, w, y] => (*s, *m, *h, *d, *mo, *w, *y),
+        _ => return Err(format!("`{}` doesn't have 6 or 7 fields", expr)),
+    };
+
+    let weekday = match weekday {
+        "*" | "?" => String::new(),
+        w => format!("{} ", weekdays(w)?),
+    };
+    Ok(format!(
+        "{}{}-{}-{} {}:{}:{}",
+        weekday,
+        field(year, 0)?,
+        field(month, 2)?,
+        field(day, 2)?,
+        field(hour, 2)?,
+        field(min, 2)?,
+        field(sec, 2)?
+    ))
+}
+
+/// field converts a cron field to calendar event syntax: ranges are written
+/// `a..b`, and values are padded to `width` digits
+fn field(f: &str, width: usize) -> Result<String, String> {
+    if f == "?" {
+        return Ok("*".to_string());
+    }
+    f.split(',')
+        .map(|item| {
+            let (range, step) = match item.split_once('/') {
+                Some((r, s)) => (r, Some(s)),
+                None => (item, None),
+            };
+            let range = match range.split_once('-') {
+                Some((a, b)) => format!("{}..{}", pad(a, width)?, pad(b, width)?),
+                None => pad(range, width)?,
+            };
+            Ok(match step {
+                Some(s) => format!("{}/{}", range, pad(s, 0)?),
+                None => range,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|items| items.join(","))
+}
+
+/// pad pads a number to `width` digits
+fn pad(n: &str, width: usize) -> Result<String, String> {
+    if n == "*" {
+        return Ok(n.to_string());
+    }
+    match n.parse::<u32>() {
+        Ok(v) => Ok(format!("{:0width$}", v, width = width)),
+        // Months may be named, which systemd doesn't support
+        Err(_) => Err(format!("`{}` isn't supported by systemd", n)),
+    }
+}
+
+/// weekdays converts the day of week field, whose days may be numbered
+fn weekdays(f: &str) -> Result<String, String> {
+    let day = |d: &str| -> Result<String, String> {
+        match d.parse::<usize>() {
+            Ok(n) if (1..=7).contains(&n) => Ok(WEEKDAYS[n - 1].to_string()),
+            Ok(_) => Err(format!("invalid day of week `{}`", d)),
+            Err(_) => Ok(d.to_string()),
+        }
+    };
+    f.split(',')
+        .map(|item| {
+            if item.contains('/') {
+                return Err(format!("steps in the day of week `{}` aren't supported by systemd", f));
+            }
+            match item.split_once('-') {
+                Some((a, b)) => Ok(format!("{}..{}", day(a)?, day(b)?)),
+                None => day(item),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|items| items.join(","))
+}
+
+/// crontab renders the jobs as a crontab. Jobs relying on features cron
+/// doesn't have are commented out, with the reasons.
+pub fn crontab(jobs: &[JobInfo]) -> String {
+    let mut out = String::from("# m h dom mon dow command\n");
+    for j in jobs {
+        let _ = writeln!(out, "\n# {} {}", j.name, j.id);
+        let schedule = crontab_schedule(&j.schedule);
+        let mut reasons = vec![];
+        if let Err(reason) = &schedule {
+            reasons.push(reason.clone());
+        }
+        if let Some(tz) = j.timezone {
+            reasons.push(format!("runs in the {} timezone", tz.name()));
+        }
+        if !j.blackout.is_empty() {
+            reasons.push("has blackout windows".to_string());
+        }
+        if j.jitter.is_some() {
+            reasons.push("delays its occurrences randomly".to_string());
+        }
+        match j.overlap_policy {
+            OverlapPolicy::Allow => {}
+            OverlapPolicy::Skip => reasons.push("skips overlapping runs".to_string()),
+            OverlapPolicy::Queue => reasons.push("queues overlapping runs".to_string()),
+            OverlapPolicy::Replace => reasons.push("replaces overlapping runs".to_string()),
+        }
+        if let Some(shell) = j.shell.as_deref().filter(|s| *s != "/bin/sh") {
+            reasons.push(format!("runs in {}", shell));
+        }
+        if let Some(user) = &j.user {
+            reasons.push(format!("runs as {}", user));
+        }
+        if let Some(group) = &j.group {
+            reasons.push(format!("runs as group {}", group));
+        }
+        if !j.limits.is_empty() {
+            reasons.push("has resource limits".to_string());
+        }
+        if !j.cgroup.is_empty() {
+            reasons.push("runs in a cgroup".to_string());
+        }
+        if let Some(nice) = j.nice {
+            reasons.push(format!("runs with nice {}", nice));
+        }
+        if let Some(io) = j.io_priority {
+            reasons.push(format!("runs with I/O priority {}", io));
+        }
+        if let Some(umask) = j.umask {
+            reasons.push(format!("runs with umask {:03o}", umask));
+        }
+        if j.path.is_some() {
+            reasons.push("runs with its own PATH".to_string());
+        }
+        if j.max_retries > 0 {
+            reasons.push("retries failed runs".to_string());
+        }
+        if j.timeout.is_some() {
+            reasons.push("is terminated after a timeout".to_string());
+        }
+        if j.lock.is_some() {
+            reasons.push("holds a lock file while running".to_string());
+        }
+        if j.singleton_cluster {
+            reasons.push("runs on a single host of the cluster".to_string());
+        }
+        if let Some(ns) = &j.namespace {
+            reasons.push(format!("is limited by namespace {}", ns));
+        }
+
+        // Variables are assigned in front of the command, for it only
+        let mut cmd = String::new();
+        for (k, v) in &j.env {
+            let _ = write!(cmd, "{}='{}' ", k, v.replace('\'', "'\\''"));
+        }
+        cmd.push_str(&j.cmd);
+        // % starts the standard input of the command in crontabs
+        let cmd = cmd.replace('%', "\\%");
+        match (schedule, reasons.is_empty()) {
+            (Ok(s), true) => {
+                let _ = writeln!(out, "{} {}", s, cmd);
+            }
+            (schedule, _) => {
+                let _ = writeln!(out, "# Not exported: the job {}", reasons.join(", "));
+                let _ = writeln!(out, "# {} {}", schedule.as_deref().unwrap_or(&j.schedule), cmd);
+            }
+        }
+    }
+    out
+}
+
+const MONTHS: &[&str] = &[
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// crontab_schedule converts a cron expression to the 5 fields of a crontab,
+/// failing if it runs on seconds or years crontabs can't express
+pub fn crontab_schedule(expr: &str) -> Result<String, String> {
+    let expr = expr.trim();
+    if expr.starts_with('@') {
+        return Ok(expr.to_string());
+    }
+    if interval(expr).is_some() {
+        return Err("runs at an interval from the end of its previous run".to_string());
+    }
+    if one_shot(expr).is_some() {
+        return Err("runs once".to_string());
+    }
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    let (sec, rest) = match fields.as_slice() {
+        [s, rest @ ..] if rest.len() == 5 => (*s, rest),
+        [s, rest @ .., "*"] if rest.len() == 5 => (*s, rest),
+        [_, _, _, _, _, _, _] => return Err("runs on given years".to_string()),
+        _ => return Err(format!("has an invalid schedule `{}`", expr)),
+    };
+    if sec.parse::<u32>() != Ok(0) {
+        return Err("runs on given seconds".to_string());
+    }
+
+    let month = numbered(rest[3], MONTHS)?;
+    // Days of week are numbered from 1 on Sunday in schedules, from 0 in
+    // crontabs
+    let weekday = shift(&numbered(rest[4], WEEKDAYS)?)?;
+    Ok(format!(
+        "{} {} {} {} {}",
+        bounded(rest[0], 59),
+        bounded(rest[1], 23),
+        bounded(rest[2], 31),
+        bounded(&month, 12),
+        bounded(&weekday, 6)
+    ))
+}
+
+/// bounded gives steps starting from a single value, e.g. `5/10`, the end
+/// of the range they run to, `5-59/10`: crontabs require a range
+fn bounded(f: &str, max: u32) -> String {
+    f.split(',')
+        .map(|item| match item.split_once('/') {
+            Some((start, step)) if start != "*" && !start.contains('-') => {
+                format!("{}-{}/{}", start, max, step)
+            }
+            _ => item.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// numbered replaces the names of `names` in the field with their number,
+/// from 1: ranges and lists of names aren't allowed in crontabs. Numbers
+/// are left as they are.
+fn numbered(f: &str, names: &[&str]) -> Result<String, String> {
+    let mut out = String::new();
+    let mut word = String::new();
+    for c in f.chars().chain(std::iter::once(',')) {
+        if c.is_ascii_alphabetic() {
+            word.push(c);
+            continue;
+        }
+        if !word.is_empty() {
+            let n = names
+                .iter()
+                .position(|n| word.to_lowercase().starts_with(&n.to_lowercase()))
+                .ok_or_else(|| format!("has an invalid name `{}`", word))?;
+            out.push_str(&(n + 1).to_string());
+            word.clear();
+        }
+        out.push(c);
+    }
+    out.pop();
+    Ok(out)
+}
+
+/// shift renumbers the days of the week from 0 instead of 1
+fn shift(f: &str) -> Result<String, String> {
+    f.split(',')
+        .map(|item| {
+            let (range, step) = match item.split_once('/') {
+                Some((r, s)) => (r, Some(s)),
+                None => (item, None),
+            };
+            let day = |d: &str| match d.parse::<u32>() {
+                Ok(n) if (1..=7).contains(&n) => Ok((n - 1).to_string()),
+                Ok(_) => Err(format!("has an invalid day of week `{}`", d)),
+                Err(_) => Ok(d.to_string()),
+            };
+            let range = match range.split_once('-') {
+                Some((a, b)) => format!("{}-{}", day(a)?, day(b)?),
+                None => day(range)?,
+            };
+            Ok(match step {
+                Some(s) => format!("{}/{}", range, s),
+                None => range,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|items| items.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::on_calendar;
+
+    #[test]
+    fn round_trips_through_import() {
+        let cases = [
+            ("0 30 8 * * Mon-Fri", "Mon..Fri *-*-* 08:30:00"),
+            ("0 0/15 * * * *", "*-*-* *:00/15:00"),
+            ("6 5 4 1 * *", "*-*-01 04:05:06"),
+            ("0 0 0 1 1,7 * 2030", "2030-01,07-01 00:00:00"),
+            ("0 0 2 * * 1,7", "Sun,Sat *-*-* 02:00:00"),
+        ];
+        for (cron, cal) in cases.iter() {
+            assert_eq!(calendar(cron).as_deref(), Ok(*cal), "{}", cron);
+        }
+        for (cron, _) in cases.iter().take(4) {
+            assert_eq!(on_calendar(&calendar(cron).unwrap()).as_deref(), Ok(*cron));
+        }
+        assert!(calendar("0 0 0 * * Mon/2").is_err());
+    }
+
+    #[test]
+    fn converts_to_crontab_schedules() {
+        let cases = [
+            ("0 */5 * * * *", "*/5 * * * *"),
+            ("0 5/10 * * * *", "5-59/10 * * * *"),
+            ("0 30 8 * * Mon-Fri", "30 8 * * 1-5"),
+            ("0 0 22 * * Sun,Sat", "0 22 * * 0,6"),
+            ("0 0 0 1 Jan,Jul *", "0 0 1 1,7 *"),
+            ("@daily", "@daily"),
+        ];
+        for (cron, tab) in cases.iter() {
+            assert_eq!(crontab_schedule(cron).as_deref(), Ok(*tab), "{}", cron);
+        }
+        assert!(crontab_schedule("30 * * * * *").is_err());
+        assert!(crontab_schedule("0 0 0 1 1 * 2030").is_err());
+    }
+}
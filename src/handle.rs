@@ -0,0 +1,233 @@
+use crate::config;
+use crate::error::Result;
+use crate::job::{JobId, JobInfo, JobSpec};
+use crate::run::{JobRunResult, RunId};
+use crate::state::Shared;
+use crate::watch;
+use crate::simulate::Firing;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Reload is what reloading the configuration changed
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Reload {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+/// ShutdownHandle stops a running `Cron` instance from another thread.
+/// Obtained with `Cron::shutdown_handle` or `CronHandle::shutdown_handle`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(shared: Arc<Shared>) -> Self {
+        ShutdownHandle { shared }
+    }
+
+    /// shutdown asks the run loop to stop and blocks until it has exited.
+    /// If `wait_children` is true, it also waits for all running jobs to complete.
+    ///
+    /// A shutdown requested before `Cron::run` is called makes run return immediately.
+    pub fn shutdown(&self, wait_children: bool) {
+        self.shared.shutdown(wait_children)
+    }
+
+    /// terminate stops the run loop and terminates the running jobs, then
+    /// blocks until everything has exited. Jobs are sent SIGTERM, and SIGKILL
+    /// if they are still running after the grace period.
+    pub fn terminate(&self, grace: Duration) {
+        self.shared.terminate(grace)
+    }
+}
+
+/// CronHandle controls a `Cron` instance from other threads. It can do
+/// everything a `ShutdownHandle` does, and more.
+/// Obtained with `Cron::handle` or returned by `Cron::start`.
+#[derive(Clone)]
+pub struct CronHandle {
+    shared: Arc<Shared>,
+}
+
+impl CronHandle {
+    pub(crate) fn new(shared: Arc<Shared>) -> Self {
+        CronHandle { shared }
+    }
+
+    /// shutdown_handle returns a handle that can only stop the instance
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.shared.clone())
+    }
+
+    /// add_job registers a new job with the running instance
+    pub fn add_job(&self, spec: JobSpec) -> Result<JobId> {
+        self.shared.add_job(spec)
+    }
+
+    /// add_jobs registers a batch of jobs with the running instance.
+    /// Returns the result of registering each job, in order.
+    pub fn add_jobs(&self, specs: Vec<JobSpec>) -> Vec<Result<JobId>> {
+        self.shared.add_jobs(specs)
+    }
+
+    /// remove_job unregisters a job, dropping its pending occurrences.
+    /// Running children of the job are left alone.
+    pub fn remove_job(&self, id: JobId) -> bool {
+        self.shared.remove_job(id)
+    }
+
+    /// reload loads the Jobfile and its fragments again and applies the
+    /// changes: new jobs are registered, changed jobs are updated in place,
+    /// keeping their id and state, and removed jobs are unregistered.
+    /// Running children are left alone. Jobs added by other means are kept.
+    ///
+    /// Fails without changing the jobs if the Jobfile can't be loaded.
+    pub fn reload(&self) -> Result<Reload> {
+        let config = config::load(self.shared.config_path.as_deref(), self.shared.config_dir.as_deref())?;
+        Ok(self.shared.configure(config))
+    }
+
+    /// watch_config spawns a thread reloading the Jobfile and its fragments
+    /// within seconds of their changes, see `reload`. Does nothing if
+    /// neither is configured.
+    pub fn watch_config(&self) {
+        let (path, dir) = (self.shared.config_path.clone(), self.shared.config_dir.clone());
+        if path.is_some() || dir.is_some() {
+            watch::spawn(self.clone(), path, dir);
+        }
+    }
+
+    /// jobs returns a snapshot of every registered job, ordered by id
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        self.shared.jobs()
+    }
+
+    /// job returns a snapshot of the job registered under the given id
+    pub fn job(&self, id: JobId) -> Option<JobInfo> {
+        self.shared.job(id)
+    }
+
+    /// upcoming returns the next `n` times the job is scheduled at, or None
+    /// if it isn't registered. Fewer are returned if its schedule finishes.
+    pub fn upcoming(&self, id: JobId, n: usize) -> Option<Vec<DateTime<Local>>> {
+        self.shared.upcoming(id, n)
+    }
+
+    /// set_metadata sets a label on the job, removing it if `value` is None.
+    /// Returns false if the job isn't registered.
+    pub fn set_metadata(&self, id: JobId, key: &str, value: Option<&str>) -> bool {
+        self.shared.set_metadata(id, key, value)
+    }
+
+    /// next_wakeup returns when the scheduler will next wake up to run jobs,
+    /// or None if nothing is scheduled
+    pub fn next_wakeup(&self) -> Option<DateTime<Local>> {
+        self.shared.next_wakeup()
+    }
+
+    /// pending returns the occurrences waiting in the queue and the
+    /// launches waiting to be retried, earliest first
+    pub fn pending(&self) -> Vec<Firing> {
+        self.shared.pending()
+    }
+
+    /// queue_depth returns the number of pending occurrences
+    pub fn queue_depth(&self) -> usize {
+        self.shared.queue_depth()
+    }
+
+    /// simulate returns the occurrences of the jobs due until `until`, in
+    /// the order they would be launched, without running them nor waiting
+    /// for them. At most `limit` occurrences are returned.
+    pub fn simulate(&self, until: DateTime<Local>, limit: usize) -> Vec<Firing> {
+        self.shared.simulate(until, limit)
+    }
+
+    /// replay returns the occurrences of the jobs from `from` until `until`,
+    /// past or future, as if they had been scheduled at `from`. Their
+    /// current state, e.g. whether they're paused, is ignored. At most `limit`
+    /// occurrences are returned.
+    pub fn replay(&self, from: DateTime<Local>, until: DateTime<Local>, limit: usize) -> Vec<Firing> {
+        self.shared.replay(from, until, limit)
+    }
+
+    /// trigger runs the job now, out of band, without affecting its schedule
+    pub fn trigger(&self, id: JobId) -> bool {
+        self.shared.trigger(id).is_some()
+    }
+
+    /// trigger_run is like `trigger` but returns the id the run will have,
+    /// to wait for it with `wait_run`
+    pub fn trigger_run(&self, id: JobId) -> Option<RunId> {
+        self.shared.trigger(id)
+    }
+
+    /// wait_run blocks until the run finishes and returns its result.
+    /// Returns None if it never ran, e.g. its job was removed first, or if
+    /// it's no longer the last run of its job.
+    pub fn wait_run(&self, run: RunId) -> Option<JobRunResult> {
+        self.shared.wait_run(run)
+    }
+
+    /// pause skips the job's occurrences until it is resumed
+    pub fn pause(&self, id: JobId) -> bool {
+        self.shared.pause(id)
+    }
+
+    /// resume undoes a previous pause
+    pub fn resume(&self, id: JobId) -> bool {
+        self.shared.resume(id)
+    }
+
+    /// shutdown asks the run loop to stop and blocks until it has exited.
+    /// If `wait_children` is true, it also waits for all running jobs to complete.
+    ///
+    /// A shutdown requested before `Cron::run` is called makes run return immediately.
+    pub fn shutdown(&self, wait_children: bool) {
+        self.shutdown_handle().shutdown(wait_children)
+    }
+
+    /// handoff stops the run loop, leaving the running jobs alone, and
+    /// blocks until it has exited. `Cron::run` then returns and the state can
+    /// be handed over to a new instance with `Cron::take_handoff`.
+    #[cfg(feature = "daemon")]
+    pub fn handoff(&self) {
+        self.shared.handoff()
+    }
+
+    /// terminate stops the run loop and terminates the running jobs, then
+    /// blocks until everything has exited. Jobs are sent SIGTERM, and SIGKILL
+    /// if they are still running after the grace period. Jobs with the `wait`
+    /// shutdown policy are left to finish first, see `CronBuilder::max_shutdown_wait`.
+    pub fn terminate(&self, grace: Duration) {
+        self.shutdown_handle().terminate(grace)
+    }
+}
+
+impl From<CronHandle> for ShutdownHandle {
+    fn from(h: CronHandle) -> Self {
+        ShutdownHandle::new(h.shared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cron;
+
+    #[test]
+    fn shuts_down_through_either_handle() {
+        let mut cron = Cron::builder().build().unwrap();
+        cron.shutdown_handle().shutdown(false);
+        // Returns right away, the shutdown was requested before
+        cron.run();
+
+        let cron = Cron::builder().build().unwrap();
+        let stop = cron.start().shutdown_handle();
+        stop.shutdown(false);
+    }
+}
@@ -0,0 +1,336 @@
+//! Upgrading the daemon in place without losing track of running jobs.
+//!
+//! The daemon stops its run loop, leaving running jobs alone, and execs the
+//! new binary. The pid doesn't change, so the jobs are still children of the
+//! new instance and it reaps them as usual. The tracked children, the state
+//! of the jobs and the file descriptors to keep are written to a pipe whose
+//! read end is named by the `XCROND_HANDOFF` environment variable.
+
+use crate::cgroup::Cgroup;
+use crate::error::{Result, XcrondError};
+use crate::job::{JobId, ShutdownPolicy};
+use crate::run::{RunId, Trigger};
+use crate::state::{Child, RunState};
+use crate::statefile::JobState;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+// Environment variable holding the read end of the handoff pipe
+const HANDOFF_ENV: &str = "XCROND_HANDOFF";
+
+/// Handoff is the state a daemon passes to the binary replacing it
+// TOML needs plain values to come before tables, the fields are ordered so
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Handoff {
+    /// id of the last run started, run ids keep increasing across the handoff
+    next_run: u64,
+    /// set if the instance was a standby yet to take over
+    #[serde(default)]
+    standby: bool,
+    #[serde(default)]
+    paused: Vec<JobId>,
+    /// file descriptors kept open for the new instance, by name
+    #[serde(default)]
+    fds: BTreeMap<String, RawFd>,
+    #[serde(default)]
+    child: Vec<RunningChild>,
+    #[serde(default)]
+    job: Vec<JobState>,
+}
+
+/// RunningChild is a job process tracked by the daemon
+// Ordered like Handoff, the timeout is a table
+#[derive(Debug, Serialize, Deserialize)]
+struct RunningChild {
+    pid: i32,
+    run: RunId,
+    job: JobId,
+    name: String,
+    trigger: Trigger,
+    shutdown_policy: ShutdownPolicy,
+    started: DateTime<Local>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    input: Option<PathBuf>,
+    #[serde(default)]
+    retry: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cgroup: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout: Option<Duration>,
+}
+
+impl Handoff {
+    /// capture takes a snapshot of the state to hand over
+    pub(crate) fn capture(state: &RunState) -> Self {
+        let mut paused: Vec<JobId> = state.paused.iter().cloned().collect();
+        paused.sort();
+        Handoff {
+            next_run: state.next_run,
+            paused,
+            fds: BTreeMap::new(),
+            child: state
+                .children
+                .iter()
+                .map(|(pid, c)| RunningChild {
+                    pid: *pid,
+                    run: c.run,
+                    job: c.job,
+                    name: c.name.clone(),
+                    trigger: c.trigger,
+                    shutdown_policy: c.shutdown_policy,
+                    started: c.started,
+                    output: c.output.clone(),
+                    input: c.input.clone(),
+                    retry: c.retry,
+                    timeout: c.timeout,
+                    cgroup: c.cgroup.as_ref().map(|cg| cg.path.clone()),
+                })
+                .collect(),
+            job: state.job_states(),
+            standby: state.standby,
+        }
+    }
+
+    /// restore applies the handed over state to the registered jobs.
+    /// Occurrences that came due while handing over are run right away.
+    pub(crate) fn restore(self, state: &mut RunState) {
+        state.next_run = std::cmp::max(state.next_run, self.next_run);
+        // Ids are matched with the jobs by name, like the state of the jobs
+        for id in self.paused {
+            let name = self.job.iter().find(|s| s.id == id).and_then(|s| s.name.as_deref());
+            if let Some(id) = state.saved_job(id, name) {
+                state.paused.insert(id);
+            }
+        }
+        for c in self.child {
+            let child = Child {
+                run: c.run,
+                job: c.job,
+                name: c.name,
+                trigger: c.trigger,
+                shutdown_policy: c.shutdown_policy,
+                started: c.started,
+                output: c.output,
+                input: c.input,
+                retry: c.retry,
+                mail: None,
+                cgroup: c.cgroup.map(|path| Cgroup { path }),
+                timeout: c.timeout,
+                terminated: None,
+            };
+            state.children.insert(c.pid, child);
+        }
+        state.restore(self.job);
+        // A standby that took over stays in charge after upgrading
+        state.standby &= self.standby;
+
+        // Jobs were registered by this instance, so their next occurrence
+        // may come after one that the previous instance didn't get to run
+        state.catch_up();
+    }
+
+    /// add_fd keeps the file descriptor open in the new instance, under `name`
+    pub fn add_fd(&mut self, name: &str, fd: RawFd) {
+        self.fds.insert(name.to_string(), fd);
+    }
+
+    /// fd returns the file descriptor kept open under `name`
+    pub fn fd(&self, name: &str) -> Option<RawFd> {
+        self.fds.get(name).cloned()
+    }
+
+    /// exec replaces the process with `cmd`, handing the state over to it.
+    /// Only returns if the handoff failed, the daemon can then resume with
+    /// `Cron::take_over`.
+    pub fn exec(&self, mut cmd: Command) -> XcrondError {
+        let payload = match toml::to_string(self) {
+            Ok(p) => p,
+            Err(err) => return XcrondError::Handoff(io::Error::new(io::ErrorKind::InvalidData, err)),
+        };
+        let pipe = match send(payload.as_bytes()) {
+            Ok(p) => p,
+            Err(err) => return XcrondError::Handoff(err),
+        };
+
+        let mut fds: Vec<RawFd> = self.fds.values().cloned().collect();
+        fds.push(pipe.as_raw_fd());
+        if let Err(err) = fds.iter().try_for_each(|fd| set_cloexec(*fd, false)) {
+            return XcrondError::Handoff(err);
+        }
+
+        let err = cmd.env(HANDOFF_ENV, pipe.as_raw_fd().to_string()).exec();
+
+        // Still running the old binary, jobs mustn't inherit our descriptors
+        for fd in &fds {
+            let _ = set_cloexec(*fd, true);
+        }
+        XcrondError::Handoff(err)
+    }
+
+    /// receive reads the state handed over by the previous instance, if
+    /// this process was started by `Handoff::exec`
+    pub fn receive() -> Result<Option<Self>> {
+        let var = match env::var(HANDOFF_ENV) {
+            Ok(v) => v,
+            Err(_) => return Ok(None),
+        };
+        // Jobs spawned by this instance mustn't see it
+        env::remove_var(HANDOFF_ENV);
+
+        let fd: RawFd = var.parse().map_err(|_| {
+            let msg = format!("invalid {} `{}`", HANDOFF_ENV, var);
+            XcrondError::Handoff(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        })?;
+        let mut content = String::new();
+        unsafe { File::from_raw_fd(fd) }
+            .read_to_string(&mut content)
+            .map_err(XcrondError::Handoff)?;
+
+        let h: Handoff = toml::from_str(&content)
+            .map_err(|err| XcrondError::Handoff(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+        for fd in h.fds.values() {
+            set_cloexec(*fd, true).map_err(XcrondError::Handoff)?;
+        }
+        Ok(Some(h))
+    }
+}
+
+/// send writes the payload to a new pipe and returns its read end.
+/// The payload is written by a short lived process, so it can exceed the
+/// capacity of the pipe. The writer is detached so it doesn't linger as a
+/// zombie in the new instance.
+fn send(payload: &[u8]) -> io::Result<File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    set_cloexec(read.as_raw_fd(), true)?;
+    set_cloexec(write.as_raw_fd(), true)?;
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if pid == 0 {
+        // Only async signal safe calls in here
+        unsafe {
+            match libc::fork() {
+                0 => {}
+                n => libc::_exit(if n < 0 { 1 } else { 0 }),
+            }
+            libc::close(read.as_raw_fd());
+            let mut buf = payload;
+            while !buf.is_empty() {
+                let n = libc::write(write.as_raw_fd(), buf.as_ptr() as *const libc::c_void, buf.len());
+                if n <= 0 {
+                    libc::_exit(1);
+                }
+                buf = &buf[n as usize..];
+            }
+            libc::_exit(0);
+        }
+    }
+
+    drop(write);
+    let mut status = 0;
+    if unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+        return Err(io::Error::other("failed to fork the handoff writer"));
+    }
+    Ok(read)
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = match cloexec {
+        true => flags | libc::FD_CLOEXEC,
+        false => flags & !libc::FD_CLOEXEC,
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::JobSpec;
+    use crate::state::Shared;
+
+    #[test]
+    fn hands_the_state_over() {
+        let spec = || JobSpec::new("backup", "/bin/true", "0 0 * * * *");
+        let old = Shared::new(None, None);
+        let id = old.add_job(spec()).unwrap();
+        assert!(old.pause(id));
+        let mut h = {
+            let mut state = old.lock();
+            state.next_run += 10;
+            let run = RunId::new(state.next_run);
+            state.children.insert(
+                4242,
+                Child {
+                    run,
+                    job: id,
+                    name: "backup".to_string(),
+                    trigger: Trigger::Manual,
+                    shutdown_policy: ShutdownPolicy::Wait,
+                    started: Local::now(),
+                    output: Some(PathBuf::from("/tmp/xcrond-backup.out")),
+                    input: None,
+                    retry: 1,
+                    mail: None,
+                    cgroup: None,
+                    timeout: Some(Duration::from_secs(30)),
+                    terminated: None,
+                },
+            );
+            Handoff::capture(&state)
+        };
+        h.add_fd("pid_file", 7);
+
+        // The payload goes through the pipe the new instance reads it from
+        let mut payload = String::new();
+        send(toml::to_string(&h).unwrap().as_bytes())
+            .unwrap()
+            .read_to_string(&mut payload)
+            .unwrap();
+        let h: Handoff = toml::from_str(&payload).unwrap();
+        assert_eq!(h.fd("pid_file"), Some(7));
+        assert_eq!(h.fd("control_socket"), None);
+
+        let new = Shared::new(None, None);
+        assert_eq!(new.add_job(spec()).unwrap(), id);
+        let next_run = old.lock().next_run;
+        let mut state = new.lock();
+        state.standby = true;
+        h.restore(&mut state);
+        assert!(state.next_run >= next_run);
+        assert!(state.paused.contains(&id));
+        // The previous instance was in charge, so the new one is too
+        assert!(!state.standby);
+        let c = &state.children[&4242];
+        assert_eq!((c.run, c.job, c.trigger, c.retry), (RunId::new(next_run), id, Trigger::Manual, 1));
+        assert_eq!(c.shutdown_policy, ShutdownPolicy::Wait);
+        assert_eq!(c.output, Some(PathBuf::from("/tmp/xcrond-backup.out")));
+        assert_eq!(c.timeout, Some(Duration::from_secs(30)));
+    }
+}
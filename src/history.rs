@@ -0,0 +1,280 @@
+//! Run history stored in an embedded SQLite database.
+//!
+//! `History` is a `SchedulerObserver` recording every finished run, so it
+//! only has to be registered with a `Cron` instance:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use xcrond::history::History;
+//! use xcrond::Cron;
+//!
+//! let history = Arc::new(History::open("/var/lib/xcrond/history.db")?);
+//! let mut c = Cron::builder().observer(history.clone()).build()?;
+//! c.init()?;
+//! # Ok::<(), xcrond::XcrondError>(())
+//! ```
+
+use crate::error::Result;
+use crate::job::JobId;
+use crate::observer::SchedulerObserver;
+use crate::run::{JobRunResult, ResourceUsage, RunId, RunStatus, Trigger};
+use chrono::{DateTime, Local, TimeZone};
+use rusqlite::{params, Connection, Row};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Schema migrations, the migration at index N upgrades version N to N + 1.
+/// The version of a database is stored in its `user_version`.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE runs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        run INTEGER NOT NULL,
+        job INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        trigger TEXT NOT NULL,
+        pid INTEGER NOT NULL,
+        started INTEGER NOT NULL,
+        finished INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        exit_code INTEGER,
+        detail TEXT,
+        user_time INTEGER,
+        system_time INTEGER,
+        max_rss INTEGER,
+        output_path TEXT
+    );
+    CREATE INDEX runs_job_started ON runs (job, started);
+", "
+    CREATE INDEX runs_run ON runs (run);
+"];
+
+/// RunRecord is a run stored in the history
+#[derive(Debug, Clone)]
+pub struct RunRecord {
+    /// id of the record, increasing with every recorded run
+    pub id: i64,
+    pub result: JobRunResult,
+    /// file the output of the run was written to, if it was kept
+    pub output_path: Option<PathBuf>,
+}
+
+/// Retention limits how many runs are kept in the history.
+/// Runs exceeding any of the limits are pruned.
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// maximum age of a run, from the time it finished
+    pub max_age: Option<Duration>,
+    /// maximum number of runs kept for each job, the latest ones are kept
+    pub max_runs: Option<usize>,
+}
+
+impl Retention {
+    /// is_unlimited returns true if no limit is set
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age.is_none() && self.max_runs.is_none()
+    }
+}
+
+/// History is a persistent store of job runs
+pub struct History {
+    conn: Mutex<Connection>,
+}
+
+impl History {
+    /// open opens the history database at `path`, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+        Ok(History {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// record stores a finished run
+    pub fn record(&self, r: &JobRunResult) -> Result<()> {
+        let (status, exit_code, detail) = match &r.status {
+            RunStatus::Exited(code) => ("exited", Some(*code), None),
+            RunStatus::Signaled(signal) => ("signaled", None, Some(signal.as_str())),
+            RunStatus::FailedToStart(reason) => ("failed_to_start", None, Some(reason.as_str())),
+        };
+        let trigger = r.trigger.name();
+        let usage = r.usage.as_ref();
+
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO runs (run, job, name, trigger, pid, started, finished,
+                status, exit_code, detail, user_time, system_time, max_rss)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                r.run.as_u64() as i64,
+                r.job.as_u64() as i64,
+                r.name,
+                trigger,
+                r.pid,
+                micros(r.started),
+                micros(r.finished),
+                status,
+                exit_code,
+                detail,
+                usage.map(|u| u.user_time.as_micros() as i64),
+                usage.map(|u| u.system_time.as_micros() as i64),
+                usage.map(|u| u.max_rss),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// runs returns the latest runs, most recent first.
+    /// Only runs of `job` are returned if given.
+    pub fn runs(&self, job: Option<JobId>, limit: usize) -> Result<Vec<RunRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, run, job, name, trigger, pid, started, finished, status,
+                exit_code, detail, user_time, system_time, max_rss, output_path
+            FROM runs
+            WHERE ?1 IS NULL OR job = ?1
+            ORDER BY id DESC
+            LIMIT ?2",
+        )?;
+        let job = job.map(|j| j.as_u64() as i64);
+        let rows = stmt.query_map(params![job, limit as i64], from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// run returns the run with the given id, e.g. as found in the
+    /// `XCROND_RUN_ID` of a job's logs
+    pub fn run(&self, run: RunId) -> Result<Option<RunRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, run, job, name, trigger, pid, started, finished, status,
+                exit_code, detail, user_time, system_time, max_rss, output_path
+            FROM runs
+            WHERE run = ?1
+            ORDER BY id DESC
+            LIMIT 1",
+        )?;
+        let mut rows = stmt.query_map(params![run.as_u64() as i64], from_row)?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// prune deletes the runs exceeding the retention limits.
+    /// Returns the number of deleted runs.
+    pub fn prune(&self, retention: &Retention) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let mut deleted = 0;
+
+        if let Some(max_age) = retention.max_age {
+            let cutoff = micros(Local::now()).saturating_sub(max_age.as_micros() as i64);
+            deleted += conn.execute("DELETE FROM runs WHERE finished < ?1", params![cutoff])?;
+        }
+
+        if let Some(max_runs) = retention.max_runs {
+            deleted += conn.execute(
+                "DELETE FROM runs WHERE id NOT IN (
+                    SELECT id FROM runs AS latest
+                    WHERE latest.job = runs.job
+                    ORDER BY id DESC
+                    LIMIT ?1
+                )",
+                params![max_runs as i64],
+            )?;
+        }
+
+        Ok(deleted)
+    }
+
+    /// start_pruning spawns a thread pruning the history every `interval`.
+    /// The thread stops once the history is dropped.
+    pub fn start_pruning(self: &Arc<Self>, retention: Retention, interval: Duration) {
+        if retention.is_unlimited() {
+            return;
+        }
+
+        let history = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            match history.upgrade() {
+                Some(h) => match h.prune(&retention) {
+                    Ok(0) => {}
+                    Ok(n) => info!("Pruned {} runs from the history", n),
+                    Err(err) => error!("Failed to prune the history: {}", err),
+                },
+                None => break,
+            }
+            thread::sleep(interval);
+        });
+    }
+}
+
+impl SchedulerObserver for History {
+    fn job_finished(&self, result: &JobRunResult) {
+        if let Err(err) = self.record(result) {
+            error!("[{} {}] Failed to record run: {}", result.name, result.job, err);
+        }
+    }
+}
+
+/// migrate upgrades the database schema to the latest version
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |r| r.get::<_, i64>(0))? as usize;
+    if version >= MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+    for (v, m) in MIGRATIONS.iter().enumerate().skip(version) {
+        debug!("Migrating history database from version {} to {}", v, v + 1);
+        tx.execute_batch(m)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+    tx.commit()
+}
+
+fn from_row(row: &Row) -> rusqlite::Result<RunRecord> {
+    let status = match row.get::<_, String>(8)?.as_str() {
+        "exited" => RunStatus::Exited(row.get(9)?),
+        "signaled" => RunStatus::Signaled(row.get(10)?),
+        _ => RunStatus::FailedToStart(row.get::<_, Option<String>>(10)?.unwrap_or_default()),
+    };
+    let trigger = match row.get::<_, String>(4)?.as_str() {
+        "manual" => Trigger::Manual,
+        "upstream" => Trigger::Upstream,
+        "retry" => Trigger::Retry,
+        _ => Trigger::Scheduled,
+    };
+    let usage = match (row.get::<_, Option<i64>>(11)?, row.get::<_, Option<i64>>(12)?) {
+        (Some(user), Some(system)) => Some(ResourceUsage {
+            user_time: Duration::from_micros(user as u64),
+            system_time: Duration::from_micros(system as u64),
+            max_rss: row.get::<_, Option<i64>>(13)?.unwrap_or(0),
+        }),
+        _ => None,
+    };
+
+    Ok(RunRecord {
+        id: row.get(0)?,
+        result: JobRunResult {
+            run: RunId::new(row.get::<_, i64>(1)? as u64),
+            job: JobId::new(row.get::<_, i64>(2)? as u64),
+            name: row.get(3)?,
+            pid: row.get(5)?,
+            started: from_micros(row.get(6)?),
+            finished: from_micros(row.get(7)?),
+            trigger,
+            status,
+            usage,
+        },
+        output_path: row.get::<_, Option<String>>(14)?.map(PathBuf::from),
+    })
+}
+
+/// micros returns the time as microseconds since the epoch
+fn micros(t: DateTime<Local>) -> i64 {
+    t.timestamp() * 1_000_000 + i64::from(t.timestamp_subsec_micros())
+}
+
+fn from_micros(us: i64) -> DateTime<Local> {
+    Local
+        .timestamp_opt(us.div_euclid(1_000_000), (us.rem_euclid(1_000_000) * 1000) as u32)
+        .unwrap()
+}
@@ -0,0 +1,684 @@
+//! Importing jobs defined for other schedulers.
+//!
+//! systemd timer units are converted to jobs running the `ExecStart` of the
+//! service they activate, on a cron expression equivalent to each of their
+//! `OnCalendar` settings. Timers relative to boot or to the last activation
+//! (`OnBootSec`, `OnUnitActiveSec`...) have no cron equivalent and are
+//! skipped with a warning.
+//!
+//! launchd property lists are converted to jobs on the schedule of their
+//! `StartCalendarInterval`, or of their `StartInterval` when cron can
+//! express it.
+//!
+//! crontab entries are converted to jobs on the equivalent 6-field schedule,
+//! run through `/bin/sh` as cron does. Environment assignments set the
+//! environment of the entries following them, `SHELL` their shell,
+//! `CRON_TZ` their timezone and `MAILTO` where their output is mailed.
+
+use crate::dialect::{crontab_weekdays, CRONTAB_WEEKDAYS};
+use crate::error::{Result, XcrondError};
+use crate::job::{JobSpec, MisfirePolicy};
+use crate::plist;
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// systemd_timer returns the jobs equivalent to the timer unit at `path`.
+/// The service is looked up next to the timer, named after it unless the
+/// timer sets `Unit=`.
+pub fn systemd_timer(path: &Path) -> Result<Vec<JobSpec>> {
+    let err = |reason: String| XcrondError::Import {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let timer = read_unit(path)?;
+    let name = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| err("invalid unit name".to_string()))?;
+
+    let settings = timer.get("Timer").cloned().unwrap_or_default();
+    for (k, _) in settings
+        .iter()
+        .filter(|(k, _)| k.starts_with("On") && k != "OnCalendar")
+    {
+        warn!("{}: {} has no cron equivalent, ignored", path.display(), k);
+    }
+    let calendars: Vec<&String> = settings
+        .iter()
+        .filter(|(k, v)| k == "OnCalendar" && !v.is_empty())
+        .map(|(_, v)| v)
+        .collect();
+    if calendars.is_empty() {
+        return Err(err("no OnCalendar setting".to_string()));
+    }
+
+    let unit = settings
+        .iter()
+        .rev()
+        .find(|(k, _)| k == "Unit")
+        .map_or_else(|| format!("{}.service", name), |(_, v)| v.clone());
+    let service_path = path.with_file_name(&unit);
+    let service = read_unit(&service_path)?;
+    let cmd = exec_start(&service).ok_or_else(|| XcrondError::Import {
+        path: service_path.clone(),
+        reason: "no ExecStart setting".to_string(),
+    })?;
+    if cmd.contains('"') || cmd.contains('\'') || cmd.contains('$') {
+        warn!(
+            "{}: commands aren't run through a shell, check the quoting and variables of `{}`",
+            service_path.display(),
+            cmd
+        );
+    }
+
+    // Without Persistent=, occurrences missed while the system was down or
+    // suspended aren't run
+    let persistent = settings
+        .iter()
+        .rev()
+        .find(|(k, _)| k == "Persistent")
+        .is_some_and(|(_, v)| ["true", "yes", "on", "1"].contains(&v.to_lowercase().as_str()));
+
+    calendars
+        .iter()
+        .enumerate()
+        .map(|(i, cal)| {
+            let schedule = on_calendar(cal)
+                .map_err(|reason| err(format!("OnCalendar={}: {}", cal, reason)))?;
+            let job_name = match calendars.len() {
+                1 => name.to_string(),
+                _ => format!("{} {}", name, i + 1),
+            };
+            let mut spec = JobSpec::new(&job_name, &cmd, &schedule);
+            spec.metadata
+                .insert("imported_from".to_string(), path.display().to_string());
+            if !persistent {
+                spec.misfire_policy = MisfirePolicy::Skip;
+            }
+            Ok(spec)
+        })
+        .collect()
+}
+
+/// Unit holds the settings of a unit file, by section
+type Unit = HashMap<String, Vec<(String, String)>>;
+
+/// read_unit parses the unit file at `path`
+fn read_unit(path: &Path) -> Result<Unit> {
+    let content = fs::read_to_string(path).map_err(|source| XcrondError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    let mut unit = Unit::new();
+    let mut section = String::new();
+    let mut pending = String::new();
+    for line in content.lines() {
+        // Lines ending with a backslash continue on the next one
+        let line = line.trim();
+        if let Some(l) = line.strip_suffix('\\') {
+            pending.push_str(l);
+            pending.push(' ');
+            continue;
+        }
+        let line = std::mem::take(&mut pending) + line;
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].to_string();
+            continue;
+        }
+        if let Some((k, v)) = line.split_once('=') {
+            let settings = unit.entry(section.clone()).or_default();
+            // An empty assignment resets the list built so far
+            if v.trim().is_empty() {
+                settings.retain(|(key, _)| key != k.trim());
+            }
+            settings.push((k.trim().to_string(), v.trim().to_string()));
+        }
+    }
+    Ok(unit)
+}
+
+/// exec_start returns the command run by the service, without the prefixes
+/// changing how systemd runs it
+fn exec_start(service: &Unit) -> Option<String> {
+    let (_, cmd) = service
+        .get("Service")?
+        .iter()
+        .find(|(k, v)| k == "ExecStart" && !v.is_empty())?;
+    Some(cmd.trim_start_matches(|c| "@-:+!".contains(c)).to_string())
+}
+
+/// on_calendar converts a systemd calendar event expression, e.g.
+/// `Mon..Fri *-*-* 08:30:00`, to the equivalent cron expression
+pub fn on_calendar(expr: &str) -> std::result::Result<String, String> {
+    let expr = expr.trim();
+    let shorthand = match expr.to_lowercase().as_str() {
+        "minutely" => Some("0 * * * * *"),
+        "hourly" => Some("0 0 * * * *"),
+        "daily" => Some("0 0 0 * * *"),
+        "weekly" => Some("0 0 0 * * Mon"),
+        "monthly" => Some("0 0 0 1 * *"),
+        "quarterly" => Some("0 0 0 1 1,4,7,10 *"),
+        "semiannually" => Some("0 0 0 1 1,7 *"),
+        "yearly" | "annually" => Some("0 0 0 1 1 *"),
+        _ => None,
+    };
+    if let Some(s) = shorthand {
+        return Ok(s.to_string());
+    }
+
+    let mut parts: Vec<&str> = expr.split_whitespace().collect();
+    let mut weekdays = "*".to_string();
+    if parts
+        .first()
+        .is_some_and(|p| p.starts_with(|c: char| c.is_ascii_alphabetic()))
+    {
+        weekdays = field(parts.remove(0))?;
+    }
+    let mut date = "*-*-*";
+    let mut time = "00:00:00";
+    for p in parts {
+        if p.contains('-') && date == "*-*-*" {
+            date = p;
+        } else if p.contains(':') {
+            time = p;
+        } else {
+            return Err(format!(
+                "`{}` isn't supported, set the timezone of the job instead",
+                p
+            ));
+        }
+    }
+    if date.contains('~') {
+        return Err("days counted from the end of the month aren't supported".to_string());
+    }
+
+    let date: Vec<&str> = date.split('-').collect();
+    let (year, month, day) = match date.as_slice() {
+        [y, m, d] => (*y, *m, *d),
+        [m, d] => ("*", *m, *d),
+        _ => return Err(format!("invalid date `{}`", date.join("-"))),
+    };
+    let time: Vec<&str> = time.split(':').collect();
+    let (hour, minute, second) = match time.as_slice() {
+        [h, m, s] => (*h, *m, *s),
+        [h, m] => (*h, *m, "0"),
+        _ => return Err(format!("invalid time `{}`", time.join(":"))),
+    };
+    // Fractions of a second are dropped
+    let second = second.split('.').next().unwrap_or("0");
+
+    let mut fields = vec![
+        field(second)?,
+        field(minute)?,
+        field(hour)?,
+        field(day)?,
+        field(month)?,
+        weekdays,
+    ];
+    if year != "*" {
+        fields.push(field(year)?);
+    }
+    let cron = fields.join(" ");
+    Schedule::from_str(&cron).map_err(|err| format!("`{}`: {}", cron, err))?;
+    Ok(cron)
+}
+
+/// field converts a field of a calendar event to cron syntax: ranges are
+/// written `a..b` instead of `a-b`, and values may be zero padded
+fn field(f: &str) -> std::result::Result<String, String> {
+    f.split(',')
+        .map(|item| {
+            if item.is_empty()
+                || !item
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "*./".contains(c))
+            {
+                return Err(format!("invalid field `{}`", f));
+            }
+            let (range, step) = match item.split_once('/') {
+                Some((r, s)) => (r, Some(s)),
+                None => (item, None),
+            };
+            let range = match range.split_once("..") {
+                Some((a, b)) => format!("{}-{}", unpad(a), unpad(b)),
+                None => unpad(range).to_string(),
+            };
+            Ok(match step {
+                Some(s) => format!("{}/{}", range, unpad(s)),
+                None => range,
+            })
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map(|items| items.join(","))
+}
+
+/// unpad strips the leading zeros of a number
+fn unpad(n: &str) -> &str {
+    match n.trim_start_matches('0') {
+        "" if !n.is_empty() => "0",
+        t => t,
+    }
+}
+
+/// launchd_plist returns the jobs equivalent to the launchd property list at
+/// `path`, one per calendar interval
+pub fn launchd_plist(path: &Path) -> Result<Vec<JobSpec>> {
+    let content = fs::read_to_string(path).map_err(|source| XcrondError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    launchd(&content, &path.display().to_string()).map_err(|reason| XcrondError::Import {
+        path: path.to_path_buf(),
+        reason,
+    })
+}
+
+// Keys of launchd jobs changing how they are run, which have no equivalent
+const LAUNCHD_IGNORED: &[&str] = &[
+    "RunAtLoad",
+    "KeepAlive",
+    "WorkingDirectory",
+    "UserName",
+    "GroupName",
+    "StandardOutPath",
+    "StandardErrorPath",
+    "WatchPaths",
+    "QueueDirectories",
+];
+
+/// launchd converts the content of a launchd property list, `source` names
+/// it in the metadata of the jobs and in warnings
+fn launchd(content: &str, source: &str) -> std::result::Result<Vec<JobSpec>, String> {
+    let doc = plist::parse(content)?;
+    let label = doc
+        .get("Label")
+        .and_then(plist::Value::as_str)
+        .ok_or_else(|| "no Label".to_string())?;
+
+    let mut args: Vec<String> = match doc.get("ProgramArguments") {
+        Some(plist::Value::Array(a)) => a.iter().filter_map(|v| v.as_str().map(str::to_string)).collect(),
+        _ => vec![],
+    };
+    if let Some(program) = doc.get("Program").and_then(plist::Value::as_str) {
+        match args.first_mut() {
+            Some(a) => *a = program.to_string(),
+            None => args.push(program.to_string()),
+        }
+    }
+    if args.is_empty() {
+        return Err("no Program nor ProgramArguments".to_string());
+    }
+    if args.iter().any(|a| a.is_empty() || a.contains(char::is_whitespace)) {
+        warn!("{}: arguments with spaces aren't supported, check `{}`", source, args.join(" "));
+    }
+
+    let mut env = BTreeMap::new();
+    if let Some(plist::Value::Dict(vars)) = doc.get("EnvironmentVariables") {
+        for (k, v) in vars {
+            match v.as_str() {
+                Some(v) => {
+                    env.insert(k.clone(), v.to_string());
+                }
+                None => warn!("{}: value of {} isn't supported, ignored", source, k),
+            }
+        }
+    }
+    let cmd = args.join(" ");
+    for k in LAUNCHD_IGNORED.iter().filter(|k| doc.get(k).is_some()) {
+        warn!("{}: {} has no equivalent, ignored", source, k);
+    }
+
+    let schedules = match (doc.get("StartCalendarInterval"), doc.get("StartInterval")) {
+        (Some(plist::Value::Array(intervals)), _) => intervals.iter().map(calendar_interval).collect(),
+        (Some(interval), _) => vec![calendar_interval(interval)],
+        (None, Some(secs)) => {
+            let secs = secs.as_integer().ok_or_else(|| "invalid StartInterval".to_string())?;
+            vec![start_interval(secs)]
+        }
+        (None, None) => return Err("no StartCalendarInterval nor StartInterval".to_string()),
+    };
+
+    let n = schedules.len();
+    schedules
+        .into_iter()
+        .enumerate()
+        .map(|(i, schedule)| {
+            let name = if n == 1 {
+                label.to_string()
+            } else {
+                format!("{} {}", label, i + 1)
+            };
+            let mut spec = JobSpec::new(&name, &cmd, &schedule?);
+            spec.metadata.insert("imported_from".to_string(), source.to_string());
+            spec.env = env.clone();
+            Ok(spec)
+        })
+        .collect()
+}
+
+/// calendar_interval converts a `StartCalendarInterval` dictionary, whose
+/// missing keys match any value, to a cron expression
+fn calendar_interval(interval: &plist::Value) -> std::result::Result<String, String> {
+    let field = |key: &str| -> std::result::Result<String, String> {
+        match interval.get(key) {
+            None => Ok("*".to_string()),
+            Some(v) => v
+                .as_integer()
+                .filter(|n| *n >= 0)
+                .map(|n| n.to_string())
+                .ok_or_else(|| format!("invalid {} in StartCalendarInterval", key)),
+        }
+    };
+    let weekday = field("Weekday")?;
+    let weekday = match weekday.parse::<usize>() {
+        Ok(n) => CRONTAB_WEEKDAYS
+            .get(n)
+            .ok_or_else(|| format!("invalid Weekday {}", n))?
+            .to_string(),
+        Err(_) => weekday,
+    };
+    let schedule = format!(
+        "0 {} {} {} {} {}",
+        field("Minute")?,
+        field("Hour")?,
+        field("Day")?,
+        field("Month")?,
+        weekday
+    );
+    Schedule::from_str(&schedule).map_err(|err| format!("invalid schedule `{}`: {}", schedule, err))?;
+    Ok(schedule)
+}
+
+/// start_interval converts a `StartInterval`, in seconds, to a cron
+/// expression. Only intervals dividing a minute, an hour or a day evenly can
+/// be expressed.
+fn start_interval(secs: i64) -> std::result::Result<String, String> {
+    let schedule = match secs {
+        s if s > 0 && 60 % s == 0 => format!("0/{} * * * * *", s),
+        s if s % 60 == 0 && 3600 % s == 0 => format!("0 0/{} * * * *", s / 60),
+        s if s % 3600 == 0 && 86400 % s == 0 => format!("0 0 0/{} * * *", s / 3600),
+        s => return Err(format!("StartInterval {} has no cron equivalent", s)),
+    };
+    Ok(schedule)
+}
+
+// Variables of crontabs that only matter to cron itself
+const CRON_VARIABLES: &[&str] = &["MAILFROM", "RANDOM_DELAY"];
+// Shell running the entries of crontabs that don't set SHELL
+const CRON_SHELL: &str = "/bin/sh";
+
+/// crontab returns the jobs equivalent to the entries of a crontab, as
+/// printed by `crontab -l` or found in the cron spool. `source` names the
+/// crontab in the names of the jobs and in warnings. Entries that can't be
+/// converted are skipped with a warning.
+pub fn crontab(content: &str, source: &str) -> Vec<JobSpec> {
+    check_crontab(content, source).0
+}
+
+/// check_crontab is `crontab`, also returning why the entries that can't be
+/// converted are skipped
+pub fn check_crontab(content: &str, source: &str) -> (Vec<JobSpec>, Vec<String>) {
+    let (jobs, skipped) = crontab_jobs(content, source, false);
+    (jobs.into_iter().map(|(_, spec)| spec).collect(), skipped)
+}
+
+/// system_crontab returns the jobs equivalent to the entries of a system
+/// crontab, such as `/etc/crontab` or the files of `/etc/cron.d`, whose
+/// entries name the user running the command after the time fields. The
+/// jobs are returned with their user.
+pub fn system_crontab(content: &str, source: &str) -> Vec<(String, JobSpec)> {
+    crontab_jobs(content, source, true)
+        .0
+        .into_iter()
+        .filter_map(|(user, spec)| Some((user?, spec)))
+        .collect()
+}
+
+/// crontab_jobs returns the jobs of a crontab with the user of the entries,
+/// if `system`, and the entries skipped
+fn crontab_jobs(content: &str, source: &str, system: bool) -> (Vec<(Option<String>, JobSpec)>, Vec<String>) {
+    let mut env = BTreeMap::new();
+    // Set by CRON_TZ for the entries following it
+    let mut timezone: Option<Tz> = None;
+    // Set by MAILTO for the entries following it
+    let mut mailto: Option<String> = None;
+    // Set by SHELL for the entries following it
+    let mut shell = CRON_SHELL.to_string();
+    let mut jobs = vec![];
+    let mut skipped = vec![];
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some((name, value)) = assignment(line) {
+            if name == "CRON_TZ" {
+                match value.parse() {
+                    Ok(tz) => timezone = Some(tz),
+                    Err(_) => warn!("{}:{}: unknown timezone {}, ignored", source, n + 1, value),
+                }
+            } else if name == "MAILTO" {
+                mailto = Some(value.to_string());
+            } else if name == "SHELL" {
+                shell = value.to_string();
+            } else if CRON_VARIABLES.contains(&name) {
+                warn!("{}:{}: {} isn't supported, ignored", source, n + 1, name);
+            } else {
+                env.insert(name.to_string(), value.to_string());
+            }
+            continue;
+        }
+
+        let entry = crontab_entry(line).and_then(|(schedule, cmd)| {
+            if !system {
+                return Ok((schedule, None, cmd));
+            }
+            match cmd.split_once(char::is_whitespace) {
+                Some((user, cmd)) => Ok((schedule, Some(user), cmd.trim_start())),
+                None => Err("missing command".to_string()),
+            }
+        });
+        match entry {
+            Ok((schedule, user, cmd)) => {
+                if cmd.contains('%') {
+                    warn!("{}:{}: `%` isn't turned into newlines, wrap `{}` in a script", source, n + 1, cmd);
+                }
+                let program = cmd_name(cmd);
+                let mut spec = JobSpec::new(&format!("{} {} {}", source, program, n + 1), cmd, &schedule);
+                spec.metadata.insert("imported_from".to_string(), format!("{}:{}", source, n + 1));
+                spec.timezone = timezone;
+                spec.mailto = mailto.clone();
+                spec.shell = Some(shell.clone());
+                spec.env = env.clone();
+                jobs.push((user.map(String::from), spec));
+            }
+            Err(reason) => {
+                warn!("{}:{}: {}, skipped", source, n + 1, reason);
+                skipped.push(format!("{}:{}: {}", source, n + 1, reason));
+            }
+        }
+    }
+    (jobs, skipped)
+}
+
+/// assignment splits an environment assignment of a crontab into the name
+/// and the value, unquoted
+fn assignment(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    let value = value.trim();
+    let unquoted = ['"', '\''].iter().find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q));
+    Some((name, unquoted.unwrap_or(value)))
+}
+
+/// cmd_name returns the name of the program run by a command
+fn cmd_name(cmd: &str) -> &str {
+    let mut words = cmd.split_whitespace();
+    let mut program = words.next().unwrap_or("");
+    if program == "/usr/bin/env" {
+        program = words.find(|w| !w.contains('=')).unwrap_or(program);
+    }
+    program.rsplit('/').next().unwrap_or(program)
+}
+
+/// crontab_entry splits a crontab entry into the equivalent 6-field
+/// schedule and the command
+fn crontab_entry(line: &str) -> std::result::Result<(String, &str), String> {
+    if line.starts_with('@') {
+        let (special, cmd) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| "missing command".to_string())?;
+        let schedule = match special {
+            "@yearly" | "@annually" => "0 0 0 1 1 *",
+            "@monthly" => "0 0 0 1 * *",
+            "@weekly" => "0 0 0 * * Sun",
+            "@daily" | "@midnight" => "0 0 0 * * *",
+            "@hourly" => "0 0 * * * *",
+            "@reboot" => return Err("@reboot has no equivalent".to_string()),
+            s => return Err(format!("unknown special string {}", s)),
+        };
+        return Ok((schedule.to_string(), cmd.trim()));
+    }
+
+    let mut rest = line;
+    let mut fields = vec![];
+    for _ in 0..5 {
+        let (f, r) = rest
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| "expected 5 time fields and a command".to_string())?;
+        fields.push(f);
+        rest = r.trim_start();
+    }
+    if rest.is_empty() {
+        return Err("missing command".to_string());
+    }
+    if fields[2] != "*" && fields[4] != "*" {
+        warn!(
+            "`{}`: cron runs on either the day of month or the day of week, xcrond when both match",
+            fields.join(" ")
+        );
+    }
+
+    let weekdays = crontab_weekdays(fields[4])?;
+    let schedule = format!(
+        "0 {} {} {} {} {}",
+        fields[0], fields[1], fields[2], fields[3], weekdays
+    );
+    Schedule::from_str(&schedule).map_err(|err| format!("invalid schedule `{}`: {}", schedule, err))?;
+    Ok((schedule, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_calendar_events() {
+        let cases = [
+            ("daily", "0 0 0 * * *"),
+            ("*:0/15", "0 0/15 * * * *"),
+            ("Mon..Fri *-*-* 08:30:00", "0 30 8 * * Mon-Fri"),
+            ("Sat,Sun 02:00", "0 0 2 * * Sat,Sun"),
+            ("*-*-01 04:05:06", "6 5 4 1 * *"),
+            ("2030-01-01 00:00:00", "0 0 0 1 1 * 2030"),
+            ("*-01,07-01", "0 0 0 1 1,7 *"),
+        ];
+        for (expr, cron) in cases.iter() {
+            assert_eq!(on_calendar(expr).as_deref(), Ok(*cron), "{}", expr);
+        }
+        assert!(on_calendar("*-*~01").is_err());
+        assert!(on_calendar("daily Europe/Berlin").is_err());
+    }
+
+    #[test]
+    fn converts_crontabs() {
+        let content = "\
+# m h dom mon dow command
+PATH=/usr/local/bin:/usr/bin
+MAILTO=root
+*/5 * * * * /usr/bin/backup --quick
+30 8 * * 1-5 /usr/bin/report
+0 22 * * 5-7 /usr/bin/weekend
+@daily /usr/bin/rotate
+@reboot /usr/bin/start
+";
+        let jobs = crontab(content, "alice");
+        let got: Vec<(&str, &str)> = jobs.iter().map(|j| (j.schedule.as_str(), j.cmd.as_str())).collect();
+        assert_eq!(
+            got,
+            vec![
+                ("0 */5 * * * *", "/usr/bin/backup --quick"),
+                ("0 30 8 * * Mon-Fri", "/usr/bin/report"),
+                ("0 0 22 * * Fri-Sat,Sun", "/usr/bin/weekend"),
+                ("0 0 0 * * *", "/usr/bin/rotate"),
+            ]
+        );
+        for j in &jobs {
+            assert_eq!(j.env.get("PATH").map(String::as_str), Some("/usr/local/bin:/usr/bin"));
+        }
+        assert_eq!(jobs[0].name, "alice backup 4");
+        assert_eq!(jobs[0].mailto.as_deref(), Some("root"));
+
+        let jobs = crontab("0 9 * * * /usr/bin/a\nCRON_TZ=Asia/Tokyo\n0 9 * * * /usr/bin/b\n", "bob");
+        assert_eq!(jobs[0].timezone, None);
+        assert_eq!(jobs[1].timezone, Some(Tz::Asia__Tokyo));
+    }
+
+    #[test]
+    fn converts_launchd_plists() {
+        let content = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>com.example.backup</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>/usr/local/bin/backup</string>
+        <string>--full</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>StartCalendarInterval</key>
+    <array>
+        <dict>
+            <key>Hour</key>
+            <integer>3</integer>
+            <key>Minute</key>
+            <integer>15</integer>
+        </dict>
+        <dict>
+            <key>Weekday</key>
+            <integer>0</integer>
+        </dict>
+    </array>
+</dict>
+</plist>
+"#;
+        let jobs = launchd(content, "backup.plist").unwrap();
+        let got: Vec<(&str, &str)> = jobs.iter().map(|j| (j.name.as_str(), j.schedule.as_str())).collect();
+        assert_eq!(
+            got,
+            vec![
+                ("com.example.backup 1", "0 15 3 * * *"),
+                ("com.example.backup 2", "0 * * * * Sun"),
+            ]
+        );
+        assert_eq!(jobs[0].cmd, "/usr/local/bin/backup --full");
+
+        assert_eq!(start_interval(300).as_deref(), Ok("0 0/5 * * * *"));
+        assert!(start_interval(90).is_err());
+    }
+}
@@ -0,0 +1,404 @@
+//! Control socket of a running daemon.
+//!
+//! The daemon listens on a Unix domain socket, `/run/xcrond.sock` by
+//! default, to be inspected and controlled without restarting it. Clients
+//! send one request per line and get one JSON reply per line:
+//!
+//! ```text
+//! list                 every registered job, with its last run
+//! queue                the occurrences and retries waiting to run, earliest first
+//! next <job>           the next times the job is scheduled at
+//! run <job>            runs the job now, without affecting its schedule,
+//!                      replying the id of the run
+//! wait <run>           waits for the run to finish and replies its result
+//! pause <job>          skips the job's occurrences until it is resumed
+//! resume <job>         undoes a previous pause
+//! reload               loads the Jobfile and its fragments again
+//! ```
+//!
+//! Jobs are named as in the Jobfile. Replies are `"done"`, `{"error": ...}`,
+//! or an object holding what was asked for, e.g. `{"jobs": [...]}`. The
+//! socket is only accessible to the daemon's user.
+
+use crate::error::{Result, XcrondError};
+use crate::handle::{CronHandle, Reload};
+use crate::job::{JobId, JobInfo};
+use crate::run::{JobRunResult, RunId};
+use crate::simulate::Firing;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+
+/// Default path of the control socket
+pub const SOCKET: &str = "/run/xcrond.sock";
+
+// Number of times replied to `next`
+const UPCOMING: usize = 5;
+
+/// Request is a request sent to the control socket
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Request {
+    List,
+    Queue,
+    Next(String),
+    Run(String),
+    Wait(RunId),
+    Pause(String),
+    Resume(String),
+    Reload,
+}
+
+impl FromStr for Request {
+    type Err = String;
+
+    /// from_str parses a request line. Job names are the rest of the line,
+    /// so they may hold spaces.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let (command, job) = match s.split_once(char::is_whitespace) {
+            Some((command, job)) => (command, job.trim()),
+            None => (s, ""),
+        };
+        let named = || match job {
+            "" => Err(format!("`{}` expects the name of a job", command)),
+            name => Ok(name.to_string()),
+        };
+        let no_job = |request| match job {
+            "" => Ok(request),
+            _ => Err(format!("`{}` expects no arguments", command)),
+        };
+        match command {
+            "list" => no_job(Request::List),
+            "queue" => no_job(Request::Queue),
+            "next" => named().map(Request::Next),
+            "run" => named().map(Request::Run),
+            "wait" => match job.parse() {
+                Ok(run) => Ok(Request::Wait(RunId::new(run))),
+                Err(_) => Err(format!("`{}` expects the number of a run", command)),
+            },
+            "pause" => named().map(Request::Pause),
+            "resume" => named().map(Request::Resume),
+            "reload" => no_job(Request::Reload),
+            c => Err(format!("unknown request `{}`", c)),
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Request::List => f.write_str("list"),
+            Request::Queue => f.write_str("queue"),
+            Request::Next(job) => write!(f, "next {}", job),
+            Request::Run(job) => write!(f, "run {}", job),
+            Request::Wait(run) => write!(f, "wait {}", run.as_u64()),
+            Request::Pause(job) => write!(f, "pause {}", job),
+            Request::Resume(job) => write!(f, "resume {}", job),
+            Request::Reload => f.write_str("reload"),
+        }
+    }
+}
+
+/// Reply is the reply of the daemon to a request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reply {
+    /// the request was carried out
+    Done,
+    /// the job was triggered, its run has the given id
+    Started(RunId),
+    /// the run waited for finished
+    Finished(JobRunResult),
+    /// the request failed
+    Error(String),
+    Jobs(Vec<JobInfo>),
+    Queue(Vec<Firing>),
+    Upcoming(Vec<DateTime<Local>>),
+    Reloaded(Reload),
+}
+
+/// ControlSocket is the control socket of a running daemon.
+/// The socket is removed when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl ControlSocket {
+    /// bind creates the control socket at `path`, taking over the socket a
+    /// previous instance left behind. Fails if another instance listens on
+    /// it, or if something else than a socket is in the way.
+    pub fn bind<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let addr = path.display().to_string();
+        let listen_err = |source| XcrondError::Listen {
+            addr: addr.clone(),
+            source,
+        };
+        match fs::symlink_metadata(&path) {
+            Ok(m) if !m.file_type().is_socket() => {
+                return Err(listen_err(io::Error::new(io::ErrorKind::AlreadyExists, "not a socket")));
+            }
+            Ok(_) if UnixStream::connect(&path).is_ok() => {
+                return Err(listen_err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another instance is listening",
+                )));
+            }
+            // Left behind, replaced below
+            Ok(_) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(listen_err(err)),
+        }
+
+        // The socket is created in a private directory then moved in place,
+        // so it's never accessible to other users
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let dir = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&dir).map_err(listen_err)?;
+        let bound = (|| {
+            let tmp = dir.join(&*name);
+            let listener = UnixListener::bind(&tmp)?;
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&tmp, &path)?;
+            Ok(listener)
+        })();
+        let _ = fs::remove_dir_all(&dir);
+        let listener = bound.map_err(listen_err)?;
+        Ok(ControlSocket { path, listener })
+    }
+
+    /// inherit takes over the control socket of the instance this one
+    /// replaced in place, from the file descriptor it handed over
+    pub fn inherit<P: Into<PathBuf>>(path: P, fd: RawFd) -> Self {
+        ControlSocket {
+            path: path.into(),
+            listener: unsafe { UnixListener::from_raw_fd(fd) },
+        }
+    }
+
+    /// serve spawns a thread answering the requests sent to the socket,
+    /// each client being served by a thread of its own
+    pub fn serve(&self, handle: CronHandle) -> Result<()> {
+        let listener = self.listener.try_clone().map_err(|source| XcrondError::Listen {
+            addr: self.path.display().to_string(),
+            source,
+        })?;
+        info!("Listening for requests on {}", self.path.display());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let handle = handle.clone();
+                        thread::spawn(move || {
+                            if let Err(err) = serve_client(stream, &handle) {
+                                warn!("Failed to answer a request of the control socket: {}", err);
+                            }
+                        });
+                    }
+                    Err(err) => error!("Failed to accept a connection to the control socket: {}", err),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl AsRawFd for ControlSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// request sends a request to the control socket at `path` and returns the
+/// reply of the daemon
+pub fn request(path: &Path, request: &Request) -> io::Result<Reply> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", request)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    if line.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the daemon"));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// serve_client answers the requests of a client until it disconnects
+fn serve_client(stream: UnixStream, handle: &CronHandle) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match line.parse() {
+            Ok(request) => execute(handle, request),
+            Err(err) => Reply::Error(err),
+        };
+        serde_json::to_writer(&mut writer, &reply)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// execute carries out a request
+fn execute(handle: &CronHandle, request: Request) -> Reply {
+    let find = |name: &str| -> std::result::Result<JobId, String> {
+        handle
+            .jobs()
+            .into_iter()
+            .find(|j| j.name == name)
+            .map(|j| j.id)
+            .ok_or_else(|| format!("no job named `{}`", name))
+    };
+    let done = |carried_out: bool, err: String| if carried_out { Reply::Done } else { Reply::Error(err) };
+    let reply = match &request {
+        Request::List => Ok(Reply::Jobs(handle.jobs())),
+        Request::Queue => Ok(Reply::Queue(handle.pending())),
+        Request::Next(name) => find(name).map(|id| match handle.upcoming(id, UPCOMING) {
+            Some(times) => Reply::Upcoming(times),
+            None => Reply::Error(format!("no job named `{}`", name)),
+        }),
+        Request::Run(name) => find(name).map(|id| match handle.trigger_run(id) {
+            Some(run) => Reply::Started(run),
+            None => Reply::Error(format!("no job named `{}`", name)),
+        }),
+        Request::Wait(run) => Ok(match handle.wait_run(*run) {
+            Some(r) => Reply::Finished(r),
+            None => Reply::Error(format!("no result for {}: it didn't run or isn't the last run of its job", run)),
+        }),
+        Request::Pause(name) => find(name).map(|id| done(handle.pause(id), format!("no job named `{}`", name))),
+        Request::Resume(name) => find(name).map(|id| done(handle.resume(id), format!("`{}` isn't paused", name))),
+        Request::Reload => Ok(match handle.reload() {
+            Ok(r) => Reply::Reloaded(r),
+            Err(err) => Reply::Error(err.to_string()),
+        }),
+    };
+    let reply = reply.unwrap_or_else(Reply::Error);
+    match (&request, &reply) {
+        (Request::List, _) | (Request::Queue, _) | (Request::Next(_), _) | (Request::Wait(_), _) => {}
+        (_, Reply::Error(err)) => warn!("Failed to `{}` through the control socket: {}", request, err),
+        _ => info!("Carried out `{}` through the control socket", request),
+    }
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::JobSpec;
+    use crate::run::RunStatus;
+    use crate::Cron;
+    use std::env;
+
+    #[test]
+    fn parses_requests() {
+        assert_eq!("list".parse(), Ok(Request::List));
+        assert_eq!(" run backup db \n".parse(), Ok(Request::Run("backup db".to_string())));
+        assert_eq!("next backup".parse::<Request>().map(|r| r.to_string()), Ok("next backup".to_string()));
+        assert!("pause".parse::<Request>().is_err());
+        assert!("reload now".parse::<Request>().is_err());
+        assert!("stop".parse::<Request>().is_err());
+        assert_eq!("wait 3".parse(), Ok(Request::Wait(RunId::new(3))));
+        assert_eq!(Request::Wait(RunId::new(3)).to_string(), "wait 3");
+        assert!("wait backup".parse::<Request>().is_err());
+    }
+
+    #[test]
+    fn binds_over_stale_sockets_only() {
+        let path = env::temp_dir().join(format!("xcrond-test-{}.ctl", std::process::id()));
+        fs::write(&path, "").unwrap();
+        assert!(ControlSocket::bind(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        fs::remove_file(&path).unwrap();
+
+        // Left behind by an instance that crashed
+        drop(UnixListener::bind(&path).unwrap());
+        let socket = ControlSocket::bind(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn serves_requests() {
+        let cron = Cron::builder().build().unwrap();
+        let handle = cron.handle();
+        handle.add_job(JobSpec::new("backup db", "/bin/true", "0 0 * * * *")).unwrap();
+
+        let path = env::temp_dir().join(format!("xcrond-test-{}.sock", std::process::id()));
+        let socket = ControlSocket::bind(&path).unwrap();
+        socket.serve(handle).unwrap();
+        assert!(ControlSocket::bind(&path).is_err());
+
+        match request(&path, &Request::List).unwrap() {
+            Reply::Jobs(jobs) => assert_eq!(jobs.iter().map(|j| j.name.as_str()).collect::<Vec<_>>(), vec!["backup db"]),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        match request(&path, &Request::Queue).unwrap() {
+            Reply::Queue(pending) => assert_eq!(pending.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), vec!["backup db"]),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        match request(&path, &Request::Next("backup db".to_string())).unwrap() {
+            Reply::Upcoming(times) => {
+                assert_eq!(times.len(), UPCOMING);
+                assert!(times.windows(2).all(|t| t[1] - t[0] == chrono::Duration::hours(1)));
+            }
+            r => panic!("unexpected reply {:?}", r),
+        }
+        assert!(matches!(request(&path, &Request::Pause("backup db".to_string())).unwrap(), Reply::Done));
+        match request(&path, &Request::List).unwrap() {
+            Reply::Jobs(jobs) => assert!(jobs[0].paused),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        assert!(matches!(request(&path, &Request::Resume("backup db".to_string())).unwrap(), Reply::Done));
+        assert!(matches!(request(&path, &Request::Resume("backup db".to_string())).unwrap(), Reply::Error(_)));
+        assert!(matches!(request(&path, &Request::Run("restore".to_string())).unwrap(), Reply::Error(_)));
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn waits_for_runs() {
+        let handle = Cron::builder().build().unwrap().start();
+        handle.add_job(JobSpec::new("check", "/bin/true", "0 0 0 1 1 *")).unwrap();
+
+        let path = env::temp_dir().join(format!("xcrond-test-{}.wait.sock", std::process::id()));
+        let socket = ControlSocket::bind(&path).unwrap();
+        socket.serve(handle.clone()).unwrap();
+
+        let run = match request(&path, &Request::Run("check".to_string())).unwrap() {
+            Reply::Started(run) => run,
+            r => panic!("unexpected reply {:?}", r),
+        };
+        match request(&path, &Request::Wait(run)).unwrap() {
+            Reply::Finished(r) => {
+                assert_eq!(r.run, run);
+                assert_eq!(r.status, RunStatus::Exited(0));
+            }
+            r => panic!("unexpected reply {:?}", r),
+        }
+        // Answered at once once recorded
+        assert!(matches!(request(&path, &Request::Wait(run)).unwrap(), Reply::Finished(_)));
+        let unknown = RunId::new(run.as_u64() + 100);
+        assert!(matches!(request(&path, &Request::Wait(unknown)).unwrap(), Reply::Error(_)));
+
+        handle.shutdown_handle().shutdown(false);
+    }
+}
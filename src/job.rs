@@ -1,73 +1,1096 @@
+use crate::blackout::{Blackout, BlackoutPolicy};
+use crate::cluster::hostname;
+use crate::dialect::{fnv1a, Dialect};
+use crate::error::{Result, XcrondError};
+use crate::login::{self, login_command, Account, Switch};
+use crate::cgroup::CgroupLimits;
+use crate::limits::Limits;
+use crate::priority::{self, IoPriority, NICE_RANGE};
+use crate::run::JobRunResult;
 use chrono::{DateTime, Local};
+use chrono_tz::Tz;
 use cron::Schedule;
-use std::{ffi::CString, str::FromStr};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
+// Delay before the first retry of a failed run if the job doesn't set one
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(10);
+
+/// JobId is a handle to a job registered with a `Cron` instance
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct JobId(u64);
+
+impl JobId {
+    pub fn new(id: u64) -> Self {
+        JobId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for JobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// ShutdownPolicy is what happens to a running job when the daemon shuts down
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownPolicy {
+    /// the job is sent SIGTERM, then SIGKILL after the grace period
+    #[default]
+    Terminate,
+    /// the daemon waits for the job to finish, up to a maximum wait
+    Wait,
+}
+
+/// MisfirePolicy is what happens to the occurrences of a job that came due
+/// while they couldn't be run, e.g. while the system was suspended or
+/// during a failover
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MisfirePolicy {
+    /// the missed occurrences are run once, right away
+    #[default]
+    RunOnce,
+    /// the missed occurrences are skipped, the job runs at its next occurrence
+    Skip,
+}
+
+/// OverlapPolicy is what happens to an occurrence of a job coming due while
+/// a previous run of the job is still running
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// the occurrence runs alongside the previous run
+    #[default]
+    Allow,
+    /// the occurrence is skipped
+    Skip,
+    /// the occurrence runs once the previous run finishes. Further
+    /// occurrences coming due meanwhile are skipped.
+    Queue,
+    /// the previous run is terminated, and the occurrence runs right away
+    Replace,
+}
+
+/// JobSpec describes a job to be scheduled: what to run and when
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JobSpec {
+    /// id to register the job under. Set it to keep the job's id stable
+    /// across restarts and renames; a new id is assigned otherwise, those
+    /// of removed jobs aren't reused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<JobId>,
+    pub name: String,
+    pub cmd: String,
+    pub schedule: String,
+    /// cron dialect the schedule is written in, xcrond's if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dialect: Option<Dialect>,
+    /// IANA timezone the schedule is evaluated in, e.g. `Europe/Paris`.
+    /// Defaults to the timezone of the scheduler.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<Tz>,
+    /// arbitrary labels (owner, ticket, runbook...) attached to the job
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    /// file locked with flock for the duration of each run. Runs are
+    /// skipped while the lock is held, even by another daemon or tool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock: Option<PathBuf>,
+    /// what happens to a running job when the daemon shuts down
+    #[serde(default)]
+    pub shutdown_policy: ShutdownPolicy,
+    /// what happens to the occurrences missed while they couldn't be run
+    #[serde(default)]
+    pub misfire_policy: MisfirePolicy,
+    /// what happens to the occurrences coming due while a previous run is
+    /// still running
+    #[serde(default)]
+    pub overlap_policy: OverlapPolicy,
+    /// record the runs in the journal, so the daemon knows which occurrences
+    /// ran after a crash. Only effective if a journal is configured.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub journal: bool,
+    /// run each occurrence on a single host of the cluster defining the job.
+    /// Only effective if a cluster lock is configured.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub singleton_cluster: bool,
+    /// namespace the job belongs to, sharing its limits with the other
+    /// jobs of the namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// run the command in the login shell of the user, with the environment
+    /// of an interactive session rather than the daemon's
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub login_shell: bool,
+    /// name of the job run when this one succeeds, with the output of this
+    /// one as its input
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipe_to: Option<String>,
+    /// wall-clock seconds the runs of the job may take per day, further
+    /// occurrences of the day are skipped once they are used up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_budget: Option<u64>,
+    /// window each occurrence is delayed by a random amount within, e.g.
+    /// `5m`, so hosts running the same schedule don't all start at once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<String>,
+    /// windows during which the job doesn't run, on top of those of every
+    /// job, e.g. `Sat 22:00-Sun 06:00`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blackout: Vec<Blackout>,
+    /// what happens to the occurrences falling in a blackout window
+    #[serde(default)]
+    pub blackout_policy: BlackoutPolicy,
+    /// time a run may take, e.g. `30m`, before it is sent SIGTERM, then
+    /// SIGKILL if it doesn't exit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+    /// times a run exiting with a non-zero code is retried
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub max_retries: u32,
+    /// delay before the first retry of a failed run, e.g. `30s`, doubled on
+    /// every further retry. 10 seconds if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<String>,
+    /// addresses the output of the runs is mailed to, separated by commas,
+    /// instead of those of the Jobfile. Empty to mail nothing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mailto: Option<String>,
+    /// shell the command is run with, e.g. `/bin/sh`, for pipes,
+    /// redirections and expansions to work. Empty to run it directly, the
+    /// default unless the Jobfile sets a shell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    /// directories the program is looked up in and PATH of the command,
+    /// separated by colons, instead of the daemon's PATH
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// environment variables of the command, on top of those of the
+    /// Jobfile and of the daemon
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// user the command runs as, by name or uid, with its groups. The
+    /// daemon's user if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// group the command runs as, by name or gid, instead of the primary
+    /// group of its user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// umask of the command, in octal, e.g. `027`. The daemon's if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub umask: Option<String>,
+    /// nice value of the command, from -20 to 19. Raising the priority
+    /// with a negative value requires root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+    /// I/O scheduling class and level of the command, e.g. `idle` or
+    /// `best-effort:7`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub io_priority: Option<IoPriority>,
+    /// resources each run may use, on top of the limits of the namespace
+    #[serde(default, skip_serializing_if = "Limits::is_empty")]
+    pub limits: Limits,
+    /// resources each run may use with all its processes, enforced by a
+    /// cgroup of its own
+    #[serde(default, skip_serializing_if = "CgroupLimits::is_empty")]
+    pub cgroup: CgroupLimits,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+impl JobSpec {
+    pub fn new(name: &str, cmd: &str, schedule: &str) -> Self {
+        JobSpec {
+            id: None,
+            name: name.to_string(),
+            cmd: cmd.to_string(),
+            schedule: schedule.to_string(),
+            dialect: None,
+            timezone: None,
+            metadata: HashMap::new(),
+            lock: None,
+            shutdown_policy: ShutdownPolicy::default(),
+            misfire_policy: MisfirePolicy::default(),
+            overlap_policy: OverlapPolicy::default(),
+            journal: false,
+            singleton_cluster: false,
+            namespace: None,
+            login_shell: false,
+            pipe_to: None,
+            daily_budget: None,
+            jitter: None,
+            blackout: vec![],
+            blackout_policy: BlackoutPolicy::default(),
+            timeout: None,
+            max_retries: 0,
+            backoff: None,
+            mailto: None,
+            shell: None,
+            path: None,
+            env: BTreeMap::new(),
+            user: None,
+            group: None,
+            umask: None,
+            nice: None,
+            io_priority: None,
+            limits: Limits::default(),
+            cgroup: CgroupLimits::default(),
+        }
+    }
+
+    /// with_metadata attaches a label to the job
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// with_dialect sets the cron dialect the schedule is written in
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = Some(dialect);
+        self
+    }
+
+    /// with_timezone evaluates the schedule in the given timezone rather
+    /// than the scheduler's
+    pub fn with_timezone(mut self, tz: Tz) -> Self {
+        self.timezone = Some(tz);
+        self
+    }
+
+    /// expression returns the schedule normalized from its dialect
+    pub fn expression(&self) -> Result<String> {
+        self.dialect
+            .unwrap_or_default()
+            .normalize(&self.schedule, &self.name)
+            .map_err(|reason| XcrondError::InvalidSchedule {
+                name: self.name.clone(),
+                expr: self.schedule.clone(),
+                reason,
+            })
+    }
+
+    /// with_shutdown_policy sets what happens to a running job on shutdown
+    pub fn with_shutdown_policy(mut self, policy: ShutdownPolicy) -> Self {
+        self.shutdown_policy = policy;
+        self
+    }
+
+    /// with_misfire_policy sets what happens to the missed occurrences
+    pub fn with_misfire_policy(mut self, policy: MisfirePolicy) -> Self {
+        self.misfire_policy = policy;
+        self
+    }
+
+    /// with_overlap_policy sets what happens to the occurrences coming due
+    /// while a previous run is still running
+    pub fn with_overlap_policy(mut self, policy: OverlapPolicy) -> Self {
+        self.overlap_policy = policy;
+        self
+    }
+
+    /// with_journal records the job's runs in the journal
+    pub fn with_journal(mut self) -> Self {
+        self.journal = true;
+        self
+    }
+
+    /// with_singleton_cluster runs each occurrence on a single host of the cluster
+    pub fn with_singleton_cluster(mut self) -> Self {
+        self.singleton_cluster = true;
+        self
+    }
+
+    /// with_namespace puts the job in a namespace
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// with_login_shell runs the command in the user's login shell
+    pub fn with_login_shell(mut self) -> Self {
+        self.login_shell = true;
+        self
+    }
+
+    /// with_pipe_to runs the job named `name` when this one succeeds, with
+    /// the output of this one as its input
+    pub fn with_pipe_to(mut self, name: &str) -> Self {
+        self.pipe_to = Some(name.to_string());
+        self
+    }
+
+    /// with_daily_budget limits the wall-clock time the runs of the job may
+    /// take per day, in seconds
+    pub fn with_daily_budget(mut self, secs: u64) -> Self {
+        self.daily_budget = Some(secs);
+        self
+    }
+
+    /// with_jitter delays each occurrence by a random amount within
+    /// `window`, e.g. `30s`, `5m` or `1h30m`
+    pub fn with_jitter(mut self, window: &str) -> Self {
+        self.jitter = Some(window.to_string());
+        self
+    }
+
+    /// with_blackout keeps the job from running during `window`
+    pub fn with_blackout(mut self, window: Blackout) -> Self {
+        self.blackout.push(window);
+        self
+    }
+
+    /// with_blackout_policy sets what happens to the occurrences falling
+    /// in a blackout window
+    pub fn with_blackout_policy(mut self, policy: BlackoutPolicy) -> Self {
+        self.blackout_policy = policy;
+        self
+    }
+
+    /// with_timeout terminates the runs of the job taking longer than
+    /// `limit`, e.g. `30s`, `5m` or `1h30m`
+    pub fn with_timeout(mut self, limit: &str) -> Self {
+        self.timeout = Some(limit.to_string());
+        self
+    }
+
+    /// with_retries retries the runs exiting with a non-zero code up to
+    /// `max` times, first after `backoff`, e.g. `30s`, then after twice the
+    /// previous delay
+    pub fn with_retries(mut self, max: u32, backoff: &str) -> Self {
+        self.max_retries = max;
+        self.backoff = Some(backoff.to_string());
+        self
+    }
+
+    /// with_mailto mails the output of the runs to `addresses`, separated by
+    /// commas, if they print something or fail
+    pub fn with_mailto(mut self, addresses: &str) -> Self {
+        self.mailto = Some(addresses.to_string());
+        self
+    }
+
+    /// with_shell runs the command with `shell -c`, e.g. `/bin/sh`
+    pub fn with_shell(mut self, shell: &str) -> Self {
+        self.shell = Some(shell.to_string());
+        self
+    }
+
+    /// with_path looks the program up in and runs the command with the
+    /// given PATH, e.g. `/usr/local/bin:/usr/bin:/bin`
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// with_env sets an environment variable of the command
+    pub fn with_env(mut self, name: &str, value: &str) -> Self {
+        self.env.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// with_user runs the command as `user`, and as `group` if given rather
+    /// than the primary group of the user
+    pub fn with_user(mut self, user: &str, group: Option<&str>) -> Self {
+        self.user = Some(user.to_string());
+        self.group = group.map(String::from);
+        self
+    }
+
+    /// with_umask sets the umask of the command, in octal, e.g. `027`
+    pub fn with_umask(mut self, umask: &str) -> Self {
+        self.umask = Some(umask.to_string());
+        self
+    }
+
+    /// with_priority lowers or raises the CPU and I/O priorities of the
+    /// command, e.g. a nice value of 10 and the idle I/O class for backups
+    pub fn with_priority(mut self, nice: Option<i32>, io: Option<IoPriority>) -> Self {
+        self.nice = nice;
+        self.io_priority = io;
+        self
+    }
+
+    /// with_limits limits the resources each run may use
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// with_cgroup limits the resources each run may use with all its
+    /// processes
+    pub fn with_cgroup(mut self, limits: CgroupLimits) -> Self {
+        self.cgroup = limits;
+        self
+    }
+
+    /// umask returns the umask of the command, parsed
+    pub fn umask(&self) -> Result<Option<u32>> {
+        match &self.umask {
+            Some(value) => u32::from_str_radix(value.trim_start_matches("0o"), 8)
+                .ok()
+                .filter(|m| *m <= 0o777)
+                .map(Some)
+                .ok_or_else(|| XcrondError::InvalidUmask {
+                    name: self.name.clone(),
+                    value: value.clone(),
+                }),
+            None => Ok(None),
+        }
+    }
+
+    /// jitter returns the window occurrences are delayed within, parsed
+    pub fn jitter(&self) -> Result<Option<Duration>> {
+        self.duration("jitter", &self.jitter)
+    }
+
+    /// timeout returns the time a run may take, parsed
+    pub fn timeout(&self) -> Result<Option<Duration>> {
+        self.duration("timeout", &self.timeout)
+    }
+
+    /// backoff returns the delay before the first retry of a failed run,
+    /// parsed
+    pub fn backoff(&self) -> Result<Option<Duration>> {
+        self.duration("backoff", &self.backoff)
+    }
+
+    fn duration(&self, key: &'static str, value: &Option<String>) -> Result<Option<Duration>> {
+        match value {
+            Some(value) => parse_duration(value).map(Some).map_err(|reason| XcrondError::InvalidDuration {
+                name: self.name.clone(),
+                key,
+                value: value.clone(),
+                reason,
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// with_lock holds a lock on the file while the job runs
+    pub fn with_lock<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.lock = Some(path.into());
+        self
+    }
+}
+
+/// JobInfo is a serializable snapshot of a registered job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: JobId,
+    pub name: String,
+    pub cmd: String,
+    /// schedule, normalized to the xcrond dialect
+    pub schedule: String,
+    pub timezone: Option<Tz>,
+    pub metadata: HashMap<String, String>,
+    pub lock: Option<PathBuf>,
+    pub shutdown_policy: ShutdownPolicy,
+    pub misfire_policy: MisfirePolicy,
+    pub overlap_policy: OverlapPolicy,
+    pub journal: bool,
+    pub singleton_cluster: bool,
+    pub namespace: Option<String>,
+    pub login_shell: bool,
+    pub pipe_to: Option<String>,
+    pub daily_budget: Option<u64>,
+    pub jitter: Option<Duration>,
+    pub blackout: Vec<Blackout>,
+    pub blackout_policy: BlackoutPolicy,
+    pub timeout: Option<Duration>,
+    pub max_retries: u32,
+    pub backoff: Option<Duration>,
+    pub mailto: Option<String>,
+    pub shell: Option<String>,
+    pub path: Option<String>,
+    pub env: BTreeMap<String, String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub umask: Option<u32>,
+    pub nice: Option<i32>,
+    pub io_priority: Option<IoPriority>,
+    pub limits: Limits,
+    pub cgroup: CgroupLimits,
+    pub prev: DateTime<Local>,
+    pub next: DateTime<Local>,
+    pub last_result: Option<JobRunResult>,
+    /// set if the job's occurrences are skipped until it is resumed
+    #[serde(default)]
+    pub paused: bool,
+    /// number of runs of the job in progress
+    #[serde(default)]
+    pub running: usize,
+}
+
+/// Job is an occurrence of a registered job.
+/// The definition is shared between all occurrences of the job, so
+/// cloning a job only copies its prev/next times.
 #[derive(Eq, PartialEq, Clone)]
 pub struct Job {
-    name: String,
+    def: Arc<JobDef>,
     prev: DateTime<Local>,
+    next: DateTime<Local>,
+}
+
+/// JobDef is the immutable definition of a job: what to run and when
+#[derive(Eq, PartialEq, Clone)]
+struct JobDef {
+    id: JobId,
+    name: String,
     cmd: String,
     params: Vec<CString>,
-    schedule: Schedule,
+    schedule: ScheduleKind,
     expression: String,
-    next: DateTime<Local>,
+    timezone: Option<Tz>,
+    metadata: HashMap<String, String>,
+    lock: Option<PathBuf>,
+    shutdown_policy: ShutdownPolicy,
+    misfire_policy: MisfirePolicy,
+    overlap_policy: OverlapPolicy,
+    journal: bool,
+    singleton_cluster: bool,
+    namespace: Option<String>,
+    login_shell: bool,
+    pipe_to: Option<String>,
+    daily_budget: Option<u64>,
+    jitter: Option<Duration>,
+    blackout: Vec<Blackout>,
+    blackout_policy: BlackoutPolicy,
+    timeout: Option<Duration>,
+    max_retries: u32,
+    backoff: Option<Duration>,
+    mailto: Option<String>,
+    /// shell running the command, None if it's run directly
+    shell: Option<String>,
+    /// PATH of the command, None for the daemon's
+    path: Option<String>,
+    env: BTreeMap<String, String>,
+    user: Option<String>,
+    group: Option<String>,
+    umask: Option<u32>,
+    nice: Option<i32>,
+    io_priority: Option<IoPriority>,
+    limits: Limits,
+    cgroup: CgroupLimits,
 }
 
 impl Job {
-    pub fn new(name: String, cmd: String, expr: &str) -> Option<Self> {
-        // Build params
-        let mut p: Vec<CString> = vec![];
-        for a in cmd.split(' ') {
-            p.push(CString::new(a).unwrap());
+    /// new builds a job with the given schedule expression.
+    /// The schedule is evaluated in `timezone` if given, else in local time.
+    pub fn new(
+        id: JobId,
+        name: String,
+        cmd: String,
+        expr: &str,
+        timezone: Option<Tz>,
+    ) -> Result<Self> {
+        Job::starting_at(id, name, cmd, None, expr, timezone, Local::now())
+    }
+
+    /// starting_at builds a job whose first occurrence is the first after
+    /// `now`, running its command with `shell` if given
+    fn starting_at(
+        id: JobId,
+        name: String,
+        cmd: String,
+        shell: Option<String>,
+        expr: &str,
+        timezone: Option<Tz>,
+        now: DateTime<Local>,
+    ) -> Result<Self> {
+        if cmd.trim().is_empty() {
+            return Err(XcrondError::EmptyCommand(name));
         }
+        // Build params
+        let words = match &shell {
+            Some(shell) => Ok(vec![shell.clone(), "-c".to_string(), cmd.clone()]),
+            None => split_words(&cmd),
+        };
+        let p = match words.and_then(|w| {
+            w.into_iter()
+                .map(|a| CString::new(a).map_err(|_| "contains a NUL byte".to_string()))
+                .collect::<std::result::Result<Vec<CString>, String>>()
+        }) {
+            Ok(p) => p,
+            Err(reason) => return Err(XcrondError::InvalidCommand { name, reason }),
+        };
 
-        let schedule = match Schedule::from_str(expr) {
-            Ok(t) => t,
-            Err(err) => {
-                error!("[{}] Invalid schedule: {}", name, err);
-                return None;
+        let schedule = match ScheduleKind::parse(expr) {
+            Ok(s) => s,
+            Err(reason) => {
+                return Err(XcrondError::InvalidSchedule {
+                    name,
+                    expr: expr.to_string(),
+                    reason,
+                });
             }
         };
 
-        let next = match schedule.upcoming(Local).next() {
+        let mut j = Job {
+            def: Arc::new(JobDef {
+                id,
+                name,
+                cmd,
+                params: p,
+                schedule,
+                expression: expr.to_string(),
+                timezone,
+                metadata: HashMap::new(),
+                lock: None,
+                shutdown_policy: ShutdownPolicy::default(),
+                misfire_policy: MisfirePolicy::default(),
+                overlap_policy: OverlapPolicy::default(),
+                journal: false,
+                singleton_cluster: false,
+                namespace: None,
+                login_shell: false,
+                pipe_to: None,
+                daily_budget: None,
+                jitter: None,
+                blackout: vec![],
+                blackout_policy: BlackoutPolicy::default(),
+                timeout: None,
+                max_retries: 0,
+                backoff: None,
+                mailto: None,
+                shell,
+                path: None,
+                env: BTreeMap::new(),
+                user: None,
+                group: None,
+                umask: None,
+                nice: None,
+                io_priority: None,
+                limits: Limits::default(),
+                cgroup: CgroupLimits::default(),
+            }),
+            prev: now,
+            next: now,
+        };
+        j.next = match j.next_after(now) {
             Some(t) => t,
-            None => {
-                error!("[{}] Failed to calculate upcoming schedule", name);
-                return None;
-            }
+            None => return Err(XcrondError::ScheduleFinished(j.def.name.clone())),
         };
+        Ok(j)
+    }
+
+    /// from_spec builds a job from its spec, registered under the given id.
+    /// Its schedule is evaluated in the timezone of the spec if it has one,
+    /// else in `timezone`. Its first occurrence is the first after `now`.
+    pub fn from_spec(id: JobId, spec: JobSpec, timezone: Option<Tz>, now: DateTime<Local>) -> Result<Self> {
+        let expr = spec.expression()?;
+        let jitter = spec.jitter()?;
+        let timeout = spec.timeout()?;
+        let backoff = spec.backoff()?;
+        let umask = spec.umask()?;
+        if let Some(value) = spec.nice.filter(|n| *n < NICE_RANGE.0 || *n > NICE_RANGE.1) {
+            return Err(XcrondError::InvalidNice { name: spec.name, value });
+        }
+        let timezone = spec.timezone.or(timezone);
+        let shell = spec.shell.filter(|s| !s.is_empty());
+        // Names can't be empty, and neither can hold `=` or NUL bytes
+        let invalid = spec.env.iter().find(|(k, v)| k.is_empty() || k.contains(['=', '\0']) || v.contains('\0'));
+        if let Some((k, v)) = invalid {
+            let var = format!("{}={}", k, v);
+            return Err(XcrondError::InvalidEnv { name: spec.name, var });
+        }
+        let mut j = Job::starting_at(id, spec.name, spec.cmd, shell, &expr, timezone, now)?;
+        let def = Arc::make_mut(&mut j.def);
+        def.metadata = spec.metadata;
+        def.lock = spec.lock;
+        def.shutdown_policy = spec.shutdown_policy;
+        def.misfire_policy = spec.misfire_policy;
+        def.overlap_policy = spec.overlap_policy;
+        def.journal = spec.journal;
+        def.singleton_cluster = spec.singleton_cluster;
+        def.namespace = spec.namespace;
+        def.login_shell = spec.login_shell;
+        def.pipe_to = spec.pipe_to;
+        def.daily_budget = spec.daily_budget;
+        def.blackout = spec.blackout;
+        def.blackout_policy = spec.blackout_policy;
+        def.timeout = timeout;
+        def.max_retries = spec.max_retries;
+        def.backoff = backoff;
+        def.mailto = spec.mailto;
+        def.path = spec.path;
+        def.env = spec.env;
+        def.user = spec.user;
+        def.group = spec.group;
+        def.umask = umask;
+        def.nice = spec.nice;
+        def.io_priority = spec.io_priority;
+        def.limits = spec.limits;
+        def.cgroup = spec.cgroup;
+        if jitter.is_some() {
+            def.jitter = jitter;
+            j.next = j.next_after(now).ok_or_else(|| XcrondError::ScheduleFinished(j.def.name.clone()))?;
+        }
+        Ok(j)
+    }
+
+    /// definition identifies what the job runs and when: jobs with the same
+    /// definition run the same command at the same times, with the same
+    /// options. Names, ids and metadata aren't part of it.
+    pub fn definition(&self) -> String {
+        let d = &self.def;
+        format!(
+            "{}\0{}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}\0{}\0{}\0{:?}\0{}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}\0{}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}\0{:?}",
+            d.cmd,
+            d.expression,
+            d.timezone,
+            d.lock,
+            d.shutdown_policy,
+            d.misfire_policy,
+            d.overlap_policy,
+            d.journal,
+            d.singleton_cluster,
+            d.namespace,
+            d.login_shell,
+            d.pipe_to,
+            d.daily_budget,
+            d.jitter,
+            d.blackout,
+            d.blackout_policy,
+            d.timeout,
+            d.max_retries,
+            d.backoff,
+            d.mailto,
+            d.shell,
+            d.path,
+            d.env,
+            d.user,
+            d.group,
+            d.umask,
+            d.nice,
+            d.io_priority,
+            d.limits,
+            d.cgroup
+        )
+    }
+
+    /// next_after returns the first occurrence of this job's schedule after `t`,
+    /// delayed by its jitter, or None if the schedule has finished. For jobs
+    /// running at an interval, `t` is when the previous run completed.
+    pub fn next_after(&self, t: DateTime<Local>) -> Option<DateTime<Local>> {
+        let window = self
+            .def
+            .jitter
+            .and_then(|j| chrono::Duration::from_std(j).ok())
+            .filter(|w| *w > chrono::Duration::zero());
+        let delayed = |scheduled| scheduled + window.map_or_else(chrono::Duration::zero, |w| self.delay(scheduled, w));
+        let schedule = match &self.def.schedule {
+            ScheduleKind::Interval(d) => return Some(delayed(t + chrono::Duration::from_std(*d).ok()?)),
+            ScheduleKind::Once(at) => return Some(delayed(*at)).filter(|n| *n > t),
+            ScheduleKind::Cron(s) => s,
+        };
+        let window = match window {
+            Some(w) => w,
+            None => return upcoming(schedule, self.def.timezone, t),
+        };
+        // The occurrence following `t` may be scheduled up to a window
+        // before it
+        let mut after = t - window;
+        loop {
+            let scheduled = upcoming(schedule, self.def.timezone, after)?;
+            let delayed = scheduled + self.delay(scheduled, window);
+            if delayed > t {
+                return Some(delayed);
+            }
+            after = scheduled;
+        }
+    }
+
+    /// delay returns how long the occurrence scheduled at `t` is delayed,
+    /// within `window`. Delays are stable for an occurrence, so they don't
+    /// change when the daemon restarts, and differ between hosts unless
+    /// the job runs on a single host of the cluster, which is told by its
+    /// scheduled time.
+    fn delay(&self, t: DateTime<Local>, window: chrono::Duration) -> chrono::Duration {
+        let mut key = format!("{}\0{}", self.def.name, t.timestamp());
+        if !self.def.singleton_cluster {
+            key = format!("{}\0{}", key, hostname());
+        }
+        let millis = window.num_milliseconds() as u64;
+        chrono::Duration::milliseconds((fnv1a(&key) % (millis + 1)) as i64)
+    }
 
-        Some(Job {
-            name,
-            cmd,
+    /// occurrence returns the occurrence of this job following the given one,
+    /// sharing the job's definition
+    pub fn occurrence(&self, prev: DateTime<Local>, next: DateTime<Local>) -> Job {
+        Job {
+            def: self.def.clone(),
+            prev,
             next,
-            expression: expr.to_string(),
-            schedule,
-            prev: Local::now(),
-            params: p,
-        })
+        }
     }
 
-    /// Getters
+    /// command builds the command running this job's process, failing if
+    /// its user or group can't be found
+    pub fn command(&self) -> io::Result<Command> {
+        let (mut cmd, switch) = self.command_as()?;
+        if let Some(s) = switch {
+            s.apply(&mut cmd);
+        }
+        Ok(cmd)
+    }
+
+    /// command_as is like `command` but leaves the switch to the job's user
+    /// to the caller, so the steps added before it, e.g. joining a cgroup,
+    /// keep the daemon's privileges
+    pub(crate) fn command_as(&self) -> io::Result<(Command, Option<Switch>)> {
+        let (account, gid) = self.credentials()?;
+        let mut cmd = if self.def.login_shell {
+            login_command(&self.def.cmd, account.as_ref())
+        } else {
+            let params = &self.def.params;
+            let mut cmd = Command::new(OsStr::from_bytes(params[0].as_bytes()));
+            cmd.args(params[1..].iter().map(|p| OsStr::from_bytes(p.as_bytes())));
+            cmd
+        };
+        let switch = login::run_as(&mut cmd, account.as_ref(), gid)?;
+        // Raising the priority takes the daemon's privileges too
+        priority::prioritize(&mut cmd, self.def.nice, self.def.io_priority);
+        let umask = self.def.umask;
+        unsafe {
+            // Each run gets its own session, detached from the terminal of
+            // the daemon, as cron does
+            cmd.pre_exec(move || {
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if let Some(umask) = umask {
+                    libc::umask(umask as libc::mode_t);
+                }
+                Ok(())
+            });
+        }
+        cmd.envs(&self.def.env);
+        // The program is then looked up in it too
+        if let Some(path) = &self.def.path {
+            cmd.env("PATH", path);
+        }
+        Ok((cmd, switch))
+    }
+
+    /// credentials returns the account of the user the job runs as and the
+    /// id of its group, None for the daemon's. They are looked up again
+    /// for every run.
+    pub(crate) fn credentials(&self) -> io::Result<(Option<Account>, Option<libc::gid_t>)> {
+        let account = self.def.user.as_deref().map(login::lookup).transpose()?;
+        let gid = self.def.group.as_deref().map(login::group_id).transpose()?;
+        Ok((account, gid))
+    }
+
+    // Getters
+
+    /// get_id returns the id this job was registered with
+    pub fn get_id(&self) -> JobId {
+        self.def.id
+    }
 
     /// get_name returns the name of this job instance
     pub fn get_name(&self) -> &str {
-        self.name.as_str()
+        self.def.name.as_str()
+    }
+
+    pub fn get_prev(&self) -> DateTime<Local> {
+        self.prev
     }
 
     pub fn get_next(&self) -> DateTime<Local> {
         self.next
     }
 
+    /// get_timezone returns the timezone the schedule is evaluated in, None
+    /// for local time
+    pub fn get_timezone(&self) -> Option<Tz> {
+        self.def.timezone
+    }
+
+    pub fn get_metadata(&self) -> &HashMap<String, String> {
+        &self.def.metadata
+    }
+
     pub fn get_params(&self) -> &Vec<CString> {
-        &self.params
+        &self.def.params
+    }
+
+    /// get_program returns the program run by the command: its shell if
+    /// it's run through one, else its first word
+    pub fn get_program(&self) -> &OsStr {
+        OsStr::from_bytes(self.def.params[0].as_bytes())
     }
 
-    pub fn get_schedule(&self) -> &Schedule {
-        &self.schedule
+    /// find_program returns the file the program of the command resolves
+    /// to, looked up in the job's PATH or the daemon's as execvp(3) does
+    /// unless it's a path. Returns None if it isn't an executable file.
+    pub fn find_program(&self) -> Option<PathBuf> {
+        let program = Path::new(self.get_program());
+        if program.as_os_str().as_bytes().contains(&b'/') {
+            return Some(program.to_path_buf()).filter(|p| is_executable(p));
+        }
+        let path = match self.def.path.as_ref().or_else(|| self.def.env.get("PATH")) {
+            Some(path) => OsString::from(path),
+            None => env::var_os("PATH")?,
+        };
+        env::split_paths(&path)
+            .map(|dir| dir.join(program))
+            .find(|p| is_executable(p))
     }
 
-    /// Setters
+    /// get_path returns the PATH the command is run with, if it isn't the
+    /// daemon's
+    pub fn get_path(&self) -> Option<&str> {
+        self.def.path.as_deref()
+    }
+
+    /// get_user returns the user the command runs as, if it isn't the
+    /// daemon's
+    pub fn get_user(&self) -> Option<&str> {
+        self.def.user.as_deref()
+    }
+
+    /// get_limits returns the resources each run may use, without the
+    /// limits of its namespace
+    pub fn get_limits(&self) -> &Limits {
+        &self.def.limits
+    }
+
+    /// get_cgroup returns the resources each run may use with all its
+    /// processes
+    pub fn get_cgroup(&self) -> &CgroupLimits {
+        &self.def.cgroup
+    }
+
+    /// get_env returns the environment variables set for the command
+    pub fn get_env(&self) -> &BTreeMap<String, String> {
+        &self.def.env
+    }
+
+    /// get_shell returns the shell the command is run with, if any
+    pub fn get_shell(&self) -> Option<&str> {
+        self.def.shell.as_deref()
+    }
+
+    pub fn get_lock(&self) -> Option<&Path> {
+        self.def.lock.as_deref()
+    }
+
+    pub fn get_shutdown_policy(&self) -> ShutdownPolicy {
+        self.def.shutdown_policy
+    }
+
+    pub fn get_misfire_policy(&self) -> MisfirePolicy {
+        self.def.misfire_policy
+    }
+
+    pub fn get_overlap_policy(&self) -> OverlapPolicy {
+        self.def.overlap_policy
+    }
+
+    /// is_journaled returns true if the job's runs are recorded in the journal
+    pub fn is_journaled(&self) -> bool {
+        self.def.journal
+    }
+
+    /// is_singleton_cluster returns true if each occurrence of the job runs
+    /// on a single host of the cluster
+    pub fn is_singleton_cluster(&self) -> bool {
+        self.def.singleton_cluster
+    }
+
+    pub fn get_namespace(&self) -> Option<&str> {
+        self.def.namespace.as_deref()
+    }
+
+    /// is_interval returns true if the job runs at a fixed interval after
+    /// its previous run completed, rather than on a cron schedule
+    pub fn is_interval(&self) -> bool {
+        matches!(self.def.schedule, ScheduleKind::Interval(_))
+    }
+
+    /// is_one_shot returns true if the job runs once, at a given time
+    pub fn is_one_shot(&self) -> bool {
+        matches!(self.def.schedule, ScheduleKind::Once(_))
+    }
+
+    /// is_login_shell returns true if the job runs in the user's login shell
+    pub fn is_login_shell(&self) -> bool {
+        self.def.login_shell
+    }
+
+    /// get_pipe_to returns the name of the job the output of this one is
+    /// piped to
+    pub fn get_pipe_to(&self) -> Option<&str> {
+        self.def.pipe_to.as_deref()
+    }
+
+    /// get_jitter returns the window occurrences are delayed within
+    pub fn get_jitter(&self) -> Option<Duration> {
+        self.def.jitter
+    }
+
+    /// get_blackouts returns the windows during which the job doesn't run,
+    /// besides those of every job
+    pub fn get_blackouts(&self) -> &[Blackout] {
+        &self.def.blackout
+    }
+
+    pub fn get_blackout_policy(&self) -> BlackoutPolicy {
+        self.def.blackout_policy
+    }
+
+    /// get_timeout returns the time a run may take before it is terminated
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.def.timeout
+    }
+
+    /// get_max_retries returns how many times a run exiting with a non-zero
+    /// code is retried
+    pub fn get_max_retries(&self) -> u32 {
+        self.def.max_retries
+    }
+
+    /// get_mailto returns the addresses the output of the runs is mailed to,
+    /// if the job overrides those of the Jobfile
+    pub fn get_mailto(&self) -> Option<&str> {
+        self.def.mailto.as_deref()
+    }
+
+    /// get_backoff returns the delay before the first retry of a failed run
+    pub fn get_backoff(&self) -> Duration {
+        self.def.backoff.unwrap_or(DEFAULT_BACKOFF)
+    }
+
+    /// get_daily_budget returns the wall-clock time the runs of the job may
+    /// take per day
+    pub fn get_daily_budget(&self) -> Option<Duration> {
+        self.def.daily_budget.map(Duration::from_secs)
+    }
+
+    // Setters
 
     pub fn set_prev(&mut self, prev: DateTime<Local>) {
         self.prev = prev;
@@ -76,10 +1099,396 @@ impl Job {
     pub fn set_next(&mut self, next: DateTime<Local>) {
         self.next = next;
     }
+
+    /// set_metadata sets a label on the job, removing it if `value` is None.
+    /// The definition is copied if it is shared with other occurrences.
+    pub fn set_metadata(&mut self, key: &str, value: Option<&str>) {
+        let metadata = &mut Arc::make_mut(&mut self.def).metadata;
+        match value {
+            Some(v) => metadata.insert(key.to_string(), v.to_string()),
+            None => metadata.remove(key),
+        };
+    }
+
+    /// set_definition makes this occurrence share the definition of `other`
+    pub fn set_definition(&mut self, other: &Job) {
+        self.def = other.def.clone();
+    }
+}
+
+impl From<&Job> for JobInfo {
+    fn from(j: &Job) -> Self {
+        JobInfo {
+            id: j.def.id,
+            name: j.def.name.clone(),
+            cmd: j.def.cmd.clone(),
+            schedule: j.def.expression.clone(),
+            timezone: j.def.timezone,
+            metadata: j.def.metadata.clone(),
+            lock: j.def.lock.clone(),
+            shutdown_policy: j.def.shutdown_policy,
+            misfire_policy: j.def.misfire_policy,
+            overlap_policy: j.def.overlap_policy,
+            journal: j.def.journal,
+            singleton_cluster: j.def.singleton_cluster,
+            namespace: j.def.namespace.clone(),
+            login_shell: j.def.login_shell,
+            pipe_to: j.def.pipe_to.clone(),
+            daily_budget: j.def.daily_budget,
+            jitter: j.def.jitter,
+            blackout: j.def.blackout.clone(),
+            blackout_policy: j.def.blackout_policy,
+            timeout: j.def.timeout,
+            max_retries: j.def.max_retries,
+            backoff: j.def.backoff,
+            mailto: j.def.mailto.clone(),
+            shell: j.def.shell.clone(),
+            path: j.def.path.clone(),
+            env: j.def.env.clone(),
+            user: j.def.user.clone(),
+            group: j.def.group.clone(),
+            umask: j.def.umask,
+            nice: j.def.nice,
+            io_priority: j.def.io_priority,
+            limits: j.def.limits,
+            cgroup: j.def.cgroup,
+            prev: j.prev,
+            next: j.next,
+            last_result: None,
+            paused: false,
+            running: 0,
+        }
+    }
+}
+
+// Prefix of the schedules running a job at a fixed interval
+const INTERVAL_PREFIX: &str = "every";
+// Prefix of the schedules running a job once
+const ONE_SHOT_PREFIX: &str = "at";
+
+/// is_executable tells whether `path` is an executable file
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// split_words splits a command into the program and its arguments the way
+/// a shell does, without expanding anything: words are separated by
+/// whitespace, unless quoted or escaped with a backslash. Within double
+/// quotes, a backslash only escapes `"`, `\`, `$` and `` ` ``.
+pub(crate) fn split_words(cmd: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = vec![];
+    // The current word, None between words
+    let mut word: Option<String> = None;
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                words.extend(word.take());
+                continue;
+            }
+            '\\' => {
+                let escaped = chars.next().ok_or("ends with a backslash")?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            '\'' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or("has an unterminated single quote")? {
+                        '\'' => break,
+                        c => w.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or("has an unterminated double quote")? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or("has an unterminated double quote")? {
+                            c @ ('"' | '\\' | '$' | '`') => w.push(c),
+                            c => {
+                                w.push('\\');
+                                w.push(c);
+                            }
+                        },
+                        c => w.push(c),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// ScheduleKind is when the occurrences of a job are due
+// Definitions are shared between occurrences, their size doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum ScheduleKind {
+    /// the occurrences of a cron expression
+    Cron(Schedule),
+    /// a fixed interval after the previous run completed, for intervals
+    /// cron can't express such as `every 90s` or `every 7h`
+    Interval(Duration),
+    /// a single occurrence, e.g. `at 2030-01-01T09:00:00+01:00`, as at(1)
+    /// does
+    Once(DateTime<Local>),
+}
+
+impl ScheduleKind {
+    /// parse parses a cron expression of the xcrond dialect, an interval or
+    /// a single occurrence
+    pub fn parse(expr: &str) -> std::result::Result<Self, String> {
+        if let Some(t) = one_shot(expr) {
+            return DateTime::parse_from_rfc3339(t)
+                .map(|t| ScheduleKind::Once(t.with_timezone(&Local)))
+                .map_err(|err| format!("expected an RFC 3339 time such as 2030-01-01T09:00:00Z: {}", err));
+        }
+        match interval(expr) {
+            Some(i) => match parse_duration(i)? {
+                d if d.as_secs() == 0 => Err("the interval must be at least a second".to_string()),
+                d => Ok(ScheduleKind::Interval(d)),
+            },
+            None => Schedule::from_str(expr).map(ScheduleKind::Cron).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/// one_shot returns the time of a schedule of the form
+/// `at 2030-01-01T09:00:00Z`
+pub(crate) fn one_shot(expr: &str) -> Option<&str> {
+    expr.trim()
+        .strip_prefix(ONE_SHOT_PREFIX)
+        .filter(|rest| rest.starts_with(char::is_whitespace))
+        .map(str::trim)
+}
+
+/// interval returns the interval of a schedule of the form `every 90s`
+pub(crate) fn interval(expr: &str) -> Option<&str> {
+    expr.trim()
+        .strip_prefix(INTERVAL_PREFIX)
+        .filter(|rest| rest.starts_with(char::is_whitespace))
+        .map(str::trim)
+}
+
+/// upcoming evaluates the schedule in the given timezone (local time if None)
+/// and returns its first occurrence after `t` in local time
+pub(crate) fn upcoming(schedule: &Schedule, tz: Option<Tz>, t: DateTime<Local>) -> Option<DateTime<Local>> {
+    match tz {
+        Some(tz) => schedule
+            .after(&t.with_timezone(&tz))
+            .next()
+            .map(|n| n.with_timezone(&Local)),
+        None => schedule.after(&t).next(),
+    }
+}
+
+/// parse_duration parses a duration given as amounts of days, hours,
+/// minutes and seconds, e.g. `90s`, `5m` or `1h30m`. Bare numbers are seconds.
+pub(crate) fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err("empty duration".to_string());
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    let mut total = 0u64;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let n: u64 = rest[..digits].parse().map_err(|_| format!("expected a number in `{}`", s))?;
+        let unit = match rest[digits..].chars().next() {
+            Some('d') => 86400,
+            Some('h') => 3600,
+            Some('m') => 60,
+            Some('s') => 1,
+            _ => return Err(format!("expected a unit (d, h, m or s) in `{}`", s)),
+        };
+        total = n
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(|| format!("`{}` is too long", s))?;
+        rest = &rest[digits + 1..];
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// time_until returns how long from `now` until the wall clock reaches `t`,
+/// zero if it's already past. Waits are timed on the monotonic clock, so callers
+/// re-check the wall clock when they wake up: it may have been adjusted in
+/// the meantime.
+pub(crate) fn time_until(now: DateTime<Local>, t: DateTime<Local>) -> Duration {
+    t.signed_duration_since(now).to_std().unwrap_or_default()
+}
+
+impl std::fmt::Display for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}", self.def.name, self.def.id)
+    }
 }
 
 impl std::fmt::Debug for Job {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Job({} -> {})", self.name, self.next)
+        write!(f, "Job({} {} -> {})", self.def.name, self.def.id, self.next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("5 minutes").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+
+    #[test]
+    fn splits_commands_into_words() {
+        let words = |cmd: &str| split_words(cmd).map(|w| w.join("|"));
+        assert_eq!(words("/bin/echo  a b"), Ok("/bin/echo|a|b".to_string()));
+        assert_eq!(words("notify --msg 'hello world'"), Ok("notify|--msg|hello world".to_string()));
+        assert_eq!(words(r#"a "b \"c\" \d" e\ f ''"#), Ok(r#"a|b "c" \d|e f|"#.to_string()));
+        assert_eq!(words("a'b'\"c\""), Ok("abc".to_string()));
+        assert!(words("echo 'hello").is_err());
+        assert!(words("echo \"hello").is_err());
+        assert!(words("echo \\").is_err());
+
+        let spec = JobSpec::new("a", "/bin/echo a\0b", "0 * * * * *");
+        assert!(matches!(
+            Job::from_spec(JobId::new(1), spec, None, Local::now()),
+            Err(XcrondError::InvalidCommand { .. })
+        ));
+    }
+
+    #[test]
+    fn waits_for_the_wall_clock() {
+        use crate::clock::{Clock, ManualClock};
+        use chrono::TimeZone;
+
+        let start = Local.timestamp_opt(1_500_000_000, 0).unwrap();
+        let target = start + chrono::Duration::seconds(60);
+        let clock = ManualClock::new(start);
+        assert_eq!(time_until(clock.now(), target), Duration::from_secs(60));
+        assert_eq!(time_until(target + chrono::Duration::seconds(1), target), Duration::from_secs(0));
+
+        // Woken once the wait elapsed, but the wall clock was set back in
+        // the meantime: the wait isn't over
+        clock.advance(Duration::from_secs(60));
+        clock.set(start + chrono::Duration::seconds(30));
+        clock.wake();
+        clock.sleep_until(Some(target));
+        assert_eq!(time_until(clock.now(), target), Duration::from_secs(30));
+
+        // Sleeps again until the target
+        clock.advance(Duration::from_secs(30));
+        clock.sleep_until(Some(target));
+        assert_eq!(time_until(clock.now(), target), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn runs_commands_through_the_shell() {
+        let args = |spec: JobSpec| {
+            let j = Job::from_spec(JobId::new(1), spec, None, Local::now()).unwrap();
+            let cmd = j.command().unwrap();
+            let mut args = vec![cmd.get_program().to_string_lossy().into_owned()];
+            args.extend(cmd.get_args().map(|a| a.to_string_lossy().into_owned()));
+            args
+        };
+        let spec = JobSpec::new("a", "/bin/echo a | wc -c > /tmp/a", "0 * * * * *");
+        assert_eq!(args(spec.clone()), vec!["/bin/echo", "a", "|", "wc", "-c", ">", "/tmp/a"]);
+        assert_eq!(args(spec.clone().with_shell("")).len(), 7);
+        assert_eq!(
+            args(spec.with_shell("/bin/sh")),
+            vec!["/bin/sh", "-c", "/bin/echo a | wc -c > /tmp/a"]
+        );
+    }
+
+    #[test]
+    fn sets_the_environment_of_commands() {
+        let spec = JobSpec::new("a", "/bin/true", "0 * * * * *")
+            .with_env("LANG", "C")
+            .with_env("PATH", "/bin")
+            .with_path("/usr/bin");
+        let j = Job::from_spec(JobId::new(1), spec.clone(), None, Local::now()).unwrap();
+        let cmd = j.command().unwrap();
+        let env: HashMap<&OsStr, Option<&OsStr>> = cmd.get_envs().collect();
+        assert_eq!(env[OsStr::new("LANG")], Some(OsStr::new("C")));
+        assert_eq!(env[OsStr::new("PATH")], Some(OsStr::new("/usr/bin")));
+
+        for var in &["", "A=B"] {
+            assert!(matches!(
+                Job::from_spec(JobId::new(1), spec.clone().with_env(var, "x"), None, Local::now()),
+                Err(XcrondError::InvalidEnv { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn runs_commands_in_their_own_session() {
+        // The session of a process is the 6th field of its stat
+        let cmd = "umask; [ \"$(cut -d' ' -f6 /proc/$$/stat)\" = $$ ] && echo leader";
+        let spec = JobSpec::new("a", cmd, "0 * * * * *").with_shell("/bin/sh").with_umask("027");
+        let j = Job::from_spec(JobId::new(1), spec.clone(), None, Local::now()).unwrap();
+        let out = j.command().unwrap().output().unwrap();
+        let out = String::from_utf8_lossy(&out.stdout);
+        assert!(out.starts_with("0027\n"), "{}", out);
+        if cfg!(target_os = "linux") {
+            assert!(out.ends_with("leader\n"), "{}", out);
+        }
+
+        for umask in &["999", "1777", "rw"] {
+            assert!(matches!(
+                Job::from_spec(JobId::new(1), spec.clone().with_umask(umask), None, Local::now()),
+                Err(XcrondError::InvalidUmask { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn runs_commands_as_other_users() {
+        let spec = JobSpec::new("a", "id -u; id -g", "0 * * * * *").with_shell("/bin/sh");
+        let output = |spec: JobSpec| {
+            let j = Job::from_spec(JobId::new(1), spec, None, Local::now()).unwrap();
+            let out = j.command()?.output()?;
+            Ok::<_, io::Error>(String::from_utf8_lossy(&out.stdout).split_whitespace().collect::<Vec<_>>().join(" "))
+        };
+        let me = login::current().unwrap();
+        let own = format!("{} {}", me.uid, me.gid);
+        assert_eq!(output(spec.clone().with_user(&me.name, None)).unwrap(), own);
+        assert_eq!(output(spec.clone().with_user(&me.uid.to_string(), None)).unwrap(), own);
+        assert!(output(spec.clone().with_user("no-such-user", None)).is_err());
+        assert!(output(spec.clone().with_user(&me.name, Some("no-such-group"))).is_err());
+
+        // Only root can switch to another user
+        if me.uid == 0 {
+            let nobody = login::lookup("nobody").unwrap();
+            let out = output(spec.clone().with_user("nobody", Some("0"))).unwrap();
+            assert_eq!(out, format!("{} 0", nobody.uid));
+            // Only the daemon may raise the priority
+            let realtime = "realtime:0".parse().ok();
+            let out = output(spec.with_user("nobody", Some("0")).with_priority(Some(-5), realtime)).unwrap();
+            assert_eq!(out, format!("{} 0", nobody.uid));
+        }
+    }
+
+    #[test]
+    fn looks_programs_up_in_the_path() {
+        let find = |spec: JobSpec| Job::from_spec(JobId::new(1), spec, None, Local::now()).unwrap().find_program();
+        let spec = |cmd: &str| JobSpec::new("a", cmd, "0 * * * * *");
+        assert_eq!(find(spec("sh -c true").with_path("/nonexistent:/bin")), Some(PathBuf::from("/bin/sh")));
+        assert_eq!(find(spec("sh -c true").with_path("/nonexistent")), None);
+        assert_eq!(find(spec("/bin/sh -c true").with_path("/nonexistent")), Some(PathBuf::from("/bin/sh")));
+        assert_eq!(find(spec("/nonexistent/sh")), None);
+        assert_eq!(find(spec("true").with_shell("sh").with_path("/bin")), Some(PathBuf::from("/bin/sh")));
+        assert_eq!(find(spec("sh -c true").with_env("PATH", "/bin")), Some(PathBuf::from("/bin/sh")));
     }
 }
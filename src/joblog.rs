@@ -0,0 +1,53 @@
+//! Logs of the output of jobs.
+//!
+//! When a log directory is set, the standard output and error of every run
+//! are written to a file of the job's own directory, named after the time
+//! the run started, e.g. `logs/backup/20300101T020000.log`, rather than to
+//! the daemon's. The standard output of jobs piping to another one still
+//! goes to the downstream job.
+
+use chrono::{DateTime, Local};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+/// open creates the log of the run of the job named `name` started at
+/// `started`. Runs of the job starting in the same second share it.
+pub(crate) fn open(dir: &Path, name: &str, started: DateTime<Local>) -> io::Result<(PathBuf, File)> {
+    let dir = dir.join(file_name(name));
+    fs::DirBuilder::new().recursive(true).mode(0o750).create(&dir)?;
+    let path = dir.join(format!("{}.log", started.format("%Y%m%dT%H%M%S")));
+    let f = OpenOptions::new().append(true).create(true).mode(0o640).open(&path)?;
+    Ok((path, f))
+}
+
+/// file_name turns a job name into the name of its directory, replacing the
+/// characters that are troublesome in paths
+pub(crate) fn file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' => c,
+            _ => '_',
+        })
+        .collect();
+    // Keeps the name from being `.`, `..` or hidden
+    match name.strip_prefix('.') {
+        Some(rest) => format!("_{}", rest),
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_the_logs_after_the_jobs() {
+        assert_eq!(file_name("backup db"), "backup_db");
+        assert_eq!(file_name("../etc/passwd"), "_._etc_passwd");
+        assert_eq!(file_name(".."), "_.");
+        assert_eq!(file_name("nightly-report_v2.1"), "nightly-report_v2.1");
+    }
+}
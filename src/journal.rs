@@ -0,0 +1,217 @@
+//! Write-ahead journal of the runs of critical jobs.
+//!
+//! An occurrence of a journaled job is recorded before its process is
+//! forked and marked complete once it's reaped, each record being synced to
+//! disk. After a crash, the journal tells which occurrences actually started,
+//! where the state file may be out of date.
+//!
+//! The journal is a text file with one record per line:
+//!
+//! ```text
+//! start <run> <job> <occurrence> <name>
+//! end <run>
+//! ```
+//!
+//! Jobs are named by the rest of the line, the records of older versions
+//! don't name them.
+
+use crate::error::{Result, XcrondError};
+use crate::job::JobId;
+use crate::run::RunId;
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Number of records after which the journal is compacted
+const COMPACT_RECORDS: usize = 10_000;
+
+/// Entry is the latest journaled occurrence of a job
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub run: RunId,
+    pub job: JobId,
+    pub name: Option<String>,
+    pub occurrence: DateTime<Local>,
+    /// false if the run was never marked complete
+    pub finished: bool,
+}
+
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: File,
+    /// latest occurrence of every journaled job, kept when compacting
+    latest: HashMap<JobId, Entry>,
+    /// journaled runs still running
+    running: HashMap<RunId, JobId>,
+    records: usize,
+}
+
+impl Journal {
+    /// open opens the journal at `path`, creating it if needed.
+    /// Returns the journal and the latest occurrence of every job found in it.
+    /// The journal is compacted and every run found is considered complete,
+    /// processes left behind by a crash aren't tracked anymore.
+    pub fn open(path: &Path) -> Result<(Self, Vec<Entry>)> {
+        let content = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(source) => {
+                return Err(XcrondError::Io {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+
+        let mut latest: HashMap<JobId, Entry> = HashMap::new();
+        let mut started: HashMap<RunId, JobId> = HashMap::new();
+        for (n, line) in content.lines().enumerate() {
+            match parse(line) {
+                Some(Record::Start(e)) => {
+                    started.insert(e.run, e.job);
+                    if latest.get(&e.job).is_none_or(|l| l.occurrence <= e.occurrence) {
+                        latest.insert(e.job, e);
+                    }
+                }
+                Some(Record::End(run)) => {
+                    let job = started.remove(&run);
+                    if let Some(e) = job.and_then(|j| latest.get_mut(&j)) {
+                        if e.run == run {
+                            e.finished = true;
+                        }
+                    }
+                }
+                // A crash while writing leaves the last record truncated
+                None => warn!("{}:{}: Ignoring invalid journal record", path.display(), n + 1),
+            }
+        }
+
+        let recovered: Vec<Entry> = latest.values().cloned().collect();
+        for e in latest.values_mut() {
+            e.finished = true;
+        }
+
+        let mut journal = Journal {
+            path: path.to_path_buf(),
+            file: open_append(path).map_err(|source| XcrondError::Write {
+                path: path.to_path_buf(),
+                source,
+            })?,
+            latest,
+            running: HashMap::new(),
+            records: 0,
+        };
+        journal.compact()?;
+        Ok((journal, recovered))
+    }
+
+    /// start records that the occurrence of the job is about to run
+    pub fn start(&mut self, run: RunId, job: JobId, name: &str, occurrence: DateTime<Local>) -> io::Result<()> {
+        let e = Entry {
+            run,
+            job,
+            name: Some(name.to_string()),
+            occurrence,
+            finished: false,
+        };
+        self.append(&format_start(&e))?;
+        self.running.insert(run, job);
+        self.latest.insert(job, e);
+        Ok(())
+    }
+
+    /// finish marks the run complete, if it was journaled
+    pub fn finish(&mut self, run: RunId) -> io::Result<()> {
+        let job = match self.running.remove(&run) {
+            Some(j) => j,
+            None => return Ok(()),
+        };
+        self.append(&format!("end {}\n", run.as_u64()))?;
+        if let Some(e) = self.latest.get_mut(&job) {
+            if e.run == run {
+                e.finished = true;
+            }
+        }
+
+        if self.records >= COMPACT_RECORDS {
+            if let Err(err) = self.compact() {
+                error!("{}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, record: &str) -> io::Result<()> {
+        self.file.write_all(record.as_bytes())?;
+        self.file.sync_data()?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// compact atomically rewrites the journal with the latest occurrence
+    /// of every job only
+    fn compact(&mut self) -> Result<()> {
+        let mut content = String::new();
+        let mut entries: Vec<&Entry> = self.latest.values().collect();
+        entries.sort_by_key(|e| e.run);
+        for e in entries {
+            content.push_str(&format_start(e));
+            if e.finished {
+                content.push_str(&format!("end {}\n", e.run.as_u64()));
+            }
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        let write = || -> io::Result<File> {
+            let mut file = File::create(&tmp)?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+            open_append(&self.path)
+        };
+        self.file = write().map_err(|source| XcrondError::Write {
+            path: self.path.clone(),
+            source,
+        })?;
+        self.records = self.latest.len();
+        Ok(())
+    }
+}
+
+enum Record {
+    Start(Entry),
+    End(RunId),
+}
+
+fn parse(line: &str) -> Option<Record> {
+    let fields: Vec<&str> = line.splitn(5, ' ').collect();
+    match fields.as_slice() {
+        ["start", run, job, occurrence, name @ ..] => Some(Record::Start(Entry {
+            run: RunId::new(run.parse().ok()?),
+            job: JobId::new(job.parse().ok()?),
+            name: name.first().map(|n| n.to_string()),
+            occurrence: DateTime::parse_from_rfc3339(occurrence).ok()?.with_timezone(&Local),
+            finished: false,
+        })),
+        ["end", run] => Some(Record::End(RunId::new(run.parse().ok()?))),
+        _ => None,
+    }
+}
+
+fn format_start(e: &Entry) -> String {
+    let mut record = format!("start {} {} {}", e.run.as_u64(), e.job.as_u64(), e.occurrence.to_rfc3339());
+    // A name spanning lines would break the record, the job is then only
+    // known by its id
+    if let Some(name) = e.name.as_deref().filter(|n| !n.contains('\n')) {
+        record.push(' ');
+        record.push_str(name);
+    }
+    record.push('\n');
+    record
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
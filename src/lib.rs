@@ -1,187 +1,1340 @@
 #[macro_use]
 extern crate log;
 
-mod event;
+pub mod access;
+#[cfg(feature = "async")]
+pub mod async_cron;
+#[cfg(feature = "daemon")]
+pub mod bench;
+mod blackout;
+mod builder;
+mod cgroup;
+pub mod clock;
+pub mod cluster;
+mod config;
+#[cfg(feature = "daemon")]
+pub mod daemonize;
+mod diagnostic;
+mod dialect;
+mod error;
+pub mod event;
+pub mod export;
+mod handle;
+#[cfg(feature = "control")]
+pub mod ipc;
+#[cfg(feature = "daemon")]
+pub mod handoff;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod import;
 mod job;
+mod joblog;
+mod journal;
+mod limits;
+mod lock;
+mod mail;
+mod login;
+mod namespace;
+mod observer;
+#[cfg(feature = "daemon")]
+pub mod pidfile;
+mod pipe;
+mod plist;
+mod priority;
+#[cfg(feature = "daemon")]
+pub mod privileges;
+mod ratelimit;
+mod replication;
+mod run;
+mod schema;
+mod simulate;
+mod sigchld;
+pub mod spool;
+mod state;
+mod statefile;
+#[cfg(feature = "daemon")]
+pub mod systemd;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+#[cfg(target_os = "linux")]
+mod timer;
+mod watch;
 
-use chrono::DateTime;
-use chrono::Local;
+use chrono::{DateTime, Local};
+#[cfg(feature = "daemon")]
 use env_logger::{Builder, Target};
 use log::{error, info};
-use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
-use nix::unistd::{execv, fork, getpid, ForkResult, Pid};
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+use std::fs::File;
+use std::io;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use std::time;
 
-use event::EventQueue;
-use job::Job;
+use cgroup::Cgroup;
+use mail::Capture;
+use sigchld::ChildSignal;
+use state::{RunState, Shared};
+
+pub use blackout::{Blackout, BlackoutPolicy};
+pub use builder::CronBuilder;
+pub use cgroup::CgroupLimits;
+pub use chrono_tz::Tz;
+pub use diagnostic::{Diagnostic, Diagnostics};
+pub use dialect::Dialect;
+pub use error::{Result, XcrondError};
+pub use handle::{CronHandle, Reload, ShutdownHandle};
+pub use job::{Job, JobId, JobInfo, JobSpec, MisfirePolicy, OverlapPolicy, ShutdownPolicy};
+pub use limits::Limits;
+pub use mail::Mailer;
+pub use namespace::Namespace;
+pub use observer::{MissReason, SchedulerObserver};
+pub use priority::{IoClass, IoPriority};
+pub use run::{
+    JobRunResult, ResourceUsage, RunId, RunStatus, Trigger, INPUT_ENV, JOB_NAME_ENV, PREV_RUN_ENV,
+    RUN_ID_ENV, SCHEDULED_TIME_ENV, TRIGGER_ENV,
+};
+pub use simulate::Firing;
+
+// How far the wall clock may get ahead of the monotonic clock between two
+// iterations of the run loop before the system is considered to have been
+// suspended, or its clock set forward
+const SUSPEND_THRESHOLD: time::Duration = time::Duration::from_secs(10);
+// How often the reaper checks for exited children when SIGCHLD can't be watched
+const REAP_INTERVAL: time::Duration = time::Duration::from_secs(1);
+// Duration of the leader lease. Standby instances take over at most this
+// long after the leader stops renewing it.
+const LEADER_LEASE: time::Duration = time::Duration::from_secs(10);
+// How long members of a shard group stay registered without renewing it.
+// Their jobs move to the other members once it expires.
+const SHARD_TTL: time::Duration = time::Duration::from_secs(10);
+// How long a standby waits before connecting to its primary again
+const RETRY_INTERVAL: time::Duration = time::Duration::from_secs(1);
+// Minimum time occurrences stay claimed in the cluster lock, covering clock
+// differences between hosts
+const MIN_CLAIM_TTL: time::Duration = time::Duration::from_secs(60);
+
+/// init_logger installs the daemon's logger, writing to stdout and
+/// configured through the `RUST_LOG` environment variable.
+/// Fails if a logger is already installed.
+#[cfg(feature = "daemon")]
+pub fn init_logger() -> Result<()> {
+    // TODO: Change this when we can load server config
+    let mut log_builder = Builder::from_default_env();
+    log_builder.target(Target::Stdout);
+    log_builder.try_init()?;
+    Ok(())
+}
 
 #[derive(Default)]
 pub struct Cron {
-    job_list: EventQueue,
-    wakeup_after: time::Duration,
+    state_path: Option<PathBuf>,
+    journal_path: Option<PathBuf>,
+    shared: Arc<Shared>,
+    // threads coordinating with other instances, joined on shutdown so
+    // they get to release what they hold
+    coordinators: Vec<thread::JoinHandle<()>>,
+    // accepts standby instances following this one, until it's served
+    replication: Option<TcpListener>,
 }
 
 impl Cron {
+    /// builder returns a `CronBuilder` to configure a new cron instance
+    pub fn builder() -> CronBuilder {
+        CronBuilder::default()
+    }
+
     /// Initialize the cron instance.
     /// This function reads all schedule files and prepares
     /// all the necessary data structures for proper operations.
     /// Any configuration related work for cron daemon should be done
     /// in this function.
-    pub fn init(&mut self) {
-        // 1. TODO: Read cron job files
-        // 2. TODO: Parse each file
-        // 3. TODO: Enqueue jobs in job_list
-        //
-        // This is how it should look like
-        //
-        // let joblist = joblist::parse_config();
-        // for j in joblist {
-        //     self.job_list.enqueue(Job::from_cronjob(j));
-        // }
-
-        // Initialize logger
-        // TODO: Change this when we can load server config
-        let mut log_builder = Builder::from_default_env();
-        log_builder.target(Target::Stdout);
-        log_builder.init();
-
-        let j1 = Job::new(
-            "Job 1".to_string(),
-            "/usr/bin/touch /tmp/1".to_string(),
-            "@minute",
-        );
-        let j2 = Job::new(
-            "Job 2".to_string(),
-            "/usr/bin/touch /tmp/2".to_string(),
-            "0 0/2 * * * *",
-        );
-        let j3 = Job::new(
-            "Job 3".to_string(),
-            "/usr/bin/touch /tmp/3".to_string(),
-            "0 0/3 * * * *",
-        );
-
-        self.job_list.enqueue(j1.unwrap());
-        self.job_list.enqueue(j2.unwrap());
-        self.job_list.enqueue(j3.unwrap());
-    }
-
-    /// This starts the actual cron server
+    ///
+    /// Fails if the Jobfile can't be loaded.
+    /// Invalid jobs in the Jobfile are logged and skipped, and so are the
+    /// fragments of the config directory that can't be loaded.
+    ///
+    /// Logging goes through the `log` facade; the application is responsible
+    /// for installing a logger, e.g. with `init_logger`.
+    pub fn init(&mut self) -> Result<()> {
+        // Enqueue jobs from the Jobfile and its fragments, if configured
+        let (path, dir) = (self.shared.config_path.as_deref(), self.shared.config_dir.as_deref());
+        if path.is_some() || dir.is_some() {
+            let config = config::load(path, dir)?;
+            let loaded = self.shared.configure(config);
+            info!("Loaded {} jobs", loaded.added);
+
+            let state = self.shared.lock();
+            for o in &state.observers {
+                o.queue_rebuilt(state.jobs.len());
+            }
+        }
+
+        // Pick up where the previous instance left off
+        if let Some(path) = &self.state_path {
+            match statefile::load_state(path) {
+                Ok(states) => self.shared.lock().restore(states),
+                Err(err) => error!("Ignoring job state: {}", err),
+            }
+        }
+
+        // The journal knows better which occurrences ran before a crash
+        if let Some(path) = &self.journal_path {
+            let (journal, entries) = journal::Journal::open(path)?;
+            let mut state = self.shared.lock();
+            state.recover(entries);
+            state.journal = Some(Arc::new(Mutex::new(journal)));
+        } else {
+            for j in self.shared.lock().jobs.values().filter(|j| j.is_journaled()) {
+                warn!("[{}] No journal is configured, runs won't be journaled", j);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// check loads the Jobfile and its fragments like `init`, but reports
+    /// every problem instead of skipping what's invalid: Jobfiles that
+    /// can't be loaded, jobs that can't be registered and commands that
+    /// can't be found, for these and the jobs already registered. The
+    /// valid jobs are registered.
+    pub fn check(&mut self) -> Vec<XcrondError> {
+        let mut errors = vec![];
+        let (path, dir) = (self.shared.config_path.clone(), self.shared.config_dir.clone());
+        if path.is_some() || dir.is_some() {
+            let mut skipped = vec![];
+            match config::load_all(path.as_deref(), dir.as_deref(), &mut skipped) {
+                Ok(config) => {
+                    for ns in config.namespace {
+                        self.set_namespace(ns);
+                    }
+                    errors.extend(self.add_jobs(config.job).into_iter().filter_map(|res| res.err()));
+                }
+                Err(err) => errors.push(err),
+            }
+            errors.extend(skipped.into_iter().map(|(_, err)| err));
+        }
+
+        for j in self.shared.lock().jobs.values().filter(|j| !j.is_login_shell()) {
+            if j.find_program().is_none() {
+                errors.push(XcrondError::CommandNotFound {
+                    name: j.get_name().to_string(),
+                    program: j.get_program().to_string_lossy().into_owned(),
+                });
+            }
+        }
+        errors
+    }
+
+    /// add_job registers a new job and schedules its next occurrence.
+    /// Returns the id of the registered job.
+    pub fn add_job(&mut self, spec: JobSpec) -> Result<JobId> {
+        self.shared.add_job(spec)
+    }
+
+    /// add_jobs registers a batch of jobs at once, which is much cheaper
+    /// than adding them one by one on a running instance.
+    /// Returns the result of registering each job, in order.
+    pub fn add_jobs(&mut self, specs: Vec<JobSpec>) -> Vec<Result<JobId>> {
+        self.shared.add_jobs(specs)
+    }
+
+    /// remove_job unregisters a job, dropping all of its pending occurrences.
+    /// Returns false if the job wasn't registered.
+    pub fn remove_job(&mut self, id: JobId) -> bool {
+        self.shared.remove_job(id)
+    }
+
+    /// set_namespace sets the limits of a namespace, replacing its previous
+    /// ones. Jobs already registered beyond its `max_jobs` are kept.
+    pub fn set_namespace(&mut self, ns: Namespace) {
+        self.shared.lock().namespaces.insert(ns.name.clone(), ns);
+    }
+
+    /// set_blackouts sets the blackout windows of every job, replacing the
+    /// previous ones. Those of the Jobfile replace them when it's loaded.
+    pub fn set_blackouts(&mut self, windows: Vec<Blackout>) {
+        self.shared.lock().blackouts = windows;
+    }
+
+    /// set_mailto sets the addresses the output of the runs is mailed to,
+    /// separated by commas, unless the job sets its own. That of the
+    /// Jobfile replaces it when it's loaded.
+    pub fn set_mailto(&mut self, addresses: Option<&str>) {
+        self.shared.lock().mailto = addresses.map(str::to_string);
+    }
+
+    /// add_observer registers an observer notified of scheduling events
+    pub fn add_observer(&mut self, o: Arc<dyn SchedulerObserver>) {
+        self.shared.lock().observers.push(o);
+    }
+
+    /// jobs returns a snapshot of every registered job, ordered by id
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        self.shared.jobs()
+    }
+
+    /// job returns a snapshot of the job registered under the given id
+    pub fn job(&self, id: JobId) -> Option<JobInfo> {
+        self.shared.job(id)
+    }
+
+    /// set_metadata sets a label on the job, removing it if `value` is None.
+    /// Returns false if the job isn't registered.
+    pub fn set_metadata(&self, id: JobId, key: &str, value: Option<&str>) -> bool {
+        self.shared.set_metadata(id, key, value)
+    }
+
+    /// next_wakeup returns when the scheduler will next wake up to run jobs,
+    /// or None if nothing is scheduled
+    pub fn next_wakeup(&self) -> Option<DateTime<Local>> {
+        self.shared.next_wakeup()
+    }
+
+    /// queue_depth returns the number of pending occurrences
+    pub fn queue_depth(&self) -> usize {
+        self.shared.queue_depth()
+    }
+
+    /// simulate returns the occurrences of the jobs due until `until`, in
+    /// the order they would be launched, without running them nor waiting
+    /// for them. At most `limit` occurrences are returned.
+    pub fn simulate(&self, until: DateTime<Local>, limit: usize) -> Vec<Firing> {
+        self.shared.simulate(until, limit)
+    }
+
+    /// replay returns the occurrences of the jobs from `from` until `until`,
+    /// past or future, as if they had been scheduled at `from`. Their
+    /// current state, e.g. whether they're paused, is ignored. At most `limit`
+    /// occurrences are returned.
+    pub fn replay(&self, from: DateTime<Local>, until: DateTime<Local>, limit: usize) -> Vec<Firing> {
+        self.shared.replay(from, until, limit)
+    }
+
+    /// shutdown_handle returns a handle that can stop the run loop from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.shared.clone())
+    }
+
+    /// handle returns a handle to control this instance from other threads
+    pub fn handle(&self) -> CronHandle {
+        CronHandle::new(self.shared.clone())
+    }
+
+    /// start runs the cron server on a background thread and returns
+    /// a handle to control it
+    pub fn start(mut self) -> CronHandle {
+        let handle = self.handle();
+        thread::spawn(move || self.run());
+        handle
+    }
+
+    /// This starts the actual cron server.
+    /// It blocks until a shutdown is requested through a `CronHandle`.
     pub fn run(&mut self) {
         // spawn a thread for reaping zombie processes
         self.zombie_reaper();
+        let coordinators = self.campaign().into_iter().chain(self.shard_membership());
+        self.coordinators = coordinators.collect();
+        if let Some(listener) = self.replication.take() {
+            replication::serve(listener, self.shared.clone());
+        }
+        self.follow_primary();
+
+        self.shared.lock().active = true;
+        self.run_loop();
+
+        for t in self.coordinators.drain(..) {
+            let _ = t.join();
+        }
+
+        let mut state = self.shared.lock();
+        if let (false, Some(grace)) = (state.handoff, state.terminate) {
+            state = self.shared.terminate_children(state, grace);
+        }
+        // Flush the results of the jobs that finished while stopping
+        let mut state = self.persist(state);
+        // Only the children left are still recorded, by the reaper, the
+        // clients waiting for other runs are let go
+        let RunState { children, waiters, handoff, .. } = &mut *state;
+        waiters.retain(|run, _| !*handoff && children.values().any(|c| c.run == *run));
+
+        state.active = false;
+        drop(state);
+        self.shared.notify();
+        info!("Scheduler stopped");
+    }
+
+    /// take_handoff returns the state to hand over to a new instance, if the
+    /// run loop was stopped by `CronHandle::handoff`
+    #[cfg(feature = "daemon")]
+    pub fn take_handoff(&self) -> Option<handoff::Handoff> {
+        let state = self.shared.lock();
+        if !state.handoff {
+            return None;
+        }
+        Some(handoff::Handoff::capture(&state))
+    }
+
+    /// take_over picks up the running jobs and the state handed over by the
+    /// previous instance. To be called after `init` and before `run`.
+    #[cfg(feature = "daemon")]
+    pub fn take_over(&mut self, h: handoff::Handoff) {
+        let mut state = self.shared.lock();
+        h.restore(&mut state);
+        state.shutdown = false;
+        state.handoff = false;
+        state.terminate = None;
+        drop(state);
+        self.shared.notify();
+    }
+
+    fn run_loop(&mut self) {
+        let mut state = self.shared.lock();
+        let clock = self.shared.clock.clone();
+        let mut clocks = (clock.now(), clock.instant());
 
         loop {
-            self.job_list.debug_print();
+            state = self.persist(state);
+            if state.shutdown {
+                break;
+            }
+
+            // Let observers know the loop isn't stuck
+            for o in &state.observers {
+                o.heartbeat();
+            }
+
+            // The monotonic clock stops while the system is suspended, the
+            // occurrences that came due in the meantime have misfired
+            let now = (clock.now(), clock.instant());
+            let jump = (now.0 - clocks.0)
+                .to_std()
+                .ok()
+                .and_then(|wall| wall.checked_sub(now.1 - clocks.1));
+            if let Some(jump) = jump.filter(|j| *j > SUSPEND_THRESHOLD) {
+                warn!("Clock jumped {:?} ahead, the system was likely suspended", jump);
+                state.misfired(now.0);
+            }
+            clocks = now;
+
+            // Terminate the runs exceeding their timeout
+            state.enforce_timeouts();
+
+            // Run jobs triggered out of band
+            let mut triggered = std::mem::take(&mut state.triggered).into_iter();
+            while let Some((id, run)) = triggered.next() {
+                let (s, launch) = self.throttle(state);
+                state = s;
+                if !launch {
+                    // Stopping, run by the next run of the loop if any
+                    state.triggered.push((id, run));
+                    state.triggered.extend(triggered);
+                    break;
+                }
+                match state.jobs.get(&id).cloned() {
+                    Some(j) => {
+                        info!("[{}] Triggered manually", j);
+                        state.reserved = Some(run);
+                        state = self.spawn(state, &j, Trigger::Manual, 1, 0, None);
+                        // Skipped, e.g. its lock is held
+                        if let Some(run) = state.reserved.take() {
+                            state.abandon(run);
+                        }
+                    }
+                    None => state.abandon(run),
+                }
+            }
+
+            // Run jobs piped to by a successful run
+            let mut piped = std::mem::take(&mut state.piped).into_iter();
+            while let Some((id, input)) = piped.next() {
+                let (s, launch) = self.throttle(state);
+                state = s;
+                if !launch {
+                    // Stopping, run by the next run of the loop if any
+                    state.piped.push((id, input));
+                    state.piped.extend(piped);
+                    break;
+                }
+                match state.jobs.get(&id).cloned() {
+                    Some(j) => {
+                        info!("[{}] Triggered by its upstream job", j);
+                        state = self.spawn(state, &j, Trigger::Upstream, 1, 0, Some(input));
+                    }
+                    None => pipe::remove(&input),
+                }
+            }
 
-            // Try to dequeue
-            let top = match self.job_list.dequeue() {
+            // Retry the launches that failed with a transient error
+            for r in state.due_retries() {
+                let (s, launch) = self.throttle(state);
+                state = s;
+                if !launch {
+                    // Stopping, retried by the next run of the loop if any
+                    state.retries.push(r);
+                    continue;
+                }
+                if state.jobs.contains_key(&r.job.get_id()) {
+                    state.reserved = r.run;
+                    state = self.spawn(state, &r.job, r.trigger, r.attempt, r.retry, r.input);
+                    if let Some(run) = state.reserved.take() {
+                        state.abandon(run);
+                    }
+                } else {
+                    if let Some(input) = &r.input {
+                        pipe::remove(input);
+                    }
+                    r.run.iter().for_each(|run| state.abandon(*run));
+                }
+            }
+
+            // Run the occurrences that waited for the previous run of their job
+            let ready: Vec<JobId> = state.queued.iter().filter(|id| state.running(**id).is_empty()).cloned().collect();
+            for id in ready {
+                let (s, launch) = self.throttle(state);
+                state = s;
+                if !launch {
+                    break;
+                }
+                state.queued.remove(&id);
+                if let Some(j) = state.jobs.get(&id).cloned() {
+                    info!("[{}] Previous run finished, running the queued occurrence", j);
+                    state = self.spawn(state, &j, Trigger::Scheduled, 1, 0, None);
+                }
+            }
+
+            // Standby instances only follow the primary until they take over
+            if state.standby {
+                let deadline = self.wakeup_deadline(&state, None);
+                state = self.shared.wait_until(state, deadline);
+                continue;
+            }
+
+            state.queue.debug_print();
+
+            // 1. Calculate wakeup after
+            let next = match state.next_time() {
                 Some(t) => t,
                 None => {
-                    // Shutdown server
-                    // Why?
-                    // Because if queue is empty, it means no jobs are scheduled.
-                    // After adding job(s) to Jobfile, the server has to be restarted
-                    // which will popluate the queue automatically. So, if queue
-                    // is empty, then it will remain empty until next server restart.
-                    //
-                    // This will change when we add support for runtime population of queue.
-                    // Till then we can simply shutdown the server, as it's basically of
-                    // no use if the queue is empty.
+                    // Nothing is scheduled, wait until a job is added
                     info!("There are no jobs to execute");
-                    break;
+                    let deadline = self.wakeup_deadline(&state, None);
+                    state = self.shared.wait_until(state, deadline);
+                    continue;
                 }
             };
 
-            // 1. Calculate wakeup after
-            let wakeup_after = match top.get_time().signed_duration_since(Local::now()).to_std() {
-                Ok(t) => t,
-                Err(err) => {
-                    error!(
-                        "Failed to calculate time difference for time {}: {}",
-                        top.get_time(),
-                        err
-                    );
-                    thread::sleep(time::Duration::from_secs(60));
+            // An event that is already due is run right away. The wall clock
+            // is checked again on every wakeup, so an adjustment of the clock
+            // while waiting can't make the event run early.
+            let wakeup_after = job::time_until(clock.now(), next);
+            if wakeup_after > time::Duration::from_secs(0) {
+                info!("Next exec after time {:?} (at {})", wakeup_after, next);
+
+                // 2. sleep until the event is due, or until the queue changes
+                let deadline = self.wakeup_deadline(&state, Some(next));
+                state = self.shared.wait_until(state, deadline);
+                continue;
+            }
+
+            let top = match state.queue.dequeue() {
+                Some(t) => t,
+                None => continue,
+            };
+
+            for j in state.fair_order(top.into_jobs()) {
+                // Occurrences of paused jobs are skipped, but stay scheduled
+                if state.paused.contains(&j.get_id()) {
+                    info!("[{}] Skipped: job is paused", j);
+                    state.missed(&j, MissReason::Paused);
+                    state.requeue(j);
                     continue;
                 }
-            };
-            self.wakeup_after = time::Duration::new(wakeup_after.as_secs(), 0);
 
-            info!("Next exec after time {:?}", self.wakeup_after);
+                // Occurrences in a blackout window are skipped, or run at its end
+                if let Some(end) = state.blackout(&j) {
+                    match j.get_blackout_policy() {
+                        BlackoutPolicy::Skip => {
+                            info!("[{}] Skipped: in a blackout window until {}", j, end);
+                            state.missed(&j, MissReason::Blackout);
+                            state.requeue(j);
+                        }
+                        BlackoutPolicy::Defer => {
+                            info!("[{}] Deferred to the end of a blackout window at {}", j, end);
+                            state.defer(j, end);
+                        }
+                    }
+                    continue;
+                }
 
-            // 2. sleep for wakeup_after duration
-            thread::sleep(self.wakeup_after);
+                // and so are those of the jobs that used up their daily budget
+                if state.over_budget(&j) {
+                    info!("[{}] Skipped: daily budget exceeded", j);
+                    state.missed(&j, MissReason::BudgetExceeded);
+                    state.requeue(j);
+                    continue;
+                }
 
-            for j in top.get_jobs() {
-                // 4. fork process
-                match fork() {
-                    Ok(ForkResult::Child) => {
-                        let path = &j.get_params()[0];
+                // Standby instances keep their queue going, but the leader runs the jobs
+                if self.shared.election.is_some() && !state.is_leader() {
+                    debug!("[{}] Skipped: not the leader", j);
+                    state.missed(&j, MissReason::NotLeader);
+                    state.requeue(j);
+                    continue;
+                }
 
-                        // 5. execve job on forked process
-                        match execv(path, &j.get_params()[..]) {
-                            Ok(_) => {
-                                info!("[{}] Launched process {}", j.get_name(), getpid());
-                            }
-                            Err(err) => {
-                                error!("Failed to execute `{:?}` in pid `{}`: {:?}", path, getpid(), err);
+                // Jobs of other members of the shard group are left to them
+                if let Some(shard) = &state.shard {
+                    if !shard.owns(j.get_name()) {
+                        debug!("[{}] Skipped: in the shard of another member", j);
+                        state.missed(&j, MissReason::OtherShard);
+                        state.requeue(j);
+                        continue;
+                    }
+                }
+
+                // Occurrences overlapping a previous run follow the job's overlap policy
+                if !state.running(j.get_id()).is_empty() {
+                    match j.get_overlap_policy() {
+                        OverlapPolicy::Allow => {}
+                        OverlapPolicy::Skip => {
+                            info!("[{}] Skipped: previous run still running", j);
+                            state.missed(&j, MissReason::Overlap);
+                            state.requeue(j);
+                            continue;
+                        }
+                        OverlapPolicy::Queue => {
+                            if state.queued.insert(j.get_id()) {
+                                info!("[{}] Queued until the previous run finishes", j);
+                            } else {
+                                info!("[{}] Skipped: an occurrence is already queued", j);
+                                state.missed(&j, MissReason::Overlap);
                             }
+                            state.requeue(j);
+                            continue;
+                        }
+                        OverlapPolicy::Replace => {
+                            info!("[{}] Replacing the previous run", j);
+                            state.terminate_runs(j.get_id());
                         }
                     }
-                    Ok(ForkResult::Parent {child}) => {
-                        info!("[{}] Spawned child {}", j.get_name(), child);
+                }
 
-                        let time_diff = DateTime::from(time::SystemTime::now() + time::Duration::from_secs(1));
+                // 3. respect the concurrency limit, skipping this occurrence if reached
+                if let Some(max) = self.shared.max_concurrent {
+                    if state.children.len() >= max {
+                        warn!(
+                            "[{}] Skipped: {} jobs already running",
+                            j,
+                            max
+                        );
+                        state.missed(&j, MissReason::ConcurrencyLimit);
+                        state.requeue(j);
+                        continue;
+                    }
+                }
 
-                        if !j.get_schedule().after(&time_diff).peekable().peek().is_some() {
-                            info!("Job Schedule Finished: {:?}", j.get_name());
+                // and the limit of the job's namespace
+                if let Some(ns) = state.namespace(&j) {
+                    if let Some(max) = ns.max_concurrent {
+                        if state.running_in(&ns.name) >= max {
+                            warn!("[{}] Skipped: {} jobs of namespace {} already running", j, max, ns.name);
+                            state.missed(&j, MissReason::NamespaceLimit);
+                            state.requeue(j);
                             continue;
                         }
-
-                        // Requeue /w new `next`
-                        let mut j_new = j.clone();
-                        j_new.set_prev(j.get_next());
-                        // This unwrap should fail in theory as we are checking `is_some` above
-                        j_new.set_next(j.get_schedule().after(&time_diff).next().unwrap());
-                        debug!("New Job: {:?}", j_new);
-                        self.job_list.enqueue(j_new);
                     }
-                    Err(_) => error!("Forking should never fail!!!.
-                    If you are seeing this message, then you have much more serious problems than this server failing."),
+                }
+
+                // 4. respect the start rate, delaying the launch as needed
+                let (s, launch) = self.throttle(state);
+                state = s;
+                if !state.is_current(&j) || state.is_queued(j.get_id()) {
+                    // Removed or replaced while waiting, a replaced job's
+                    // occurrence was queued again with its new definition
+                    continue;
+                }
+                if !launch {
+                    // Stopping, the occurrence stays pending instead of being
+                    // recorded as run
+                    state.queue.enqueue(j);
+                    continue;
+                }
+                state = self.spawn(state, &j, Trigger::Scheduled, 1, 0, None);
+                // Unless it was removed or replaced while spawning
+                if state.is_current(&j) && !state.is_queued(j.get_id()) {
+                    state.requeue(j);
                 }
             }
         }
     }
 
-    /// zombie_reaper spawns a thread to reap zombie processes
-    fn zombie_reaper(&self) {
-        thread::spawn(|| loop {
-            match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
-                Ok(s) => match s {
-                    WaitStatus::Exited(pid, code) => {
-                        info!("[Reaper] Process {} exited with code {}", pid, code)
-                    }
-                    WaitStatus::Stopped(pid, signal) => {
-                        info!("[Reaper] Process {} stopped by signal {:?}", pid, signal)
-                    }
-                    WaitStatus::Signaled(pid, signal, _) => info!(
-                        "[Reaper] Process {} signaled to stop with {:?}",
-                        pid, signal
-                    ),
-                    _ => {
-                        info!("[Reaper] Wait Signal: {:?}", s);
-                        thread::sleep(time::Duration::from_secs(60));
-                        continue;
+    /// throttle waits until the start rate allows launching a job, if one
+    /// is set. Returns false once a shutdown is requested.
+    fn throttle<'a>(&'a self, mut state: MutexGuard<'a, RunState>) -> (MutexGuard<'a, RunState>, bool) {
+        loop {
+            if state.shutdown {
+                return (state, false);
+            }
+            let wait = match state.start_rate.as_mut().map(|b| b.take()) {
+                Some(Err(wait)) => wait,
+                _ => return (state, true),
+            };
+            debug!("Start rate reached, delaying the next launch by {:?}", wait);
+            state = self.shared.wait(state, Some(wait));
+            for o in &state.observers {
+                o.heartbeat();
+            }
+        }
+    }
+
+    /// wakeup_deadline returns when the run loop has to wake up next, to run
+    /// the next event, retry a launch, send a heartbeat or terminate a job
+    /// exceeding its timeout, whichever comes first
+    fn wakeup_deadline(&self, state: &RunState, next: Option<DateTime<Local>>) -> Option<DateTime<Local>> {
+        let now = self.shared.clock.instant();
+        let after = |d: time::Duration| chrono::Duration::from_std(d).ok().map(|d| self.shared.clock.now() + d);
+        let beat = self.shared.heartbeat.and_then(after);
+        let retry = state
+            .retries
+            .iter()
+            .map(|r| r.due.saturating_duration_since(now))
+            .min()
+            .and_then(after);
+        let timeout = state.timeout_deadline();
+        [next, beat, retry, timeout].iter().flatten().min().cloned()
+    }
+
+    /// capture opens the file the standard output and error of the run go
+    /// to: its log if the output of the runs is logged, or a file capturing
+    /// it if it's mailed, along with what is mailed. Returns None if the
+    /// output goes to the daemon's.
+    fn capture(&self, state: &RunState, j: &Job) -> io::Result<Option<(File, Option<Capture>)>> {
+        let to = state.mail_to(j);
+        if let Some(dir) = &self.shared.log_dir {
+            let (path, f) = joblog::open(dir, j.get_name(), state.clock.now())?;
+            debug!("[{}] Logging its output to {}", j, path.display());
+            let capture = to.map(|to| Capture::log(&path, &f, &to)).transpose()?;
+            return Ok(Some((f, capture)));
+        }
+        match to {
+            Some(to) => {
+                let (capture, f) = Capture::create(state.next_run_id(), &to)?;
+                Ok(Some((f, Some(capture))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// persist writes the state of the jobs to the state file, if configured
+    /// and out of date. The lock is released while writing.
+    fn persist<'a>(&'a self, mut state: MutexGuard<'a, RunState>) -> MutexGuard<'a, RunState> {
+        let path = match &self.state_path {
+            Some(p) if state.dirty => p,
+            _ => return state,
+        };
+
+        let states = state.job_states();
+        state.dirty = false;
+        drop(state);
+
+        if let Err(err) = statefile::save_state(path, states) {
+            error!("{}", err);
+        }
+        self.shared.lock()
+    }
+
+    /// spawn starts the job's process.
+    /// Children are created with `std::process::Command`, which only does
+    /// async signal safe work between fork and exec, so spawning is safe
+    /// no matter how many threads the daemon runs.
+    ///
+    /// Launches failing with a transient error are retried after a delay,
+    /// `attempt` being the number of this attempt. `retry` is the number of
+    /// the retry of a failed run the launch is, 0 for the first run.
+    ///
+    /// `input` is the output of the upstream job for runs started by a pipe,
+    /// removed unless the run or its retry takes it over.
+    fn spawn<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, RunState>,
+        j: &Job,
+        trigger: Trigger,
+        attempt: u32,
+        retry: u32,
+        input: Option<PathBuf>,
+    ) -> MutexGuard<'a, RunState> {
+        let mut input = pipe::Staged::new(input);
+        let (mut cmd, switch) = match j.command_as() {
+            Ok(c) => c,
+            Err(err) => {
+                error!("[{}] Failed to set up its command: {}", j, err);
+                state.failed(j, trigger, format!("failed to set up the command: {}", err));
+                return state;
+            }
+        };
+        let mut limits = *j.get_limits();
+        if let Some(ns) = state.namespace(j) {
+            limits = limits.lowest(&ns.limits());
+        }
+        limits.apply(&mut cmd);
+
+        // The lock is released when the child and its own children exit
+        let lock = match j.get_lock() {
+            Some(path) => match lock::try_lock(path) {
+                Ok(Some(f)) => Some(f),
+                Ok(None) => {
+                    info!("[{}] Skipped: lock {} held", j, path.display());
+                    state.missed(j, MissReason::LockHeld);
+                    return state;
+                }
+                Err(err) => {
+                    error!("[{}] Failed to lock {}: {}", j, path.display(), err);
+                    state.failed(j, trigger, format!("failed to lock {}: {}", path.display(), err));
+                    return state;
+                }
+            },
+            None => None,
+        };
+        if let Some(f) = &lock {
+            lock::inherit(&mut cmd, f);
+        }
+
+        // Hosts defining the same job race to claim each of its occurrences,
+        // retries run the occurrence claimed by the first attempt. The lock
+        // may be a remote store, it's claimed with the state unlocked.
+        if let (Some(cluster), true, Trigger::Scheduled, 1) =
+            (&self.shared.cluster_lock, j.is_singleton_cluster(), trigger, attempt)
+        {
+            let occurrence = j.get_next();
+            let key = format!("xcrond:{}:{}", j.get_name(), occurrence.timestamp());
+            let ttl = j
+                .next_after(occurrence)
+                .and_then(|n| n.signed_duration_since(occurrence).to_std().ok())
+                .map_or(MIN_CLAIM_TTL, |d| std::cmp::max(d, MIN_CLAIM_TTL));
+            drop(state);
+            let claimed = cluster.try_acquire(&key, ttl);
+            state = self.shared.lock();
+            if !state.is_current(j) {
+                // Removed or replaced while claiming, the claim expires on
+                // its own
+                info!("[{}] Skipped: removed or replaced while claiming {}", j, key);
+                return state;
+            }
+            match claimed {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("[{}] Skipped: claimed by another host", j);
+                    state.missed(j, MissReason::ClusterLockHeld);
+                    return state;
+                }
+                Err(err) => {
+                    error!("[{}] Failed to claim {}: {}", j, key, err);
+                    state.failed(j, trigger, format!("failed to claim {}: {}", key, err));
+                    return state;
+                }
+            }
+        }
+
+        // Scheduled occurrences of journaled jobs are recorded before forking,
+        // they aren't run if that fails. The record is synced to disk with
+        // the state unlocked, the id of the run is reserved meanwhile.
+        if let (Some(journal), true, Trigger::Scheduled) = (state.journal.clone(), j.is_journaled(), trigger) {
+            let run = state.take_run_id();
+            state.reserved = Some(run);
+            drop(state);
+            let journaled = journal.lock().unwrap().start(run, j.get_id(), j.get_name(), j.get_next());
+            state = self.shared.lock();
+            if let Err(err) = journaled {
+                error!("[{}] Failed to journal {}: {}", j, run, err);
+                state.failed(j, trigger, format!("failed to journal the run: {}", err));
+                return state;
+            }
+            if !state.is_current(j) {
+                // Removed or replaced while journaling, the run never starts
+                state.reserved = None;
+                if let Err(err) = journal.lock().unwrap().finish(run) {
+                    error!("[{}] Failed to journal the end of {}: {}", j, run, err);
+                }
+                return state;
+            }
+        }
+
+        // The state stays locked until the run is recorded under this id
+        cmd.env(RUN_ID_ENV, state.next_run_id().as_u64().to_string())
+            .env(JOB_NAME_ENV, j.get_name())
+            .env(PREV_RUN_ENV, j.get_prev().to_rfc3339())
+            .env(TRIGGER_ENV, trigger.name());
+        if trigger == Trigger::Scheduled {
+            cmd.env(SCHEDULED_TIME_ENV, j.get_next().to_rfc3339());
+        }
+
+        if let Some(path) = input.path() {
+            match File::open(path) {
+                Ok(f) => {
+                    cmd.stdin(f).env(INPUT_ENV, path);
+                }
+                Err(err) => {
+                    error!("[{}] Failed to open its input {}: {}", j, path.display(), err);
+                    state.failed(j, trigger, format!("failed to open {}: {}", path.display(), err));
+                    return state;
+                }
+            }
+        }
+        // The run's output is lost rather than the run if it can't be
+        // captured
+        let mut mail = None;
+        let captured = self
+            .capture(&state, j)
+            .and_then(|c| c.map(|(f, capture)| Ok((f.try_clone()?, f, capture))).transpose());
+        match captured {
+            Ok(Some((stdout, stderr, capture))) => {
+                cmd.stdout(stdout).stderr(stderr);
+                mail = capture;
+            }
+            Ok(None) => {}
+            Err(err) => error!("[{}] Failed to capture its output: {}", j, err),
+        }
+        let mut output = pipe::Staged::new(None);
+        if j.get_pipe_to().is_some() {
+            match pipe::output(state.next_run_id()) {
+                Ok((path, f)) => {
+                    cmd.stdout(f);
+                    output = pipe::Staged::new(Some(path));
+                }
+                Err(err) => {
+                    error!("[{}] Failed to create the file capturing its output: {}", j, err);
+                    state.failed(j, trigger, format!("failed to capture the output: {}", err));
+                    mail.iter().for_each(Capture::remove);
+                    return state;
+                }
+            }
+        }
+
+        // The cgroup is removed once the run is reaped
+        let mut cgroup = None;
+        if let (Some(dir), false) = (&self.shared.cgroup_dir, j.get_cgroup().is_empty()) {
+            let created = Cgroup::create(dir, j.get_name(), state.next_run_id(), j.get_cgroup())
+                .and_then(|cg| cg.enter(&mut cmd).map(|()| cg));
+            match created {
+                Ok(cg) => cgroup = Some(cg),
+                Err(err) => {
+                    error!("[{}] Failed to create its cgroup in {}: {}", j, dir.display(), err);
+                    state.failed(j, trigger, format!("failed to create the cgroup: {}", err));
+                    mail.iter().for_each(Capture::remove);
+                    return state;
+                }
+            }
+        }
+
+        // Last, joining the cgroup takes the daemon's privileges
+        if let Some(s) = switch {
+            s.apply(&mut cmd);
+        }
+        match cmd.spawn() {
+            Ok(child) => {
+                // The handle is dropped, the reaper waits for the child by pid
+                let pid = child.id() as i32;
+                let run = state.started(pid, j, trigger, retry, output.take(), input.take());
+                if let Some(c) = state.children.get_mut(&pid) {
+                    c.mail = mail;
+                    c.cgroup = cgroup;
+                }
+                info!("[{}] Spawned child {} for {}", j, pid, run);
+                // Wake up the reaper if it's waiting for children
+                self.shared.notify();
+            }
+            Err(err) => {
+                mail.iter().for_each(Capture::remove);
+                cgroup.iter().for_each(Cgroup::remove);
+                state.spawn_failed(j, trigger, attempt, retry, input.take(), err);
+            }
+        }
+        state
+    }
+
+    /// campaign spawns a thread acquiring and renewing the leader lease, if
+    /// leader election is configured. The lease is released on shutdown.
+    fn campaign(&self) -> Option<thread::JoinHandle<()>> {
+        let (election, key) = self.shared.election.clone()?;
+
+        let shared = self.shared.clone();
+        let t = thread::spawn(move || loop {
+            // The lease is only trusted for as long as it was granted for,
+            // counted from before asking for it
+            let asked = time::Instant::now();
+            let res = election.campaign(&key, LEADER_LEASE);
+
+            let mut state = shared.lock();
+            let was_leader = state.is_leader();
+            match res {
+                Ok(true) => state.leader_until = Some(asked + LEADER_LEASE),
+                Ok(false) => state.leader_until = None,
+                // Keep leading until the lease expires, it may still be renewed
+                Err(err) => error!("Failed to campaign for {}: {}", key, err),
+            }
+            match (was_leader, state.is_leader()) {
+                (false, true) => info!("Elected leader"),
+                (true, false) => warn!("Lost the leadership"),
+                _ => {}
+            }
+
+            // Renew well before the lease expires
+            let renew = asked + LEADER_LEASE / 3;
+            while !state.shutdown && time::Instant::now() < renew {
+                let timeout = renew.saturating_duration_since(time::Instant::now());
+                state = shared.wait(state, Some(timeout));
+            }
+            if state.shutdown {
+                // The lease is kept when handing over, the next instance renews it
+                let resign = state.leader_until.take().is_some() && !state.handoff;
+                drop(state);
+                if resign {
+                    if let Err(err) = election.resign(&key) {
+                        error!("Failed to resign the leadership: {}", err);
+                    }
+                }
+                break;
+            }
+        });
+        Some(t)
+    }
+
+    /// shard_membership spawns a thread keeping this instance registered in
+    /// its shard group and its shard up to date, if sharding is configured.
+    /// The instance leaves the group on shutdown.
+    fn shard_membership(&self) -> Option<thread::JoinHandle<()>> {
+        let (membership, group) = self.shared.sharding.clone()?;
+
+        let shared = self.shared.clone();
+        let t = thread::spawn(move || loop {
+            let started = time::Instant::now();
+            let res = membership
+                .join(&group, SHARD_TTL)
+                .and_then(|_| membership.members(&group));
+
+            let mut state = shared.lock();
+            match res {
+                Ok(mut members) => {
+                    let me = membership.id();
+                    if !members.contains(&me) {
+                        members.push(me.clone());
+                    }
+                    members.sort();
+                    let shard = cluster::Shard { me, members };
+                    if state.shard.as_ref() != Some(&shard) {
+                        let owned = state.jobs.values().filter(|j| shard.owns(j.get_name())).count();
+                        info!(
+                            "Shard group {} has {} members, running {} of {} jobs",
+                            group,
+                            shard.members.len(),
+                            owned,
+                            state.jobs.len()
+                        );
+                        state.shard = Some(shard);
+                    }
+                }
+                // The last known members keep sharing the jobs
+                Err(err) => error!("Failed to update the shard group {}: {}", group, err),
+            }
+
+            let refresh = started + SHARD_TTL / 3;
+            while !state.shutdown && time::Instant::now() < refresh {
+                let timeout = refresh.saturating_duration_since(time::Instant::now());
+                state = shared.wait(state, Some(timeout));
+            }
+            if state.shutdown {
+                // Handed over instances stay in the group under the same name
+                let leave = state.shard.take().is_some() && !state.handoff;
+                drop(state);
+                if leave {
+                    if let Err(err) = membership.leave(&group) {
+                        error!("Failed to leave the shard group {}: {}", group, err);
+                    }
+                }
+                break;
+            }
+        });
+        Some(t)
+    }
+
+    /// follow_primary spawns a thread replicating the state of the primary
+    /// while this instance is a standby. It takes over scheduling if it
+    /// doesn't hear from the primary within the failover timeout.
+    fn follow_primary(&self) {
+        let (primary, failover_after) = match &self.shared.standby {
+            Some(s) if self.shared.lock().standby => s.clone(),
+            _ => return,
+        };
+
+        let shared = self.shared.clone();
+        thread::spawn(move || {
+            info!("Standing by for {}", primary);
+            let mut heard = time::Instant::now();
+            loop {
+                let res = replication::follow(&primary, |snapshot| {
+                    let mut state = shared.lock();
+                    if !state.standby || state.shutdown {
+                        return false;
                     }
-                },
-                Err(e) => {
-                    info!("[Reaper] No childs present: {:?}", e);
-                    thread::sleep(time::Duration::from_secs(60));
+                    snapshot.apply(&mut state);
+                    heard = time::Instant::now();
+                    true
+                });
+
+                let mut state = shared.lock();
+                if !state.standby || state.shutdown {
+                    return;
+                }
+                if heard.elapsed() >= failover_after {
+                    warn!("No heartbeat from {} for {:?}, taking over", primary, heard.elapsed());
+                    state.standby = false;
+                    state.catch_up();
+                    drop(state);
+                    shared.notify();
+                    return;
+                }
+                if let Err(err) = res {
+                    debug!("Failed to follow {}: {}", primary, err);
+                }
+
+                // Retry shortly, the primary may just be restarting
+                drop(shared.wait(state, Some(RETRY_INTERVAL)));
+            }
+        });
+    }
+
+    /// zombie_reaper spawns a thread to reap zombie processes.
+    /// The thread blocks until SIGCHLD is received while children are running,
+    /// and exits once a shutdown was requested and every child was reaped.
+    fn zombie_reaper(&self) {
+        // Listen for SIGCHLD before any job is spawned, so no exit is missed
+        let signal = match ChildSignal::new() {
+            Ok(s) => Some(s),
+            Err(err) => {
+                warn!("Failed to watch SIGCHLD, polling for exited children: {}", err);
+                None
+            }
+        };
+
+        let shared = self.shared.clone();
+        thread::spawn(move || loop {
+            {
+                // Wait until there is something to reap
+                let mut state = shared.lock();
+                while state.children.is_empty() && !state.shutdown {
+                    state = shared.wait(state, None);
+                }
+                // Children are handed over to the next instance as they are
+                if state.children.is_empty() || state.handoff {
+                    break;
+                }
+
+                if reap_children(&mut state) {
+                    shared.notify();
+                }
+                if state.children.is_empty() {
                     continue;
                 }
             }
+
+            // Block until one of the children exits
+            match &signal {
+                Some(s) => {
+                    if let Err(err) = s.wait() {
+                        error!("[Reaper] Failed to wait for SIGCHLD: {}", err);
+                        thread::sleep(REAP_INTERVAL);
+                    }
+                }
+                None => thread::sleep(REAP_INTERVAL),
+            }
         });
     }
 }
+
+/// reap_children collects every child that exited, without blocking.
+/// Returns true if at least one child was reaped.
+fn reap_children(state: &mut RunState) -> bool {
+    let pids: Vec<i32> = state.children.keys().cloned().collect();
+    let mut reaped = false;
+    for pid in pids {
+        let mut raw = 0;
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        let ret = unsafe { libc::wait4(pid, &mut raw, libc::WNOHANG, &mut usage) };
+        if ret == 0 {
+            // Still running
+            continue;
+        }
+        if ret < 0 {
+            // The child was reaped by someone else, stop tracking it
+            warn!("[Reaper] Lost track of process {}: {}", pid, io::Error::last_os_error());
+            state.forget(pid);
+            reaped = true;
+            continue;
+        }
+
+        let status = match WaitStatus::from_raw(Pid::from_raw(pid), raw) {
+            Ok(WaitStatus::Exited(_, code)) => {
+                debug!("[Reaper] Process {} exited with code {}", pid, code);
+                RunStatus::Exited(code)
+            }
+            Ok(WaitStatus::Signaled(_, signal, _)) => {
+                debug!("[Reaper] Process {} signaled to stop with {:?}", pid, signal);
+                RunStatus::Signaled(format!("{:?}", signal))
+            }
+            _ => continue,
+        };
+
+        if let Some(r) = state.reaped(pid, status, Some(ResourceUsage::from(&usage))) {
+            info!(
+                "[{} {}] Process {} of {} finished: {:?}",
+                r.name, r.job, r.pid, r.run, r.status
+            );
+        }
+        reaped = true;
+    }
+    reaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::ClusterLock;
+    use crate::config::Jobfile;
+    use crate::observer::MissReason;
+    use std::os::unix::process::CommandExt;
+
+    // Claims every occurrence or none, recording the keys asked for
+    struct StubLock {
+        claims: bool,
+        keys: Mutex<Vec<String>>,
+    }
+
+    impl ClusterLock for StubLock {
+        fn try_acquire(&self, key: &str, _ttl: time::Duration) -> io::Result<bool> {
+            self.keys.lock().unwrap().push(key.to_string());
+            Ok(self.claims)
+        }
+    }
+
+    // Claims every occurrence, reloading the jobs first as if a reload
+    // happened while claiming
+    struct ReloadingLock {
+        shared: Mutex<Option<(Arc<Shared>, Jobfile)>>,
+    }
+
+    impl ClusterLock for ReloadingLock {
+        fn try_acquire(&self, _key: &str, _ttl: time::Duration) -> io::Result<bool> {
+            if let Some((shared, config)) = self.shared.lock().unwrap().take() {
+                shared.configure(config);
+            }
+            Ok(true)
+        }
+    }
+
+    #[derive(Default)]
+    struct Misses(Mutex<Vec<MissReason>>);
+
+    impl SchedulerObserver for Misses {
+        fn job_missed(&self, _job: &JobInfo, reason: MissReason) {
+            self.0.lock().unwrap().push(reason);
+        }
+    }
+
+    #[test]
+    fn runs_the_occurrences_claimed_in_the_cluster() {
+        for claims in [true, false] {
+            let lock = Arc::new(StubLock {
+                claims,
+                keys: Mutex::new(Vec::new()),
+            });
+            let misses = Arc::new(Misses::default());
+            let mut cron = Cron::builder().cluster_lock(lock.clone()).observer(misses.clone()).build().unwrap();
+            let id = cron.add_job(JobSpec::new("a", "/bin/true", "0 0 * * * *").with_singleton_cluster()).unwrap();
+            let j = cron.shared.lock().jobs[&id].clone();
+
+            let state = cron.spawn(cron.shared.lock(), &j, Trigger::Scheduled, 1, 0, None);
+            let pids: Vec<i32> = state.children.keys().cloned().collect();
+            drop(state);
+            for pid in &pids {
+                nix::sys::wait::waitpid(Pid::from_raw(*pid), None).unwrap();
+            }
+            let key = format!("xcrond:a:{}", j.get_next().timestamp());
+            assert_eq!(*lock.keys.lock().unwrap(), vec![key]);
+            if claims {
+                assert_eq!(pids.len(), 1);
+                assert!(misses.0.lock().unwrap().is_empty());
+            } else {
+                assert!(pids.is_empty());
+                assert_eq!(*misses.0.lock().unwrap(), vec![MissReason::ClusterLockHeld]);
+            }
+        }
+    }
+
+    #[test]
+    fn skips_the_occurrences_replaced_while_claiming() {
+        let config = |cmd: &str| Jobfile {
+            namespace: vec![],
+            job: vec![JobSpec::new("a", cmd, "0 0 * * * *").with_singleton_cluster()],
+            blackout: vec![],
+            mailto: None,
+            shell: None,
+            path: None,
+            env: Default::default(),
+        };
+        let lock = Arc::new(ReloadingLock {
+            shared: Mutex::new(None),
+        });
+        let cron = Cron::builder().cluster_lock(lock.clone()).build().unwrap();
+        cron.shared.configure(config("/bin/true"));
+        let j = cron.shared.lock().jobs.values().next().unwrap().clone();
+        *lock.shared.lock().unwrap() = Some((cron.shared.clone(), config("/bin/false")));
+
+        let state = cron.spawn(cron.shared.lock(), &j, Trigger::Scheduled, 1, 0, None);
+        assert!(state.children.is_empty());
+        assert!(!state.is_current(&j));
+        assert!(state.is_queued(j.get_id()));
+    }
+
+    #[test]
+    fn joins_the_cgroup_before_switching_user() {
+        // Only root can switch to another user
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("xcrond-test-{}.cgroup-user", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let limits = CgroupLimits {
+            pids_max: Some(10),
+            ..Default::default()
+        };
+        let spec = JobSpec::new("a", "id -u", "0 0 * * * *")
+            .with_shell("/bin/sh")
+            .with_user("nobody", None)
+            .with_cgroup(limits);
+        let j = Job::from_spec(JobId::new(1), spec, None, chrono::Local::now()).unwrap();
+
+        // Steps as spawn takes them. cgroupfs checks that the writer of
+        // cgroup.procs may, the stand-in that it's still root.
+        let (mut cmd, switch) = j.command_as().unwrap();
+        let cg = Cgroup::create(&dir, j.get_name(), RunId::new(1), j.get_cgroup()).unwrap();
+        std::fs::write(cg.path.join("cgroup.procs"), "").unwrap();
+        cg.enter(&mut cmd).unwrap();
+        unsafe {
+            cmd.pre_exec(|| match libc::geteuid() {
+                0 => Ok(()),
+                _ => Err(io::Error::from_raw_os_error(libc::EACCES)),
+            });
+        }
+        switch.unwrap().apply(&mut cmd);
+        let out = cmd.output().unwrap();
+        let nobody = login::lookup("nobody").unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), nobody.uid.to_string());
+        assert_eq!(std::fs::read_to_string(cg.path.join("cgroup.procs")).unwrap(), "0");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! Resource limits of the runs of jobs.
+//!
+//! Limits are set with rlimits between fork and exec, so a runaway run is
+//! stopped by the kernel instead of exhausting the host: it gets SIGXCPU
+//! once its CPU time is used up, allocations beyond its memory fail, and so
+//! do opening files beyond its open files and writing files beyond its file
+//! size. Limits of a job and of its namespace both apply, the lowest wins.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// Limits are the resources each run may use
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// CPU time, in seconds. The process gets SIGXCPU once it's used up,
+    /// and SIGKILL a second later.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_time: Option<u64>,
+    /// address space, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<u64>,
+    /// number of open file descriptors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_files: Option<u64>,
+    /// size of the files written, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<u64>,
+}
+
+impl Limits {
+    /// is_empty returns true if no resource is limited
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    /// lowest returns the lowest of both limits for every resource
+    pub fn lowest(&self, other: &Limits) -> Limits {
+        let min = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
+        Limits {
+            cpu_time: min(self.cpu_time, other.cpu_time),
+            memory: min(self.memory, other.memory),
+            open_files: min(self.open_files, other.open_files),
+            file_size: min(self.file_size, other.file_size),
+        }
+    }
+
+    /// apply limits the resources of the process run by `cmd`
+    pub(crate) fn apply(&self, cmd: &mut Command) {
+        if self.is_empty() {
+            return;
+        }
+        let limits = *self;
+        unsafe {
+            // Only async signal safe calls between fork and exec
+            cmd.pre_exec(move || {
+                if let Some(secs) = limits.cpu_time {
+                    // The hard limit is where SIGKILL is sent
+                    setrlimit(libc::RLIMIT_CPU, secs, secs.saturating_add(1))?;
+                }
+                if let Some(bytes) = limits.memory {
+                    setrlimit(libc::RLIMIT_AS, bytes, bytes)?;
+                }
+                if let Some(n) = limits.open_files {
+                    setrlimit(libc::RLIMIT_NOFILE, n, n)?;
+                }
+                if let Some(bytes) = limits.file_size {
+                    setrlimit(libc::RLIMIT_FSIZE, bytes, bytes)?;
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+// glibc has its own type for the resources
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type Resource = libc::c_int;
+
+/// setrlimit lowers the limit of the resource, the hard limit can't be raised
+fn setrlimit(resource: Resource, soft: u64, hard: u64) -> io::Result<()> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(resource, &mut limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let max = limit.rlim_max;
+    limit.rlim_cur = std::cmp::min(soft as libc::rlim_t, max);
+    limit.rlim_max = std::cmp::min(hard as libc::rlim_t, max);
+    if unsafe { libc::setrlimit(resource, &limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_the_resources_of_runs() {
+        let job = Limits {
+            open_files: Some(64),
+            file_size: Some(4096),
+            ..Default::default()
+        };
+        let namespace = Limits {
+            open_files: Some(32),
+            memory: Some(1 << 30),
+            ..Default::default()
+        };
+        let limits = job.lowest(&namespace);
+        assert_eq!(limits.memory, Some(1 << 30));
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "ulimit -n; ulimit -f"].iter());
+        limits.apply(&mut cmd);
+        let out = cmd.output().unwrap();
+        // ulimit -f counts blocks of 512 bytes
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "32\n8\n");
+    }
+}
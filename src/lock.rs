@@ -0,0 +1,48 @@
+//! Lock files held by job processes for the duration of a run.
+//!
+//! The lock is taken with flock by the daemon and its file descriptor is
+//! inherited by the job's process, so the lock is held for as long as the
+//! process runs, even if the daemon itself is restarted in the meantime.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+/// try_lock takes an exclusive lock on the file at `path`, creating it if needed.
+/// Returns None if the lock is already held.
+pub(crate) fn try_lock(path: &Path) -> io::Result<Option<File>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(file))
+}
+
+/// inherit makes the locked file descriptor survive the exec of `cmd`,
+/// so the spawned process keeps holding the lock
+pub(crate) fn inherit(cmd: &mut Command, lock: &File) {
+    let fd = lock.as_raw_fd();
+    unsafe {
+        // Only async signal safe calls between fork and exec
+        cmd.pre_exec(move || {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
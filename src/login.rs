@@ -0,0 +1,217 @@
+//! Login shell emulation and accounts of the users jobs run as.
+//!
+//! Jobs normally run with the daemon's environment, which often differs from
+//! the one users see in their terminal. Jobs with `login_shell = true` run in
+//! the login shell of their user with a fresh environment instead, as
+//! `su -l` does: the shell sources the profile, setting PATH and the other
+//! variables as for an interactive session.
+//!
+//! Jobs run as the daemon's user unless they set another one, e.g. when a
+//! daemon started as root runs the jobs of unprivileged users. The child
+//! switches to the user and its groups between fork and exec, after the
+//! other steps taking the daemon's privileges, e.g. joining a cgroup.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::ptr;
+
+// PATH the profile starts from, as set by login(1)
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Account is the passwd entry of a user
+pub(crate) struct Account {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub home: String,
+    pub shell: String,
+}
+
+/// login_command builds the command running `cmd` in the login shell of
+/// `account`, the daemon's user if None
+pub(crate) fn login_command(cmd: &str, account: Option<&Account>) -> Command {
+    let own;
+    let account = match account {
+        Some(a) => a,
+        None => {
+            own = current().unwrap_or_else(|err| {
+                warn!("Failed to look up the current user, using the environment: {}", err);
+                Account {
+                    name: std::env::var("USER").unwrap_or_default(),
+                    uid: unsafe { libc::geteuid() },
+                    gid: unsafe { libc::getegid() },
+                    home: std::env::var("HOME").unwrap_or_else(|_| "/".to_string()),
+                    shell: std::env::var("SHELL").unwrap_or_default(),
+                }
+            });
+            &own
+        }
+    };
+    let shell = if account.shell.is_empty() {
+        "/bin/sh"
+    } else {
+        account.shell.as_str()
+    };
+
+    let mut c = Command::new(shell);
+    c.args(["-l", "-c", cmd].iter())
+        .env_clear()
+        .env("HOME", &account.home)
+        .env("SHELL", shell)
+        .env("USER", &account.name)
+        .env("LOGNAME", &account.name)
+        .env("PATH", DEFAULT_PATH)
+        .current_dir(&account.home);
+    c
+}
+
+/// Switch is the switch of a process to another user and group
+pub(crate) struct Switch {
+    uid: Option<libc::uid_t>,
+    gid: libc::gid_t,
+    groups: Option<Vec<libc::gid_t>>,
+}
+
+/// run_as sets up the environment of the command for the user of `account`
+/// and returns the switch making it run as that user with its groups, and
+/// with `gid` as its group if given. Either can be None to keep the
+/// daemon's, there's no switch to make if both are. Switching to another
+/// user requires the privileges to.
+pub(crate) fn run_as(cmd: &mut Command, account: Option<&Account>, gid: Option<libc::gid_t>) -> io::Result<Option<Switch>> {
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let (uid, gid, groups) = match account {
+        Some(a) if a.uid == euid && gid.is_none_or(|g| g == egid) => return Ok(None),
+        Some(a) => {
+            let gid = gid.unwrap_or(a.gid);
+            (Some(a.uid), gid, Some(group_list(&a.name, gid)?))
+        }
+        None if gid == Some(egid) => return Ok(None),
+        None => match gid {
+            Some(gid) => (None, gid, None),
+            None => return Ok(None),
+        },
+    };
+    if let Some(a) = account {
+        cmd.env("HOME", &a.home).env("USER", &a.name).env("LOGNAME", &a.name);
+    }
+    Ok(Some(Switch { uid, gid, groups }))
+}
+
+impl Switch {
+    /// apply makes the process run by `cmd` switch before exec. It has to
+    /// be the last step added, those after it run without the daemon's
+    /// privileges.
+    pub(crate) fn apply(self, cmd: &mut Command) {
+        let Switch { uid, gid, groups } = self;
+        unsafe {
+            // Only async signal safe calls between fork and exec. The groups
+            // go first, they can't be changed once the user is.
+            cmd.pre_exec(move || {
+                if let Some(groups) = &groups {
+                    check(libc::setgroups(groups.len() as _, groups.as_ptr()))?;
+                }
+                check(libc::setgid(gid))?;
+                if let Some(uid) = uid {
+                    check(libc::setuid(uid))?;
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// current returns the account of the effective user
+pub(crate) fn current() -> io::Result<Account> {
+    let uid = unsafe { libc::geteuid() };
+    passwd(|pwd, buf, res| unsafe { libc::getpwuid_r(uid, pwd, buf.as_mut_ptr(), buf.len(), res) })
+}
+
+/// lookup returns the account of the user named `name`, or of the given
+/// uid if it's a number
+pub(crate) fn lookup(name: &str) -> io::Result<Account> {
+    if let Ok(uid) = name.parse::<libc::uid_t>() {
+        return passwd(|pwd, buf, res| unsafe { libc::getpwuid_r(uid, pwd, buf.as_mut_ptr(), buf.len(), res) });
+    }
+    let name = CString::new(name)?;
+    passwd(|pwd, buf, res| unsafe { libc::getpwnam_r(name.as_ptr(), pwd, buf.as_mut_ptr(), buf.len(), res) })
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => io::Error::new(err.kind(), format!("no user {}", name.to_string_lossy())),
+            _ => err,
+        })
+}
+
+/// group_id returns the gid of the group named `name`, or the given gid if
+/// it's a number
+pub(crate) fn group_id(name: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let cname = CString::new(name)?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut grp: libc::group = unsafe { mem::zeroed() };
+        let mut res = ptr::null_mut();
+        let ret = unsafe { libc::getgrnam_r(cname.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut res) };
+        match ret {
+            0 if res.is_null() => return Err(io::Error::new(io::ErrorKind::NotFound, format!("no group {}", name))),
+            0 => return Ok(grp.gr_gid),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+/// passwd calls the getpw*_r function `get` until its buffer is large
+/// enough, returning the account found
+fn passwd<F>(mut get: F) -> io::Result<Account>
+where
+    F: FnMut(&mut libc::passwd, &mut Vec<libc::c_char>, &mut *mut libc::passwd) -> libc::c_int,
+{
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut res = ptr::null_mut();
+        match get(&mut pwd, &mut buf, &mut res) {
+            0 if res.is_null() => return Err(io::Error::new(io::ErrorKind::NotFound, "no such user")),
+            0 => {
+                let s = |p: *const libc::c_char| unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned();
+                return Ok(Account {
+                    name: s(pwd.pw_name),
+                    uid: pwd.pw_uid,
+                    gid: pwd.pw_gid,
+                    home: s(pwd.pw_dir),
+                    shell: s(pwd.pw_shell),
+                });
+            }
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+/// group_list returns the groups of the user named `name`, with `gid`
+fn group_list(name: &str, gid: libc::gid_t) -> io::Result<Vec<libc::gid_t>> {
+    let name = CString::new(name)?;
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut n = groups.len() as libc::c_int;
+        let ret = unsafe { libc::getgrouplist(name.as_ptr(), gid as _, groups.as_mut_ptr() as *mut _, &mut n) };
+        if ret >= 0 {
+            groups.truncate(n as usize);
+            return Ok(groups);
+        }
+        // n is set to the number of groups when the list is too short
+        let len = std::cmp::max(n as usize, groups.len() * 2);
+        groups.resize(len, 0);
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
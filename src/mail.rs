@@ -0,0 +1,292 @@
+//! Mailing the output of runs, as cron does with `MAILTO`.
+//!
+//! The output of the runs of jobs with a mail address, their own or the
+//! one of the Jobfile, is captured and mailed once the run is over, if it
+//! printed something or failed. The output is taken from the log of the
+//! run if the daemon logs the output of runs, else from a file of the
+//! temporary directory only readable by the daemon's user, removed once
+//! mailed. Mails are handed to sendmail, or to an SMTP relay.
+
+use crate::cluster::hostname;
+use crate::login;
+use crate::run::{RunId, RunStatus};
+use chrono::Local;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Default sendmail binary
+pub const SENDMAIL: &str = "/usr/sbin/sendmail";
+
+// Longest output mailed, the rest is cut off
+const MAX_OUTPUT: u64 = 1024 * 1024;
+// Timeout of the exchanges with SMTP relays
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Mailer is how the output of runs is mailed
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Mailer {
+    /// piped to the given sendmail binary
+    Sendmail(PathBuf),
+    /// sent to the SMTP relay at the given address, e.g. `localhost:25`,
+    /// without authentication or TLS
+    Smtp(String),
+}
+
+impl Default for Mailer {
+    fn default() -> Self {
+        Mailer::Sendmail(PathBuf::from(SENDMAIL))
+    }
+}
+
+/// Capture is the file capturing the output of a run to be mailed
+pub(crate) struct Capture {
+    pub path: PathBuf,
+    /// where the output of the run starts in the file
+    pub offset: u64,
+    /// set if the file was created for the run, removed once mailed
+    pub temporary: bool,
+    /// addresses to mail the output to, separated by commas
+    pub to: String,
+}
+
+impl Capture {
+    /// create creates the file capturing the output of the run
+    pub fn create(run: RunId, to: &str) -> io::Result<(Self, File)> {
+        let path = env::temp_dir().join(format!("xcrond-{}.mail", run.as_u64()));
+        let f = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        let capture = Capture {
+            path,
+            offset: 0,
+            temporary: true,
+            to: to.to_string(),
+        };
+        Ok((capture, f))
+    }
+
+    /// log captures the output written to the log of the run from now on
+    pub fn log(path: &Path, f: &File, to: &str) -> io::Result<Self> {
+        Ok(Capture {
+            path: path.to_path_buf(),
+            offset: f.metadata()?.len(),
+            temporary: false,
+            to: to.to_string(),
+        })
+    }
+
+    /// remove deletes the file if it was created for the run
+    pub fn remove(&self) {
+        if self.temporary {
+            if let Err(err) = fs::remove_file(&self.path) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to remove {}: {}", self.path.display(), err);
+                }
+            }
+        }
+    }
+}
+
+/// send mails the output of the run of the job named `name` in the
+/// background, if it printed something or failed
+pub(crate) fn send(mailer: Mailer, capture: Capture, name: String, status: RunStatus) {
+    thread::spawn(move || {
+        let output = read(&capture);
+        capture.remove();
+        let output = match output {
+            Ok(o) => o,
+            Err(err) => {
+                error!("[{}] Failed to read the output to mail from {}: {}", name, capture.path.display(), err);
+                return;
+            }
+        };
+        if output.is_empty() && status.success() {
+            return;
+        }
+
+        let from = format!(
+            "{}@{}",
+            login::current().map_or_else(|_| "xcrond".to_string(), |a| a.name),
+            hostname()
+        );
+        let to: Vec<&str> = capture.to.split(',').map(str::trim).filter(|a| !a.is_empty()).collect();
+        let message = message(&from, &to, &name, &status, &output);
+        let sent = match &mailer {
+            Mailer::Sendmail(path) => sendmail(path, &message),
+            Mailer::Smtp(addr) => smtp(addr, &from, &to, &message),
+        };
+        match sent {
+            Ok(()) => info!("[{}] Mailed its output to {}", name, to.join(", ")),
+            Err(err) => error!("[{}] Failed to mail its output to {}: {}", name, to.join(", "), err),
+        }
+    });
+}
+
+/// read returns the output of the run, cut off after `MAX_OUTPUT` bytes
+fn read(capture: &Capture) -> io::Result<String> {
+    let mut f = File::open(&capture.path)?;
+    f.seek(SeekFrom::Start(capture.offset))?;
+    let mut buf = vec![];
+    let n = f.take(MAX_OUTPUT + 1).read_to_end(&mut buf)?;
+    let mut output = String::from_utf8_lossy(&buf[..std::cmp::min(n, MAX_OUTPUT as usize)]).into_owned();
+    if n as u64 > MAX_OUTPUT {
+        output.push_str("\n[output cut off]\n");
+    }
+    Ok(output)
+}
+
+/// message formats the mail of the output of a run
+fn message(from: &str, to: &[&str], name: &str, status: &RunStatus, output: &str) -> String {
+    let outcome = match status {
+        RunStatus::Exited(0) => "succeeded".to_string(),
+        RunStatus::Exited(code) => format!("exited with {}", code),
+        RunStatus::Signaled(signal) => format!("killed by {}", signal),
+        RunStatus::FailedToStart(reason) => format!("failed to start: {}", reason),
+    };
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: [xcrond] {}: {}\r\nDate: {}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\r\n",
+        from,
+        to.join(", "),
+        name,
+        outcome,
+        Local::now().to_rfc2822()
+    );
+    for line in output.lines() {
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message
+}
+
+/// sendmail hands the message to the sendmail binary at `path`, reading the
+/// recipients from its headers
+fn sendmail(path: &Path, message: &str) -> io::Result<()> {
+    let mut child = Command::new(path)
+        .args(["-t", "-oi"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(message.as_bytes())?;
+    }
+    match child.wait()? {
+        status if status.success() => Ok(()),
+        status => Err(io::Error::other(format!("{} {}", path.display(), status))),
+    }
+}
+
+/// smtp sends the message through the SMTP relay at `addr`
+fn smtp(addr: &str, from: &str, to: &[&str], message: &str) -> io::Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(SMTP_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    expect(&mut reader, 220)?;
+    command(&mut writer, &mut reader, &format!("HELO {}", hostname()), 250)?;
+    command(&mut writer, &mut reader, &format!("MAIL FROM:<{}>", from), 250)?;
+    for rcpt in to {
+        command(&mut writer, &mut reader, &format!("RCPT TO:<{}>", rcpt), 250)?;
+    }
+    command(&mut writer, &mut reader, "DATA", 354)?;
+    // Lines starting with a dot are escaped, a lone dot ends the message
+    let mut data = String::new();
+    for line in message.split("\r\n") {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push('.');
+    command(&mut writer, &mut reader, &data, 250)?;
+    command(&mut writer, &mut reader, "QUIT", 221)
+}
+
+/// command sends a command to the SMTP relay and checks its reply
+fn command<R: BufRead>(writer: &mut TcpStream, reader: &mut R, line: &str, code: u16) -> io::Result<()> {
+    writer.write_all(line.as_bytes())?;
+    writer.write_all(b"\r\n")?;
+    expect(reader, code)
+}
+
+/// expect reads a reply of the SMTP relay, failing unless it has `code`
+fn expect<R: BufRead>(reader: &mut R, code: u16) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the relay"));
+        }
+        // Lines of multiline replies but the last have a dash after the code
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        return match line.get(..3).and_then(|c| c.parse::<u16>().ok()) {
+            Some(c) if c == code => Ok(()),
+            _ => Err(io::Error::other(format!("unexpected reply: {}", line.trim_end()))),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn sends_through_smtp_relays() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let relay = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut received = vec![];
+            let mut reply = |code: &str| writer.write_all(format!("{}\r\n", code).as_bytes()).unwrap();
+            reply("220-relay\r\n220 ready");
+            let mut data = false;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_string();
+                match line.as_str() {
+                    "." => {
+                        data = false;
+                        reply("250 queued");
+                    }
+                    _ if data => {}
+                    "DATA" => {
+                        data = true;
+                        reply("354 go ahead");
+                    }
+                    "QUIT" => {
+                        reply("221 bye");
+                        received.push(line);
+                        return received;
+                    }
+                    _ => reply("250 ok"),
+                }
+                received.push(line);
+            }
+        });
+
+        let message = message("me@host", &["ops@example.com"], "backup", &RunStatus::Exited(1), ".hidden\nout\n");
+        assert!(message.contains("Subject: [xcrond] backup: exited with 1\r\n"));
+        smtp(&addr, "me@host", &["ops@example.com"], &message).unwrap();
+        let received = relay.join().unwrap();
+        assert!(received.contains(&"MAIL FROM:<me@host>".to_string()));
+        assert!(received.contains(&"RCPT TO:<ops@example.com>".to_string()));
+        assert!(received.contains(&"..hidden".to_string()));
+    }
+}
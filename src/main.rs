@@ -1,14 +1,973 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use clap::{Args, Parser, Subcommand};
+use log::{error, info, warn};
+use nix::sys::signal::{SigSet, Signal};
+use serde::Serialize;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use xcrond::access::{self, Access};
+use xcrond::bench::BenchOptions;
+use xcrond::cluster::{ClusterLock, FileLock, LeaderElection, Membership, RedisLock};
+use xcrond::daemonize::{daemonize, redirect_logs};
+use xcrond::handoff::Handoff;
+use xcrond::history::{History, Retention};
+use xcrond::ipc::{self, ControlSocket};
+use xcrond::pidfile::PidFile;
+use xcrond::privileges::{check_writable, drop_privileges};
+use xcrond::spool::{self, Spool};
+use xcrond::systemd;
 use xcrond::*;
 
+// How often the daemon prunes the run history
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// How often the status reported to systemd is refreshed
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+// Name the PID file is handed over under when upgrading
+const PID_FILE_FD: &str = "pid_file";
+// Name the control socket is handed over under when upgrading
+const CONTROL_SOCKET_FD: &str = "control_socket";
+// How often the spool is checked for changes, as cron does
+const SPOOL_INTERVAL: Duration = Duration::from_secs(60);
+// Environment variable giving the Jobfile when --jobfile isn't
+const JOBFILE_ENV: &str = "XCROND_JOBFILE";
+
+/// A cron server written in rust.
+///
+/// Send SIGUSR2 to upgrade the daemon in place: it execs the binary it was
+/// started from, which takes over the running jobs. Not supported with
+/// --user, the new binary couldn't run jobs as other users. The Jobfile and
+/// its fragments are reloaded when they change, or on SIGHUP.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    sources: Sources,
+
+    /// Check the Jobfile, its fragments and the crontabs, print the jobs
+    /// with when they run next and exit. Exits with 1 if any is invalid
+    #[arg(long, conflicts_with = "daemon")]
+    check: bool,
+
+    /// Detach from the terminal and run in the background
+    #[arg(long)]
+    daemon: bool,
+
+    /// Append the logs to this file instead of stdout. Logs are discarded
+    /// when running with --daemon and no log file
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Write the daemon's pid to this file, refusing to start if another
+    /// instance holds it
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+
+    /// Terminate the instance holding the PID file and take over
+    #[arg(long, requires = "pid_file")]
+    replace: bool,
+
+    /// Seconds given to running jobs to exit after SIGTERM when shutting
+    /// down, before they are killed
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    shutdown_grace: u64,
+
+    /// Seconds to wait when shutting down for jobs with
+    /// `shutdown_policy = 'wait'` to finish, before terminating them
+    #[arg(long, value_name = "SECS")]
+    shutdown_max_wait: Option<u64>,
+
+    /// Switch to this user once the PID file, logs, history, Jobfile and
+    /// journal are open. Requires starting as root. The directories of the
+    /// history and the journal, and --job-log-dir and --cgroup-dir, have to
+    /// be writable by the user. Jobs setting a user still run as theirs.
+    /// The daemon can't be upgraded with SIGUSR2 then, only restarted
+    #[arg(long, value_name = "NAME")]
+    user: Option<String>,
+
+    /// Write the output of every run to a file of this directory, e.g.
+    /// DIR/backup/20300101T020000.log, rather than to the daemon's output
+    #[arg(long, value_name = "DIR")]
+    job_log_dir: Option<PathBuf>,
+
+    /// Run the runs of jobs with cgroup limits in cgroups of their own
+    /// created in this cgroup v2 directory, e.g. /sys/fs/cgroup/xcrond
+    #[arg(long, value_name = "DIR")]
+    cgroup_dir: Option<PathBuf>,
+
+    /// Journal the runs of jobs with `journal = true` to this file, to know
+    /// which of their occurrences ran after a crash
+    #[arg(long, value_name = "PATH")]
+    journal: Option<PathBuf>,
+
+    /// Mail the output of runs through the SMTP relay at this address, e.g.
+    /// localhost:25, rather than sendmail. See `mailto` in the Jobfile
+    #[arg(long, value_name = "HOST:PORT")]
+    smtp: Option<String>,
+
+    /// Where occurrences are claimed by the hosts of the cluster: a Redis
+    /// server, e.g. redis://host:6379/0, or a directory on a shared
+    /// filesystem, e.g. file:///mnt/shared/xcrond. Jobs with
+    /// `singleton_cluster = true` only run on the host claiming their
+    /// occurrence first
+    #[arg(long, value_name = "URL")]
+    cluster_lock: Option<String>,
+
+    /// Only run jobs while elected leader among the instances sharing this
+    /// Redis server or shared directory, given like --cluster-lock. Standby
+    /// instances take over within seconds when the leader goes away
+    #[arg(long, value_name = "URL")]
+    leader_election: Option<String>,
+
+    /// Name of the lease instances of the same config compete for
+    #[arg(long, value_name = "NAME", default_value = "xcrond:leader", requires = "leader_election")]
+    leader_key: String,
+
+    /// Launch at most this many jobs per second, delaying the others
+    #[arg(long, value_name = "N")]
+    max_start_rate: Option<u32>,
+
+    /// Serve the state of the jobs to standby instances on this address
+    #[arg(long, value_name = "ADDR")]
+    replicate: Option<String>,
+
+    /// Follow the state of the primary instance at this address, and only
+    /// take over scheduling if it stops responding
+    #[arg(long, value_name = "ADDR")]
+    standby_of: Option<String>,
+
+    /// Seconds without hearing from the primary before taking over
+    #[arg(long, value_name = "SECS", default_value_t = 10, requires = "standby_of")]
+    failover_after: u64,
+
+    /// Split the jobs between the instances registered in this Redis server
+    /// or shared directory, given like --cluster-lock. Jobs are rebalanced
+    /// as instances join or leave
+    #[arg(long, value_name = "URL")]
+    sharding: Option<String>,
+
+    /// Name of the group instances sharing their jobs register in
+    #[arg(long, value_name = "NAME", default_value = "xcrond:shard", requires = "sharding")]
+    shard_group: String,
+
+    /// Record every run in the SQLite database at this path
+    #[arg(long, value_name = "PATH")]
+    history: Option<PathBuf>,
+
+    #[command(flatten)]
+    retention: RetentionArgs,
+
+    /// Run the jobs of the system cron's crontabs too, following their
+    /// changes: the users' crontabs and those of /etc/cron.d
+    #[arg(long)]
+    spool: bool,
+
+    /// Directory of the users' crontabs
+    #[arg(long, value_name = "DIR", default_value = spool::CRONTABS_DIR, requires = "spool")]
+    crontabs_dir: PathBuf,
+
+    /// Directory of the system crontabs
+    #[arg(long, value_name = "DIR", default_value = spool::CRON_D_DIR, requires = "spool")]
+    cron_d_dir: PathBuf,
+
+    #[command(flatten)]
+    access: AccessArgs,
+
+    /// Don't reload the Jobfile and its fragments when they change, only
+    /// on SIGHUP
+    #[arg(long)]
+    no_watch: bool,
+
+    /// Listen for requests inspecting and controlling the daemon on this
+    /// Unix socket, e.g. to list the jobs or run one now
+    #[arg(long, value_name = "PATH", default_value = ipc::SOCKET)]
+    control_socket: PathBuf,
+
+    /// Don't listen on the control socket
+    #[arg(long, conflicts_with = "control_socket")]
+    no_control_socket: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect and maintain the run history
+    #[command(subcommand)]
+    History(HistoryCommand),
+
+    /// Print when the jobs would run, without running them nor waiting
+    Simulate {
+        /// Number of hours to simulate, from now
+        #[arg(long, value_name = "N", default_value_t = 24)]
+        hours: u32,
+
+        /// Stop after this number of runs
+        #[arg(long, value_name = "N", default_value_t = 1000)]
+        limit: usize,
+    },
+
+    /// Convert jobs defined for other schedulers to Jobfile entries, printed
+    /// on stdout
+    #[command(subcommand)]
+    Import(ImportCommand),
+
+    /// Convert jobs to unit files of other schedulers
+    #[command(subcommand)]
+    Export(ExportCommand),
+
+    /// Measure the performance of the scheduler on synthetic jobs, run
+    /// against a virtual clock
+    Bench {
+        /// Number of jobs
+        #[arg(long, value_name = "N", default_value_t = 100_000)]
+        jobs: usize,
+
+        /// Number of seconds of the minute the jobs are spread over, from 1
+        /// (all due at once) to 60
+        #[arg(long, value_name = "SECS", default_value_t = 60)]
+        spread: u32,
+
+        /// Minutes of virtual time to run the scheduler for
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        minutes: u64,
+    },
+
+    /// Print when each job runs over a past or future date range
+    Replay {
+        /// Start of the range, as `YYYY-MM-DD`, `YYYY-MM-DD HH:MM[:SS]` or
+        /// RFC 3339, in local time unless an offset is given
+        #[arg(long, value_name = "TIME", value_parser = parse_time)]
+        from: DateTime<Local>,
+
+        /// End of the range, included, in the same formats
+        #[arg(long, value_name = "TIME", value_parser = parse_time)]
+        to: DateTime<Local>,
+
+        /// Stop after this number of runs
+        #[arg(long, value_name = "N", default_value_t = 100_000)]
+        limit: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum HistoryCommand {
+    /// Delete the runs exceeding the retention limits
+    Prune {
+        /// Path of the history database
+        #[arg(long, value_name = "PATH")]
+        db: PathBuf,
+
+        #[command(flatten)]
+        retention: RetentionArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum ImportCommand {
+    /// Convert systemd timer units, with the services they activate
+    Systemd {
+        /// Paths of the `.timer` units
+        #[arg(value_name = "UNIT", required = true)]
+        units: Vec<PathBuf>,
+    },
+
+    /// Convert launchd property lists with a StartCalendarInterval or a
+    /// StartInterval
+    Launchd {
+        /// Paths of the `.plist` files, in XML format
+        #[arg(value_name = "PLIST", required = true)]
+        plists: Vec<PathBuf>,
+    },
+
+    /// Convert the entries of a crontab, the current user's by default
+    Crontab {
+        /// Read the crontab of this user, as listed by `crontab -l -u`
+        #[arg(long, value_name = "NAME", conflicts_with = "file")]
+        user: Option<String>,
+
+        /// Read the crontab at this path, e.g. a spool file
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+
+        /// Write the jobs to this file instead of stdout
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        access: AccessArgs,
+    },
+}
+
+/// Which users may have jobs, as with cron.allow and cron.deny
+#[derive(Args)]
+struct AccessArgs {
+    /// Only the users listed in this file may have jobs, if it exists
+    #[arg(long, value_name = "PATH", default_value = access::ALLOW_FILE)]
+    allow_file: PathBuf,
+
+    /// The users listed in this file may not have jobs, if it exists and
+    /// there is no allow file
+    #[arg(long, value_name = "PATH", default_value = access::DENY_FILE)]
+    deny_file: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum ExportCommand {
+    /// Write a systemd timer unit and the service it activates for each job
+    Systemd {
+        /// Directory to write the units to
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+
+        /// Names of the jobs to export, all of them if none is given
+        #[arg(value_name = "JOB")]
+        jobs: Vec<String>,
+    },
+
+    /// Print the jobs as a crontab. Jobs relying on features cron doesn't
+    /// have are commented out.
+    Crontab,
+}
+
+/// Files defining the jobs
+#[derive(Args, Clone)]
+struct Sources {
+    /// Jobfile defining the jobs, XCROND_JOBFILE if not given
+    #[arg(long, global = true, value_name = "PATH")]
+    jobfile: Option<PathBuf>,
+
+    /// Load the jobs of the Jobfile fragments of this directory too, its
+    /// `*.toml` files, e.g. /etc/xcrond.d
+    #[arg(long, global = true, value_name = "DIR")]
+    jobfile_dir: Option<PathBuf>,
+
+    /// Run the jobs of this crontab too, written in the crontab(5) syntax.
+    /// Can be given several times
+    #[arg(long, global = true, value_name = "PATH")]
+    crontab: Vec<PathBuf>,
+}
+
+impl Sources {
+    /// is_empty tells whether no file defining jobs was given
+    fn is_empty(&self) -> bool {
+        self.jobfile.is_none() && self.jobfile_dir.is_none() && self.crontab.is_empty()
+    }
+
+    /// builder returns a builder of a cron instance running the jobs of
+    /// the Jobfile, its fragments and the crontabs
+    fn builder(&self) -> Result<CronBuilder> {
+        let mut builder = Cron::builder();
+        if let Some(path) = &self.jobfile {
+            builder = builder.config_path(path);
+        }
+        if let Some(dir) = &self.jobfile_dir {
+            builder = builder.config_dir(dir);
+        }
+        for path in &self.crontab {
+            let (source, content) = read_crontab(path)?;
+            let jobs = xcrond::import::crontab(&content, &source);
+            info!("Loaded {} jobs from {}", jobs.len(), path.display());
+            builder = jobs.into_iter().fold(builder, |b, spec| b.job(spec));
+        }
+        Ok(builder)
+    }
+}
+
+/// Retention limits of the run history
+#[derive(Args)]
+struct RetentionArgs {
+    /// Delete runs older than this number of days
+    #[arg(long, value_name = "DAYS")]
+    max_age_days: Option<u64>,
+
+    /// Keep at most this number of runs for each job
+    #[arg(long, value_name = "N")]
+    max_runs_per_job: Option<usize>,
+}
+
+impl From<&RetentionArgs> for Retention {
+    fn from(args: &RetentionArgs) -> Self {
+        Retention {
+            max_age: args.max_age_days.map(|d| Duration::from_secs(d * 24 * 60 * 60)),
+            max_runs: args.max_runs_per_job,
+        }
+    }
+}
+
 fn main() {
+    let mut cli = Cli::parse();
+    if cli.sources.jobfile.is_none() {
+        cli.sources.jobfile = std::env::var_os(JOBFILE_ENV).map(PathBuf::from);
+    }
+    let sources = cli.sources.clone();
+    let res = match &cli.command {
+        None if cli.check => check(&sources),
+        None => run(&mut cli),
+        Some(Command::History(HistoryCommand::Prune { db, retention })) => prune(db, retention),
+        Some(Command::Simulate { hours, limit }) => simulate(&sources, *hours, *limit),
+        Some(Command::Replay { from, to, limit }) => replay(&sources, *from, *to, *limit),
+        Some(Command::Import(ImportCommand::Systemd { units })) => import_systemd(units),
+        Some(Command::Import(ImportCommand::Launchd { plists })) => import_launchd(plists),
+        Some(Command::Import(ImportCommand::Crontab {
+            user,
+            file,
+            output,
+            access,
+        })) => import_crontab(user.as_deref(), file.as_deref(), output.as_deref(), access),
+        Some(Command::Export(ExportCommand::Systemd { dir, jobs })) => export_systemd(dir, &sources, jobs),
+        Some(Command::Export(ExportCommand::Crontab)) => export_crontab(&sources),
+        Some(Command::Bench { jobs, spread, minutes }) => bench(*jobs, *spread, *minutes),
+    };
+
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn run(cli: &mut Cli) -> Result<()> {
+    // Set when started by an upgrade of the previous instance
+    let handoff = Handoff::receive()?;
+    // Resolved before the binary can be replaced and the directory changed,
+    // upgrades run the new binary from the same directory
+    let exe = std::env::current_exe().expect("Failed to locate the xcrond binary");
+    let cwd = std::env::current_dir().ok();
+
+    if cli.daemon {
+        // The working directory changes to / once detached
+        for p in vec![&mut cli.sources.jobfile, &mut cli.sources.jobfile_dir, &mut cli.log_file, &mut cli.pid_file, &mut cli.history, &mut cli.journal, &mut cli.job_log_dir, &mut cli.cgroup_dir]
+            .into_iter()
+            .flatten()
+            .chain(cli.sources.crontab.iter_mut())
+            .chain(std::iter::once(&mut cli.control_socket))
+        {
+            *p = absolute(p);
+        }
+        match handoff {
+            // Already detached, only the working directory was restored
+            Some(_) => std::env::set_current_dir("/").map_err(|source| XcrondError::Io {
+                path: "/".into(),
+                source,
+            })?,
+            None => daemonize(cli.log_file.as_deref())?,
+        }
+    } else if let (None, Some(path)) = (&handoff, &cli.log_file) {
+        redirect_logs(path)?;
+    }
+
+    init_logger()?;
+
+    // Upgrades are requested with SIGUSR2 and reloads with SIGHUP, blocked
+    // before any thread is spawned so they're only received by the thread
+    // waiting for them
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGUSR2);
+    signals.add(Signal::SIGHUP);
+    signals.thread_block().expect("Failed to block SIGUSR2 and SIGHUP");
+
+    // Held until the daemon exits
+    let inherited = handoff.as_ref().and_then(|h| h.fd(PID_FILE_FD));
+    let pid_file = match (&cli.pid_file, inherited) {
+        (Some(path), Some(fd)) => Some(PidFile::inherit(path, fd)),
+        (Some(path), None) => Some(PidFile::acquire(path, cli.replace)?),
+        (None, _) => None,
+    };
+
+    let mut builder = cli.sources.builder()?;
+    if cli.sources.is_empty() && !cli.spool {
+        info!("No Jobfile given with --jobfile or {}, no jobs to run", JOBFILE_ENV);
+    }
+
+    if let Some(path) = &cli.history {
+        let history = Arc::new(History::open(path)?);
+        history.start_pruning(Retention::from(&cli.retention), PRUNE_INTERVAL);
+        builder = builder.observer(history);
+    }
+
+    if let Some(url) = &cli.cluster_lock {
+        let lock: Arc<dyn ClusterLock> = match url.strip_prefix("file://") {
+            Some(dir) => Arc::new(FileLock::new(dir)?),
+            None => Arc::new(RedisLock::new(url)?),
+        };
+        builder = builder.cluster_lock(lock);
+    }
+
+    if let Some(url) = &cli.leader_election {
+        let election: Arc<dyn LeaderElection> = match url.strip_prefix("file://") {
+            Some(dir) => Arc::new(FileLock::new(dir)?),
+            None => Arc::new(RedisLock::new(url)?),
+        };
+        builder = builder.leader_election(election, &cli.leader_key);
+    }
+
+    if let Some(url) = &cli.sharding {
+        let membership: Arc<dyn Membership> = match url.strip_prefix("file://") {
+            Some(dir) => Arc::new(FileLock::new(dir)?),
+            None => Arc::new(RedisLock::new(url)?),
+        };
+        builder = builder.sharding(membership, &cli.shard_group);
+    }
+
+    if let Some(addr) = &cli.replicate {
+        let listener = TcpListener::bind(addr).map_err(|source| XcrondError::Listen {
+            addr: addr.clone(),
+            source,
+        })?;
+        builder = builder.replicate(listener);
+    }
+
+    if let Some(primary) = &cli.standby_of {
+        builder = builder.standby(primary, Duration::from_secs(cli.failover_after));
+    }
+
+    if let Some(path) = &cli.journal {
+        builder = builder.journal_path(path);
+    }
+
+    if let Some(dir) = &cli.job_log_dir {
+        builder = builder.log_dir(dir);
+    }
+
+    if let Some(dir) = &cli.cgroup_dir {
+        builder = builder.cgroup_dir(dir);
+    }
+
+    if let Some(addr) = &cli.smtp {
+        builder = builder.mailer(Mailer::Smtp(addr.clone()));
+    }
+
+    if let Some(rate) = cli.max_start_rate {
+        builder = builder.max_start_rate(rate);
+    }
+
+    if let Some(secs) = cli.shutdown_max_wait {
+        builder = builder.max_shutdown_wait(Duration::from_secs(secs));
+    }
+
+    if let Some(interval) = systemd::watchdog_interval() {
+        builder = builder
+            .heartbeat(interval)
+            .observer(Arc::new(systemd::Watchdog));
+    }
+
+    let mut c = builder.build()?;
+
+    // Bound while privileged, as /run usually is. The daemon runs on
+    // without it rather than failing to start.
+    let inherited = handoff.as_ref().and_then(|h| h.fd(CONTROL_SOCKET_FD));
+    let control = match (cli.no_control_socket, inherited) {
+        (true, _) => None,
+        (false, Some(fd)) => Some(ControlSocket::inherit(&cli.control_socket, fd)),
+        (false, None) => ControlSocket::bind(&cli.control_socket)
+            .map_err(|err| error!("{}, the daemon can't be controlled", err))
+            .ok(),
+    };
+
+    // The Jobfile, the journal and the state are read while privileged
+    c.init()?;
+    if let Some(h) = handoff {
+        info!("Taking over from the previous instance");
+        c.take_over(h);
+    }
+
+    // Jobs are spawned by this thread, which keeps what's left of the privileges
+    if let Some(user) = &cli.user {
+        drop_privileges(user)?;
+        // Written to once running: the journal and the history are rewritten
+        // or journaled next to themselves
+        let dirs = cli.journal.iter().chain(&cli.history).filter_map(|p| p.parent());
+        for dir in dirs.chain(cli.job_log_dir.as_deref()).chain(cli.cgroup_dir.as_deref()) {
+            if let Err(err) = check_writable(dir) {
+                error!("{} has to be writable by {}", dir.display(), user);
+                return Err(err);
+            }
+        }
+    }
+
     // Intitialize signal handler
+    let handle = c.handle();
+    let grace = Duration::from_secs(cli.shutdown_grace);
     ctrlc::set_handler(move || {
         println!("Terminate signal received. Exiting.");
-        std::process::exit(0);
+        notify("STOPPING=1");
+        handle.terminate(grace);
     })
-    .expect("Failed to set SIGINT handler");
+    .expect("Failed to set SIGINT/SIGTERM handler");
+
+    if !cli.no_watch {
+        c.handle().watch_config();
+    }
+    if let Some(socket) = &control {
+        socket.serve(c.handle())?;
+    }
+    if cli.spool {
+        Spool::new(&cli.crontabs_dir, &cli.cron_d_dir)
+            .access(&cli.access.allow_file, &cli.access.deny_file)
+            .watch(c.handle(), SPOOL_INTERVAL);
+    }
+
+    // The Jobfile is loaded and the queue built, we're ready
+    if notify("READY=1") {
+        report_status(c.handle());
+    }
+    watch_signals(c.handle(), signals, cli.user.is_none());
+
+    loop {
+        c.run();
+        let mut h = match c.take_handoff() {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        if let Some(f) = &pid_file {
+            h.add_fd(PID_FILE_FD, f.as_raw_fd());
+        }
+        if let Some(socket) = &control {
+            h.add_fd(CONTROL_SOCKET_FD, socket.as_raw_fd());
+        }
+
+        info!("Handing over to {}", exe.display());
+        notify("RELOADING=1");
+        let mut cmd = process::Command::new(&exe);
+        cmd.args(std::env::args_os().skip(1));
+        if let Some(dir) = &cwd {
+            cmd.current_dir(dir);
+        }
+        let err = h.exec(cmd);
+
+        error!("{}, resuming", err);
+        c.take_over(h);
+        notify("READY=1");
+    }
+}
+
+/// watch_signals spawns a thread waiting for the blocked `signals`: the
+/// Jobfile is reloaded on SIGHUP, and the daemon handed over to a new
+/// instance on SIGUSR2 if `upgrades` is set.
+///
+/// Upgrades are refused once privileges are dropped: the capabilities kept
+/// to run jobs as other users are lost when exec'ing the new binary.
+fn watch_signals(handle: CronHandle, signals: SigSet, upgrades: bool) {
+    std::thread::spawn(move || loop {
+        match signals.wait() {
+            Ok(Signal::SIGHUP) => {
+                info!("Received SIGHUP, reloading");
+                notify("RELOADING=1");
+                match handle.reload() {
+                    Ok(r) => info!("Reloaded: {} jobs added, {} updated, {} removed", r.added, r.updated, r.removed),
+                    Err(err) => error!("Failed to reload, keeping the current jobs: {}", err),
+                }
+                notify("READY=1");
+            }
+            Ok(signal) if !upgrades => {
+                warn!("Received {:?}, upgrades aren't supported with --user, restart the daemon instead", signal);
+            }
+            Ok(signal) => {
+                info!("Received {:?}, upgrading", signal);
+                handle.handoff();
+            }
+            Err(err) => {
+                error!("Failed to wait for signals: {}", err);
+                return;
+            }
+        }
+    });
+}
+
+/// notify sends a state to systemd, returning false when not run by systemd
+fn notify(state: &str) -> bool {
+    match systemd::notify(state) {
+        Ok(notified) => notified,
+        Err(err) => {
+            error!("Failed to notify systemd: {}", err);
+            false
+        }
+    }
+}
+
+/// report_status spawns a thread keeping the status shown by systemd up to date
+fn report_status(handle: CronHandle) {
+    std::thread::spawn(move || {
+        let mut last = String::new();
+        loop {
+            let status = match handle.next_wakeup() {
+                Some(next) => format!("{} pending runs, next at {}", handle.queue_depth(), next),
+                None => "No jobs scheduled".to_string(),
+            };
+            if status != last {
+                notify(&format!("STATUS={}", status));
+                last = status;
+            }
+            std::thread::sleep(STATUS_INTERVAL);
+        }
+    });
+}
+
+fn simulate(sources: &Sources, hours: u32, limit: usize) -> Result<()> {
+    let mut cron = sources.builder()?.build()?;
+    cron.init()?;
+    let until = Local::now() + chrono::Duration::hours(i64::from(hours));
+    for f in cron.simulate(until, limit) {
+        println!("{} {} {}", f.time.format("%Y-%m-%d %H:%M:%S %z"), f.name, f.job);
+    }
+    Ok(())
+}
+
+fn check(sources: &Sources) -> Result<()> {
+    let mut builder = Cron::builder();
+    if let Some(path) = &sources.jobfile {
+        builder = builder.config_path(path);
+    }
+    if let Some(dir) = &sources.jobfile_dir {
+        builder = builder.config_dir(dir);
+    }
+    let mut cron = builder.build()?;
+
+    let mut problems = vec![];
+    for path in &sources.crontab {
+        match read_crontab(path) {
+            Ok((source, content)) => {
+                let (jobs, skipped) = xcrond::import::check_crontab(&content, &source);
+                problems.extend(skipped);
+                problems.extend(cron.add_jobs(jobs).into_iter().filter_map(|res| res.err()).map(|err| err.to_string()));
+            }
+            Err(err) => problems.push(err.to_string()),
+        }
+    }
+    problems.extend(cron.check().into_iter().map(|err| err.to_string()));
+
+    let jobs = cron.jobs();
+    let width = jobs.iter().map(|j| j.name.len()).max().unwrap_or(0).max(4);
+    println!("{:width$}  {:>4}  {:20}  NEXT", "NAME", "ID", "SCHEDULE", width = width);
+    for j in &jobs {
+        println!(
+            "{:width$}  {:>4}  {:20}  {}",
+            j.name,
+            j.id.to_string(),
+            j.schedule,
+            j.next.format("%Y-%m-%d %H:%M:%S %z"),
+            width = width
+        );
+    }
+
+    if !problems.is_empty() {
+        for p in &problems {
+            eprintln!("{}", p);
+        }
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn replay(sources: &Sources, from: DateTime<Local>, to: DateTime<Local>, limit: usize) -> Result<()> {
+    let mut cron = sources.builder()?.build()?;
+    cron.init()?;
+    let firings = cron.replay(from, to, limit);
+    if firings.len() >= limit {
+        eprintln!("Stopped after {} runs", limit);
+    }
+
+    for j in cron.jobs() {
+        let times: Vec<_> = firings.iter().filter(|f| f.job == j.id).map(|f| f.time).collect();
+        println!("{} {}: {} runs", j.name, j.id, times.len());
+        for t in times {
+            println!("  {}", t.format("%Y-%m-%d %H:%M:%S %z"));
+        }
+    }
+    Ok(())
+}
+
+fn import_systemd(units: &[PathBuf]) -> Result<()> {
+    init_import_logger()?;
+    let mut jobs = vec![];
+    for path in units {
+        jobs.extend(xcrond::import::systemd_timer(path)?);
+    }
+    write_jobs(jobs, None)
+}
+
+fn import_launchd(plists: &[PathBuf]) -> Result<()> {
+    init_import_logger()?;
+    let mut jobs = vec![];
+    for path in plists {
+        jobs.extend(xcrond::import::launchd_plist(path)?);
+    }
+    write_jobs(jobs, None)
+}
+
+fn import_crontab(
+    user: Option<&str>,
+    file: Option<&Path>,
+    output: Option<&Path>,
+    access: &AccessArgs,
+) -> Result<()> {
+    init_import_logger()?;
+    let (source, content) = match file {
+        Some(path) => read_crontab(path)?,
+        None => {
+            let name = user
+                .map(str::to_string)
+                .or_else(|| std::env::var("USER").ok())
+                .unwrap_or_else(|| "crontab".to_string());
+            Access::load(&access.allow_file, &access.deny_file)?.check(&name)?;
+
+            let mut cmd = process::Command::new("crontab");
+            cmd.arg("-l");
+            if let Some(u) = user {
+                cmd.args(["-u", u].iter());
+            }
+            let err = |source| XcrondError::Io {
+                path: "crontab -l".into(),
+                source,
+            };
+            let out = cmd.output().map_err(err)?;
+            if !out.status.success() {
+                let msg = String::from_utf8_lossy(&out.stderr).trim().to_string();
+                return Err(err(io::Error::other(msg)));
+            }
+            (name, String::from_utf8_lossy(&out.stdout).into_owned())
+        }
+    };
+    write_jobs(xcrond::import::crontab(&content, &source), output)
+}
+
+/// read_crontab returns the name of the crontab at `path`, naming its jobs,
+/// and its content
+fn read_crontab(path: &Path) -> Result<(String, String)> {
+    let content = std::fs::read_to_string(path).map_err(|source| XcrondError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let name = path.file_name().map_or("crontab".into(), |n| n.to_string_lossy());
+    Ok((name.to_string(), content))
+}
+
+/// init_import_logger logs to stderr, the imported jobs are printed to stdout
+fn init_import_logger() -> Result<()> {
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Warn)
+        .target(env_logger::Target::Stderr)
+        .try_init()?;
+    Ok(())
+}
+
+/// write_jobs writes the jobs as Jobfile entries to `output`, or stdout
+fn write_jobs(jobs: Vec<JobSpec>, output: Option<&Path>) -> Result<()> {
+    #[derive(Serialize)]
+    struct Jobs {
+        job: Vec<JobSpec>,
+    }
+
+    let path = output.unwrap_or_else(|| Path::new("-"));
+    // Converted to a value first, which puts tables after the other values
+    let toml = toml::Value::try_from(Jobs { job: jobs })
+        .and_then(|v| toml::to_string(&v))
+        .map_err(|source| XcrondError::Serialize {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    match output {
+        Some(path) => std::fs::write(path, toml).map_err(|source| XcrondError::Write {
+            path: path.to_path_buf(),
+            source,
+        }),
+        None => {
+            print!("{}", toml);
+            Ok(())
+        }
+    }
+}
+
+/// load_jobs returns the jobs of the Jobfile, its fragments and the crontabs
+fn load_jobs(sources: &Sources) -> Result<Vec<JobInfo>> {
+    let mut cron = sources.builder()?.build()?;
+    cron.init()?;
+    Ok(cron.jobs())
+}
+
+fn export_crontab(sources: &Sources) -> Result<()> {
+    print!("{}", xcrond::export::crontab(&load_jobs(sources)?));
+    Ok(())
+}
+
+fn export_systemd(dir: &Path, sources: &Sources, names: &[String]) -> Result<()> {
+    let jobs: Vec<JobInfo> = load_jobs(sources)?
+        .into_iter()
+        .filter(|j| names.is_empty() || names.contains(&j.name))
+        .collect();
+    if let Some(name) = names.iter().find(|n| !jobs.iter().any(|j| &j.name == *n)) {
+        eprintln!("No job named {}", name);
+        process::exit(1);
+    }
+
+    for j in jobs {
+        let units = match xcrond::export::systemd_units(&j) {
+            Ok(u) => u,
+            Err(reason) => {
+                eprintln!("Skipping {}: {}", j.name, reason);
+                continue;
+            }
+        };
+        for (ext, content) in [("timer", &units.timer), ("service", &units.service)].iter() {
+            let path = dir.join(format!("{}.{}", units.name, ext));
+            std::fs::write(&path, content).map_err(|source| XcrondError::Write {
+                path: path.clone(),
+                source,
+            })?;
+            println!("{}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn bench(jobs: usize, spread: u32, minutes: u64) -> Result<()> {
+    let report = xcrond::bench::run(BenchOptions {
+        jobs,
+        spread,
+        duration: Duration::from_secs(minutes * 60),
+    })?;
+    println!("{}", report);
+    Ok(())
+}
+
+/// parse_time parses a time given on the command line
+fn parse_time(s: &str) -> std::result::Result<DateTime<Local>, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Local));
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .or_else(|| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)))
+        .ok_or_else(|| format!("invalid time `{}`", s))?;
+    // The earliest of the two times repeated when the clocks go back
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("`{}` is skipped by a clock change", s))
+}
+
+fn prune(db: &Path, retention: &RetentionArgs) -> Result<()> {
+    let retention = Retention::from(retention);
+    if retention.is_unlimited() {
+        eprintln!("No retention limit given, nothing to prune");
+        return Ok(());
+    }
+
+    let n = History::open(db)?.prune(&retention)?;
+    println!("Pruned {} runs", n);
+    Ok(())
+}
 
-    let mut c = Cron::default();
-    c.init();
-    c.run();
+/// absolute resolves a path relative to the current working directory
+fn absolute(p: &Path) -> PathBuf {
+    match std::env::current_dir() {
+        Ok(cwd) => cwd.join(p),
+        Err(_) => p.to_path_buf(),
+    }
 }
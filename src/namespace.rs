@@ -0,0 +1,72 @@
+//! Namespaces grouping the jobs of a team or tenant under shared limits.
+//!
+//! The number of jobs of a namespace is limited when they are registered,
+//! and the number of its jobs running at once when they are dispatched.
+//! CPU time and memory are limited per run with rlimits, so a runaway job is
+//! stopped by the kernel instead of starving the jobs of other namespaces.
+//! Jobs may have lower limits of their own.
+
+use crate::limits::Limits;
+use serde::{Deserialize, Serialize};
+
+/// Namespace is a group of jobs with the limits they share
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Namespace {
+    pub name: String,
+    /// maximum number of jobs registered in the namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_jobs: Option<usize>,
+    /// maximum number of jobs of the namespace running at the same time.
+    /// Occurrences that would exceed it are skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<usize>,
+    /// CPU time each run may use, in seconds. The process gets SIGXCPU
+    /// once it's used up, and SIGKILL a second later.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_time: Option<u64>,
+    /// address space each run may use, in bytes. Allocations fail beyond it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<u64>,
+}
+
+impl Namespace {
+    pub fn new(name: &str) -> Self {
+        Namespace {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// with_max_jobs limits the number of jobs registered in the namespace
+    pub fn with_max_jobs(mut self, max: usize) -> Self {
+        self.max_jobs = Some(max);
+        self
+    }
+
+    /// with_max_concurrent limits the number of its jobs running at once
+    pub fn with_max_concurrent(mut self, max: usize) -> Self {
+        self.max_concurrent = Some(max);
+        self
+    }
+
+    /// with_cpu_time limits the CPU time of each run, in seconds
+    pub fn with_cpu_time(mut self, secs: u64) -> Self {
+        self.cpu_time = Some(secs);
+        self
+    }
+
+    /// with_memory limits the address space of each run, in bytes
+    pub fn with_memory(mut self, bytes: u64) -> Self {
+        self.memory = Some(bytes);
+        self
+    }
+
+    /// limits returns the limits of each run of the jobs of the namespace
+    pub(crate) fn limits(&self) -> Limits {
+        Limits {
+            cpu_time: self.cpu_time,
+            memory: self.memory,
+            ..Default::default()
+        }
+    }
+}
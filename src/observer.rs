@@ -0,0 +1,77 @@
+use crate::job::JobInfo;
+use crate::run::{JobRunResult, RunId};
+use std::time::Duration;
+
+/// MissReason is why an occurrence of a job didn't run
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MissReason {
+    /// the job was paused
+    Paused,
+    /// the maximum number of concurrently running jobs was reached
+    ConcurrencyLimit,
+    /// the maximum number of running jobs of the job's namespace was reached
+    NamespaceLimit,
+    /// the job's lock file is held by another run or process
+    LockHeld,
+    /// the occurrence was claimed by another host of the cluster
+    ClusterLockHeld,
+    /// this instance isn't the elected leader
+    NotLeader,
+    /// the job is in the shard of another member of the shard group
+    OtherShard,
+    /// the occurrence came due while it couldn't be run, and the job's
+    /// misfire policy is to skip it
+    Misfired,
+    /// the runs of the job used up its daily budget
+    BudgetExceeded,
+    /// the occurrence fell in a blackout window, and the job's blackout
+    /// policy is to skip it
+    Blackout,
+    /// a previous run of the job was still running, and the job's overlap
+    /// policy is to skip the occurrence
+    Overlap,
+}
+
+/// SchedulerObserver gets notified of what the scheduler does.
+///
+/// Register observers with `CronBuilder::observer` or `Cron::add_observer`
+/// to plug in metrics, notifications or auditing.
+/// Every callback has an empty default implementation.
+///
+/// Callbacks are invoked from the scheduler's threads while its state is
+/// locked, so they should return quickly and must not call back into the
+/// `Cron` instance or its handles.
+pub trait SchedulerObserver: Send + Sync {
+    /// job_scheduled is called when the next occurrence of a job is enqueued
+    fn job_scheduled(&self, _job: &JobInfo) {}
+
+    /// job_started is called when a job's process has been spawned for the
+    /// run `run`
+    fn job_started(&self, _job: &JobInfo, _pid: i32, _run: RunId) {}
+
+    /// job_finished is called when a job's process has been reaped
+    fn job_finished(&self, _result: &JobRunResult) {}
+
+    /// job_spawn_failed is called when a job's process couldn't be spawned
+    /// because of a transient error such as EAGAIN or ENOMEM, with the number
+    /// of attempts so far. Repeated failures are worth alerting on.
+    /// The launch is retried until the maximum number of attempts, the run
+    /// is then recorded as failed to start.
+    fn job_spawn_failed(&self, _job: &JobInfo, _attempts: u32, _error: &str) {}
+
+    /// job_missed is called when an occurrence of a job is skipped
+    fn job_missed(&self, _job: &JobInfo, _reason: MissReason) {}
+
+    /// job_over_budget is called when the runs of a job use up its daily
+    /// budget, with the time they took that day. The next occurrences of
+    /// the day are skipped.
+    fn job_over_budget(&self, _job: &JobInfo, _spent: Duration) {}
+
+    /// queue_rebuilt is called when the queue has been (re)built from the
+    /// configuration, with the number of registered jobs
+    fn queue_rebuilt(&self, _jobs: usize) {}
+
+    /// heartbeat is called on every iteration of the run loop, and at least
+    /// once per `CronBuilder::heartbeat` interval if one is set
+    fn heartbeat(&self) {}
+}
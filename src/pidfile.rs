@@ -0,0 +1,120 @@
+//! PID file of the daemon, also enforcing that a single instance runs.
+//!
+//! The PID file is locked with flock for as long as the daemon runs. The
+//! lock is released by the kernel when the daemon exits, even if it crashes,
+//! so a leftover PID file that isn't locked is simply taken over.
+
+use crate::error::{Result, XcrondError};
+use crate::lock;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long to wait for a replaced instance to exit before giving up
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// PidFile is the locked PID file of a running daemon.
+/// The file is removed when dropped.
+pub struct PidFile {
+    path: PathBuf,
+    // holds the lock
+    file: File,
+}
+
+impl PidFile {
+    /// acquire locks the PID file at `path` and writes our pid to it.
+    /// If another instance holds it, fails unless `replace` is true, in
+    /// which case the other instance is asked to terminate first.
+    pub fn acquire<P: Into<PathBuf>>(path: P, replace: bool) -> Result<Self> {
+        let path = path.into();
+        let mut file = match try_lock(&path)? {
+            Some(f) => f,
+            None => {
+                let pid = read_pid(&path);
+                if !replace {
+                    return Err(XcrondError::AlreadyRunning { path, pid });
+                }
+                take_over(&path, pid)?
+            }
+        };
+
+        let write_err = |source| XcrondError::Write {
+            path: path.clone(),
+            source,
+        };
+        file.set_len(0).map_err(write_err)?;
+        file.seek(SeekFrom::Start(0)).map_err(write_err)?;
+        writeln!(file, "{}", std::process::id()).map_err(write_err)?;
+
+        Ok(PidFile { path, file })
+    }
+
+    /// inherit takes over the PID file locked by the instance this one
+    /// replaced in place, from the file descriptor it handed over
+    pub fn inherit<P: Into<PathBuf>>(path: P, fd: RawFd) -> Self {
+        PidFile {
+            path: path.into(),
+            file: unsafe { File::from_raw_fd(fd) },
+        }
+    }
+}
+
+impl AsRawFd for PidFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn try_lock(path: &Path) -> Result<Option<File>> {
+    lock::try_lock(path).map_err(|source| XcrondError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// read_pid returns the pid written in the PID file, if any
+fn read_pid(path: &Path) -> Option<i32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// take_over terminates the instance holding the PID file and locks it
+fn take_over(path: &Path, pid: Option<i32>) -> Result<File> {
+    let pid = match pid {
+        Some(p) => p,
+        None => {
+            return Err(XcrondError::AlreadyRunning {
+                path: path.to_path_buf(),
+                pid,
+            })
+        }
+    };
+
+    info!("Replacing running instance {}", pid);
+    if let Err(err) = kill(Pid::from_raw(pid), Signal::SIGTERM) {
+        warn!("Failed to terminate instance {}: {}", pid, err);
+    }
+
+    let deadline = Instant::now() + REPLACE_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Some(f) = try_lock(path)? {
+            return Ok(f);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    Err(XcrondError::AlreadyRunning {
+        path: path.to_path_buf(),
+        pid: Some(pid),
+    })
+}
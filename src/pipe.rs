@@ -0,0 +1,61 @@
+//! Outputs of jobs piped to the jobs they trigger.
+//!
+//! The standard output of a job piping to another one is written to a file
+//! of the temporary directory, only readable by the daemon's user. When the
+//! job succeeds, the downstream job is run with the file as its standard
+//! input and its path in `XCROND_INPUT`. The file is removed once the
+//! downstream run is over, or right away if the job failed.
+
+use crate::run::RunId;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// output creates the file capturing the output of the run
+pub(crate) fn output(run: RunId) -> io::Result<(PathBuf, File)> {
+    let path = env::temp_dir().join(format!("xcrond-{}.out", run.as_u64()));
+    let f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    Ok((path, f))
+}
+
+/// remove deletes the captured output of a run
+pub(crate) fn remove(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            warn!("Failed to remove {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Staged is a captured output not handed over to a run or retry yet,
+/// removed when dropped
+pub(crate) struct Staged(Option<PathBuf>);
+
+impl Staged {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Staged(path)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.0.as_deref()
+    }
+
+    /// take hands the output over, it isn't removed anymore
+    pub fn take(&mut self) -> Option<PathBuf> {
+        self.0.take()
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            remove(path);
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Minimal reader of XML property lists, as used by launchd.
+//!
+//! Only the XML format is supported, binary property lists can be converted
+//! with `plutil -convert xml1`. Dates and data are read as strings.
+
+/// Value is a value of a property list
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Dict(Vec<(String, Value)>),
+    Array(Vec<Value>),
+    String(String),
+    Integer(i64),
+    Real(f64),
+    Bool(bool),
+}
+
+impl Value {
+    /// get returns the value of `key` if this is a dictionary holding it
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+}
+
+/// parse parses an XML property list
+pub fn parse(text: &str) -> Result<Value, String> {
+    if text.starts_with("bplist") {
+        return Err(
+            "binary property lists aren't supported, convert it with `plutil -convert xml1`"
+                .to_string(),
+        );
+    }
+    let mut p = Parser { text, pos: 0 };
+    loop {
+        match p.tag()? {
+            Some(Tag::Open(name)) if name == "plist" => break,
+            Some(_) => continue,
+            None => return Err("no <plist> element".to_string()),
+        }
+    }
+    match p.tag()? {
+        Some(t) => p.value(t),
+        None => Err("empty property list".to_string()),
+    }
+}
+
+#[derive(Debug)]
+enum Tag {
+    Open(String),
+    Close(String),
+    Empty(String),
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    /// tag returns the next tag, skipping the text, comments and
+    /// declarations before it
+    fn tag(&mut self) -> Result<Option<Tag>, String> {
+        loop {
+            let start = match self.text[self.pos..].find('<') {
+                Some(i) => self.pos + i,
+                None => return Ok(None),
+            };
+            let rest = &self.text[start..];
+            let (end_marker, skip) = if rest.starts_with("<!--") {
+                ("-->", true)
+            } else if rest.starts_with("<?") || rest.starts_with("<!") {
+                (">", true)
+            } else {
+                (">", false)
+            };
+            let end = rest
+                .find(end_marker)
+                .ok_or_else(|| "unterminated tag".to_string())?;
+            let inner = &rest[1..end];
+            self.pos = start + end + end_marker.len();
+            if skip {
+                continue;
+            }
+
+            let name = |s: &str| s.split_whitespace().next().unwrap_or("").to_string();
+            return Ok(Some(match inner.strip_prefix('/') {
+                Some(n) => Tag::Close(name(n)),
+                None => match inner.strip_suffix('/') {
+                    Some(n) => Tag::Empty(name(n)),
+                    None => Tag::Open(name(inner)),
+                },
+            }));
+        }
+    }
+
+    /// text returns the text up to the closing tag of `name`
+    fn text(&mut self, name: &str) -> Result<String, String> {
+        let close = format!("</{}>", name);
+        let end = self.text[self.pos..]
+            .find(&close)
+            .ok_or_else(|| format!("unterminated <{}>", name))?;
+        let raw = &self.text[self.pos..self.pos + end];
+        self.pos += end + close.len();
+        Ok(unescape(raw))
+    }
+
+    /// value parses the value starting with `tag`
+    fn value(&mut self, tag: Tag) -> Result<Value, String> {
+        match tag {
+            Tag::Empty(name) => match name.as_str() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                "string" | "data" | "date" => Ok(Value::String(String::new())),
+                "dict" => Ok(Value::Dict(vec![])),
+                "array" => Ok(Value::Array(vec![])),
+                n => Err(format!("unexpected <{}/>", n)),
+            },
+            Tag::Open(name) => match name.as_str() {
+                "string" | "data" | "date" => Ok(Value::String(self.text(&name)?)),
+                "integer" => {
+                    let t = self.text(&name)?;
+                    t.trim()
+                        .parse()
+                        .map(Value::Integer)
+                        .map_err(|_| format!("invalid integer `{}`", t))
+                }
+                "real" => {
+                    let t = self.text(&name)?;
+                    t.trim()
+                        .parse()
+                        .map(Value::Real)
+                        .map_err(|_| format!("invalid real `{}`", t))
+                }
+                "array" => {
+                    let mut items = vec![];
+                    loop {
+                        match self.tag()? {
+                            Some(Tag::Close(n)) if n == "array" => return Ok(Value::Array(items)),
+                            Some(t) => items.push(self.value(t)?),
+                            None => return Err("unterminated <array>".to_string()),
+                        }
+                    }
+                }
+                "dict" => {
+                    let mut entries = vec![];
+                    loop {
+                        let key = match self.tag()? {
+                            Some(Tag::Close(n)) if n == "dict" => return Ok(Value::Dict(entries)),
+                            Some(Tag::Open(n)) if n == "key" => self.text("key")?,
+                            Some(t) => {
+                                return Err(format!("expected a <key> in <dict>, found {:?}", t))
+                            }
+                            None => return Err("unterminated <dict>".to_string()),
+                        };
+                        let value = match self.tag()? {
+                            Some(t) => self.value(t)?,
+                            None => return Err(format!("missing the value of {}", key)),
+                        };
+                        entries.push((key, value));
+                    }
+                }
+                n => Err(format!("unexpected <{}>", n)),
+            },
+            Tag::Close(name) => Err(format!("unexpected </{}>", name)),
+        }
+    }
+}
+
+/// unescape replaces the entities of XML text
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,153 @@
+//! CPU and I/O priorities of the runs of jobs.
+//!
+//! Heavy batch jobs, such as backups or indexing, can be given a nice value
+//! and an I/O scheduling class so they don't slow down the rest of the
+//! system. Both are set in the child between fork and exec. I/O priorities
+//! are only supported on Linux, and are ignored elsewhere.
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::str::FromStr;
+
+/// Lowest and highest nice values
+pub const NICE_RANGE: (i32, i32) = (-20, 19);
+
+/// IoClass is an I/O scheduling class, as set by ionice(1)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoClass {
+    /// served before the other classes, requires root
+    Realtime,
+    /// the default class
+    BestEffort,
+    /// served when no other process needs the disk
+    Idle,
+}
+
+/// IoPriority is the I/O scheduling class of a run and its level within
+/// the class, from 0 (highest) to 7. The idle class has no levels.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IoPriority {
+    pub class: IoClass,
+    pub level: u8,
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+
+    /// from_str parses `idle`, or `realtime` and `best-effort` with an
+    /// optional level, e.g. `best-effort:7`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, level) = match s.trim().split_once(':') {
+            Some((class, level)) => (class, Some(level)),
+            None => (s.trim(), None),
+        };
+        let class = match class {
+            "realtime" => IoClass::Realtime,
+            "best-effort" => IoClass::BestEffort,
+            "idle" => IoClass::Idle,
+            c => return Err(format!("unknown class `{}`, expected realtime, best-effort or idle", c)),
+        };
+        let level = match (class, level) {
+            (IoClass::Idle, Some(_)) => return Err("the idle class has no levels".to_string()),
+            (_, Some(level)) => level
+                .parse()
+                .ok()
+                .filter(|l| *l <= 7)
+                .ok_or_else(|| format!("invalid level `{}`, expected 0 to 7", level))?,
+            // The level the kernel gives processes of the default nice value
+            (_, None) => 4,
+        };
+        Ok(IoPriority { class, level })
+    }
+}
+
+impl TryFrom<String> for IoPriority {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<IoPriority> for String {
+    fn from(p: IoPriority) -> Self {
+        p.to_string()
+    }
+}
+
+impl fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.class {
+            IoClass::Realtime => write!(f, "realtime:{}", self.level),
+            IoClass::BestEffort => write!(f, "best-effort:{}", self.level),
+            IoClass::Idle => f.write_str("idle"),
+        }
+    }
+}
+
+/// prioritize sets the nice value and the I/O priority of the process run
+/// by `cmd`, if given
+pub(crate) fn prioritize(cmd: &mut Command, nice: Option<i32>, io: Option<IoPriority>) {
+    if nice.is_none() && io.is_none() {
+        return;
+    }
+    unsafe {
+        // Only async signal safe calls between fork and exec
+        cmd.pre_exec(move || {
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(io) = io {
+                set_io_priority(io)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_priority(p: IoPriority) -> io::Result<()> {
+    // From linux/ioprio.h
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    let class = match p.class {
+        IoClass::Realtime => 1,
+        IoClass::BestEffort => 2,
+        IoClass::Idle => 3,
+    };
+    let value = class << IOPRIO_CLASS_SHIFT | libc::c_int::from(p.level);
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_io_priority(_: IoPriority) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_io_priorities() {
+        let p = |s: &str| s.parse::<IoPriority>().map(|p| p.to_string());
+        assert_eq!(p("idle"), Ok("idle".to_string()));
+        assert_eq!(p("best-effort"), Ok("best-effort:4".to_string()));
+        assert_eq!(p("best-effort:7"), Ok("best-effort:7".to_string()));
+        assert_eq!(p("realtime:0"), Ok("realtime:0".to_string()));
+        assert!(p("idle:3").is_err());
+        assert!(p("best-effort:8").is_err());
+        assert!(p("low").is_err());
+    }
+}
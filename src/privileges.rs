@@ -0,0 +1,147 @@
+//! Dropping the privileges of a daemon started as root.
+//!
+//! The daemon switches to an unprivileged user once the files it needs as
+//! root are open. The directories it writes to later on have to be writable
+//! by the user, see `check_writable`. On Linux, it keeps the few capabilities needed to run
+//! and signal jobs as other users; they are never passed on to jobs.
+//!
+//! The capabilities don't survive an exec either, so a daemon that dropped
+//! its privileges can't be upgraded in place: the new binary would run
+//! without them.
+
+use crate::error::{Result, XcrondError};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+/// drop_privileges switches the process to `user` and its groups.
+/// Does nothing if the process already runs as `user`.
+///
+/// Capabilities are per thread on Linux: they are kept by the calling
+/// thread and inherited by the threads it spawns afterwards, not by the
+/// threads already running. Jobs have to be spawned by one of those.
+pub fn drop_privileges(user: &str) -> Result<()> {
+    let err = |source| XcrondError::DropPrivileges {
+        user: user.to_string(),
+        source,
+    };
+
+    let (uid, gid) = lookup(user).map_err(err)?;
+    let (euid, ruid) = unsafe { (libc::geteuid(), libc::getuid()) };
+    if euid == uid && ruid == uid {
+        return Ok(());
+    }
+    if euid != 0 {
+        return Err(err(io::Error::from_raw_os_error(libc::EPERM)));
+    }
+
+    switch(user, uid, gid).map_err(err)?;
+    info!("Dropped privileges to {}", user);
+    Ok(())
+}
+
+/// check_writable fails unless the process may create files in `dir`, or
+/// in its closest existing ancestor if it's yet to be created. To be called
+/// once privileges are dropped.
+pub fn check_writable(dir: &Path) -> Result<()> {
+    // e.g. the parent of a relative file name
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let missing = |d: &Path| matches!(fs::metadata(d), Err(ref e) if e.kind() == io::ErrorKind::NotFound);
+    let existing = dir.ancestors().find(|d| !missing(d)).unwrap_or(dir);
+    let err = |source| XcrondError::Write {
+        path: existing.to_path_buf(),
+        source,
+    };
+    let path = CString::new(existing.as_os_str().as_bytes()).map_err(|e| err(e.into()))?;
+    if unsafe { libc::access(path.as_ptr(), libc::W_OK) } < 0 {
+        return Err(err(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn switch(user: &str, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    let name = CString::new(user)?;
+
+    #[cfg(target_os = "linux")]
+    check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) })?;
+    check(unsafe { libc::initgroups(name.as_ptr(), gid as _) })?;
+    check(unsafe { libc::setgid(gid) })?;
+    check(unsafe { libc::setuid(uid) })?;
+    #[cfg(target_os = "linux")]
+    caps::retain()?;
+    Ok(())
+}
+
+/// lookup returns the uid and primary gid of `user`
+fn lookup(user: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user)?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut res = ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut res)
+        };
+        match ret {
+            0 if res.is_null() => return Err(io::Error::new(io::ErrorKind::NotFound, "no such user")),
+            0 => return Ok((pwd.pw_uid, pwd.pw_gid)),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod caps {
+    use std::io;
+
+    // From linux/capability.h
+    const VERSION_3: u32 = 0x2008_0522;
+    const CAP_KILL: u32 = 5;
+    const CAP_SETGID: u32 = 6;
+    const CAP_SETUID: u32 = 7;
+
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct Data {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    /// retain limits the capabilities of the thread to switching users and
+    /// signaling processes of other users
+    pub fn retain() -> io::Result<()> {
+        let mut header = Header {
+            version: VERSION_3,
+            pid: 0,
+        };
+        let mut data = [Data::default(); 2];
+        let keep = 1 << CAP_KILL | 1 << CAP_SETGID | 1 << CAP_SETUID;
+        data[0].effective = keep;
+        data[0].permitted = keep;
+
+        let ret = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
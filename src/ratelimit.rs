@@ -0,0 +1,43 @@
+//! Rate limiting of job launches.
+//!
+//! Pathological configurations, e.g. hundreds of jobs due at the top of the
+//! hour, would otherwise fork a thundering herd. Launches beyond the rate
+//! are delayed, not skipped.
+
+use std::time::{Duration, Instant};
+
+/// TokenBucket limits the rate of job launches. It holds up to a second
+/// worth of launches, so bursts are smoothed out over time instead of
+/// forking every due job at once.
+pub(crate) struct TokenBucket {
+    /// launches allowed per second
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> Self {
+        let rate = f64::from(std::cmp::max(rate, 1));
+        TokenBucket {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// take takes a token if one is available, or returns how long to wait
+    /// for the next one
+    pub fn take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = f64::min(self.rate, self.tokens + elapsed * self.rate);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+}
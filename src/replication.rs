@@ -0,0 +1,145 @@
+//! Replicating the run state of a primary daemon to hot standby instances.
+//!
+//! Standby instances connect to the primary, which sends them a snapshot of
+//! the state of its jobs every `REPLICATION_INTERVAL`. The snapshots double as
+//! heartbeats: a standby that hasn't received one for its failover timeout
+//! takes over scheduling from the last replicated state.
+//!
+//! Each snapshot is a TOML document preceded by a header line:
+//!
+//! ```text
+//! snapshot <length>
+//! ```
+
+use crate::job::JobId;
+use crate::state::{RunState, Shared};
+use crate::statefile::JobState;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How often the primary sends its state to standby instances
+const REPLICATION_INTERVAL: Duration = Duration::from_secs(1);
+// Timeout of connecting to the primary and of waiting for the next snapshot
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+// Upper bound on the size of a snapshot, guarding against garbage
+const MAX_SNAPSHOT: usize = 64 << 20;
+
+/// Snapshot is the replicated state of the primary
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    /// id of the last run started, so run ids keep increasing on failover
+    next_run: u64,
+    #[serde(default)]
+    paused: Vec<JobId>,
+    #[serde(default)]
+    job: Vec<JobState>,
+}
+
+impl Snapshot {
+    fn capture(state: &RunState) -> Self {
+        let mut paused: Vec<JobId> = state.paused.iter().cloned().collect();
+        paused.sort();
+        Snapshot {
+            next_run: state.next_run,
+            paused,
+            job: state.job_states(),
+        }
+    }
+
+    /// apply updates the state of the registered jobs with the primary's
+    pub fn apply(self, state: &mut RunState) {
+        state.next_run = std::cmp::max(state.next_run, self.next_run);
+        state.paused = self
+            .paused
+            .into_iter()
+            .filter(|id| state.jobs.contains_key(id))
+            .collect();
+        state.restore(self.job);
+        state.dirty = true;
+    }
+}
+
+/// serve spawns a thread accepting standby instances on `listener`.
+/// Each standby is sent snapshots until the daemon shuts down.
+pub(crate) fn serve(listener: TcpListener, shared: Arc<Shared>) {
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let stream = match conn {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("Failed to accept a standby: {}", err);
+                    continue;
+                }
+            };
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().map_or("unknown".to_string(), |a| a.to_string());
+                info!("Standby {} connected", peer);
+                match replicate(stream, &shared) {
+                    Ok(()) => info!("Disconnected standby {}", peer),
+                    Err(err) => warn!("Lost standby {}: {}", peer, err),
+                }
+            });
+        }
+    });
+}
+
+/// replicate sends snapshots to the standby until the daemon shuts down
+fn replicate(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut state = shared.lock();
+    while !state.shutdown {
+        let payload = toml::to_string(&Snapshot::capture(&state))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        drop(state);
+
+        stream.write_all(format!("snapshot {}\n", payload.len()).as_bytes())?;
+        stream.write_all(payload.as_bytes())?;
+
+        let sent = std::time::Instant::now();
+        state = shared.lock();
+        while !state.shutdown && sent.elapsed() < REPLICATION_INTERVAL {
+            state = shared.wait(state, Some(REPLICATION_INTERVAL.saturating_sub(sent.elapsed())));
+        }
+    }
+    Ok(())
+}
+
+/// follow connects to the primary and passes the snapshots it sends to `f`,
+/// until `f` returns false or the connection fails
+pub(crate) fn follow<F: FnMut(Snapshot) -> bool>(primary: &str, mut f: F) -> io::Result<()> {
+    let addr = primary
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+    let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let len = match header.trim_end().strip_prefix("snapshot ").map(str::parse) {
+            Some(Ok(n)) if n <= MAX_SNAPSHOT => n,
+            _ => {
+                let msg = format!("invalid header `{}`", header.trim_end());
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        };
+
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        let snapshot = std::str::from_utf8(&payload)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            .and_then(|p| toml::from_str(p).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)))?;
+        if !f(snapshot) {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::job::JobId;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Environment variable holding the id of the run in the job's process
+pub const RUN_ID_ENV: &str = "XCROND_RUN_ID";
+/// Environment variable holding the name of the job
+pub const JOB_NAME_ENV: &str = "XCROND_JOB_NAME";
+/// Environment variable holding the occurrence being run, in RFC 3339
+/// format. Not set for manual runs.
+pub const SCHEDULED_TIME_ENV: &str = "XCROND_SCHEDULED_TIME";
+/// Environment variable holding the previous occurrence of the schedule, or
+/// when the job was registered if it's the first, in RFC 3339 format
+pub const PREV_RUN_ENV: &str = "XCROND_PREV_RUN";
+/// Environment variable holding what started the run, see `Trigger::name`
+pub const TRIGGER_ENV: &str = "XCROND_TRIGGER";
+/// Environment variable holding the path of the output of the upstream job,
+/// also given as standard input. Only set for runs started by a pipe.
+pub const INPUT_ENV: &str = "XCROND_INPUT";
+
+/// RunId identifies a single run of a job. Ids increase with every run and
+/// start from the time the instance started, in microseconds, so they stay
+/// unique across restarts.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RunId(u64);
+
+impl RunId {
+    pub fn new(id: u64) -> Self {
+        RunId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for RunId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "run {}", self.0)
+    }
+}
+
+/// RunStatus is how a job's process terminated
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "value")]
+pub enum RunStatus {
+    /// the process exited with the given code
+    Exited(i32),
+    /// the process was killed by the named signal
+    Signaled(String),
+    /// the process couldn't be started, with the reason why
+    FailedToStart(String),
+}
+
+impl RunStatus {
+    pub fn success(&self) -> bool {
+        *self == RunStatus::Exited(0)
+    }
+}
+
+/// Trigger is what started a run
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// the job's schedule was due
+    Scheduled,
+    /// the job was triggered out of band
+    Manual,
+    /// the job was run with the output of the job piping to it
+    Upstream,
+    /// the previous run exited with a non-zero code and was retried
+    Retry,
+}
+
+impl Trigger {
+    /// name returns the name of the trigger, `scheduled`, `manual`,
+    /// `upstream` or `retry`
+    pub fn name(self) -> &'static str {
+        match self {
+            Trigger::Scheduled => "scheduled",
+            Trigger::Manual => "manual",
+            Trigger::Upstream => "upstream",
+            Trigger::Retry => "retry",
+        }
+    }
+}
+
+/// ResourceUsage is the resources consumed by a job's process
+// TOML needs plain values to come before tables, the fields are ordered so
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// maximum resident set size, in kilobytes
+    pub max_rss: i64,
+    pub user_time: Duration,
+    pub system_time: Duration,
+}
+
+impl From<&libc::rusage> for ResourceUsage {
+    // the width of the rusage fields depends on the platform
+    #[allow(clippy::unnecessary_cast)]
+    fn from(r: &libc::rusage) -> Self {
+        let duration = |t: libc::timeval| {
+            Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+        };
+        ResourceUsage {
+            max_rss: r.ru_maxrss as i64,
+            user_time: duration(r.ru_utime),
+            system_time: duration(r.ru_stime),
+        }
+    }
+}
+
+/// JobRunResult records a single run of a job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRunResult {
+    pub run: RunId,
+    pub job: JobId,
+    pub name: String,
+    /// pid of the job's process, 0 if it couldn't be started
+    pub pid: i32,
+    pub started: DateTime<Local>,
+    pub finished: DateTime<Local>,
+    pub trigger: Trigger,
+    pub status: RunStatus,
+    /// resources used by the process, if it was started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ResourceUsage>,
+}
@@ -0,0 +1,63 @@
+//! Versioning of the files xcrond reads.
+//!
+//! Every file format carries a top level `version` key. When the format
+//! changes, its version is bumped and a migration upgrading documents from
+//! the previous version is appended to the format's migration list, so files
+//! written for older releases keep loading.
+
+use crate::error::{Result, XcrondError};
+use std::path::Path;
+use toml::Value;
+
+/// Migration upgrades a document by exactly one version
+pub type Migration = fn(&mut Value) -> Result<()>;
+
+/// Migrations of the Jobfile format, the migration at index N upgrades
+/// version N to N + 1. The current version is the number of migrations.
+pub const JOBFILE_MIGRATIONS: &[Migration] = &[
+    // 0 -> 1: unversioned Jobfiles have the same layout as version 1
+    |_| Ok(()),
+];
+
+/// Migrations of the state file format, see `JOBFILE_MIGRATIONS`
+pub const STATE_MIGRATIONS: &[Migration] = &[
+    // 0 -> 1: state files without a version have the same layout as version 1
+    |_| Ok(()),
+];
+
+/// migrate upgrades `doc` to version `migrations.len()`, stamping the version.
+/// Documents without a version are considered to be version 0.
+/// Fails if the document was written by a newer, unsupported release.
+pub fn migrate(doc: &mut Value, migrations: &[Migration], path: &Path) -> Result<()> {
+    let current = migrations.len() as u32;
+    let version = match doc.get("version") {
+        None => 0,
+        Some(v) => match v.as_integer() {
+            Some(v) if v >= 0 => v as u32,
+            _ => {
+                return Err(XcrondError::InvalidVersion {
+                    path: path.to_path_buf(),
+                    version: v.to_string(),
+                });
+            }
+        },
+    };
+
+    if version > current {
+        return Err(XcrondError::UnsupportedVersion {
+            path: path.to_path_buf(),
+            version,
+            supported: current,
+        });
+    }
+
+    for (v, m) in migrations.iter().enumerate().skip(version as usize) {
+        debug!("Migrating {} from version {} to {}", path.display(), v, v + 1);
+        m(doc)?;
+    }
+
+    if let Some(t) = doc.as_table_mut() {
+        t.insert("version".to_string(), Value::Integer(i64::from(current)));
+    }
+    Ok(())
+}
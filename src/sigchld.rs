@@ -0,0 +1,157 @@
+//! SIGCHLD notifications for the reaper, using the self-pipe trick.
+//!
+//! A process wide SIGCHLD handler writes a byte to the pipe of every
+//! registered `ChildSignal`, so the reaper can block until a child exits
+//! and only then collect it with a non blocking waitpid. Signal handlers
+//! installed before ours are still called.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Once;
+
+// Maximum number of `ChildSignal` alive at the same time
+const SLOTS: usize = 16;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicI32 = AtomicI32::new(-1);
+// Write ends of the registered pipes, -1 for free slots
+static LISTENERS: [AtomicI32; SLOTS] = [EMPTY; SLOTS];
+
+static INSTALL: Once = Once::new();
+// Handler that was installed before ours, only written before installing ours
+static mut PREVIOUS: Option<libc::sigaction> = None;
+
+/// ChildSignal is notified every time the process receives SIGCHLD
+pub(crate) struct ChildSignal {
+    read: RawFd,
+    write: RawFd,
+    slot: usize,
+}
+
+impl ChildSignal {
+    pub fn new() -> io::Result<Self> {
+        let mut installed = Ok(());
+        INSTALL.call_once(|| installed = install());
+        installed?;
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = (fds[0], fds[1]);
+        for fd in &fds {
+            unsafe {
+                libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK);
+            }
+        }
+
+        for (slot, l) in LISTENERS.iter().enumerate() {
+            if l.compare_exchange(-1, write, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                return Ok(ChildSignal { read, write, slot });
+            }
+        }
+
+        unsafe {
+            libc::close(read);
+            libc::close(write);
+        }
+        Err(io::Error::other("too many SIGCHLD listeners"))
+    }
+
+    /// wait blocks until SIGCHLD is received.
+    /// Signals received since the last wait make it return immediately.
+    pub fn wait(&self) -> io::Result<()> {
+        let mut fd = libc::pollfd {
+            fd: self.read,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let n = unsafe { libc::poll(&mut fd, 1, -1) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+
+        // Empty the pipe, a single check handles any number of signals
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(self.read, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+        Ok(())
+    }
+}
+
+impl Drop for ChildSignal {
+    fn drop(&mut self) {
+        LISTENERS[self.slot].store(-1, Ordering::SeqCst);
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+/// install sets our SIGCHLD handler, keeping the previous one around
+fn install() -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_NOCLDSTOP;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut previous: libc::sigaction = mem::zeroed();
+        if libc::sigaction(libc::SIGCHLD, &action, &mut previous) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        PREVIOUS = Some(previous);
+    }
+    Ok(())
+}
+
+extern "C" fn handler(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    // Only async signal safe calls in here
+    let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+    for l in LISTENERS.iter() {
+        let fd = l.load(Ordering::SeqCst);
+        if fd >= 0 {
+            unsafe { libc::write(fd, &1u8 as *const u8 as *const libc::c_void, 1) };
+        }
+    }
+
+    if let Some(previous) = unsafe { PREVIOUS } {
+        let h = previous.sa_sigaction;
+        if h != libc::SIG_DFL && h != libc::SIG_IGN {
+            unsafe {
+                if previous.sa_flags & libc::SA_SIGINFO != 0 {
+                    let f: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                        mem::transmute(h);
+                    f(sig, info, ctx);
+                } else {
+                    let f: extern "C" fn(libc::c_int) = mem::transmute(h);
+                    f(sig);
+                }
+            }
+        }
+    }
+
+    unsafe { *errno_location() = errno };
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__error()
+}
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno()
+}
@@ -0,0 +1,189 @@
+//! Simulation of the schedule of the registered jobs.
+//!
+//! The simulation replays the scheduling of a copy of the jobs on a
+//! `ManualClock`, jumping straight from one occurrence to the next, and
+//! records the occurrences instead of running them. It answers "what would
+//! run, and when" without waiting nor executing anything, either from the
+//! current state of the scheduler or over any past or future date range.
+
+use crate::clock::{Clock, ManualClock, SharedClock};
+use crate::job::JobId;
+use crate::state::RunState;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Firing is an occurrence of a job found by the simulation
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Firing {
+    pub time: DateTime<Local>,
+    pub job: JobId,
+    pub name: String,
+}
+
+/// simulate returns the occurrences of the jobs of `state` due until
+/// `until`, in the order they would be dispatched, up to `limit` of them.
+/// Paused jobs are left out.
+pub(crate) fn simulate(state: &RunState, until: DateTime<Local>, limit: usize) -> Vec<Firing> {
+    let clock = Arc::new(ManualClock::new(state.clock.now()));
+    let mut sim = scratch(&clock, state);
+    sim.paused = state.paused.clone();
+    sim.launched = state.launched.clone();
+    for j in sim.jobs.values() {
+        sim.queue.enqueue(j.clone());
+    }
+    run(sim, &clock, until, limit)
+}
+
+/// replay returns the occurrences of the jobs of `state` from `from` until
+/// `until`, up to `limit` of them, as if the jobs had been scheduled at
+/// `from`. The current state of the jobs, e.g. whether they're paused,
+/// doesn't matter: the schedules alone are replayed.
+pub(crate) fn replay(
+    state: &RunState,
+    from: DateTime<Local>,
+    until: DateTime<Local>,
+    limit: usize,
+) -> Vec<Firing> {
+    let clock = Arc::new(ManualClock::new(from));
+    let mut sim = scratch(&clock, state);
+    // Occurrences due right at `from` are included
+    let start = from - chrono::Duration::nanoseconds(1);
+    for j in sim.jobs.values_mut() {
+        match j.next_after(start) {
+            Some(next) => {
+                j.set_prev(start);
+                j.set_next(next);
+                sim.queue.enqueue(j.clone());
+            }
+            None => debug!("[{}] No occurrence after {}", j, from),
+        }
+    }
+    run(sim, &clock, until, limit)
+}
+
+/// scratch returns a run state holding copies of the jobs of `state` and
+/// telling the time with `clock`. Observers, children and the other side
+/// effects are left out.
+fn scratch(clock: &Arc<ManualClock>, state: &RunState) -> RunState {
+    let mut sim = RunState::default();
+    sim.clock = SharedClock::new(clock.clone());
+    sim.jobs = state.jobs.clone();
+    sim
+}
+
+/// run dispatches the occurrences queued in `sim` until `until`, moving the
+/// clock from one to the next
+fn run(
+    mut sim: RunState,
+    clock: &ManualClock,
+    until: DateTime<Local>,
+    limit: usize,
+) -> Vec<Firing> {
+    let mut firings = vec![];
+    while step(&mut sim, clock, until, limit, &mut firings) {}
+    firings
+}
+
+/// step moves the clock to the next occurrence queued in `sim` and dispatches
+/// the jobs due then, recording them in `firings`. Returns false once the
+/// next occurrence is past `until` or `limit` firings are recorded.
+pub(crate) fn step(
+    sim: &mut RunState,
+    clock: &ManualClock,
+    until: DateTime<Local>,
+    limit: usize,
+    firings: &mut Vec<Firing>,
+) -> bool {
+    let t = match sim.next_time() {
+        Some(t) if t <= until && firings.len() < limit => t,
+        _ => return false,
+    };
+    clock.set(std::cmp::max(t, clock.now()));
+
+    let top = match sim.queue.dequeue() {
+        Some(e) => e,
+        None => return false,
+    };
+    for j in sim.fair_order(top.into_jobs()) {
+        if !sim.paused.contains(&j.get_id()) && firings.len() < limit {
+            firings.push(Firing {
+                time: t,
+                job: j.get_id(),
+                name: j.get_name().to_string(),
+            });
+            sim.launched.insert(j.get_id(), t);
+        }
+        sim.requeue(j);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::JobSpec;
+    use crate::state::Shared;
+    use chrono::TimeZone;
+    use chrono_tz::Tz;
+
+    fn at(secs: i64) -> DateTime<Local> {
+        Local.timestamp_opt(1_500_000_000, 0).unwrap() + chrono::Duration::seconds(secs)
+    }
+
+    #[test]
+    fn records_occurrences_until_the_end() {
+        let mut shared = Shared::new(None, None);
+        shared.set_clock(Arc::new(ManualClock::new(at(0))));
+        shared
+            .add_job(JobSpec::new("minute", "/bin/true", "0 * * * * *"))
+            .unwrap();
+        shared
+            .add_job(JobSpec::new("half", "/bin/true", "0/30 * * * * *"))
+            .unwrap();
+
+        let firings = simulate(&shared.lock(), at(120), 100);
+        let got: Vec<(i64, &str)> = firings
+            .iter()
+            .map(|f| ((f.time - at(0)).num_seconds(), f.name.as_str()))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                (30, "half"),
+                (60, "minute"),
+                (60, "half"),
+                (90, "half"),
+                (120, "minute"),
+                (120, "half")
+            ]
+        );
+
+        assert_eq!(simulate(&shared.lock(), at(120), 2).len(), 2);
+    }
+
+    #[test]
+    fn replays_any_date_range() {
+        let berlin: Tz = "Europe/Berlin".parse().unwrap();
+        let day = |d: u32, h: u32, m: u32| {
+            berlin
+                .with_ymd_and_hms(2021, 3, d, h, m, 0)
+                .unwrap()
+                .with_timezone(&Local)
+        };
+        let mut shared = Shared::new(Some(berlin), None);
+        shared.set_clock(Arc::new(ManualClock::new(at(0))));
+        let id = shared
+            .add_job(JobSpec::new("night", "/bin/true", "0 30 2 * * *"))
+            .unwrap();
+        shared.pause(id);
+        let next = shared.lock().jobs[&id].get_next();
+
+        // 2:30 is skipped on the 28th, when clocks go forward from 2:00 to 3:00
+        let firings = replay(&shared.lock(), day(27, 2, 30), day(30, 2, 30), 100);
+        let got: Vec<DateTime<Local>> = firings.iter().map(|f| f.time).collect();
+        assert_eq!(got, vec![day(27, 2, 30), day(29, 2, 30), day(30, 2, 30)]);
+        // The state of the scheduler is left untouched
+        assert_eq!(shared.lock().jobs[&id].get_next(), next);
+    }
+}
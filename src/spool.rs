@@ -0,0 +1,259 @@
+//! Compatibility with the spool of the system cron.
+//!
+//! In this mode xcrond runs the jobs of the users' crontabs, in
+//! `/var/spool/cron/crontabs`, and of the system crontabs of `/etc/cron.d`,
+//! so it can replace crond without migrating them first. The spool is
+//! checked periodically and the jobs are kept in sync with it: jobs whose
+//! entry is unchanged keep their state, the others are replaced.
+//!
+//! The entries of other users than the daemon's run as their user, which
+//! requires the daemon to be started as root. The users' crontabs are
+//! subject to `xcrond.allow` and `xcrond.deny`.
+
+use crate::access::{Access, ALLOW_FILE, DENY_FILE};
+use crate::handle::CronHandle;
+use crate::import;
+use crate::job::{JobInfo, JobSpec};
+use crate::login;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Default directory of the users' crontabs
+pub const CRONTABS_DIR: &str = "/var/spool/cron/crontabs";
+/// Default directory of the system crontabs
+pub const CRON_D_DIR: &str = "/etc/cron.d";
+
+// Metadata key holding the crontab a job was loaded from
+const SPOOL_KEY: &str = "spool";
+
+/// Spool is the set of crontabs of the system cron
+pub struct Spool {
+    crontabs: PathBuf,
+    cron_d: PathBuf,
+    allow_file: PathBuf,
+    deny_file: PathBuf,
+}
+
+impl Spool {
+    pub fn new<P: Into<PathBuf>, Q: Into<PathBuf>>(crontabs: P, cron_d: Q) -> Self {
+        Spool {
+            crontabs: crontabs.into(),
+            cron_d: cron_d.into(),
+            allow_file: PathBuf::from(ALLOW_FILE),
+            deny_file: PathBuf::from(DENY_FILE),
+        }
+    }
+
+    /// access sets the allow and deny files of the users' crontabs, read
+    /// every time the spool is loaded
+    pub fn access<P: Into<PathBuf>, Q: Into<PathBuf>>(mut self, allow_file: P, deny_file: Q) -> Self {
+        self.allow_file = allow_file.into();
+        self.deny_file = deny_file.into();
+        self
+    }
+
+    /// load returns the jobs of the crontabs, set to run as their user when
+    /// it isn't the daemon's. Crontabs that can't be read are skipped with
+    /// an error.
+    pub fn load(&self) -> Vec<JobSpec> {
+        let me = match login::current() {
+            Ok(account) => account.name,
+            Err(err) => {
+                error!("Failed to look up the daemon's user, not loading the spool: {}", err);
+                return vec![];
+            }
+        };
+
+        let access = match Access::load(&self.allow_file, &self.deny_file) {
+            Ok(a) => a,
+            Err(err) => {
+                error!("{}, not loading the users' crontabs", err);
+                Access::deny_all()
+            }
+        };
+
+        let mut jobs = vec![];
+        for path in files(&self.crontabs) {
+            let user = match path.file_name() {
+                Some(n) => n.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            if !access.permits(&user) {
+                warn!("{}: {} isn't allowed to have jobs, skipped", path.display(), user);
+                continue;
+            }
+            if let Some(content) = read(&path) {
+                let mut specs = import::crontab(&content, &user);
+                if user != me {
+                    specs.iter_mut().for_each(|spec| spec.user = Some(user.clone()));
+                }
+                jobs.extend(spooled(&path, specs));
+            }
+        }
+
+        for path in files(&self.cron_d) {
+            let content = match read(&path) {
+                Some(c) => c,
+                None => continue,
+            };
+            let source = format!("cron.d/{}", path.file_name().unwrap_or_default().to_string_lossy());
+            let mut specs = vec![];
+            for (user, mut spec) in import::system_crontab(&content, &source) {
+                if user != me {
+                    spec.user = Some(user);
+                }
+                specs.push(spec);
+            }
+            jobs.extend(spooled(&path, specs));
+        }
+        jobs
+    }
+
+    /// watch spawns a thread checking the spool for changes every
+    /// `interval`, and keeping the jobs of `handle` in sync with it
+    pub fn watch(self, handle: CronHandle, interval: Duration) {
+        thread::spawn(move || {
+            let mut last = None;
+            loop {
+                let current = self.fingerprint();
+                if last.as_ref() != Some(&current) {
+                    let (added, removed) = sync(&handle, self.load());
+                    if added > 0 || removed > 0 {
+                        info!("Spool changed: {} jobs added, {} removed", added, removed);
+                    }
+                    last = Some(current);
+                }
+                thread::sleep(interval);
+            }
+        });
+    }
+
+    /// fingerprint identifies the state of the spool: the crontabs, when
+    /// they were last modified and their size. Errors are logged when the
+    /// spool is loaded.
+    fn fingerprint(&self) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+        list(&self.crontabs)
+            .unwrap_or_default()
+            .into_iter()
+            .chain(list(&self.cron_d).unwrap_or_default())
+            .chain(vec![self.allow_file.clone(), self.deny_file.clone()])
+            .map(|p| {
+                let meta = fs::metadata(&p).ok();
+                let modified = meta.as_ref().and_then(|m| m.modified().ok());
+                let len = meta.map_or(0, |m| m.len());
+                (p, modified, len)
+            })
+            .collect()
+    }
+}
+
+/// sync replaces the jobs of `handle` loaded from the spool by `specs`.
+/// Jobs with an identical spec are kept as they are. Returns the number of
+/// jobs added and removed.
+pub fn sync(handle: &CronHandle, specs: Vec<JobSpec>) -> (usize, usize) {
+    let current: Vec<JobInfo> = handle
+        .jobs()
+        .into_iter()
+        .filter(|j| j.metadata.contains_key(SPOOL_KEY))
+        .collect();
+    let same = |j: &JobInfo, s: &JobSpec| {
+        j.name == s.name && j.cmd == s.cmd && j.schedule == s.schedule && j.metadata == s.metadata
+    };
+
+    let mut removed = 0;
+    for j in current.iter().filter(|j| !specs.iter().any(|s| same(j, s))) {
+        if handle.remove_job(j.id) {
+            removed += 1;
+        }
+    }
+    let mut added = 0;
+    for spec in specs.into_iter().filter(|s| !current.iter().any(|j| same(j, s))) {
+        let name = spec.name.clone();
+        match handle.add_job(spec) {
+            Ok(_) => added += 1,
+            Err(err) => error!("[{}] Failed to load from the spool: {}", name, err),
+        }
+    }
+    (added, removed)
+}
+
+/// spooled marks the jobs as loaded from the crontab at `path`
+fn spooled(path: &Path, mut specs: Vec<JobSpec>) -> Vec<JobSpec> {
+    for spec in &mut specs {
+        spec.metadata.insert(SPOOL_KEY.to_string(), path.display().to_string());
+    }
+    specs
+}
+
+/// files returns the crontabs of `dir`, sorted, logging why the directory
+/// can't be read
+fn files(dir: &Path) -> Vec<PathBuf> {
+    list(dir).unwrap_or_else(|err| {
+        error!("Failed to read {}: {}", dir.display(), err);
+        vec![]
+    })
+}
+
+/// list returns the crontabs of `dir`, sorted. Like cron, files whose name
+/// has a dot, such as editor backups and package manager leftovers, are
+/// ignored.
+fn list(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| !n.contains('.') && !n.ends_with('~'))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn read(path: &Path) -> Option<String> {
+    match fs::read_to_string(path) {
+        Ok(c) => Some(c),
+        Err(err) => {
+            error!("Failed to read {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cron;
+
+    #[test]
+    fn syncs_the_changed_jobs() {
+        let cron = Cron::builder().build().unwrap();
+        let handle = cron.handle();
+        let own = handle.add_job(JobSpec::new("own", "/bin/true", "0 0 * * * *")).unwrap();
+
+        let spool = |content: &str| spooled(Path::new("alice"), import::crontab(content, "alice"));
+        assert_eq!(sync(&handle, spool("0 1 * * * /bin/a\n0 2 * * * /bin/b\n")), (2, 0));
+        let b = handle.jobs().iter().find(|j| j.cmd == "/bin/b").map(|j| j.id);
+
+        // b is unchanged and keeps its id, a is replaced by c
+        assert_eq!(sync(&handle, spool("0 3 * * * /bin/c\n0 2 * * * /bin/b\n")), (1, 1));
+        let jobs = handle.jobs();
+        assert_eq!(jobs.len(), 3);
+        assert!(jobs.iter().any(|j| j.id == own));
+        assert_eq!(jobs.iter().find(|j| j.cmd == "/bin/b").map(|j| j.id), b);
+        assert!(jobs.iter().all(|j| j.cmd != "/bin/a"));
+
+        assert_eq!(sync(&handle, vec![]), (0, 2));
+        assert_eq!(handle.jobs().len(), 1);
+    }
+}
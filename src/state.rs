@@ -0,0 +1,2072 @@
+use crate::blackout::Blackout;
+use crate::cgroup::Cgroup;
+use crate::clock::{Clock, SharedClock};
+use crate::cluster::{ClusterLock, LeaderElection, Membership, Shard};
+use crate::config::FRAGMENT_KEY;
+use crate::config::Jobfile;
+use crate::error::{Result, XcrondError};
+use crate::handle::Reload;
+use crate::event::EventQueue;
+use crate::job::{Job, JobId, JobInfo, JobSpec, MisfirePolicy, ShutdownPolicy};
+use crate::journal::{self, Journal};
+use crate::mail::{self, Capture, Mailer};
+use crate::namespace::Namespace;
+use crate::observer::{MissReason, SchedulerObserver};
+use crate::pipe;
+use crate::ratelimit::TokenBucket;
+use crate::run::{JobRunResult, ResourceUsage, RunId, RunStatus, Trigger};
+use crate::simulate::{self, Firing};
+use crate::statefile::JobState;
+use chrono::{DateTime, Local, NaiveDate};
+use chrono_tz::Tz;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::time::{Duration, Instant};
+
+// How long killed jobs are waited for when shutting down
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+// Default bound on the time spent waiting for jobs when shutting down
+const MAX_SHUTDOWN_WAIT: Duration = Duration::from_secs(60 * 60);
+// Longest delay between the retries of a failed run
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+// How long jobs sent SIGTERM for exceeding their timeout have to exit
+// before they are killed
+const TIMEOUT_GRACE: Duration = Duration::from_secs(10);
+// Delay before retrying a launch that failed with a transient error,
+// doubled on every attempt
+const SPAWN_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Number of attempts at launching a run before giving up
+const MAX_SPAWN_ATTEMPTS: u32 = 5;
+
+/// State shared between the run loop, the reaper and handles held by other threads
+pub(crate) struct Shared {
+    state: Mutex<RunState>,
+    cond: Condvar,
+    /// tells the time and wakes up the run loop when it comes
+    pub clock: SharedClock,
+    timezone: Option<Tz>,
+    pub max_concurrent: Option<usize>,
+    /// maximum time between two iterations of the run loop
+    pub heartbeat: Option<Duration>,
+    /// how long jobs with the `wait` shutdown policy are waited for
+    pub max_shutdown_wait: Duration,
+    /// Jobfile the jobs are loaded from, if any
+    pub config_path: Option<PathBuf>,
+    /// directory of Jobfile fragments the jobs are loaded from, if any
+    pub config_dir: Option<PathBuf>,
+    /// directory the output of the runs is logged to, the daemon's own
+    /// output if not set
+    pub log_dir: Option<PathBuf>,
+    /// directory the cgroups of the runs are created in, cgroup limits
+    /// are ignored if not set
+    pub cgroup_dir: Option<PathBuf>,
+    /// claims the occurrences of `singleton_cluster` jobs
+    pub cluster_lock: Option<Arc<dyn ClusterLock>>,
+    /// elects the instance running jobs, with the name of the lease
+    pub election: Option<(Arc<dyn LeaderElection>, String)>,
+    /// registers the instance in its shard group, with the name of the group
+    pub sharding: Option<(Arc<dyn Membership>, String)>,
+    /// primary followed by this standby instance, with the time after which
+    /// it takes over if it stops hearing from the primary
+    pub standby: Option<(String, Duration)>,
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Shared::new(None, None)
+    }
+}
+
+/// Child is a spawned job process that hasn't been reaped yet
+pub(crate) struct Child {
+    pub run: RunId,
+    pub job: JobId,
+    pub name: String,
+    pub trigger: Trigger,
+    pub shutdown_policy: ShutdownPolicy,
+    pub started: DateTime<Local>,
+    /// file capturing the output of the run, if the job pipes to another
+    pub output: Option<PathBuf>,
+    /// output of the upstream run given as input, if started by a pipe
+    pub input: Option<PathBuf>,
+    /// number of the retry of a failed run, 0 for the first run
+    pub retry: u32,
+    /// file capturing the output of the run, if it's mailed
+    pub mail: Option<Capture>,
+    /// cgroup of the run, if the job has cgroup limits
+    pub cgroup: Option<Cgroup>,
+    /// time the run may take before it is terminated
+    pub timeout: Option<Duration>,
+    /// when the run was sent SIGTERM, for exceeding its timeout or being
+    /// replaced by a new run, and whether it was killed since
+    pub terminated: Option<(DateTime<Local>, bool)>,
+}
+
+impl Child {
+    /// deadline returns when the run has to be signaled next, for exceeding
+    /// its timeout or not exiting once terminated
+    fn deadline(&self) -> Option<DateTime<Local>> {
+        let after = |t: DateTime<Local>, d: Duration| chrono::Duration::from_std(d).ok().map(|d| t + d);
+        match self.terminated {
+            None => after(self.started, self.timeout?),
+            Some((t, false)) => after(t, TIMEOUT_GRACE),
+            Some((_, true)) => None,
+        }
+    }
+}
+
+/// Retry is a launch attempted again after a transient spawn failure, or a
+/// run retried after exiting with a non-zero code
+pub(crate) struct Retry {
+    pub due: Instant,
+    pub job: Job,
+    pub trigger: Trigger,
+    /// number of the next attempt, the first one being 1
+    pub attempt: u32,
+    /// number of the retry of a failed run, 0 for the first run
+    pub retry: u32,
+    /// output of the upstream run given as input, if started by a pipe
+    pub input: Option<PathBuf>,
+    /// id reserved for the run, if it was triggered manually
+    pub run: Option<RunId>,
+}
+
+#[derive(Default)]
+pub(crate) struct RunState {
+    /// set once a shutdown has been requested
+    pub shutdown: bool,
+    /// grace period given to running jobs to exit when shutting down,
+    /// set if they have to be terminated
+    pub terminate: Option<Duration>,
+    /// set when the run loop stops to hand over to a new instance,
+    /// running jobs are left alone
+    pub handoff: bool,
+    /// true while the run loop is executing
+    pub active: bool,
+    /// spawned children not yet reaped, by pid
+    pub children: HashMap<i32, Child>,
+    /// result of the last completed run of each job
+    pub results: HashMap<JobId, JobRunResult>,
+    /// when each job was last launched, to dispatch the jobs that waited
+    /// the longest first
+    pub launched: HashMap<JobId, DateTime<Local>>,
+    /// wall-clock time taken by the runs of each job with a daily budget,
+    /// on the day they last finished
+    pub spent: HashMap<JobId, (NaiveDate, Duration)>,
+    /// pending occurrences of the registered jobs
+    pub queue: EventQueue,
+    /// registered jobs by id
+    pub jobs: HashMap<JobId, Job>,
+    /// registered jobs by their definition, see `Job::definition`
+    pub definitions: HashMap<String, JobId>,
+    /// specs of the jobs loaded from the Jobfile and its fragments
+    pub configured: HashMap<JobId, JobSpec>,
+    /// limits of the namespaces, by name
+    pub namespaces: HashMap<String, Namespace>,
+    /// jobs whose occurrences are skipped until resumed
+    pub paused: HashSet<JobId>,
+    /// jobs running at an interval, queued again once their run completes
+    pub awaiting: HashSet<JobId>,
+    /// blackout windows of every job
+    pub blackouts: Vec<Blackout>,
+    /// addresses the output of the runs is mailed to, unless the job sets
+    /// its own
+    pub mailto: Option<String>,
+    /// how the output of the runs is mailed
+    pub mailer: Mailer,
+    /// limits the rate of job launches, if set
+    pub start_rate: Option<TokenBucket>,
+    /// launches to retry after transient spawn failures
+    pub retries: Vec<Retry>,
+    /// jobs to be run out of band by the run loop, with the id reserved
+    /// for their run
+    pub triggered: Vec<(JobId, RunId)>,
+    /// id reserved for the run being launched, given to it instead of a new
+    /// one
+    pub reserved: Option<RunId>,
+    /// clients waiting for the result of a run
+    pub waiters: HashMap<RunId, Vec<Sender<Option<JobRunResult>>>>,
+    /// jobs with an occurrence waiting for their previous run to finish,
+    /// see `OverlapPolicy::Queue`
+    pub queued: HashSet<JobId>,
+    /// jobs to be run by the run loop with the output of the job piping to
+    /// them
+    pub piped: Vec<(JobId, PathBuf)>,
+    /// observers notified of scheduling events
+    pub observers: Vec<Arc<dyn SchedulerObserver>>,
+    /// tells the time
+    pub clock: SharedClock,
+    /// set when the persisted state of the jobs is out of date
+    pub dirty: bool,
+    /// write-ahead journal of the runs of journaled jobs, if configured.
+    /// Locked on its own so runs are journaled with the state unlocked.
+    pub journal: Option<Arc<Mutex<Journal>>>,
+    /// until when this instance holds the leader lease, if it does
+    pub leader_until: Option<Instant>,
+    /// jobs run by this instance among the members of its shard group,
+    /// set once the group is known
+    pub shard: Option<Shard>,
+    /// set while this standby instance follows the primary, jobs aren't
+    /// scheduled until it takes over
+    pub standby: bool,
+    next_id: u64,
+    /// id of the last run started
+    pub next_run: u64,
+}
+
+impl Shared {
+    pub fn new(timezone: Option<Tz>, max_concurrent: Option<usize>) -> Self {
+        let clock = SharedClock::default();
+        Shared {
+            state: Mutex::new(RunState {
+                clock: clock.clone(),
+                next_run: first_run(),
+                ..Default::default()
+            }),
+            cond: Condvar::new(),
+            clock,
+            timezone,
+            max_concurrent,
+            heartbeat: None,
+            max_shutdown_wait: MAX_SHUTDOWN_WAIT,
+            config_path: None,
+            config_dir: None,
+            log_dir: None,
+            cgroup_dir: None,
+            cluster_lock: None,
+            election: None,
+            sharding: None,
+            standby: None,
+        }
+    }
+
+    /// set_clock makes the instance tell the time with `clock`.
+    /// To be called before any job is registered.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = SharedClock::new(clock);
+        self.lock().clock = self.clock.clone();
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, RunState> {
+        self.state.lock().unwrap()
+    }
+
+    /// notify wakes up every thread waiting on the shared state
+    pub fn notify(&self) {
+        self.cond.notify_all();
+        self.clock.wake();
+    }
+
+    /// wait_until blocks the run loop until the deadline or until notified.
+    /// Without a deadline, it blocks until notified.
+    pub fn wait_until<'a>(
+        &'a self,
+        state: MutexGuard<'a, RunState>,
+        deadline: Option<DateTime<Local>>,
+    ) -> MutexGuard<'a, RunState> {
+        drop(state);
+        self.clock.sleep_until(deadline);
+        self.lock()
+    }
+
+    /// wait blocks until notified, or until the timeout elapses if one is given
+    pub fn wait<'a>(
+        &self,
+        state: MutexGuard<'a, RunState>,
+        timeout: Option<Duration>,
+    ) -> MutexGuard<'a, RunState> {
+        match timeout {
+            Some(t) => self.cond.wait_timeout(state, t).unwrap().0,
+            None => self.cond.wait(state).unwrap(),
+        }
+    }
+
+    /// add_job registers a new job and schedules its next occurrence.
+    /// Returns the id of the registered job.
+    pub fn add_job(&self, spec: JobSpec) -> Result<JobId> {
+        let mut state = self.lock();
+        let id = state.register(spec, self.timezone)?;
+        self.notify();
+        Ok(id)
+    }
+
+    /// add_jobs registers a batch of jobs under a single lock and wakeup.
+    /// Returns the result of registering each job, in order.
+    pub fn add_jobs(&self, specs: Vec<JobSpec>) -> Vec<Result<JobId>> {
+        let mut state = self.lock();
+        let ids = specs
+            .into_iter()
+            .map(|spec| state.register(spec, self.timezone))
+            .collect();
+        self.notify();
+        ids
+    }
+
+    /// remove_job unregisters a job, dropping all of its pending occurrences.
+    /// Returns false if the job wasn't registered.
+    pub fn remove_job(&self, id: JobId) -> bool {
+        let removed = self.lock().unregister(id);
+        if removed {
+            self.notify();
+        }
+        removed
+    }
+
+    /// configure registers the jobs of the Jobfile and its fragments,
+    /// replacing the jobs of the previous configuration. Jobs are matched by
+    /// name: unchanged jobs are kept as they are, changed ones are updated
+    /// in place and the others are unregistered.
+    pub fn configure(&self, config: Jobfile) -> Reload {
+        let mut reload = Reload::default();
+        let mut state = self.lock();
+        // Limits apply to the jobs as they are registered
+        for ns in config.namespace {
+            state.namespaces.insert(ns.name.clone(), ns);
+        }
+        state.blackouts = config.blackout;
+        state.mailto = config.mailto;
+
+        let mut previous = std::mem::take(&mut state.configured);
+        let mut matched = vec![];
+        let mut changed = vec![];
+        for spec in config.job {
+            let id = previous
+                .iter()
+                .find(|(id, old)| old.name == spec.name && spec.id.is_none_or(|i| i == **id))
+                .map(|(id, _)| *id);
+            match id.and_then(|id| previous.remove(&id).map(|old| (id, old))) {
+                Some((id, old)) => matched.push((spec, Some((id, old)))),
+                None => matched.push((spec, None)),
+            }
+        }
+
+        // Removed first, their definitions may be taken over by new jobs
+        for id in previous.keys() {
+            if state.unregister(*id) {
+                reload.removed += 1;
+            }
+        }
+        for (spec, old) in matched {
+            match old {
+                Some((id, old)) if old == spec => {
+                    state.configured.insert(id, spec);
+                }
+                Some((id, old)) => match state.replace(id, spec.clone(), self.timezone) {
+                    Ok(()) => {
+                        info!("[{} {}] Updated job", spec.name, id);
+                        state.configured.insert(id, spec);
+                        changed.push(id);
+                        reload.updated += 1;
+                    }
+                    Err(err) => {
+                        error!("{}", err);
+                        state.configured.insert(id, old);
+                    }
+                },
+                None => match state.register(spec.clone(), self.timezone) {
+                    Ok(id) => {
+                        state.configured.insert(id, spec);
+                        changed.push(id);
+                        reload.added += 1;
+                    }
+                    Err(err) => error!("{}", err),
+                },
+            }
+        }
+        // The job is kept, the program, user or group may be added before
+        // it's due
+        for j in changed.iter().filter_map(|id| state.jobs.get(id)) {
+            if let Err(err) = j.credentials() {
+                error!("[{}] Can't run: {}", j.get_name(), err);
+            }
+            if self.cgroup_dir.is_none() && !j.get_cgroup().is_empty() {
+                warn!("[{}] cgroup limits ignored, no cgroup directory is set", j.get_name());
+            }
+            if !j.is_login_shell() && j.find_program().is_none() {
+                let err = XcrondError::CommandNotFound {
+                    name: j.get_name().to_string(),
+                    program: j.get_program().to_string_lossy().into_owned(),
+                };
+                error!("{}", err);
+            }
+        }
+        state.dirty = true;
+        drop(state);
+        self.notify();
+        reload
+    }
+
+    /// trigger asks the run loop to run the job now, without affecting its schedule.
+    /// Returns the id the run will have, or None if the job isn't registered.
+    pub fn trigger(&self, id: JobId) -> Option<RunId> {
+        let mut state = self.lock();
+        if !state.jobs.contains_key(&id) {
+            return None;
+        }
+
+        state.next_run += 1;
+        let run = RunId::new(state.next_run);
+        state.triggered.push((id, run));
+        self.notify();
+        Some(run)
+    }
+
+    /// wait_run blocks until the run is recorded and returns its result.
+    /// Returns None if it never ran, e.g. its job was removed first, or if
+    /// it isn't known: the last run of its job is another one.
+    pub fn wait_run(&self, run: RunId) -> Option<JobRunResult> {
+        let mut state = self.lock();
+        if !state.is_pending(run) {
+            return state.results.values().find(|r| r.run == run).cloned();
+        }
+        if state.shutdown && !state.children.values().any(|c| c.run == run) {
+            // Stopping, it won't start
+            return None;
+        }
+        let (tx, rx) = channel();
+        state.waiters.entry(run).or_default().push(tx);
+        drop(state);
+        rx.recv().ok().flatten()
+    }
+
+    /// pause skips the job's occurrences until it is resumed.
+    /// Returns false if the job isn't registered.
+    pub fn pause(&self, id: JobId) -> bool {
+        let mut state = self.lock();
+        if !state.jobs.contains_key(&id) {
+            return false;
+        }
+
+        info!("Paused job {}", id);
+        state.paused.insert(id);
+        true
+    }
+
+    /// resume undoes a previous pause.
+    /// Returns false if the job wasn't paused.
+    pub fn resume(&self, id: JobId) -> bool {
+        let resumed = self.lock().paused.remove(&id);
+        if resumed {
+            info!("Resumed job {}", id);
+        }
+        resumed
+    }
+
+    /// jobs returns a snapshot of every registered job, ordered by id
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        let state = self.lock();
+        let mut jobs: Vec<JobInfo> = state
+            .jobs
+            .values()
+            .map(|j| state.info(j))
+            .collect();
+        jobs.sort_by_key(|j| j.id);
+        jobs
+    }
+
+    /// job returns a snapshot of the job registered under the given id
+    pub fn job(&self, id: JobId) -> Option<JobInfo> {
+        let state = self.lock();
+        Some(state.info(state.jobs.get(&id)?))
+    }
+
+    /// pending returns the occurrences waiting in the queue and the
+    /// launches waiting to be retried, earliest first
+    pub fn pending(&self) -> Vec<Firing> {
+        let state = self.lock();
+        let (now, instant) = (state.clock.now(), state.clock.instant());
+        let firing = |j: &Job, time| Firing {
+            time,
+            job: j.get_id(),
+            name: j.get_name().to_string(),
+        };
+        let retries = state.retries.iter().map(|r| {
+            let wait = chrono::Duration::from_std(r.due.saturating_duration_since(instant)).unwrap_or_default();
+            firing(&r.job, now + wait)
+        });
+        let mut pending: Vec<Firing> = state.queue.iter().map(|j| firing(j, j.get_next())).chain(retries).collect();
+        pending.sort_by_key(|f| f.time);
+        pending
+    }
+
+    /// upcoming returns the next `n` times the job is scheduled at, or None
+    /// if it isn't registered. Fewer are returned if its schedule finishes.
+    pub fn upcoming(&self, id: JobId, n: usize) -> Option<Vec<DateTime<Local>>> {
+        let state = self.lock();
+        let j = state.jobs.get(&id)?;
+        let mut times = vec![j.get_next()];
+        while times.len() < n {
+            match times.last().and_then(|t| j.next_after(*t)) {
+                Some(t) => times.push(t),
+                None => break,
+            }
+        }
+        times.truncate(n);
+        Some(times)
+    }
+
+    /// set_metadata sets a label on the job, removing it if `value` is None.
+    /// Returns false if the job isn't registered.
+    pub fn set_metadata(&self, id: JobId, key: &str, value: Option<&str>) -> bool {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let registered = match state.jobs.get_mut(&id) {
+            Some(j) => j,
+            None => return false,
+        };
+        registered.set_metadata(key, value);
+
+        // Pending occurrences pick up the updated definition
+        let next = registered.get_next();
+        for j in state.queue.iter_at_mut(next).filter(|j| j.get_id() == id) {
+            j.set_definition(registered);
+        }
+        true
+    }
+
+    /// next_wakeup returns when the run loop will next wake up to run jobs,
+    /// or None if nothing is scheduled
+    pub fn next_wakeup(&self) -> Option<DateTime<Local>> {
+        self.lock().next_time()
+    }
+
+    /// queue_depth returns the number of pending occurrences
+    pub fn queue_depth(&self) -> usize {
+        self.lock().queue.len()
+    }
+
+    /// simulate returns the occurrences due until `until`, without running
+    /// them, up to `limit` of them
+    pub fn simulate(&self, until: DateTime<Local>, limit: usize) -> Vec<Firing> {
+        simulate::simulate(&self.lock(), until, limit)
+    }
+
+    /// replay returns the occurrences of the jobs from `from` until `until`,
+    /// up to `limit` of them
+    pub fn replay(&self, from: DateTime<Local>, until: DateTime<Local>, limit: usize) -> Vec<Firing> {
+        simulate::replay(&self.lock(), from, until, limit)
+    }
+
+    /// shutdown asks the run loop to stop and blocks until it has exited.
+    /// If `wait_children` is true, it also waits for all running jobs to complete.
+    pub fn shutdown(&self, wait_children: bool) {
+        let mut state = self.lock();
+        state.shutdown = true;
+        self.notify();
+
+        while state.active || (wait_children && !state.children.is_empty()) {
+            state = self.wait(state, None);
+        }
+    }
+
+    /// terminate asks the run loop to stop and to terminate the running jobs,
+    /// then blocks until it has exited. Jobs are sent SIGTERM, and SIGKILL if
+    /// they are still running after the grace period.
+    pub fn terminate(&self, grace: Duration) {
+        let mut state = self.lock();
+        state.shutdown = true;
+        state.terminate = Some(grace);
+        self.notify();
+
+        while state.active {
+            state = self.wait(state, None);
+        }
+    }
+
+    /// handoff asks the run loop to stop without touching the running jobs,
+    /// so the daemon can hand over to a new instance, then blocks until it
+    /// has exited
+    #[cfg(feature = "daemon")]
+    pub fn handoff(&self) {
+        let mut state = self.lock();
+        state.shutdown = true;
+        state.handoff = true;
+        self.notify();
+
+        while state.active {
+            state = self.wait(state, None);
+        }
+    }
+
+    /// terminate_children signals the running jobs to exit and waits for them
+    /// to be reaped, killing the ones still running after the grace period.
+    /// Jobs with the `wait` shutdown policy are left to finish first, for up
+    /// to `max_shutdown_wait`.
+    pub fn terminate_children<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, RunState>,
+        grace: Duration,
+    ) -> MutexGuard<'a, RunState> {
+        let waits = |c: &Child| c.shutdown_policy == ShutdownPolicy::Wait;
+        state = self.kill_children(state, grace, |c| !waits(c));
+
+        let waiting = state.children.values().filter(|c| waits(c)).count();
+        if waiting > 0 {
+            info!(
+                "Waiting up to {:?} for {} jobs to finish",
+                self.max_shutdown_wait, waiting
+            );
+            let deadline = Instant::now() + self.max_shutdown_wait;
+            state = self.wait_children(state, deadline, |_| true);
+        }
+
+        state = self.kill_children(state, grace, |_| true);
+        if !state.children.is_empty() {
+            warn!("{} jobs are still running after being killed", state.children.len());
+        }
+        state
+    }
+
+    /// kill_children sends SIGTERM to the running jobs matching `f`, then
+    /// SIGKILL to the ones still running after the grace period
+    fn kill_children<'a, F: Fn(&Child) -> bool>(
+        &'a self,
+        mut state: MutexGuard<'a, RunState>,
+        grace: Duration,
+        f: F,
+    ) -> MutexGuard<'a, RunState> {
+        for signal in &[Signal::SIGTERM, Signal::SIGKILL] {
+            let pids: Vec<i32> = state
+                .children
+                .iter()
+                .filter(|(_, c)| f(c))
+                .map(|(pid, _)| *pid)
+                .collect();
+            if pids.is_empty() {
+                break;
+            }
+
+            info!("Sending {:?} to {} running jobs", signal, pids.len());
+            for pid in pids {
+                let child = &state.children[&pid];
+                if let Err(err) = kill(Pid::from_raw(pid), *signal) {
+                    warn!("[{} {}] Failed to signal process {}: {}", child.name, child.job, pid, err);
+                }
+            }
+
+            // Killed jobs get a few more seconds to be reaped
+            let deadline = match signal {
+                Signal::SIGTERM => Instant::now() + grace,
+                _ => Instant::now() + KILL_TIMEOUT,
+            };
+            state = self.wait_children(state, deadline, &f);
+        }
+        state
+    }
+
+    /// wait_children blocks until no running job matches `f` or until the
+    /// deadline, keeping the heartbeat going while waiting
+    fn wait_children<'a, F: Fn(&Child) -> bool>(
+        &'a self,
+        mut state: MutexGuard<'a, RunState>,
+        deadline: Instant,
+        f: F,
+    ) -> MutexGuard<'a, RunState> {
+        while state.children.values().any(&f) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            let mut timeout = deadline - now;
+            if let Some(beat) = self.heartbeat {
+                timeout = std::cmp::min(timeout, beat);
+                for o in &state.observers {
+                    o.heartbeat();
+                }
+            }
+            state = self.wait(state, Some(timeout));
+        }
+        state
+    }
+}
+
+/// first_run returns the id preceding the first run of an instance, the
+/// current time in microseconds
+fn first_run() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
+}
+
+/// origin describes where a job was loaded from, if known
+fn origin(j: &Job) -> String {
+    let metadata = j.get_metadata();
+    let source = ["spool", FRAGMENT_KEY, "imported_from"].iter().find_map(|k| metadata.get(*k));
+    match source {
+        Some(source) => format!(" (from {})", source),
+        None => String::new(),
+    }
+}
+
+/// is_transient returns true if spawning failed for lack of resources,
+/// which may be available again shortly
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EAGAIN) | Some(libc::ENOMEM))
+}
+
+impl RunState {
+    /// register adds a job to the registry and schedules its next occurrence.
+    /// Returns the id of the registered job.
+    fn register(&mut self, spec: JobSpec, timezone: Option<Tz>) -> Result<JobId> {
+        let id = match spec.id {
+            Some(id) if self.jobs.contains_key(&id) => {
+                return Err(XcrondError::DuplicateId {
+                    name: spec.name,
+                    id,
+                });
+            }
+            Some(id) => id,
+            None => JobId::new(self.next_id),
+        };
+        // Ids aren't reused, the history, results and handles of a removed
+        // job would refer to another one
+        self.next_id = std::cmp::max(self.next_id, id.as_u64().saturating_add(1));
+        if let Some(ns) = spec.namespace.as_ref().and_then(|n| self.namespaces.get(n)) {
+            let registered = self.jobs.values().filter(|j| j.get_namespace() == Some(&ns.name));
+            if let Some(max) = ns.max_jobs.filter(|max| registered.count() >= *max) {
+                return Err(XcrondError::NamespaceFull {
+                    name: spec.name,
+                    namespace: ns.name.clone(),
+                    max,
+                });
+            }
+        }
+        let job = Job::from_spec(id, spec, timezone, self.clock.now())?;
+
+        // Jobs defined by several sources would run twice per occurrence
+        let definition = job.definition();
+        if let Some(other) = self.definitions.get(&definition).and_then(|id| self.jobs.get(id)) {
+            return Err(XcrondError::DuplicateJob {
+                name: format!("{}{}", job.get_name(), origin(&job)),
+                other: format!("{}{}", other, origin(other)),
+            });
+        }
+
+        debug!("[{}] Registered job", job);
+        self.definitions.insert(definition, id);
+        self.jobs.insert(id, job.clone());
+        self.observe(&job, |o, info| o.job_scheduled(info));
+        self.queue.enqueue(job);
+        Ok(id)
+    }
+
+    /// unregister removes a job, dropping all of its pending occurrences.
+    /// Returns false if the job wasn't registered.
+    fn unregister(&mut self, id: JobId) -> bool {
+        let job = match self.jobs.remove(&id) {
+            Some(j) => j,
+            None => return false,
+        };
+
+        // The registry tracks when the pending occurrence is due
+        self.queue.remove_at(id, job.get_next());
+        self.definitions.remove(&job.definition());
+        self.configured.remove(&id);
+        self.paused.remove(&id);
+        self.awaiting.remove(&id);
+        self.results.remove(&id);
+        self.launched.remove(&id);
+        self.spent.remove(&id);
+        let abandoned: Vec<RunId> = self
+            .triggered
+            .iter()
+            .filter(|(t, _)| *t == id)
+            .map(|(_, run)| *run)
+            .chain(self.retries.iter().filter(|r| r.job.get_id() == id).filter_map(|r| r.run))
+            .collect();
+        abandoned.into_iter().for_each(|run| self.abandon(run));
+        self.triggered.retain(|(t, _)| *t != id);
+        self.queued.remove(&id);
+        // The outputs of upstream runs waiting for the job aren't needed anymore
+        let inputs = self
+            .retries
+            .iter()
+            .filter(|r| r.job.get_id() == id)
+            .filter_map(|r| r.input.as_ref())
+            .chain(self.piped.iter().filter(|(p, _)| *p == id).map(|(_, input)| input));
+        inputs.for_each(|input| pipe::remove(input));
+        self.retries.retain(|r| r.job.get_id() != id);
+        self.piped.retain(|(p, _)| *p != id);
+        info!("Removed job {}", id);
+        true
+    }
+
+    /// replace replaces the definition of a registered job, keeping its id,
+    /// state and running children. The job is left as it was if the new
+    /// definition can't be registered.
+    fn replace(&mut self, id: JobId, mut spec: JobSpec, timezone: Option<Tz>) -> Result<()> {
+        spec.id = Some(id);
+        let old = match self.jobs.remove(&id) {
+            Some(j) => j,
+            None => return self.register(spec, timezone).map(|_| ()),
+        };
+        self.queue.remove_at(id, old.get_next());
+        self.definitions.remove(&old.definition());
+        // Queued again right away
+        let awaiting = self.awaiting.remove(&id);
+
+        if let Err(err) = self.register(spec, timezone) {
+            self.definitions.insert(old.definition(), id);
+            self.jobs.insert(id, old.clone());
+            if awaiting {
+                self.awaiting.insert(id);
+            } else {
+                self.queue.enqueue(old);
+            }
+            return Err(err);
+        }
+        let next = match self.jobs.get_mut(&id) {
+            Some(j) => {
+                j.set_prev(old.get_prev());
+                j.get_next()
+            }
+            None => return Ok(()),
+        };
+        for j in self.queue.iter_at_mut(next).filter(|j| j.get_id() == id) {
+            j.set_prev(old.get_prev());
+        }
+        Ok(())
+    }
+
+    /// is_current returns true if the job is registered with the same
+    /// definition. A reload may have replaced it, keeping its id, while the
+    /// state was unlocked.
+    pub fn is_current(&self, j: &Job) -> bool {
+        self.jobs.get(&j.get_id()).is_some_and(|r| r.definition() == j.definition())
+    }
+
+    /// is_queued returns true if the next occurrence of the job is queued.
+    /// The occurrence being run isn't, unless a reload replaced the job
+    /// meanwhile, which queues it again.
+    pub fn is_queued(&self, id: JobId) -> bool {
+        self.jobs.get(&id).is_some_and(|r| self.queue.contains_at(id, r.get_next()))
+    }
+
+    /// requeue schedules the next occurrence of the job, if any. Jobs
+    /// running at an interval are scheduled once their run completes.
+    pub fn requeue(&mut self, j: Job) {
+        if j.is_interval() && self.children.values().any(|c| c.job == j.get_id()) {
+            if let Some(registered) = self.jobs.get_mut(&j.get_id()) {
+                registered.set_prev(j.get_next());
+                self.awaiting.insert(j.get_id());
+                self.dirty = true;
+            }
+            return;
+        }
+
+        // Never schedule the occurrence that's being run again
+        let after = std::cmp::max(j.get_next(), self.clock.now());
+
+        let next = match j.next_after(after) {
+            Some(n) => n,
+            None if j.is_one_shot() => {
+                info!("[{}] Its only occurrence is past, not scheduled again", j);
+                return;
+            }
+            None => {
+                info!("[{}] Job Schedule Finished", j);
+                return;
+            }
+        };
+
+        // Requeue /w new `next`, sharing the job's definition
+        let j_new = j.occurrence(j.get_next(), next);
+        debug!("New Job: {:?}", j_new);
+        if let Some(registered) = self.jobs.get_mut(&j.get_id()) {
+            registered.set_prev(j.get_next());
+            registered.set_next(next);
+            self.dirty = true;
+        }
+        self.observe(&j_new, |o, info| o.job_scheduled(info));
+        self.queue.enqueue(j_new);
+    }
+
+    /// missed notifies observers that an occurrence of the job was skipped
+    pub fn missed(&self, j: &Job, reason: MissReason) {
+        self.observe(j, |o, info| o.job_missed(info, reason.clone()));
+    }
+
+    /// observe calls `f` on every observer with a snapshot of the job
+    pub fn observe<F>(&self, j: &Job, f: F)
+    where
+        F: Fn(&dyn SchedulerObserver, &JobInfo),
+    {
+        if self.observers.is_empty() {
+            return;
+        }
+
+        let info = JobInfo::from(j);
+        for o in &self.observers {
+            f(o.as_ref(), &info);
+        }
+    }
+
+    /// info returns a snapshot of the registered job with its run state
+    pub fn info(&self, j: &Job) -> JobInfo {
+        let id = j.get_id();
+        let mut info = JobInfo::from(j);
+        info.last_result = self.results.get(&id).cloned();
+        info.paused = self.paused.contains(&id);
+        info.running = self.children.values().filter(|c| c.job == id).count();
+        info
+    }
+
+    /// next_run_id returns the id the next run will be given
+    pub fn next_run_id(&self) -> RunId {
+        self.reserved.unwrap_or_else(|| RunId::new(self.next_run + 1))
+    }
+
+    /// take_run_id returns the id of a new run: the reserved one if any
+    pub fn take_run_id(&mut self) -> RunId {
+        if let Some(run) = self.reserved.take() {
+            return run;
+        }
+        self.next_run += 1;
+        RunId::new(self.next_run)
+    }
+
+    /// is_pending returns true if the run is yet to be recorded
+    fn is_pending(&self, run: RunId) -> bool {
+        self.reserved == Some(run)
+            || self.triggered.iter().any(|(_, r)| *r == run)
+            || self.retries.iter().any(|r| r.run == Some(run))
+            || self.children.values().any(|c| c.run == run)
+    }
+
+    /// abandon gives up the run reserved for a launch that didn't happen,
+    /// letting the clients waiting for it know
+    pub fn abandon(&mut self, run: RunId) {
+        for w in self.waiters.remove(&run).unwrap_or_default() {
+            let _ = w.send(None);
+        }
+    }
+
+    /// started records a child process spawned to run the job, with the
+    /// number of the retry it is and the files of its output and input if
+    /// it's part of a pipe. Returns the id of the new run.
+    pub fn started(
+        &mut self,
+        pid: i32,
+        j: &Job,
+        trigger: Trigger,
+        retry: u32,
+        output: Option<PathBuf>,
+        input: Option<PathBuf>,
+    ) -> RunId {
+        let run = self.take_run_id();
+        self.children.insert(
+            pid,
+            Child {
+                run,
+                job: j.get_id(),
+                name: j.get_name().to_string(),
+                trigger,
+                shutdown_policy: j.get_shutdown_policy(),
+                started: self.clock.now(),
+                output,
+                input,
+                retry,
+                mail: None,
+                cgroup: None,
+                timeout: j.get_timeout(),
+                terminated: None,
+            },
+        );
+        self.launched.insert(j.get_id(), self.clock.now());
+        self.observe(j, |o, info| o.job_started(info, pid, run));
+        run
+    }
+
+    /// failed records a run of the job whose process couldn't be started
+    pub fn failed(&mut self, j: &Job, trigger: Trigger, reason: String) {
+        let run = self.take_run_id();
+        let now = self.clock.now();
+        let result = JobRunResult {
+            run,
+            job: j.get_id(),
+            name: j.get_name().to_string(),
+            pid: 0,
+            started: now,
+            finished: now,
+            trigger,
+            status: RunStatus::FailedToStart(reason),
+            usage: None,
+        };
+        self.finished(result);
+    }
+
+    /// reaped records the termination of a child process and returns the
+    /// result of the run, if the child was a job spawned by us
+    pub fn reaped(
+        &mut self,
+        pid: i32,
+        status: RunStatus,
+        usage: Option<ResourceUsage>,
+    ) -> Option<JobRunResult> {
+        let child = self.children.remove(&pid)?;
+        // The input is kept for the retry
+        let retried = match status {
+            RunStatus::Exited(code) if code != 0 => self.retry_failed(&child, code),
+            _ => false,
+        };
+        if let (Some(input), false) = (&child.input, retried) {
+            pipe::remove(input);
+        }
+        if let Some(capture) = child.mail {
+            mail::send(self.mailer.clone(), capture, child.name.clone(), status.clone());
+        }
+        if let Some(cgroup) = &child.cgroup {
+            cgroup.remove();
+        }
+        if let Some(output) = child.output {
+            self.pipe(child.job, &child.name, status.success(), output);
+        }
+        let result = JobRunResult {
+            run: child.run,
+            job: child.job,
+            name: child.name,
+            pid,
+            started: child.started,
+            finished: self.clock.now(),
+            trigger: child.trigger,
+            status,
+            usage,
+        };
+
+        self.finished(result.clone());
+        Some(result)
+    }
+
+    /// enforce_timeouts sends SIGTERM to the runs exceeding the timeout of
+    /// their job, then SIGKILL to the terminated runs still running after a
+    /// grace period
+    pub fn enforce_timeouts(&mut self) {
+        let now = self.clock.now();
+        for (pid, c) in self.children.iter_mut() {
+            if c.deadline().is_none_or(|t| t > now) {
+                continue;
+            }
+            let signal = match c.terminated {
+                None => {
+                    warn!(
+                        "[{} {}] Timed out after {:?}, sending SIGTERM to process {}",
+                        c.name, c.job, c.timeout.unwrap_or_default(), pid
+                    );
+                    c.terminated = Some((now, false));
+                    Signal::SIGTERM
+                }
+                Some((t, _)) => {
+                    warn!(
+                        "[{} {}] Process {} still running {:?} after SIGTERM, sending SIGKILL",
+                        c.name, c.job, pid, TIMEOUT_GRACE
+                    );
+                    c.terminated = Some((t, true));
+                    // Along with the processes it forked
+                    if let Some(cgroup) = &c.cgroup {
+                        cgroup.kill();
+                    }
+                    Signal::SIGKILL
+                }
+            };
+            if let Err(err) = kill(Pid::from_raw(*pid), signal) {
+                warn!("[{} {}] Failed to signal process {}: {}", c.name, c.job, pid, err);
+            }
+        }
+    }
+
+    /// timeout_deadline returns when a running job has to be signaled next
+    /// for exceeding its timeout
+    pub fn timeout_deadline(&self) -> Option<DateTime<Local>> {
+        self.children.values().filter_map(Child::deadline).min()
+    }
+
+    /// running returns the processes of the runs of the job still running
+    pub fn running(&self, id: JobId) -> Vec<i32> {
+        let mut pids: Vec<i32> = self
+            .children
+            .iter()
+            .filter(|(_, c)| c.job == id)
+            .map(|(pid, _)| *pid)
+            .collect();
+        pids.sort();
+        pids
+    }
+
+    /// terminate_runs sends SIGTERM to the running processes of the job,
+    /// then SIGKILL to the ones still running after a grace period
+    pub fn terminate_runs(&mut self, id: JobId) {
+        let now = self.clock.now();
+        for pid in self.running(id) {
+            let c = self.children.get_mut(&pid).expect("running child");
+            if c.terminated.is_some() {
+                continue;
+            }
+            info!("[{} {}] Sending SIGTERM to process {} of {}", c.name, c.job, pid, c.run);
+            c.terminated = Some((now, false));
+            if let Err(err) = kill(Pid::from_raw(pid), Signal::SIGTERM) {
+                warn!("[{} {}] Failed to signal process {}: {}", c.name, c.job, pid, err);
+            }
+        }
+    }
+
+    /// retry_failed schedules a retry of the run that exited with `code`,
+    /// unless its job doesn't retry failed runs or it was the last retry.
+    /// The delay before the retry doubles with every retry.
+    /// Returns true if the run is retried.
+    fn retry_failed(&mut self, child: &Child, code: i32) -> bool {
+        let j = match self.jobs.get(&child.job) {
+            Some(j) if j.get_max_retries() > 0 => j,
+            _ => return false,
+        };
+        let max = j.get_max_retries();
+        if child.retry >= max {
+            warn!("[{}] Exited with code {}, giving up after {} retries", j, code, max);
+            return false;
+        }
+        let delay = j.get_backoff().saturating_mul(2u32.saturating_pow(child.retry));
+        let delay = std::cmp::min(delay, MAX_BACKOFF);
+        let retry = child.retry + 1;
+        warn!("[{}] Exited with code {}, retry {}/{} in {:?}", j, code, retry, max, delay);
+        self.retries.push(Retry {
+            due: self.clock.instant() + delay,
+            job: j.clone(),
+            trigger: Trigger::Retry,
+            attempt: 1,
+            retry,
+            input: child.input.clone(),
+            run: None,
+        });
+        true
+    }
+
+    /// spawn_failed records a launch of the job whose process couldn't be
+    /// spawned. Launches failing with a transient error are retried after a
+    /// delay, `attempt` being the number of this attempt, until
+    /// `MAX_SPAWN_ATTEMPTS`. `input` is removed unless the retry takes it over.
+    pub fn spawn_failed(
+        &mut self,
+        j: &Job,
+        trigger: Trigger,
+        attempt: u32,
+        retry: u32,
+        input: Option<PathBuf>,
+        err: io::Error,
+    ) {
+        let transient = is_transient(&err);
+        if transient {
+            self.observe(j, |o, info| o.job_spawn_failed(info, attempt, &err.to_string()));
+        }
+        if transient && attempt < MAX_SPAWN_ATTEMPTS {
+            let delay = SPAWN_RETRY_DELAY * 2u32.pow(attempt - 1);
+            warn!("[{}] Failed to execute {:?}: {}, retrying in {:?}", j, j.get_program(), err, delay);
+            self.retries.push(Retry {
+                due: self.clock.instant() + delay,
+                job: j.clone(),
+                trigger,
+                attempt: attempt + 1,
+                retry,
+                input,
+                run: self.reserved.take(),
+            });
+            return;
+        }
+
+        match attempt {
+            1 => error!("[{}] Failed to execute {:?}: {}", j, j.get_program(), err),
+            n => error!("[{}] Failed to execute {:?} after {} attempts: {}", j, j.get_program(), n, err),
+        }
+        if let Some(input) = &input {
+            pipe::remove(input);
+        }
+        self.failed(j, trigger, err.to_string());
+    }
+
+    /// due_retries takes the launches to retry by now out of the pending ones
+    pub fn due_retries(&mut self) -> Vec<Retry> {
+        let now = self.clock.instant();
+        let (due, pending) = std::mem::take(&mut self.retries)
+            .into_iter()
+            .partition(|r| r.due <= now);
+        self.retries = pending;
+        due
+    }
+
+    /// forget stops tracking a child process reaped by someone else
+    pub fn forget(&mut self, pid: i32) {
+        if let Some(child) = self.children.remove(&pid) {
+            for path in child.output.iter().chain(child.input.iter()) {
+                pipe::remove(path);
+            }
+            if let Some(capture) = child.mail {
+                capture.remove();
+            }
+            if let Some(cgroup) = child.cgroup {
+                cgroup.remove();
+            }
+        }
+    }
+
+    /// pipe queues the job the job `id` pipes to, with `output` as its
+    /// input, if the run succeeded. Otherwise the output is removed.
+    fn pipe(&mut self, id: JobId, name: &str, success: bool, output: PathBuf) {
+        let to = self.jobs.get(&id).and_then(|j| j.get_pipe_to());
+        let downstream = to.and_then(|to| self.jobs.values().find(|j| j.get_name() == to));
+        match (success, to, downstream) {
+            (true, _, Some(d)) => {
+                let id = d.get_id();
+                self.piped.push((id, output));
+                return;
+            }
+            (true, Some(to), None) => warn!("[{}] Not piped: no job named {}", name, to),
+            (false, Some(to), _) => info!("[{}] Not piped to {}: the run failed", name, to),
+            _ => {}
+        }
+        pipe::remove(&output);
+    }
+
+    /// mail_to returns the addresses the output of the job's runs is mailed
+    /// to, if any
+    pub fn mail_to(&self, j: &Job) -> Option<String> {
+        j.get_mailto()
+            .or(self.mailto.as_deref())
+            .filter(|to| !to.trim().is_empty())
+            .map(str::to_string)
+    }
+
+    /// blackout returns the end of the blackout window the occurrence of the
+    /// job falls in, if any. Windows may overlap, the occurrence is out of
+    /// them at the latest end.
+    pub fn blackout(&self, j: &Job) -> Option<DateTime<Local>> {
+        let t = j.get_next();
+        j.get_blackouts()
+            .iter()
+            .chain(&self.blackouts)
+            .filter_map(|b| b.end_of(t, j.get_timezone()))
+            .max()
+    }
+
+    /// defer queues the occurrence of the job again at `t`
+    pub fn defer(&mut self, j: Job, t: DateTime<Local>) {
+        let deferred = j.occurrence(j.get_prev(), t);
+        if let Some(registered) = self.jobs.get_mut(&j.get_id()) {
+            registered.set_next(t);
+            self.dirty = true;
+        }
+        self.observe(&deferred, |o, info| o.job_scheduled(info));
+        self.queue.enqueue(deferred);
+    }
+
+    /// over_budget tells whether the runs of the job used up its daily
+    /// budget today
+    pub fn over_budget(&self, j: &Job) -> bool {
+        let today = self.clock.now().naive_local().date();
+        match (j.get_daily_budget(), self.spent.get(&j.get_id())) {
+            (Some(budget), Some((day, spent))) => *day == today && *spent >= budget,
+            _ => false,
+        }
+    }
+
+    /// spend counts the time taken by the run against the daily budget of
+    /// its job, if it has one. Runs count for the day they finish on.
+    fn spend(&mut self, result: &JobRunResult) {
+        let j = match self.jobs.get(&result.job) {
+            Some(j) => j.clone(),
+            None => return,
+        };
+        let budget = match j.get_daily_budget() {
+            Some(b) => b,
+            None => return,
+        };
+        let day = result.finished.naive_local().date();
+        let took = (result.finished - result.started).to_std().unwrap_or_default();
+        let entry = self.spent.entry(j.get_id()).or_insert((day, Duration::from_secs(0)));
+        if entry.0 != day {
+            *entry = (day, Duration::from_secs(0));
+        }
+        let before = entry.1;
+        entry.1 += took;
+        let spent = entry.1;
+        if before < budget && spent >= budget {
+            warn!("[{}] Used up its daily budget of {:?} ({:?}), skipping the runs of the day", j, budget, spent);
+            self.observe(&j, |o, info| o.job_over_budget(info, spent));
+        }
+    }
+
+    /// finished stores the result of a run and notifies observers
+    fn finished(&mut self, result: JobRunResult) {
+        self.spend(&result);
+        if self.awaiting.remove(&result.job) {
+            self.resume_interval(result.job, result.finished);
+        }
+        if let Some(journal) = &self.journal {
+            if let Err(err) = journal.lock().unwrap().finish(result.run) {
+                error!("[{} {}] Failed to journal the end of {}: {}", result.name, result.job, result.run, err);
+            }
+        }
+
+        for o in &self.observers {
+            o.job_finished(&result);
+        }
+        for w in self.waiters.remove(&result.run).unwrap_or_default() {
+            let _ = w.send(Some(result.clone()));
+        }
+        if self.jobs.contains_key(&result.job) {
+            self.results.insert(result.job, result);
+            self.dirty = true;
+        }
+    }
+
+    /// resume_interval queues the next occurrence of a job running at an
+    /// interval, whose run completed at `completed`
+    fn resume_interval(&mut self, id: JobId, completed: DateTime<Local>) {
+        let j = match self.jobs.get(&id) {
+            Some(j) => j,
+            None => return,
+        };
+        let next = match j.next_after(completed) {
+            Some(n) => n,
+            None => return,
+        };
+        let j = j.occurrence(j.get_prev(), next);
+        self.observe(&j, |o, info| o.job_scheduled(info));
+        self.queue.enqueue(j.clone());
+        self.jobs.insert(id, j);
+        self.dirty = true;
+    }
+
+    /// job_states returns the state of every registered job to be persisted
+    pub fn job_states(&self) -> Vec<JobState> {
+        let mut states: Vec<JobState> = self
+            .jobs
+            .values()
+            .map(|j| JobState {
+                id: j.get_id(),
+                name: Some(j.get_name().to_string()),
+                prev: j.get_prev(),
+                last_result: self.results.get(&j.get_id()).cloned(),
+            })
+            .collect();
+        states.sort_by_key(|s| s.id);
+        states
+    }
+
+    /// restore applies persisted state to the registered jobs.
+    /// State of jobs that aren't registered anymore is dropped.
+    pub fn restore(&mut self, states: Vec<JobState>) {
+        for s in states {
+            let id = match self.saved_job(s.id, s.name.as_deref()) {
+                Some(id) => id,
+                None => continue,
+            };
+            let next = match self.jobs.get_mut(&id) {
+                Some(j) => {
+                    j.set_prev(s.prev);
+                    j.get_next()
+                }
+                None => continue,
+            };
+            for j in self.queue.iter_at_mut(next).filter(|j| j.get_id() == id) {
+                j.set_prev(s.prev);
+            }
+            if let Some(mut r) = s.last_result {
+                r.job = id;
+                self.results.insert(id, r);
+            }
+        }
+    }
+
+    /// saved_job returns the id of the registered job that the state saved
+    /// under `id` for the job named `name` belongs to, if any. Jobs without
+    /// an explicit id get theirs in load order, so once the Jobfile changed
+    /// the id may be another job's and the job is found by name. States
+    /// saved by older versions have no name, their id is trusted.
+    pub(crate) fn saved_job(&self, id: JobId, name: Option<&str>) -> Option<JobId> {
+        let name = match name {
+            Some(n) => n,
+            None => return self.jobs.contains_key(&id).then_some(id),
+        };
+        if self.jobs.get(&id).is_some_and(|j| j.get_name() == name) {
+            return Some(id);
+        }
+        let mut named = self.jobs.values().filter(|j| j.get_name() == name);
+        match (named.next(), named.next()) {
+            (Some(j), None) => Some(j.get_id()),
+            (None, _) => None,
+            (Some(_), Some(_)) => {
+                warn!("Ignoring the saved state of {} {}, several jobs are named so", name, id);
+                None
+            }
+        }
+    }
+
+    /// namespace returns the limits of the job's namespace, if any are set
+    pub fn namespace(&self, j: &Job) -> Option<&Namespace> {
+        j.get_namespace().and_then(|n| self.namespaces.get(n))
+    }
+
+    /// running_in returns the number of running jobs of the namespace
+    pub fn running_in(&self, namespace: &str) -> usize {
+        self.children
+            .values()
+            .filter(|c| self.jobs.get(&c.job).and_then(|j| j.get_namespace()) == Some(namespace))
+            .count()
+    }
+
+    /// fair_order orders the jobs due at the same time for dispatch, so the
+    /// same jobs aren't always the ones left out by the concurrency limits.
+    /// Namespaces take turns, and the jobs that waited the longest for a run
+    /// go first within a namespace.
+    pub fn fair_order(&self, jobs: Vec<Job>) -> Vec<Job> {
+        if jobs.len() < 2 {
+            return jobs;
+        }
+
+        // Jobs that never ran come first
+        let launched = |j: &Job| self.launched.get(&j.get_id()).cloned();
+        let total = jobs.len();
+        let mut namespaces: BTreeMap<Option<String>, Vec<Job>> = BTreeMap::new();
+        for j in jobs {
+            let ns = j.get_namespace().map(str::to_string);
+            namespaces.entry(ns).or_default().push(j);
+        }
+        let mut turns: Vec<VecDeque<Job>> = namespaces
+            .into_values()
+            .map(|mut jobs| {
+                jobs.sort_by_key(launched);
+                jobs.into()
+            })
+            .collect();
+        turns.sort_by_key(|jobs| launched(&jobs[0]));
+
+        let mut ordered = Vec::with_capacity(total);
+        while !turns.is_empty() {
+            turns.retain_mut(|jobs| {
+                ordered.extend(jobs.pop_front());
+                !jobs.is_empty()
+            });
+        }
+        ordered
+    }
+
+    /// catch_up reschedules every job right after its last scheduled
+    /// occurrence, which may be older than the queued one if this instance
+    /// wasn't the one scheduling. Occurrences that came due in the meantime
+    /// are handled according to the misfire policy of the job.
+    pub fn catch_up(&mut self) {
+        let now = self.clock.now();
+        // Jobs waiting for their run to complete aren't queued
+        let ids: Vec<JobId> = self.jobs.keys().filter(|id| !self.awaiting.contains(id)).cloned().collect();
+        for id in ids {
+            let j = &self.jobs[&id];
+            let (prev, queued) = (j.get_prev(), j.get_next());
+            let after = match j.get_misfire_policy() {
+                MisfirePolicy::RunOnce => prev,
+                MisfirePolicy::Skip => std::cmp::max(prev, now),
+            };
+            let next = match j.next_after(after) {
+                Some(n) if n != queued => n,
+                _ => continue,
+            };
+
+            let j = j.occurrence(prev, next);
+            self.queue.remove_at(id, queued);
+            self.queue.enqueue(j.clone());
+            self.jobs.insert(id, j);
+        }
+    }
+
+    /// misfired applies the misfire policy of the jobs whose queued
+    /// occurrence came due while the scheduler couldn't run it, e.g. while
+    /// the system was suspended
+    pub fn misfired(&mut self, now: DateTime<Local>) {
+        let overdue: Vec<Job> = self
+            .jobs
+            .values()
+            .filter(|j| j.get_next() < now && !self.awaiting.contains(&j.get_id()))
+            .cloned()
+            .collect();
+        for j in overdue {
+            if j.get_misfire_policy() == MisfirePolicy::RunOnce {
+                info!("[{}] Running the occurrence missed at {} now", j, j.get_next());
+                continue;
+            }
+
+            let next = match j.next_after(now) {
+                Some(n) => n,
+                None => continue,
+            };
+            info!("[{}] Skipped the occurrence missed at {}", j, j.get_next());
+            self.missed(&j, MissReason::Misfired);
+            let rescheduled = j.occurrence(j.get_prev(), next);
+            self.queue.remove_at(j.get_id(), j.get_next());
+            self.queue.enqueue(rescheduled.clone());
+            self.jobs.insert(j.get_id(), rescheduled);
+            self.dirty = true;
+        }
+    }
+
+    /// recover applies the occurrences found in the journal, which are more
+    /// recent than the state file if the daemon crashed
+    pub fn recover(&mut self, entries: Vec<journal::Entry>) {
+        for e in entries {
+            let id = match self.saved_job(e.job, e.name.as_deref()) {
+                Some(id) => id,
+                None => continue,
+            };
+            let j = &self.jobs[&id];
+            if !e.finished {
+                warn!(
+                    "[{}] {} of the occurrence at {} never completed, it was interrupted",
+                    j, e.run, e.occurrence
+                );
+            }
+            if e.occurrence <= j.get_prev() {
+                continue;
+            }
+
+            // The occurrence ran, so it mustn't be caught up
+            let next = j.get_next();
+            for j in self.queue.iter_at_mut(next).filter(|j| j.get_id() == id) {
+                j.set_prev(e.occurrence);
+            }
+            if let Some(j) = self.jobs.get_mut(&id) {
+                j.set_prev(e.occurrence);
+            }
+            self.dirty = true;
+        }
+    }
+
+    /// is_leader returns true if this instance holds the leader lease
+    pub fn is_leader(&self) -> bool {
+        self.leader_until.is_some_and(|t| Instant::now() < t)
+    }
+
+    /// next_time returns the time of the earliest pending event
+    pub fn next_time(&self) -> Option<DateTime<Local>> {
+        self.queue.peek().map(|e| e.get_time())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blackout::BlackoutPolicy;
+    use crate::job::OverlapPolicy;
+    use crate::clock::ManualClock;
+    use chrono::TimeZone;
+
+    fn at(secs: i64) -> DateTime<Local> {
+        Local.timestamp_opt(1_500_000_000, 0).unwrap() + chrono::Duration::seconds(secs)
+    }
+
+    fn shared(clock: &Arc<ManualClock>) -> Shared {
+        let mut shared = Shared::new(None, None);
+        shared.set_clock(clock.clone());
+        shared
+    }
+
+    fn next(shared: &Shared, id: JobId) -> DateTime<Local> {
+        shared.lock().jobs[&id].get_next()
+    }
+
+    #[test]
+    fn schedules_after_the_clock() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let id = shared.add_job(JobSpec::new("a", "/bin/true", "0 * * * * *")).unwrap();
+        assert_eq!(next(&shared, id), at(60));
+
+        clock.advance(Duration::from_secs(90));
+        let mut state = shared.lock();
+        let j = state.queue.dequeue().unwrap().into_jobs().remove(0);
+        state.requeue(j);
+        assert_eq!(state.jobs[&id].get_prev(), at(60));
+        assert_eq!(state.jobs[&id].get_next(), at(120));
+    }
+
+    #[test]
+    fn schedules_in_the_timezone_of_the_job() {
+        // 02:40 UTC, 11:40 in Tokyo and 22:40 the day before in New York
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let tokyo = JobSpec::new("tokyo", "/bin/true", "0 0 9 * * *").with_timezone(Tz::Asia__Tokyo);
+        let tokyo = shared.add_job(tokyo).unwrap();
+        let new_york = JobSpec::new("new york", "/bin/true", "0 0 9 * * *").with_timezone(Tz::America__New_York);
+        let new_york = shared.add_job(new_york).unwrap();
+        assert_eq!(next(&shared, new_york), at(37_200));
+        assert_eq!(next(&shared, tokyo), at(76_800));
+
+        let first = shared.lock().queue.dequeue().unwrap().into_jobs().remove(0);
+        assert_eq!(first.get_id(), new_york);
+    }
+
+    #[test]
+    fn delays_occurrences_within_the_jitter() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let id = shared.add_job(JobSpec::new("a", "/bin/true", "0 0 * * * *").with_jitter("10m")).unwrap();
+        // 02:40, the next occurrence is scheduled at 03:00
+        let first = next(&shared, id);
+        assert!(first >= at(1200) && first <= at(1800), "{}", first);
+
+        clock.set(first);
+        let mut state = shared.lock();
+        let j = state.queue.dequeue().unwrap().into_jobs().remove(0);
+        state.requeue(j);
+        let second = state.jobs[&id].get_next();
+        assert!(second >= at(4800) && second <= at(5400), "{}", second);
+
+        let j = &state.jobs[&id];
+        assert_eq!(j.next_after(first), Some(second));
+        assert_eq!(j.next_after(at(0)), Some(first));
+    }
+
+    #[test]
+    fn runs_intervals_from_the_previous_completion() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let id = shared.add_job(JobSpec::new("a", "/bin/true", "every 90s")).unwrap();
+        assert_eq!(next(&shared, id), at(90));
+
+        clock.set(at(90));
+        let mut state = shared.lock();
+        let j = state.queue.dequeue().unwrap().into_jobs().remove(0);
+        state.started(1, &j, Trigger::Scheduled, 0, None, None);
+        state.requeue(j);
+        assert_eq!(state.queue.len(), 0);
+
+        // The run took longer than the interval
+        clock.set(at(200));
+        state.reaped(1, RunStatus::Exited(0), None);
+        assert_eq!(state.jobs[&id].get_prev(), at(90));
+        assert_eq!(state.jobs[&id].get_next(), at(290));
+        assert_eq!(state.queue.len(), 1);
+    }
+
+    #[test]
+    fn runs_one_shots_once() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let once = |t: DateTime<Local>| JobSpec::new("a", "/bin/true", &format!("at {}", t.to_rfc3339()));
+        let id = shared.add_job(once(at(60))).unwrap();
+        assert_eq!(next(&shared, id), at(60));
+
+        clock.set(at(60));
+        let mut state = shared.lock();
+        let j = state.queue.dequeue().unwrap().into_jobs().remove(0);
+        state.requeue(j);
+        assert_eq!(state.queue.len(), 0);
+        drop(state);
+
+        match shared.add_job(once(at(30))) {
+            Err(XcrondError::ScheduleFinished(_)) => {}
+            res => panic!("registered a past one-shot: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn defers_occurrences_in_blackout_windows() {
+        // 02:40 UTC, the next occurrence is at 03:00
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let spec = JobSpec::new("a", "/bin/true", "0 0 * * * *")
+            .with_timezone(Tz::UTC)
+            .with_blackout_policy(BlackoutPolicy::Defer);
+        let id = shared.add_job(spec).unwrap();
+        let mut state = shared.lock();
+        assert_eq!(state.blackout(&state.jobs[&id]), None);
+
+        state.blackouts = vec!["02:00-03:30".parse().unwrap()];
+        clock.set(at(1200));
+        let j = state.queue.dequeue().unwrap().into_jobs().remove(0);
+        assert_eq!(state.blackout(&j), Some(at(3000)));
+        state.defer(j, at(3000));
+        assert_eq!(state.jobs[&id].get_next(), at(3000));
+
+        let j = state.queue.dequeue().unwrap().into_jobs().remove(0);
+        assert_eq!(j.get_next(), at(3000));
+        assert_eq!(state.blackout(&j), None);
+    }
+
+    #[test]
+    fn applies_the_misfire_policy() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let once = shared.add_job(JobSpec::new("once", "/bin/true", "0 * * * * *")).unwrap();
+        let skip = JobSpec::new("skip", "/bin/true", "0 * * * * *").with_misfire_policy(MisfirePolicy::Skip);
+        let skip = shared.add_job(skip).unwrap();
+
+        // Suspended for 10 minutes
+        clock.set(at(600));
+        shared.lock().misfired(clock.now());
+        assert_eq!(next(&shared, once), at(60));
+        assert_eq!(next(&shared, skip), at(660));
+        assert_eq!(shared.queue_depth(), 2);
+    }
+
+    #[test]
+    fn registers_duplicates_once() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let a = shared.add_job(JobSpec::new("a", "/bin/true", "0 * * * * *")).unwrap();
+        let mut b = JobSpec::new("b", "/bin/true", "0 * * * * *");
+        b.metadata.insert("spool".to_string(), "/etc/cron.d/b".to_string());
+        match shared.add_job(b.clone()) {
+            Err(XcrondError::DuplicateJob { name, other }) => {
+                assert_eq!(name, "b (from /etc/cron.d/b)");
+                assert_eq!(other, format!("a {}", a));
+            }
+            res => panic!("registered a duplicate: {:?}", res),
+        }
+
+        // Registered once the other is removed
+        assert!(shared.remove_job(a));
+        assert!(shared.add_job(b).is_ok());
+    }
+
+    #[test]
+    fn never_reuses_job_ids() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let a = shared.add_job(JobSpec::new("a", "/bin/echo a", "0 * * * * *")).unwrap();
+        assert!(shared.remove_job(a));
+        let b = shared.add_job(JobSpec::new("b", "/bin/echo b", "0 * * * * *")).unwrap();
+        assert_ne!(a, b);
+
+        // Nor those given explicitly
+        let mut c = JobSpec::new("c", "/bin/echo c", "0 * * * * *");
+        c.id = Some(JobId::new(b.as_u64() + 5));
+        let c = shared.add_job(c).unwrap();
+        let d = shared.add_job(JobSpec::new("d", "/bin/echo d", "0 * * * * *")).unwrap();
+        assert!(d.as_u64() > c.as_u64());
+    }
+
+    #[test]
+    fn reloads_the_changed_jobs() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let own = shared.add_job(JobSpec::new("own", "/bin/true", "0 0 * * * *")).unwrap();
+        let config = |jobs: &[(&str, &str)]| Jobfile {
+            namespace: vec![],
+            job: jobs.iter().map(|(name, schedule)| JobSpec::new(name, &format!("/bin/{}", name), schedule)).collect(),
+            blackout: vec![],
+            mailto: None,
+            shell: None,
+            path: None,
+            env: Default::default(),
+        };
+        let id = |name: &str| shared.jobs().iter().find(|j| j.name == name).map(|j| j.id);
+
+        let loaded = shared.configure(config(&[("a", "0 * * * * *"), ("b", "0 * * * * *"), ("c", "0 * * * * *")]));
+        assert_eq!(loaded.added, 3);
+        let (a, b) = (id("a"), id("b"));
+        let jobs = |id: Option<JobId>| shared.lock().jobs[&id.unwrap()].clone();
+        let (old_a, old_b) = (jobs(a), jobs(b));
+
+        let reload = shared.configure(config(&[("a", "0 * * * * *"), ("b", "0 0 * * * *"), ("d", "0 * * * * *")]));
+        assert_eq!(reload, Reload { added: 1, updated: 1, removed: 1 });
+        assert_eq!((id("a"), id("b")), (a, b));
+        assert!(shared.lock().is_current(&old_a));
+        assert!(!shared.lock().is_current(&old_b));
+        assert!(shared.lock().is_queued(b.unwrap()));
+        // at(1200) is the top of the next hour
+        assert_eq!(next(&shared, b.unwrap()), at(1200));
+        assert!(id("c").is_none());
+        assert!(shared.lock().jobs.contains_key(&own));
+    }
+
+    #[test]
+    fn pipes_successful_runs() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let a = JobSpec::new("a", "/bin/true", "0 * * * * *").with_pipe_to("b");
+        let a = shared.add_job(a).unwrap();
+        let b = shared.add_job(JobSpec::new("b", "/bin/cat", "0 0 * * * *")).unwrap();
+        let mut state = shared.lock();
+        let j = state.jobs[&a].clone();
+
+        let (ok, _) = pipe::output(state.next_run_id()).unwrap();
+        state.started(1, &j, Trigger::Scheduled, 0, Some(ok.clone()), None);
+        let (failed, _) = pipe::output(state.next_run_id()).unwrap();
+        state.started(2, &j, Trigger::Scheduled, 0, Some(failed.clone()), None);
+
+        state.reaped(1, RunStatus::Exited(0), None);
+        assert_eq!(state.piped, vec![(b, ok.clone())]);
+        state.reaped(2, RunStatus::Exited(1), None);
+        assert!(!failed.exists());
+
+        // The input is removed once the downstream run is over
+        let j = state.jobs[&b].clone();
+        state.piped.clear();
+        state.started(3, &j, Trigger::Upstream, 0, None, Some(ok.clone()));
+        state.reaped(3, RunStatus::Exited(0), None);
+        assert!(!ok.exists());
+    }
+
+    #[test]
+    fn terminates_the_runs_exceeding_their_timeout() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let spec = JobSpec::new("a", "/bin/sleep 30", "0 * * * * *").with_timeout("1m");
+        let id = shared.add_job(spec).unwrap();
+        let mut state = shared.lock();
+        let j = state.jobs[&id].clone();
+
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id() as i32;
+        state.started(pid, &j, Trigger::Scheduled, 0, None, None);
+        assert_eq!(state.timeout_deadline(), Some(at(60)));
+        state.enforce_timeouts();
+        assert!(child.try_wait().unwrap().is_none());
+
+        // SIGTERM once timed out, then SIGKILL after the grace period
+        clock.advance(Duration::from_secs(60));
+        state.enforce_timeouts();
+        assert_eq!(state.timeout_deadline(), Some(at(70)));
+        clock.advance(TIMEOUT_GRACE);
+        state.enforce_timeouts();
+        assert_eq!(state.timeout_deadline(), None);
+        assert!(child.wait().unwrap().signal().is_some());
+    }
+
+    #[test]
+    fn terminates_the_replaced_runs() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let spec = JobSpec::new("a", "/bin/sleep 30", "0 * * * * *").with_overlap_policy(OverlapPolicy::Replace);
+        let id = shared.add_job(spec).unwrap();
+        let mut state = shared.lock();
+        let j = state.jobs[&id].clone();
+
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id() as i32;
+        state.started(pid, &j, Trigger::Scheduled, 0, None, None);
+        assert_eq!(state.running(id), vec![pid]);
+        assert_eq!(state.timeout_deadline(), None);
+
+        // Killed if it doesn't exit within the grace period
+        state.terminate_runs(id);
+        assert_eq!(state.timeout_deadline(), Some(at(10)));
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+        state.reaped(pid, RunStatus::Signaled("SIGTERM".to_string()), None);
+        assert!(state.running(id).is_empty());
+    }
+
+    #[test]
+    fn launches_the_jobs_that_waited_longest_first() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let add = |name: &str| shared.add_job(JobSpec::new(name, &format!("/bin/echo {}", name), "0 * * * * *")).unwrap();
+        let (a, b, c) = (add("a"), add("b"), add("c"));
+        let mut state = shared.lock();
+
+        // c was launched before b, a never was
+        let started = |state: &mut RunState, pid: i32, id: JobId| {
+            let j = state.jobs[&id].clone();
+            state.started(pid, &j, Trigger::Manual, 0, None, None);
+        };
+        clock.set(at(-120));
+        started(&mut state, 1, c);
+        clock.set(at(-60));
+        started(&mut state, 2, b);
+
+        // Only one launch is allowed right away, the others are delayed in order
+        let dispatch = |state: &mut RunState, pid: i32, t: DateTime<Local>| {
+            clock.set(t);
+            let top = state.queue.dequeue().unwrap();
+            assert_eq!(top.get_time(), t);
+            let order: Vec<JobId> = state.fair_order(top.into_jobs()).iter().map(|j| j.get_id()).collect();
+            let first = state.jobs[&order[0]].clone();
+            let mut rate = TokenBucket::new(1);
+            assert!(rate.take().is_ok());
+            state.started(pid, &first, Trigger::Scheduled, 0, None, None);
+            assert!(rate.take().is_err());
+            for id in &order {
+                let j = state.jobs[id].clone();
+                state.requeue(j);
+            }
+            order
+        };
+        assert_eq!(dispatch(&mut state, 3, at(60)), vec![a, c, b]);
+        assert_eq!(dispatch(&mut state, 4, at(120)), vec![c, b, a]);
+    }
+
+    #[test]
+    fn retries_failed_runs() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let spec = JobSpec::new("a", "/bin/false", "0 * * * * *").with_retries(2, "1m");
+        let id = shared.add_job(spec).unwrap();
+        let mut state = shared.lock();
+        let j = state.jobs[&id].clone();
+
+        // The run failed after 10s, it's retried a minute later
+        state.started(1, &j, Trigger::Scheduled, 0, None, None);
+        clock.advance(Duration::from_secs(10));
+        state.reaped(1, RunStatus::Exited(1), None);
+        assert_eq!((state.retries[0].trigger, state.retries[0].retry), (Trigger::Retry, 1));
+        drop(state);
+        let pending: Vec<DateTime<Local>> = shared.pending().iter().map(|f| f.time).collect();
+        assert_eq!(pending, vec![at(60), at(70)]);
+        let mut state = shared.lock();
+        clock.advance(Duration::from_secs(59));
+        assert!(state.due_retries().is_empty());
+        clock.advance(Duration::from_secs(1));
+        let r = state.due_retries().remove(0);
+        assert_eq!((r.trigger, r.retry), (Trigger::Retry, 1));
+
+        // The backoff doubles, until the last retry
+        state.started(2, &j, Trigger::Retry, 1, None, None);
+        state.reaped(2, RunStatus::Exited(1), None);
+        assert_eq!(state.retries[0].retry, 2);
+        assert_eq!(state.retries[0].due, clock.instant() + Duration::from_secs(120));
+        state.retries.clear();
+        state.started(3, &j, Trigger::Retry, 2, None, None);
+        state.reaped(3, RunStatus::Exited(1), None);
+        assert!(state.retries.is_empty());
+
+        // Successful and killed runs aren't retried
+        state.started(4, &j, Trigger::Scheduled, 0, None, None);
+        state.reaped(4, RunStatus::Exited(0), None);
+        state.started(5, &j, Trigger::Scheduled, 0, None, None);
+        state.reaped(5, RunStatus::Signaled("SIGKILL".to_string()), None);
+        assert!(state.retries.is_empty());
+    }
+
+    #[test]
+    fn recovers_the_interrupted_runs() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let id = shared.add_job(JobSpec::new("a", "/bin/true", "0 * * * * *")).unwrap();
+        let other = shared.add_job(JobSpec::new("b", "/bin/false", "0 * * * * *")).unwrap();
+
+        // The daemon crashed while running the occurrence of `a` at 60s,
+        // the one of `b` completed
+        let path = std::env::temp_dir().join(format!("xcrond-test-{}.journal", std::process::id()));
+        let record = |run: u64, job: JobId, t: i64| format!("start {} {} {}\n", run, job.as_u64(), at(t).to_rfc3339());
+        let content = record(1, other, 60) + "end 1\n" + &record(2, id, 60);
+        std::fs::write(&path, content).unwrap();
+
+        clock.set(at(90));
+        let (journal, mut entries) = Journal::open(&path).unwrap();
+        entries.sort_by_key(|e| e.run);
+        let finished: Vec<(RunId, bool)> = entries.iter().map(|e| (e.run, e.finished)).collect();
+        assert_eq!(finished, vec![(RunId::new(1), true), (RunId::new(2), false)]);
+        let mut state = shared.lock();
+        state.recover(entries);
+        assert_eq!(state.jobs[&id].get_prev(), at(60));
+        assert_eq!(state.jobs[&other].get_prev(), at(60));
+        drop(journal);
+
+        // Once recovered, the run isn't reported again
+        let (_, entries) = Journal::open(&path).unwrap();
+        assert!(entries.iter().all(|e| e.finished));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restores_the_state_of_renumbered_jobs() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let a = shared.add_job(JobSpec::new("a", "/bin/echo a", "0 * * * * *")).unwrap();
+        let b = shared.add_job(JobSpec::new("b", "/bin/echo b", "0 * * * * *")).unwrap();
+        let saved = |id: JobId, name: Option<&str>, t: i64| JobState {
+            id,
+            name: name.map(|n| n.to_string()),
+            prev: at(t),
+            last_result: None,
+        };
+
+        // Saved when `a` and `b` had each other's ids, and by a version
+        // not naming the jobs
+        let mut state = shared.lock();
+        state.restore(vec![saved(b, Some("a"), 60), saved(a, Some("b"), 120)]);
+        assert_eq!((state.jobs[&a].get_prev(), state.jobs[&b].get_prev()), (at(60), at(120)));
+        state.restore(vec![saved(a, None, 180), saved(JobId::new(a.as_u64() + 10), Some("c"), 240)]);
+        assert_eq!((state.jobs[&a].get_prev(), state.jobs[&b].get_prev()), (at(180), at(120)));
+
+        let entry = journal::Entry {
+            run: RunId::new(1),
+            job: a,
+            name: Some("b".to_string()),
+            occurrence: at(300),
+            finished: true,
+        };
+        state.recover(vec![entry]);
+        assert_eq!((state.jobs[&a].get_prev(), state.jobs[&b].get_prev()), (at(180), at(300)));
+    }
+
+    #[test]
+    fn retries_transient_spawn_failures() {
+        #[derive(Default)]
+        struct Failures(Mutex<Vec<u32>>);
+        impl SchedulerObserver for Failures {
+            fn job_spawn_failed(&self, _job: &JobInfo, attempts: u32, _error: &str) {
+                self.0.lock().unwrap().push(attempts);
+            }
+        }
+
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let id = shared.add_job(JobSpec::new("a", "/bin/true", "0 * * * * *")).unwrap();
+        let failures = Arc::new(Failures::default());
+        let mut state = shared.lock();
+        state.observers.push(failures.clone());
+        let j = state.jobs[&id].clone();
+
+        let eagain = || io::Error::from_raw_os_error(libc::EAGAIN);
+        state.spawn_failed(&j, Trigger::Scheduled, 1, 0, None, eagain());
+        let mut delay = SPAWN_RETRY_DELAY;
+        for attempt in 2..=MAX_SPAWN_ATTEMPTS {
+            clock.advance(delay - Duration::from_millis(1));
+            assert!(state.due_retries().is_empty());
+            clock.advance(Duration::from_millis(1));
+            let r = state.due_retries().remove(0);
+            assert_eq!((r.job.get_id(), r.trigger, r.attempt), (id, Trigger::Scheduled, attempt));
+            state.spawn_failed(&r.job, r.trigger, r.attempt, r.retry, r.input, eagain());
+            delay *= 2;
+        }
+
+        // Given up after the last attempt
+        assert!(state.retries.is_empty());
+        assert!(matches!(state.results[&id].status, RunStatus::FailedToStart(_)));
+        assert_eq!(*failures.0.lock().unwrap(), (1..=MAX_SPAWN_ATTEMPTS).collect::<Vec<_>>());
+
+        // Other failures aren't retried
+        let enoent = io::Error::from_raw_os_error(libc::ENOENT);
+        state.spawn_failed(&j, Trigger::Manual, 1, 0, None, enoent);
+        assert!(state.retries.is_empty());
+        assert_eq!(failures.0.lock().unwrap().len(), MAX_SPAWN_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn enforces_the_daily_budget() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let shared = shared(&clock);
+        let id = JobSpec::new("a", "/bin/true", "0 * * * * *").with_daily_budget(60);
+        let id = shared.add_job(id).unwrap();
+        let j = shared.lock().jobs[&id].clone();
+
+        for pid in 1..3 {
+            assert!(!shared.lock().over_budget(&j));
+            shared.lock().started(pid, &j, Trigger::Scheduled, 0, None, None);
+            clock.advance(Duration::from_secs(40));
+            shared.lock().reaped(pid, RunStatus::Exited(0), None);
+        }
+        assert!(shared.lock().over_budget(&j));
+
+        // The budget is renewed the next day
+        clock.advance(Duration::from_secs(86_400));
+        assert!(!shared.lock().over_budget(&j));
+    }
+}
@@ -0,0 +1,141 @@
+//! Scheduling state of the jobs persisted across restarts.
+//!
+//! The state file records when each job was last scheduled and the result
+//! of its last completed run, keyed by job id and name. It is rewritten
+//! atomically so a crash never leaves a truncated file behind.
+
+use crate::error::{Result, XcrondError};
+use crate::job::JobId;
+use crate::run::JobRunResult;
+use crate::schema::{self, STATE_MIGRATIONS};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// JobState is the persisted state of a single job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct JobState {
+    pub id: JobId,
+    /// name of the job, missing from the files of older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// last scheduled occurrence of the job
+    pub prev: DateTime<Local>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_result: Option<JobRunResult>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    #[serde(default)]
+    job: Vec<JobState>,
+}
+
+/// load_state reads the state file at `path`.
+/// A missing file is an empty state, as on the very first start.
+pub fn load_state(path: &Path) -> Result<Vec<JobState>> {
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(source) => {
+            return Err(XcrondError::Io {
+                path: path.to_path_buf(),
+                source,
+            })
+        }
+    };
+
+    let parse_err = |source| XcrondError::Parse {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    let mut doc: toml::Value = toml::from_str(&content).map_err(parse_err)?;
+    schema::migrate(&mut doc, STATE_MIGRATIONS, path)?;
+
+    let f: StateFile = doc.try_into().map_err(parse_err)?;
+    Ok(f.job)
+}
+
+/// save_state atomically replaces the state file at `path`
+pub fn save_state(path: &Path, jobs: Vec<JobState>) -> Result<()> {
+    let f = StateFile {
+        version: STATE_MIGRATIONS.len() as u32,
+        job: jobs,
+    };
+    let content = toml::to_string(&f).map_err(|source| XcrondError::Serialize {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    // Write to a temporary file next to the state file, then move it in place
+    let tmp = path.with_extension("tmp");
+    let write = || -> io::Result<()> {
+        let mut file = File::create(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    };
+    write().map_err(|source| XcrondError::Write {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{ResourceUsage, RunId, RunStatus, Trigger};
+    use std::env;
+    use std::time::Duration;
+
+    #[test]
+    fn saves_and_loads_the_state() {
+        let path = env::temp_dir().join(format!("xcrond-test-{}.state", std::process::id()));
+        let now = Local::now();
+        let result = JobRunResult {
+            run: RunId::new(7),
+            job: JobId::new(1),
+            name: "backup".to_string(),
+            pid: 42,
+            started: now,
+            finished: now,
+            trigger: Trigger::Scheduled,
+            status: RunStatus::Exited(3),
+            usage: Some(ResourceUsage {
+                max_rss: 1024,
+                user_time: Duration::from_millis(1500),
+                system_time: Duration::from_millis(20),
+            }),
+        };
+        let jobs = vec![
+            JobState {
+                id: JobId::new(1),
+                name: Some("backup".to_string()),
+                prev: now,
+                last_result: Some(result),
+            },
+            JobState {
+                id: JobId::new(2),
+                name: None,
+                prev: now,
+                last_result: None,
+            },
+        ];
+        save_state(&path, jobs).unwrap();
+        let loaded = load_state(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.iter().map(|j| j.id).collect::<Vec<_>>(), vec![JobId::new(1), JobId::new(2)]);
+        assert_eq!(loaded[0].name.as_deref(), Some("backup"));
+        assert_eq!(loaded[0].prev, now);
+        let r = loaded[0].last_result.as_ref().unwrap();
+        assert_eq!((r.run, r.pid, &r.status, r.trigger), (RunId::new(7), 42, &RunStatus::Exited(3), Trigger::Scheduled));
+        assert_eq!(r.usage.as_ref().map(|u| (u.max_rss, u.user_time)), Some((1024, Duration::from_millis(1500))));
+        assert!(loaded[1].last_result.is_none());
+        assert!(load_state(&path).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,74 @@
+//! Readiness and status notifications for systemd services of `Type=notify`.
+//!
+//! Implements the sd_notify protocol: state lines are sent as a datagram to
+//! the unix socket named by `$NOTIFY_SOCKET`. Without the variable, i.e.
+//! when not started by systemd, notifications are silently dropped.
+
+use crate::observer::SchedulerObserver;
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// notify sends state lines such as `READY=1` or `STATUS=...` to systemd.
+/// Returns false if the service wasn't started by systemd.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(p) => p,
+        None => return Ok(false),
+    };
+
+    let sock = UnixDatagram::unbound()?;
+    match path.to_str().and_then(|p| p.strip_prefix('@')) {
+        // Sockets in the abstract namespace are prefixed with @
+        Some(name) => send_abstract(&sock, name, state)?,
+        None => {
+            sock.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(true)
+}
+
+/// watchdog_interval returns how often the watchdog has to be petted, half
+/// the timeout configured with `WatchdogSec=`, or None if it's disabled
+pub fn watchdog_interval() -> Option<Duration> {
+    // The watchdog applies to a single process of the service
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Watchdog pets the systemd watchdog on every heartbeat of the run loop.
+/// Register it as an observer along with `CronBuilder::heartbeat`.
+pub struct Watchdog;
+
+impl SchedulerObserver for Watchdog {
+    fn heartbeat(&self) {
+        if let Err(err) = notify("WATCHDOG=1") {
+            error!("Failed to pet the systemd watchdog: {}", err);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(sock: &UnixDatagram, name: &str, state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+    sock.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_: &UnixDatagram, _: &str, _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
@@ -0,0 +1,95 @@
+//! Test support for the scheduling primitives, enabled by the `test-support`
+//! feature.
+//!
+//! Provides proptest generators of payloads due at colliding times, and
+//! checks of the invariants of `EventQueue`: events come out in time order,
+//! payloads due at the same time are merged into one event in the order they
+//! were enqueued, and no payload is lost nor duplicated.
+
+use crate::event::{Event, EventQueue, Scheduled};
+use chrono::{DateTime, Duration, Local, TimeZone};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::fmt::Debug;
+
+/// Item is a payload identified by its position in the generated sequence
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Item {
+    pub id: usize,
+    pub time: DateTime<Local>,
+}
+
+impl Scheduled for Item {
+    fn time(&self) -> DateTime<Local> {
+        self.time
+    }
+}
+
+/// time generates times on the minute within an hour, so generated payloads
+/// often fall due at the same time
+pub fn time() -> impl Strategy<Value = DateTime<Local>> {
+    let start = Local.timestamp_opt(1_500_000_000, 0).unwrap();
+    (0i64..60).prop_map(move |m| start + Duration::minutes(m))
+}
+
+/// items generates up to `max` items, numbered in order
+pub fn items(max: usize) -> impl Strategy<Value = Vec<Item>> {
+    vec(time(), 0..=max).prop_map(|times| {
+        times
+            .into_iter()
+            .enumerate()
+            .map(|(id, time)| Item { id, time })
+            .collect()
+    })
+}
+
+/// drain dequeues every event of the queue, checking that they come out in
+/// time order, hold only payloads due at their time and that the length of
+/// the queue accounts for their payloads
+pub fn drain<T: Scheduled + Debug>(q: &mut EventQueue<T>) -> Result<Vec<Event<T>>, TestCaseError> {
+    let mut events: Vec<Event<T>> = vec![];
+    let mut len = q.len();
+    while let Some(e) = q.dequeue() {
+        if e.get_jobs().is_empty() {
+            return Err(TestCaseError::fail(format!("empty event at {}", e.get_time())));
+        }
+        if let Some(prev) = events.last() {
+            if prev.get_time() >= e.get_time() {
+                return Err(TestCaseError::fail(format!("event at {} dequeued after {}", e.get_time(), prev.get_time())));
+            }
+        }
+        if let Some(j) = e.get_jobs().iter().find(|j| j.time() != e.get_time()) {
+            return Err(TestCaseError::fail(format!("{:?} in the event at {}", j, e.get_time())));
+        }
+
+        len = len
+            .checked_sub(e.get_jobs().len())
+            .ok_or_else(|| TestCaseError::fail(format!("queue length is short by {}", e.get_jobs().len() - len)))?;
+        if q.len() != len {
+            return Err(TestCaseError::fail(format!("queue length is {} instead of {}", q.len(), len)));
+        }
+        events.push(e);
+    }
+    if len != 0 || !q.is_empty() {
+        return Err(TestCaseError::fail(format!("{} payloads left in the drained queue", len)));
+    }
+    Ok(events)
+}
+
+/// check_dequeued checks that the `events` drained from a queue hold each of
+/// the `expected` items exactly once, items due at the same time in the
+/// order they were enqueued
+pub fn check_dequeued(expected: &[Item], events: &[Event<Item>]) -> Result<(), TestCaseError> {
+    let mut expected = expected.to_vec();
+    // Stable: the ids keep their order among items due at the same time
+    expected.sort_by_key(|i| i.time);
+    let dequeued: Vec<&Item> = events.iter().flat_map(|e| e.get_jobs()).collect();
+
+    if dequeued.len() != expected.len() {
+        return Err(TestCaseError::fail(format!("{} items dequeued out of {}", dequeued.len(), expected.len())));
+    }
+    match expected.iter().zip(dequeued).find(|(a, b)| a != b) {
+        Some((a, b)) => Err(TestCaseError::fail(format!("dequeued {:?} instead of {:?}", b, a))),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,137 @@
+//! Precise, cancellable wakeups for the run loop, backed by timerfd.
+//!
+//! The timer is armed at the absolute wall clock time of the next event
+//! with TFD_TIMER_CANCEL_ON_SET, so the wait is interrupted as soon as the
+//! system clock is changed. An eventfd is polled alongside the timer to wake
+//! the loop when the queue changes or a shutdown is requested.
+
+use chrono::{DateTime, Local};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+// Not exported by older libc releases
+const TFD_TIMER_CANCEL_ON_SET: libc::c_int = 1 << 1;
+
+/// WakeReason is why `Waker::wait_until` returned
+#[derive(Debug, PartialEq)]
+pub(crate) enum WakeReason {
+    /// the deadline was reached
+    Deadline,
+    /// `Waker::wake` was called
+    Notified,
+    /// the wall clock was changed while waiting
+    ClockChanged,
+}
+
+pub(crate) struct Waker {
+    timer: RawFd,
+    event: RawFd,
+}
+
+impl Waker {
+    pub fn new() -> io::Result<Self> {
+        let timer = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_REALTIME,
+                libc::TFD_CLOEXEC | libc::TFD_NONBLOCK,
+            )
+        };
+        if timer < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let event = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if event < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(timer) };
+            return Err(err);
+        }
+
+        Ok(Waker { timer, event })
+    }
+
+    /// wake interrupts a thread blocked in `wait_until`.
+    /// If no thread is waiting, the next wait returns immediately.
+    pub fn wake(&self) {
+        let one: u64 = 1;
+        unsafe { libc::write(self.event, &one as *const u64 as *const libc::c_void, 8) };
+    }
+
+    /// wait_until blocks until the deadline, a call to `wake` or a change
+    /// of the wall clock. Without a deadline, it only waits for `wake`.
+    pub fn wait_until(&self, deadline: Option<DateTime<Local>>) -> io::Result<WakeReason> {
+        self.arm(deadline)?;
+
+        let mut fds = [
+            libc::pollfd {
+                fd: self.timer,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.event,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+
+        loop {
+            let n = unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) };
+            if n >= 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+
+        if fds[1].revents & libc::POLLIN != 0 {
+            let _ = drain(self.event);
+            return Ok(WakeReason::Notified);
+        }
+
+        match drain(self.timer) {
+            Err(ref e) if e.raw_os_error() == Some(libc::ECANCELED) => Ok(WakeReason::ClockChanged),
+            _ => Ok(WakeReason::Deadline),
+        }
+    }
+
+    /// arm sets the timer to expire at the deadline, or disarms it
+    fn arm(&self, deadline: Option<DateTime<Local>>) -> io::Result<()> {
+        let mut spec: libc::itimerspec = unsafe { std::mem::zeroed() };
+        let mut flags = 0;
+        if let Some(t) = deadline {
+            // Deadlines in the past expire right away
+            spec.it_value.tv_sec = t.timestamp() as libc::time_t;
+            spec.it_value.tv_nsec = t.timestamp_subsec_nanos() as libc::c_long;
+            flags = libc::TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET;
+        }
+
+        let ret = unsafe { libc::timerfd_settime(self.timer, flags, &spec, ptr::null_mut()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.timer);
+            libc::close(self.event);
+        }
+    }
+}
+
+/// drain reads the counter of a timerfd or eventfd, resetting it
+fn drain(fd: RawFd) -> io::Result<u64> {
+    let mut buf: u64 = 0;
+    let n = unsafe { libc::read(fd, &mut buf as *mut u64 as *mut libc::c_void, 8) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(buf)
+}
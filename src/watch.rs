@@ -0,0 +1,220 @@
+//! Reloading of the Jobfile and its fragments when they change.
+//!
+//! On Linux the directories holding them are watched with inotify, rather
+//! than the files themselves: editors and package managers usually replace
+//! files by renaming a new one over them. Changes come in bursts, the jobs
+//! are reloaded once the files have been quiet for a moment. Elsewhere, or
+//! if inotify can't be used, the files are checked periodically.
+
+use crate::config;
+use crate::handle::CronHandle;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// How long the files have to be quiet before they are reloaded
+const SETTLE: Duration = Duration::from_millis(500);
+// How often the files are checked when they can't be watched
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// spawn starts a thread reloading the jobs of `handle` whenever the
+/// Jobfile at `path` or the fragments of `dir` change
+pub(crate) fn spawn(handle: CronHandle, path: Option<PathBuf>, dir: Option<PathBuf>) {
+    thread::spawn(move || {
+        #[cfg(target_os = "linux")]
+        match inotify::Watch::new(path.as_deref(), dir.as_deref()) {
+            Ok(watch) => {
+                info!("Watching the Jobfile for changes");
+                return watch.run(|| reload(&handle));
+            }
+            Err(err) => warn!("Failed to watch the Jobfile, checking it every {:?}: {}", POLL_INTERVAL, err),
+        }
+
+        let mut last = fingerprint(path.as_deref(), dir.as_deref());
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let current = fingerprint(path.as_deref(), dir.as_deref());
+            if current != last {
+                reload(&handle);
+                last = current;
+            }
+        }
+    });
+}
+
+fn reload(handle: &CronHandle) {
+    match handle.reload() {
+        Ok(r) if r.added + r.updated + r.removed == 0 => {}
+        Ok(r) => info!("Jobfile changed: {} jobs added, {} updated, {} removed", r.added, r.updated, r.removed),
+        Err(err) => error!("Failed to reload, keeping the current jobs: {}", err),
+    }
+}
+
+/// fingerprint identifies the state of the Jobfile and its fragments: when
+/// they were last modified and their size
+fn fingerprint(path: Option<&Path>, dir: Option<&Path>) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+    let fragments = dir.and_then(|d| config::fragments(d).ok()).unwrap_or_default();
+    path.map(Path::to_path_buf)
+        .into_iter()
+        .chain(fragments)
+        .map(|p| {
+            let meta = fs::metadata(&p).ok();
+            let modified = meta.as_ref().and_then(|m| m.modified().ok());
+            let len = meta.map_or(0, |m| m.len());
+            (p, modified, len)
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use super::SETTLE;
+    use std::ffi::{CString, OsStr};
+    use std::io;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::RawFd;
+    use std::path::Path;
+
+    // Events of the watched directories that may change the jobs
+    const EVENTS: u32 = libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_TO
+        | libc::IN_MOVED_FROM
+        | libc::IN_CREATE
+        | libc::IN_DELETE;
+
+    /// Watch is an inotify instance watching the directories of the
+    /// Jobfile and of its fragments
+    pub(super) struct Watch {
+        fd: RawFd,
+        /// watch of the directory of the Jobfile, with its name
+        jobfile: Option<(i32, Vec<u8>)>,
+        /// watch of the directory of the fragments
+        fragments: Option<i32>,
+    }
+
+    impl Watch {
+        pub fn new(path: Option<&Path>, dir: Option<&Path>) -> io::Result<Self> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut watch = Watch {
+                fd,
+                jobfile: None,
+                fragments: None,
+            };
+            if let Some(path) = path {
+                let parent = match path.parent() {
+                    Some(p) if !p.as_os_str().is_empty() => p,
+                    _ => Path::new("."),
+                };
+                let name = path.file_name().unwrap_or_default().as_bytes().to_vec();
+                watch.jobfile = Some((watch.add(parent)?, name));
+            }
+            if let Some(dir) = dir {
+                watch.fragments = Some(watch.add(dir)?);
+            }
+            Ok(watch)
+        }
+
+        fn add(&self, dir: &Path) -> io::Result<i32> {
+            let path = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+            let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), EVENTS) };
+            if wd < 0 {
+                let err = io::Error::last_os_error();
+                return Err(io::Error::new(err.kind(), format!("{}: {}", dir.display(), err)));
+            }
+            Ok(wd)
+        }
+
+        /// run calls `reload` every time the watched files change, once
+        /// they have settled
+        pub fn run<F: Fn()>(self, reload: F) {
+            loop {
+                match self.changed(-1) {
+                    Ok(false) => continue,
+                    Ok(true) => {}
+                    Err(err) => {
+                        error!("Failed to watch the Jobfile: {}", err);
+                        return;
+                    }
+                }
+                // Wait for the burst of events to end
+                while let Ok(true) = self.wait(SETTLE.as_millis() as i32) {
+                    if let Err(err) = self.changed(0) {
+                        error!("Failed to watch the Jobfile: {}", err);
+                        return;
+                    }
+                }
+                reload();
+            }
+        }
+
+        /// wait tells whether events are available within `timeout`
+        /// milliseconds, forever if negative
+        fn wait(&self, timeout: i32) -> io::Result<bool> {
+            let mut fd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut fd, 1, timeout) } {
+                n if n < 0 => {
+                    let err = io::Error::last_os_error();
+                    match err.kind() {
+                        io::ErrorKind::Interrupted => Ok(false),
+                        _ => Err(err),
+                    }
+                }
+                n => Ok(n > 0),
+            }
+        }
+
+        /// changed reads the pending events, waiting up to `timeout`
+        /// milliseconds for them, and tells whether one of the watched
+        /// files changed
+        fn changed(&self, timeout: i32) -> io::Result<bool> {
+            if !self.wait(timeout)? {
+                return Ok(false);
+            }
+            let mut buf = [0u64; 512];
+            let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, mem::size_of_val(&buf)) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, n as usize) };
+
+            let header = mem::size_of::<libc::inotify_event>();
+            let mut changed = false;
+            let mut offset = 0;
+            while offset + header <= bytes.len() {
+                let event = unsafe { &*(bytes.as_ptr().add(offset) as *const libc::inotify_event) };
+                let name = &bytes[offset + header..offset + header + event.len as usize];
+                // Names are padded with NUL bytes
+                let name = name.split(|b| *b == 0).next().unwrap_or_default();
+                changed |= event.mask & libc::IN_Q_OVERFLOW != 0 || self.watched(event.wd, name);
+                offset += header + event.len as usize;
+            }
+            Ok(changed)
+        }
+
+        /// watched tells whether `name`, in the directory watched by `wd`,
+        /// is the Jobfile or one of its fragments
+        fn watched(&self, wd: i32, name: &[u8]) -> bool {
+            let jobfile = self.jobfile.as_ref().is_some_and(|(w, n)| *w == wd && n == name);
+            let fragment = self.fragments == Some(wd)
+                && !name.starts_with(b".")
+                && Path::new(OsStr::from_bytes(name)).extension().is_some_and(|e| e == "toml");
+            jobfile || fragment
+        }
+    }
+
+    impl Drop for Watch {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}